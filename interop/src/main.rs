@@ -130,7 +130,9 @@ impl State {
             .await
             .map_err(|e| anyhow!("failed to open stream: {}", e))?;
         get(stream).await?;
-        conn.force_key_update();
+        if !conn.force_key_update() {
+            return Err(anyhow!("key update not permitted"));
+        }
         let stream = conn
             .open_bi()
             .await
@@ -340,7 +342,6 @@ fn run(options: Opt) -> Result<()> {
 
     Ok(())
 }
-const H3_INITIAL_CAPACITY: usize = 256;
 const H3_MAX_LEN: usize = 256 * 1024;
 
 async fn h3_get(conn: &quinn_h3::client::Connection) -> Result<()> {
@@ -355,7 +356,7 @@ async fn h3_get(conn: &quinn_h3::client::Connection) -> Result<()> {
         .await?
         .into_parts();
 
-    body.read_to_end(H3_INITIAL_CAPACITY, H3_MAX_LEN).await?;
+    body.read_to_end(H3_MAX_LEN).await?;
     Ok(())
 }
 
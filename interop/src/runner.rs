@@ -0,0 +1,242 @@
+//! Client for the QUIC interop runner
+//!
+//! Configured through the environment variables set by the runner: `TESTCASE` names the test,
+//! `REQUESTS` lists the URLs to download and `DOWNLOADS` the directory to save them in. Exits
+//! with 127 for unsupported test cases.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use futures::{future, TryFutureExt};
+use http::Uri;
+use quinn_h3::resolve::{Resolve, SystemResolver};
+use tokio::runtime::Builder;
+use tracing::{info, warn};
+
+/// Largest file downloaded
+const MAX_SIZE: usize = 64 * 1024 * 1024;
+
+fn main() {
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::FmtSubscriber::builder()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .finish(),
+    )
+    .unwrap();
+
+    let testcase = env::var("TESTCASE").unwrap_or_default();
+    let requests = env::var("REQUESTS").unwrap_or_default();
+    let downloads = env::var("DOWNLOADS").unwrap_or_else(|_| "/downloads".into());
+    let test = match Test::from_name(&testcase) {
+        Some(test) => test,
+        None => {
+            eprintln!("unsupported test case: {:?}", testcase);
+            std::process::exit(127);
+        }
+    };
+
+    let mut runtime = Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    let result = runtime.block_on(async {
+        let requests = requests
+            .split_whitespace()
+            .map(|x| x.parse::<Uri>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let runner = Runner::new(requests, downloads.into()).await?;
+        runner.run(test).await
+    });
+    if let Err(e) = result {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Test {
+    /// Download the files over a single hq-interop connection, concurrently
+    Transfer,
+    /// Download the first file, then the others over a resumed connection
+    Resumption,
+    /// Download the first file, then the others as 0-RTT data
+    ZeroRtt,
+    /// Download the files over HTTP/3
+    Http3,
+}
+
+impl Test {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "handshake" | "transfer" | "multiplexing" | "retry" => Test::Transfer,
+            "resumption" => Test::Resumption,
+            "zerortt" => Test::ZeroRtt,
+            "http3" => Test::Http3,
+            _ => return None,
+        })
+    }
+}
+
+struct Runner {
+    requests: Vec<Uri>,
+    downloads: PathBuf,
+    host: String,
+    remote: std::net::SocketAddr,
+}
+
+impl Runner {
+    async fn new(requests: Vec<Uri>, downloads: PathBuf) -> Result<Self> {
+        let first = requests.first().ok_or_else(|| anyhow!("no requests"))?;
+        let host = first
+            .host()
+            .ok_or_else(|| anyhow!("no host in {}", first))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let port = first.port_u16().unwrap_or(443);
+        let remote = *SystemResolver
+            .resolve(&host, port)
+            .await?
+            .first()
+            .ok_or_else(|| anyhow!("couldn't resolve {}", host))?;
+        Ok(Self {
+            requests,
+            downloads,
+            host,
+            remote,
+        })
+    }
+
+    async fn run(&self, test: Test) -> Result<()> {
+        if test == Test::Http3 {
+            return self.h3().await;
+        }
+
+        let mut config = quinn::ClientConfigBuilder::default();
+        config
+            .protocols(&[quinn_h3::hq::ALPN])
+            .enable_keylog()
+            .certificate_verifier(Arc::new(AcceptAny));
+        let mut endpoint = quinn::Endpoint::builder();
+        endpoint.default_client_config(config.build());
+        let (driver, endpoint, _) = endpoint.bind(&"[::]:0".parse().unwrap())?;
+        tokio::spawn(driver.unwrap_or_else(|e| eprintln!("IO error: {}", e)));
+
+        let (first, rest) = match test {
+            Test::Transfer => (&self.requests[..], &[][..]),
+            _ => self.requests.split_at(1),
+        };
+        let conn = self.connect(&endpoint).await?;
+        self.hq_download(&conn, first).await?;
+        conn.close(0u32.into(), b"done");
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let connecting = endpoint.connect(&self.remote, &self.server_name())?;
+        let conn = match test {
+            Test::ZeroRtt => match connecting.into_0rtt() {
+                Ok((new_conn, _)) => {
+                    tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+                    new_conn.connection
+                }
+                Err(_) => bail!("no 0-RTT ticket was received"),
+            },
+            _ => {
+                let new_conn = connecting.await?;
+                tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+                new_conn.connection
+            }
+        };
+        self.hq_download(&conn, rest).await?;
+        conn.close(0u32.into(), b"done");
+        Ok(())
+    }
+
+    async fn connect(&self, endpoint: &quinn::Endpoint) -> Result<quinn::Connection> {
+        let new_conn = endpoint
+            .connect(&self.remote, &self.server_name())?
+            .await
+            .map_err(|e| anyhow!("failed to connect: {}", e))?;
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        Ok(new_conn.connection)
+    }
+
+    async fn hq_download(&self, conn: &quinn::Connection, requests: &[Uri]) -> Result<()> {
+        future::try_join_all(requests.iter().map(|uri| async move {
+            let body = quinn_h3::hq::get(conn, uri.path(), MAX_SIZE).await?;
+            self.save(uri, &body)
+        }))
+        .await?;
+        Ok(())
+    }
+
+    async fn h3(&self) -> Result<()> {
+        let mut builder = quinn_h3::client::Builder::default();
+        builder.enable_keylog().accept_insecure_certs();
+        let (driver, client) = builder.bind(&"[::]:0".parse().unwrap())?;
+        tokio::spawn(driver.unwrap_or_else(|e| eprintln!("IO error: {}", e)));
+
+        let (quic_driver, h3_driver, conn) = client
+            .connect_any(vec![self.remote], &self.server_name())
+            .await?;
+        tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+        tokio::spawn(h3_driver.unwrap_or_else(|_| ()));
+
+        let connection = &conn;
+        future::try_join_all(self.requests.iter().map(|uri| async move {
+            let request = http::Request::get(uri.clone()).body(())?;
+            let response = connection.request(request).send().await?;
+            if !response.status().is_success() {
+                bail!("{} failed with {}", uri, response.status());
+            }
+            let (body, _) = response.into_body().read_to_end(MAX_SIZE).await?;
+            self.save(uri, &body)
+        }))
+        .await?;
+        conn.close();
+        Ok(())
+    }
+
+    /// The name to verify the certificate against, which must not be an IP address
+    fn server_name(&self) -> String {
+        if webpki::DNSNameRef::try_from_ascii_str(&self.host).is_ok() {
+            self.host.clone()
+        } else {
+            warn!("invalid hostname, using \"example.com\"");
+            "example.com".into()
+        }
+    }
+
+    fn save(&self, uri: &Uri, body: &[u8]) -> Result<()> {
+        let path = self
+            .downloads
+            .join(Path::new(uri.path().trim_start_matches('/')));
+        info!("saving {} bytes to {}", body.len(), path.display());
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, body)?;
+        Ok(())
+    }
+}
+
+/// Interop runner servers use self-signed certificates
+struct AcceptAny;
+
+impl rustls::ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
@@ -6,7 +6,7 @@ use std::{
 };
 
 use anyhow::{anyhow, Result};
-use futures::{StreamExt, TryFutureExt};
+use futures::TryFutureExt;
 use http::{header::HeaderValue, method::Method, HeaderMap, Request, Response, StatusCode};
 use structopt::{self, StructOpt};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -17,7 +17,7 @@ use quinn_h3::{
     self,
     body::RecvBody,
     client::{Builder as ClientBuilder, Client},
-    connection::ConnectionDriver,
+    connection::{ConnectionDriver, Driver},
     server::{Builder as ServerBuilder, IncomingRequest, Sender},
 };
 
@@ -108,7 +108,7 @@ fn server(options: Opt, certs: (CertificateChain, PrivateKey)) -> Result<quinn::
 
     tokio::spawn(async move {
         println!("server listening");
-        while let Some(connecting) = incoming.next().await {
+        while let Some(connecting) = incoming.accept().await {
             println!("server received connection");
             let connection = connecting
                 .await
@@ -124,12 +124,6 @@ fn server(options: Opt, certs: (CertificateChain, PrivateKey)) -> Result<quinn::
 
 async fn handle_connection(conn: (QuicDriver, ConnectionDriver, IncomingRequest)) {
     let (quic_driver, h3_driver, mut incoming) = conn;
-    tokio::spawn(async move {
-        if let Err(e) = h3_driver.await {
-            eprintln!("h3 server error: {}", e)
-        }
-    });
-
     tokio::spawn(async move {
         while let Some(request) = incoming.next().await {
             let (req, send) = request.await.expect("receiving request failed");
@@ -139,8 +133,8 @@ async fn handle_connection(conn: (QuicDriver, ConnectionDriver, IncomingRequest)
         }
     });
 
-    if let Err(e) = quic_driver.await {
-        eprintln!("quic server error: {}", e)
+    if let Err(e) = Driver::new(quic_driver, h3_driver).await {
+        eprintln!("server connection error: {}", e)
     }
 }
 
@@ -148,13 +142,11 @@ async fn handle_request(request: Request<RecvBody>, sender: Sender) -> Result<()
     println!("received request: {:?}", request);
     let (_, body) = request.into_parts();
     let (content, trailers) = body
-        .read_to_end(1024, 10 * 1024)
+        .read_to_end(10 * 1024)
         .await
         .map_err(|e| anyhow!("receive body failed: {:?}", e))?;
 
-    if let Some(content) = content {
-        println!("server received body len: {:?}", content.len());
-    }
+    println!("server received body len: {:?}", content.len());
     if let Some(trailers) = trailers {
         println!("received trailers: {:?}", trailers);
     }
@@ -189,18 +181,10 @@ async fn handle_request(request: Request<RecvBody>, sender: Sender) -> Result<()
 }
 
 fn build_client(cert: Certificate) -> Result<(Client, quinn::EndpointDriver)> {
-    let mut endpoint = quinn::Endpoint::builder();
-    let mut client_config = quinn::ClientConfigBuilder::default();
-    client_config.protocols(&[quinn_h3::ALPN]);
-
-    client_config.add_certificate_authority(cert)?;
-    endpoint.default_client_config(client_config.build());
-
-    let (endpoint_driver, endpoint, _) = endpoint.bind(&"[::]:0".parse().unwrap())?;
-    Ok((
-        ClientBuilder::new().endpoint(endpoint.clone()),
-        endpoint_driver,
-    ))
+    let mut client = ClientBuilder::new();
+    client.add_certificate_authority(cert)?;
+    let (endpoint_driver, client) = client.bind(&"[::]:0".parse().unwrap())?;
+    Ok((client, endpoint_driver))
 }
 
 async fn client_request(client: Client, remote: &SocketAddr) -> Result<()> {
@@ -211,12 +195,6 @@ async fn client_request(client: Client, remote: &SocketAddr) -> Result<()> {
         .map_err(|e| anyhow!("failed ot connect: {:?}", e))?;
     eprintln!("client connected at {:?}", start.elapsed());
 
-    tokio::spawn(async move {
-        if let Err(e) = h3_driver.await {
-            eprintln!("h3 client error: {}", e)
-        }
-    });
-
     tokio::spawn(async move {
         let request = Request::builder()
             .method(Method::POST)
@@ -269,8 +247,8 @@ async fn client_request(client: Client, remote: &SocketAddr) -> Result<()> {
         conn.close();
     });
 
-    if let Err(e) = quic_driver.await {
-        eprintln!("quic client error: {}", e)
+    if let Err(e) = Driver::new(quic_driver, h3_driver).await {
+        eprintln!("client connection error: {}", e)
     }
     Ok(())
 }
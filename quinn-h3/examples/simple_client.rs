@@ -29,7 +29,6 @@ struct Opt {
     cert: Option<PathBuf>,
 }
 
-const INITIAL_CAPACITY: usize = 256;
 const MAX_LEN: usize = 256 * 1024;
 
 #[tokio::main]
@@ -107,14 +106,9 @@ async fn request(client: Client, remote: &SocketAddr) -> Result<()> {
 
         println!("received response: {:?}", response);
 
-        let (content, trailers) = body
-            .read_to_end(INITIAL_CAPACITY, MAX_LEN)
-            .await
-            .expect("read body");
+        let (content, trailers) = body.read_to_end(MAX_LEN).await.expect("read body");
 
-        if let Some(content) = content {
-            println!("received body: {}", String::from_utf8_lossy(&content));
-        }
+        println!("received body: {}", String::from_utf8_lossy(&content));
         if let Some(trailers) = trailers {
             println!("received trailers: {:?}", trailers);
         }
@@ -1,7 +1,6 @@
 use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
 use anyhow::{anyhow, Result};
-use futures::StreamExt;
 use http::{Request, Response, StatusCode};
 use structopt::{self, StructOpt};
 
@@ -72,7 +71,7 @@ async fn main() -> Result<()> {
     });
 
     println!("server listening");
-    while let Some(connecting) = incoming.next().await {
+    while let Some(connecting) = incoming.accept().await {
         println!("server received connection");
         let connection = connecting
             .await
@@ -114,7 +113,6 @@ async fn handle_connection(conn: (QuicDriver, ConnectionDriver, IncomingRequest)
     Ok(())
 }
 
-const INITIAL_CAPACITY: usize = 256;
 const MAX_LEN: usize = 256;
 
 async fn handle_request(request: Request<RecvBody>, sender: Sender) -> Result<()> {
@@ -123,13 +121,11 @@ async fn handle_request(request: Request<RecvBody>, sender: Sender) -> Result<()
     let (_, body) = request.into_parts();
 
     let (content, trailers) = body
-        .read_to_end(INITIAL_CAPACITY, MAX_LEN)
+        .read_to_end(MAX_LEN)
         .await
         .map_err(|e| anyhow!("failed to send response headers: {:?}", e))?;
 
-    if let Some(content) = content {
-        println!("received body: {}", String::from_utf8_lossy(&content));
-    }
+    println!("received body: {}", String::from_utf8_lossy(&content));
     if let Some(trailers) = trailers {
         println!("received trailers: {:?}", trailers);
     }
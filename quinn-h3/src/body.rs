@@ -1,6 +1,8 @@
 use std::{
-    cmp, fmt,
-    io::{self, ErrorKind},
+    cmp,
+    collections::VecDeque,
+    fmt,
+    io::{self, ErrorKind, IoSlice},
     mem,
     pin::Pin,
     task::{Context, Poll},
@@ -8,26 +10,31 @@ use std::{
 
 use bytes::{Bytes, BytesMut};
 use futures::{
+    future,
     io::{AsyncRead, AsyncWrite},
     ready,
     stream::Stream,
+    task,
 };
 use http::HeaderMap;
 use quinn::SendStream;
 use quinn_proto::StreamId;
 use std::future::Future;
+use tracing::Span;
 
 use crate::{
     connection::ConnectionRef,
     frame::{FrameStream, WriteFrame},
+    har::Recording,
     headers::{DecodeHeaders, SendHeaders},
+    metrics::ResponseTracker,
     proto::{
-        frame::{DataFrame, HeadersFrame, HttpFrame},
+        frame::{ExtensionFrame, HeadersFrame, HttpFrame},
         headers::Header,
         ErrorCode,
     },
     streams::Reset,
-    try_take, Error,
+    Error,
 };
 
 pub enum Body {
@@ -57,7 +64,9 @@ pub struct RecvBody {
     recv: FrameStream,
     conn: ConnectionRef,
     stream_id: StreamId,
+    span: Span,
     finish_request: bool,
+    recording: Option<Recording>,
 }
 
 #[must_use = "body must be read or canceled"] // else, request might never be finished
@@ -66,28 +75,37 @@ impl RecvBody {
         recv: FrameStream,
         conn: ConnectionRef,
         stream_id: StreamId,
+        span: Span,
         finish_request: bool,
+        recording: Option<Recording>,
     ) -> Self {
         RecvBody {
             conn,
             stream_id,
+            span,
             recv,
             finish_request,
+            recording,
         }
     }
 
-    pub fn read_to_end(self, capacity: usize, size_limit: usize) -> ReadToEnd {
+    /// Receive the whole body and the trailers, if any
+    ///
+    /// Fails with `Error::BodyTooLarge` once more than `max_size` bytes of data are received.
+    pub fn read_to_end(self, max_size: usize) -> ReadToEnd {
         ReadToEnd::new(
             self.recv,
-            capacity,
-            size_limit,
+            max_size,
             self.conn,
             self.stream_id,
+            self.span,
             self.finish_request,
+            self.recording,
         )
     }
 
     pub fn cancel(self) {
+        self.conn.request_cancelled();
         self.recv.reset(ErrorCode::REQUEST_CANCELLED);
         if self.finish_request {
             self.conn
@@ -100,11 +118,23 @@ impl RecvBody {
     }
 
     pub fn into_reader(self) -> BodyReader {
-        BodyReader::new(self.recv, self.conn, self.stream_id, self.finish_request)
+        BodyReader::new(
+            self.recv,
+            self.conn,
+            self.stream_id,
+            self.span,
+            self.finish_request,
+        )
     }
 
     pub fn into_stream(self) -> RecvBodyStream {
-        RecvBodyStream::new(self.recv, self.conn, self.stream_id, self.finish_request)
+        RecvBodyStream::new(
+            self.recv,
+            self.conn,
+            self.stream_id,
+            self.span,
+            self.finish_request,
+        )
     }
 }
 
@@ -116,92 +146,118 @@ impl fmt::Debug for RecvBody {
 
 pub struct ReadToEnd {
     state: ReadToEndState,
-    body: Option<Bytes>,
+    chunks: Vec<Bytes>,
+    len: usize,
+    max_size: usize,
     conn: ConnectionRef,
     stream_id: StreamId,
+    span: Span,
     finish_request: bool,
+    recording: Option<Recording>,
 }
 
 impl ReadToEnd {
     pub(crate) fn new(
         recv: FrameStream,
-        capacity: usize,
-        size_limit: usize,
+        max_size: usize,
         conn: ConnectionRef,
         stream_id: StreamId,
+        span: Span,
         finish_request: bool,
+        recording: Option<Recording>,
     ) -> Self {
         Self {
             conn,
             stream_id,
-            body: None,
-            state: ReadToEndState::Receiving(recv, BytesMut::with_capacity(capacity), size_limit),
+            span,
+            recording,
+            chunks: Vec::new(),
+            len: 0,
+            max_size,
+            state: ReadToEndState::Receiving(recv),
             finish_request,
         }
     }
 
     pub fn cancel(mut self) {
+        self.conn.request_cancelled();
         let state = mem::replace(&mut self.state, ReadToEndState::Finished);
-        if let ReadToEndState::Receiving(recv, _, _) = state {
+        if let ReadToEndState::Receiving(recv) = state {
             recv.reset(ErrorCode::REQUEST_CANCELLED);
         }
     }
+
+    /// Concatenate the received chunks, copying only if there is more than one
+    fn body(&mut self) -> Bytes {
+        let mut chunks = mem::take(&mut self.chunks);
+        let body = if chunks.len() <= 1 {
+            chunks.pop().unwrap_or_default()
+        } else {
+            let mut body = BytesMut::with_capacity(self.len);
+            for chunk in chunks {
+                body.extend_from_slice(&chunk);
+            }
+            body.freeze()
+        };
+        if let Some(ref recording) = self.recording {
+            recording.response_body(&body);
+        }
+        body
+    }
 }
 
 enum ReadToEndState {
-    Receiving(FrameStream, BytesMut, usize),
+    Receiving(FrameStream),
     Decoding(DecodeHeaders),
     Finished,
 }
 
 impl Future for ReadToEnd {
-    type Output = Result<(Option<Bytes>, Option<HeaderMap>), crate::Error>;
+    type Output = Result<(Bytes, Option<HeaderMap>), crate::Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let span = self.span.clone();
+        let _guard = span.enter();
         loop {
             match self.state {
-                ReadToEndState::Receiving(ref mut recv, ref mut body, size_limit) => {
+                ReadToEndState::Receiving(ref mut recv) => {
                     match ready!(Pin::new(recv).poll_next(cx)) {
-                        Some(Err(e)) => return Poll::Ready(Err(e.into())),
                         Some(Ok(HttpFrame::Data(d))) => {
-                            if d.payload.len() + body.len() >= size_limit {
-                                return Poll::Ready(Err(Error::Overflow));
+                            if d.payload.len() > self.max_size - self.len {
+                                let limit = self.max_size;
+                                if let ReadToEndState::Receiving(recv) =
+                                    mem::replace(&mut self.state, ReadToEndState::Finished)
+                                {
+                                    recv.reset(ErrorCode::REQUEST_CANCELLED);
+                                }
+                                return Poll::Ready(Err(Error::BodyTooLarge { limit }));
                             }
-                            body.extend(d.payload);
+                            self.len += d.payload.len();
+                            self.chunks.push(d.payload);
                         }
+                        Some(Ok(HttpFrame::Extension(_))) => (),
                         Some(Ok(HttpFrame::Headers(t))) => {
                             let decode_trailer =
                                 DecodeHeaders::new(t, self.conn.clone(), self.stream_id);
-                            let old_state = mem::replace(
-                                &mut self.state,
-                                ReadToEndState::Decoding(decode_trailer),
-                            );
-                            match old_state {
-                                ReadToEndState::Receiving(_, b, _) => self.body = Some(b.freeze()),
-                                _ => unreachable!(),
-                            };
+                            self.state = ReadToEndState::Decoding(decode_trailer);
                         }
                         None => {
-                            let body = match mem::replace(&mut self.state, ReadToEndState::Finished)
-                            {
-                                ReadToEndState::Receiving(_, b, _) => match b.len() {
-                                    0 => None,
-                                    _ => Some(b.freeze()),
-                                },
-                                _ => unreachable!(),
-                            };
-                            return Poll::Ready(Ok((body, None)));
+                            self.state = ReadToEndState::Finished;
+                            return Poll::Ready(Ok((self.body(), None)));
                         }
                         Some(x) => {
                             let (err_code, error) = match x {
                                 Ok(_) => (
                                     ErrorCode::FRAME_UNEXPECTED,
-                                    Poll::Ready(Err(Error::peer("invalid frame type in data"))),
+                                    Poll::Ready(Err(Error::peer(
+                                        ErrorCode::FRAME_UNEXPECTED,
+                                        "invalid frame type in data",
+                                    ))),
                                 ),
                                 Err(e) => (e.code(), Poll::Ready(Err(e.into()))),
                             };
                             match mem::replace(&mut self.state, ReadToEndState::Finished) {
-                                ReadToEndState::Receiving(recv, _, _) => {
+                                ReadToEndState::Receiving(recv) => {
                                     recv.reset(err_code);
                                 }
                                 _ => unreachable!(),
@@ -213,10 +269,7 @@ impl Future for ReadToEnd {
                 ReadToEndState::Decoding(ref mut trailer) => {
                     let trailer = ready!(Pin::new(trailer).poll(cx))?;
                     self.state = ReadToEndState::Finished;
-                    return Poll::Ready(Ok((
-                        Some(try_take(&mut self.body, "body absent")?),
-                        Some(trailer.into_fields()),
-                    )));
+                    return Poll::Ready(Ok((self.body(), Some(trailer.into_fields()))));
                 }
                 _ => return Poll::Ready(Err(Error::Poll)),
             }
@@ -240,8 +293,11 @@ impl Drop for ReadToEnd {
 pub struct RecvBodyStream {
     recv: Option<FrameStream>,
     trailers: Option<HeadersFrame>,
+    extensions: ExtensionFrames,
+    buf: Option<Bytes>,
     conn: ConnectionRef,
     stream_id: StreamId,
+    span: Span,
     finish_request: bool,
 }
 
@@ -250,17 +306,34 @@ impl RecvBodyStream {
         recv: FrameStream,
         conn: ConnectionRef,
         stream_id: StreamId,
+        span: Span,
         finish_request: bool,
     ) -> Self {
         RecvBodyStream {
             conn,
             stream_id,
+            span,
             finish_request,
             recv: Some(recv),
             trailers: None,
+            extensions: ExtensionFrames::default(),
+            buf: None,
         }
     }
 
+    /// Keep extension frames of type `ty` received from now on
+    ///
+    /// Extension frames of types which weren't registered are ignored.
+    pub fn recv_extension_frames(&mut self, ty: u64) -> &mut Self {
+        self.extensions.types.push(ty);
+        self
+    }
+
+    /// Take the oldest extension frame received so far
+    pub fn extension_frame(&mut self) -> Option<ExtensionFrame> {
+        self.extensions.frames.pop_front()
+    }
+
     pub fn has_trailers(&self) -> bool {
         self.trailers.is_some()
     }
@@ -274,10 +347,10 @@ impl RecvBodyStream {
     }
 
     pub fn cancel(mut self) {
-        self.recv
-            .take()
-            .unwrap()
-            .reset(ErrorCode::REQUEST_CANCELLED);
+        self.conn.request_cancelled();
+        if let Some(recv) = self.recv.take() {
+            recv.reset(ErrorCode::REQUEST_CANCELLED);
+        }
     }
 }
 
@@ -285,22 +358,40 @@ impl Stream for RecvBodyStream {
     type Item = Result<Bytes, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let res = ready!(Pin::new(&mut self.recv.as_mut().unwrap()).poll_next(cx));
-        match res {
-            None => Poll::Ready(None),
-            Some(Ok(HttpFrame::Data(d))) => Poll::Ready(Some(Ok(d.payload))),
-            Some(Ok(HttpFrame::Headers(d))) => {
-                self.trailers = Some(d);
-                Poll::Ready(None)
-            }
-            Some(Ok(_)) => {
-                self.recv.take().unwrap().reset(ErrorCode::FRAME_UNEXPECTED);
-                Poll::Ready(Some(Err(Error::peer("invalid frame type in data"))))
-            }
-            Some(Err(e)) => {
-                self.recv.take().unwrap().reset(e.code());
-                Poll::Ready(Some(Err(e.into())))
-            }
+        let span = self.span.clone();
+        let _guard = span.enter();
+        if let Some(buf) = self.buf.take() {
+            return Poll::Ready(Some(Ok(buf)));
+        }
+        loop {
+            let recv = match self.recv.as_mut() {
+                Some(recv) => recv,
+                None => return Poll::Ready(None),
+            };
+            let res = ready!(Pin::new(recv).poll_next(cx));
+            return match res {
+                None => Poll::Ready(None),
+                Some(Ok(HttpFrame::Data(d))) => Poll::Ready(Some(Ok(d.payload))),
+                Some(Ok(HttpFrame::Headers(d))) => {
+                    self.trailers = Some(d);
+                    Poll::Ready(None)
+                }
+                Some(Ok(HttpFrame::Extension(f))) => {
+                    self.extensions.push(f);
+                    continue;
+                }
+                Some(Ok(_)) => {
+                    self.recv.take().unwrap().reset(ErrorCode::FRAME_UNEXPECTED);
+                    Poll::Ready(Some(Err(Error::peer(
+                        ErrorCode::FRAME_UNEXPECTED,
+                        "invalid frame type in data",
+                    ))))
+                }
+                Some(Err(e)) => {
+                    self.recv.take().unwrap().reset(e.code());
+                    Poll::Ready(Some(Err(e.into())))
+                }
+            };
         }
     }
 }
@@ -323,7 +414,9 @@ pub struct BodyReader {
     trailers: Option<HeadersFrame>,
     conn: ConnectionRef,
     stream_id: StreamId,
+    span: Span,
     buf: Option<Bytes>,
+    extensions: ExtensionFrames,
     finish_request: bool,
 }
 
@@ -332,18 +425,34 @@ impl BodyReader {
         recv: FrameStream,
         conn: ConnectionRef,
         stream_id: StreamId,
+        span: Span,
         finish_request: bool,
     ) -> Self {
         BodyReader {
             conn,
             stream_id,
+            span,
             finish_request,
             buf: None,
             trailers: None,
             recv: Some(recv),
+            extensions: ExtensionFrames::default(),
         }
     }
 
+    /// Keep extension frames of type `ty` received from now on
+    ///
+    /// Extension frames of types which weren't registered are ignored.
+    pub fn recv_extension_frames(&mut self, ty: u64) -> &mut Self {
+        self.extensions.types.push(ty);
+        self
+    }
+
+    /// Take the oldest extension frame received so far
+    pub fn extension_frame(&mut self) -> Option<ExtensionFrame> {
+        self.extensions.frames.pop_front()
+    }
+
     fn buf_read(&mut self, buf: &mut [u8]) -> usize {
         match self.buf {
             None => 0,
@@ -372,10 +481,28 @@ impl BodyReader {
     }
 
     pub fn cancel(mut self) {
+        self.conn.request_cancelled();
         if let Some(recv) = self.recv.take() {
             recv.reset(ErrorCode::REQUEST_CANCELLED);
         }
     }
+
+    /// Receive the rest of the body as a stream of chunks, without copying them
+    ///
+    /// Trailers are available from the stream once it has ended.
+    pub fn into_stream(mut self) -> RecvBodyStream {
+        let finish_request = mem::replace(&mut self.finish_request, false);
+        RecvBodyStream {
+            recv: self.recv.take(),
+            trailers: self.trailers.take(),
+            extensions: mem::take(&mut self.extensions),
+            buf: self.buf.take(),
+            conn: self.conn.clone(),
+            stream_id: self.stream_id,
+            span: self.span.clone(),
+            finish_request,
+        }
+    }
 }
 
 impl AsyncRead for BodyReader {
@@ -384,46 +511,54 @@ impl AsyncRead for BodyReader {
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<Result<usize, io::Error>> {
+        let span = self.span.clone();
+        let _guard = span.enter();
         let size = self.buf_read(buf);
         if size == buf.len() {
             return Poll::Ready(Ok(size));
         }
 
-        match Pin::new(self.recv.as_mut().unwrap()).poll_next(cx) {
-            Poll::Ready(None) => Poll::Ready(Ok(size)),
-            Poll::Pending => {
-                if size > 0 {
+        loop {
+            return match Pin::new(self.recv.as_mut().unwrap()).poll_next(cx) {
+                Poll::Ready(None) => Poll::Ready(Ok(size)),
+                Poll::Pending => {
+                    if size > 0 {
+                        Poll::Ready(Ok(size))
+                    } else {
+                        Poll::Ready(Err(io::Error::new(ErrorKind::WouldBlock, "stream blocked")))
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    self.recv.take().unwrap().reset(e.code());
+                    Poll::Ready(Err(io::Error::new(
+                        ErrorKind::Other,
+                        format!("read error: {:?}", e),
+                    )))
+                }
+                Poll::Ready(Some(Ok(HttpFrame::Data(mut d)))) => {
+                    if d.payload.len() >= buf.len() - size {
+                        let tail = d.payload.split_off(buf.len() - size);
+                        self.buf_put(tail);
+                    }
+                    buf[size..size + d.payload.len()].copy_from_slice(&d.payload);
+                    Poll::Ready(Ok(size + d.payload.len()))
+                }
+                Poll::Ready(Some(Ok(HttpFrame::Headers(d)))) => {
+                    self.trailers = Some(d);
                     Poll::Ready(Ok(size))
-                } else {
-                    Poll::Ready(Err(io::Error::new(ErrorKind::WouldBlock, "stream blocked")))
                 }
-            }
-            Poll::Ready(Some(Err(e))) => {
-                self.recv.take().unwrap().reset(e.code());
-                Poll::Ready(Err(io::Error::new(
-                    ErrorKind::Other,
-                    format!("read error: {:?}", e),
-                )))
-            }
-            Poll::Ready(Some(Ok(HttpFrame::Data(mut d)))) => {
-                if d.payload.len() >= buf.len() - size {
-                    let tail = d.payload.split_off(buf.len() - size);
-                    self.buf_put(tail);
+                Poll::Ready(Some(Ok(HttpFrame::Extension(f)))) => {
+                    self.extensions.push(f);
+                    continue;
                 }
-                buf[size..size + d.payload.len()].copy_from_slice(&d.payload);
-                Poll::Ready(Ok(size + d.payload.len()))
-            }
-            Poll::Ready(Some(Ok(HttpFrame::Headers(d)))) => {
-                self.trailers = Some(d);
-                Poll::Ready(Ok(size))
-            }
-            Poll::Ready(Some(Ok(_))) => {
-                self.recv.take().unwrap().reset(ErrorCode::FRAME_UNEXPECTED);
-                Poll::Ready(Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    "received an invalid frame type",
-                )))
-            }
+                Poll::Ready(Some(Ok(_))) => {
+                    self.recv.take().unwrap().reset(ErrorCode::FRAME_UNEXPECTED);
+                    Poll::Ready(Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "received an invalid frame type",
+                    )))
+                }
+            };
         }
     }
 }
@@ -451,12 +586,28 @@ impl Drop for BodyReader {
     }
 }
 
+/// Writes smaller than this are buffered and sent together in a single DATA frame
+const DEFAULT_COALESCE_SIZE: usize = 16 * 1024;
+/// Larger writes are split into DATA frames of at most this size
+const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Sends a request or response body
+///
+/// Data written through `AsyncWrite` is buffered until `coalesce_size` bytes have accumulated, so
+/// it must be flushed to be sent immediately. Closing or dropping the writer flushes any buffered
+/// data. If a dropped writer's data can't be handed to the stream at once, for lack of flow control
+/// credit, the stream is reset so the peer doesn't mistake the truncated body for a complete one.
 pub struct BodyWriter {
     state: BodyWriterState,
     conn: ConnectionRef,
     stream_id: StreamId,
+    span: Span,
     trailers: Option<HeaderMap>,
     finish_request: bool,
+    pending: BytesMut,
+    coalesce_size: usize,
+    max_frame_size: usize,
+    tracker: Option<ResponseTracker>,
 }
 
 impl BodyWriter {
@@ -464,41 +615,96 @@ impl BodyWriter {
         send: SendStream,
         conn: ConnectionRef,
         stream_id: StreamId,
+        span: Span,
         trailers: Option<HeaderMap>,
         finish_request: bool,
+        tracker: Option<ResponseTracker>,
     ) -> Self {
         Self {
             conn,
             stream_id,
+            span,
             trailers,
             state: BodyWriterState::Idle(send),
             finish_request,
+            pending: BytesMut::new(),
+            coalesce_size: DEFAULT_COALESCE_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            tracker,
         }
     }
 
+    /// Amount of written data to buffer before sending it as a DATA frame
+    ///
+    /// A size of 0 sends every write immediately. Sizes over `max_frame_size` are reduced to it.
+    pub fn coalesce_size(&mut self, size: usize) -> &mut Self {
+        self.coalesce_size = cmp::min(size, self.max_frame_size);
+        self
+    }
+
+    /// Maximum payload size of the DATA frames sent, larger writes are split
+    ///
+    /// A size of 0 is raised to 1.
+    pub fn max_frame_size(&mut self, size: usize) -> &mut Self {
+        let size = cmp::max(size, 1);
+        self.max_frame_size = size;
+        self.coalesce_size = cmp::min(self.coalesce_size, size);
+        self
+    }
+
     pub async fn trailers(mut self, trailers: HeaderMap) -> Result<(), Error> {
-        match mem::replace(&mut self.state, BodyWriterState::Finished) {
-            BodyWriterState::Idle(send) => {
-                Self::_trailers(trailers, &self.conn, send, self.stream_id).await
+        let send = self.take_stream().await?;
+        Self::_trailers(trailers, &self.conn, send, self.stream_id).await
+    }
+
+    /// Send `data` after any buffered data without copying it
+    ///
+    /// `data` is split into DATA frames of at most `max_frame_size` bytes.
+    pub async fn send_data(&mut self, mut data: Bytes) -> Result<(), Error> {
+        let mut send = self.take_stream().await?;
+        while !data.is_empty() {
+            let payload = data.split_to(cmp::min(data.len(), self.max_frame_size));
+            if let Some(ref mut tracker) = self.tracker {
+                tracker.body(payload.len());
             }
-            _ => panic!("cannot send trailers while not in idle state"),
+            send = self.conn.write_data(send, payload).await?;
         }
+        self.state = BodyWriterState::Idle(send);
+        Ok(())
+    }
+
+    /// Send an extension frame ahead of the remaining data
+    pub async fn send_extension_frame(&mut self, frame: ExtensionFrame) -> Result<(), Error> {
+        if !ExtensionFrame::is_valid_type(frame.ty) {
+            return Err(Error::internal("invalid extension frame type"));
+        }
+        let mut send = self.take_stream().await?;
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        let res = send.write_all(&buf).await;
+        self.state = BodyWriterState::Idle(send);
+        res.map_err(Into::into)
     }
 
     pub async fn close(mut self) -> Result<(), Error> {
-        let trailers = self.trailers.take();
-        let state = mem::replace(&mut self.state, BodyWriterState::Finished);
+        let mut send = self.take_stream().await?;
+        match self.trailers.take() {
+            Some(t) => Self::_trailers(t, &self.conn, send, self.stream_id).await,
+            None => send.finish().await.map_err(Into::into),
+        }
+    }
 
-        match (trailers, state) {
-            (Some(t), BodyWriterState::Idle(send)) => {
-                Self::_trailers(t, &self.conn, send, self.stream_id).await
-            }
-            (None, BodyWriterState::Idle(mut send)) => send.finish().await.map_err(Into::into),
-            _ => panic!("cannot close while not in idle state"),
+    /// Take the stream once all buffered data has been written
+    async fn take_stream(&mut self) -> Result<SendStream, Error> {
+        future::poll_fn(|cx| self.poll_send_pending(cx, 0)).await?;
+        match mem::replace(&mut self.state, BodyWriterState::Finished) {
+            BodyWriterState::Idle(send) => Ok(send),
+            _ => unreachable!(),
         }
     }
 
     pub fn cancel(mut self) {
+        self.conn.request_cancelled();
         let state = mem::replace(&mut self.state, BodyWriterState::Finished);
         match state {
             BodyWriterState::Idle(mut send) => {
@@ -529,22 +735,31 @@ enum BodyWriterState {
     Finished,
 }
 
-impl AsyncWrite for BodyWriter {
-    fn poll_write(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context,
-        buf: &[u8],
-    ) -> Poll<Result<usize, io::Error>> {
+impl BodyWriter {
+    /// Send buffered data as DATA frames until less than `min` bytes remain
+    fn poll_send_pending(&mut self, cx: &mut Context, min: usize) -> Poll<Result<(), io::Error>> {
+        let span = self.span.clone();
+        let _guard = span.enter();
         loop {
             match self.state {
-                BodyWriterState::Finished => panic!(),
+                BodyWriterState::Finished => {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::Other,
+                        "body already finished",
+                    )));
+                }
                 BodyWriterState::Idle(_) => {
-                    let frame = DataFrame {
-                        payload: Bytes::copy_from_slice(buf),
-                    };
+                    if self.pending.is_empty() || self.pending.len() < min {
+                        return Poll::Ready(Ok(()));
+                    }
+                    let len = cmp::min(self.pending.len(), self.max_frame_size);
+                    if let Some(ref mut tracker) = self.tracker {
+                        tracker.body(len);
+                    }
+                    let payload = self.pending.split_to(len).freeze();
                     self.state = match mem::replace(&mut self.state, BodyWriterState::Finished) {
                         BodyWriterState::Idle(send) => {
-                            BodyWriterState::Writing(WriteFrame::new(send, frame))
+                            BodyWriterState::Writing(self.conn.write_data(send, payload))
                         }
                         _ => unreachable!(),
                     }
@@ -552,41 +767,68 @@ impl AsyncWrite for BodyWriter {
                 BodyWriterState::Writing(ref mut write) => {
                     let send = ready!(Pin::new(write).poll(cx))?;
                     self.state = BodyWriterState::Idle(send);
-                    return Poll::Ready(Ok(buf.len()));
                 }
             }
         }
     }
+}
+
+impl AsyncWrite for BodyWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[IoSlice],
+    ) -> Poll<Result<usize, io::Error>> {
+        let coalesce_size = self.coalesce_size;
+        ready!(self.poll_send_pending(cx, coalesce_size))?;
+
+        let mut room = self.max_frame_size - self.pending.len();
+        let mut written = 0;
+        for buf in bufs {
+            let n = cmp::min(buf.len(), room);
+            self.pending.extend_from_slice(&buf[..n]);
+            room -= n;
+            written += n;
+        }
+
+        // Start sending right away if enough data has accumulated
+        if let Poll::Ready(Err(e)) = self.poll_send_pending(cx, coalesce_size) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(written))
+    }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        if let BodyWriterState::Finished = self.state {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.poll_send_pending(cx, 0))?;
         match self.state {
-            BodyWriterState::Finished => Poll::Ready(Ok(())),
-            BodyWriterState::Idle(ref mut send) => {
-                ready!(Pin::new(send).poll_flush(cx))?;
-                self.state = BodyWriterState::Finished;
-                Poll::Ready(Ok(()))
-            }
-            BodyWriterState::Writing(ref mut write) => {
-                let send = ready!(Pin::new(write).poll(cx))?;
-                self.state = BodyWriterState::Idle(send);
-                Poll::Pending
-            }
+            BodyWriterState::Idle(ref mut send) => Pin::new(send).poll_flush(cx),
+            _ => unreachable!(),
         }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        if let BodyWriterState::Finished = self.state {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.poll_send_pending(cx, 0))?;
         match self.state {
-            BodyWriterState::Finished => Poll::Ready(Ok(())),
             BodyWriterState::Idle(ref mut send) => {
                 ready!(Pin::new(send).poll_close(cx))?;
                 self.state = BodyWriterState::Finished;
                 Poll::Ready(Ok(()))
             }
-            BodyWriterState::Writing(ref mut write) => {
-                let send = ready!(Pin::new(write).poll(cx))?;
-                self.state = BodyWriterState::Idle(send);
-                Poll::Pending
-            }
+            _ => unreachable!(),
         }
     }
 }
@@ -611,6 +853,23 @@ impl tokio::io::AsyncWrite for BodyWriter {
 
 impl Drop for BodyWriter {
     fn drop(&mut self) {
+        // Hand what's left to the stream, which is finished when dropped. This can't wait for flow
+        // control credit, so data which doesn't fit would be lost.
+        if let BodyWriterState::Idle(_) | BodyWriterState::Writing(_) = self.state {
+            let mut cx = Context::from_waker(task::noop_waker_ref());
+            let _ = self.poll_send_pending(&mut cx, 0);
+        }
+        match mem::replace(&mut self.state, BodyWriterState::Finished) {
+            BodyWriterState::Idle(mut send) => {
+                if !self.pending.is_empty() {
+                    send.reset(ErrorCode::REQUEST_CANCELLED.into());
+                }
+            }
+            BodyWriterState::Writing(write) => {
+                write.reset(ErrorCode::REQUEST_CANCELLED);
+            }
+            BodyWriterState::Finished => {}
+        }
         if self.finish_request {
             self.conn
                 .h3
@@ -621,3 +880,70 @@ impl Drop for BodyWriter {
         }
     }
 }
+
+#[derive(Default)]
+struct ExtensionFrames {
+    types: Vec<u64>,
+    frames: VecDeque<ExtensionFrame>,
+}
+
+impl ExtensionFrames {
+    fn push(&mut self, frame: ExtensionFrame) {
+        if self.types.contains(&frame.ty) {
+            self.frames.push_back(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{io::AsyncWriteExt, TryFutureExt};
+    use http::{Method, Request};
+    use quinn::{Certificate, CertificateChain, PrivateKey};
+
+    use crate::{client, server};
+
+    #[tokio::test]
+    async fn write_then_drop() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let key = PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+        let cert = Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+
+        let mut server = server::Builder::new(quinn::Endpoint::builder());
+        server
+            .certificate(CertificateChain::from_certs(vec![cert.clone()]), key)
+            .unwrap();
+        let (driver, server, mut incoming) = server.bind(&"[::1]:0".parse().unwrap()).unwrap();
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+
+        let mut client = client::Builder::new();
+        client.add_certificate_authority(cert).unwrap();
+        let (driver, client) = client.bind(&"[::1]:0".parse().unwrap()).unwrap();
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        let (quic_driver, h3_driver, conn) = client
+            .connect(&server.local_addr(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+        tokio::spawn(h3_driver.unwrap_or_else(|_| ()));
+
+        // Less than the coalescing size, so still buffered when the writer is dropped
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("https://localhost/")
+            .body(())
+            .unwrap();
+        let (mut writer, _) = conn.request(request).stream().await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        drop(writer);
+
+        let (quic_driver, h3_driver, mut requests) =
+            incoming.accept().await.unwrap().await.unwrap();
+        tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+        tokio::spawn(h3_driver.unwrap_or_else(|_| ()));
+        let (request, _) = requests.next().await.unwrap().await.unwrap();
+        let (body, _) = request.into_body().read_to_end(1024).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+    }
+}
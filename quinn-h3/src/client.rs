@@ -1,58 +1,289 @@
 use std::{
+    collections::HashMap,
     future::Future,
-    mem,
-    net::SocketAddr,
+    io, mem,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use futures::{ready, Stream};
-use http::{request, HeaderMap, Request, Response};
-use quinn::{Endpoint, OpenBi};
+use futures::{
+    future::{self, Either},
+    ready,
+    stream::{FuturesUnordered, StreamExt},
+    Stream,
+};
+use http::{request, HeaderMap, HeaderValue, Request, Response};
+use quinn::{
+    AuthenticationData, Certificate, CertificateChain, ClientConfigBuilder, Endpoint,
+    EndpointDriver, EndpointError, OpenBi, PrivateKey, SendStream, TransportConfig,
+    ZeroRttAccepted,
+};
 use quinn_proto::{Side, StreamId};
+use rustls::{CipherSuite, StoresClientSessions, TLSError};
+use tracing::Span;
 
 use crate::{
     body::{Body, BodyWriter, RecvBody},
-    connection::{ConnectionDriver, ConnectionRef},
-    frame::{FrameDecoder, FrameStream, WriteFrame},
-    headers::{DecodeHeaders, SendHeaders},
-    proto::{
-        frame::{DataFrame, HttpFrame},
-        headers::Header,
-        ErrorCode,
+    connection::{
+        record_request, record_stream, ConnectionDriver, ConnectionRef, Options, UniStreams,
     },
+    frame::{FrameStream, WriteFrame},
+    har::{Recorder, Recording},
+    headers::{DecodeHeaders, SendHeaders},
+    proto::{connection::Stats, frame::HttpFrame, headers::Header, ErrorCode},
+    resolve::{Resolve, SystemResolver},
     streams::Reset,
     try_take, Error, Settings,
 };
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct Builder {
     settings: Settings,
+    options: Options,
+    client_config: ClientConfigBuilder,
+    protocols: Vec<Vec<u8>>,
 }
 
-impl Builder {
-    pub fn new() -> Self {
+impl Default for Builder {
+    fn default() -> Self {
+        let mut client_config = ClientConfigBuilder::default();
+        client_config.protocols(&[crate::ALPN]);
         Self {
             settings: Settings::default(),
+            options: Options::default(),
+            client_config,
+            protocols: vec![crate::ALPN.into()],
         }
     }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
     pub fn settings(&mut self, settings: Settings) -> &mut Self {
         self.settings = settings;
         self
     }
 
+    /// Fraction of connections on which reserved settings, frames and stream types are sent
+    ///
+    /// Greasing keeps peers and middleboxes from relying on the absence of extensions. Defaults
+    /// to `DEFAULT_GREASE`. Panics if `rate` is not within `0.0..=1.0`.
+    pub fn grease(&mut self, rate: f64) -> &mut Self {
+        assert!((0.0..=1.0).contains(&rate), "grease rate out of range");
+        self.options.grease = rate;
+        self
+    }
+
+    /// Give up on header blocks still waiting for dynamic table updates after `timeout`
+    ///
+    /// The request stream is then reset and `Error::Timeout` returned. By default, header blocks
+    /// wait until the connection closes.
+    pub fn qpack_blocked_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.options.qpack_blocked_timeout = timeout;
+        self
+    }
+
+    /// Write a qlog trace of each connection to the sink returned by `sink`
+    ///
+    /// `sink` is called with the connection's identifier, see `RequestId::connection`. Returning
+    /// `None` disables tracing of that connection. See `qlog::dir` for writing to files.
+    pub fn qlog<F>(&mut self, sink: F) -> &mut Self
+    where
+        F: Fn(u64) -> Option<Box<dyn io::Write + Send>> + Send + Sync + 'static,
+    {
+        self.options.qlog = Some(Arc::new(sink));
+        self
+    }
+
+    /// Record the requests sent and their responses in `recorder`
+    ///
+    /// Meant for debugging, as captured headers are kept in memory until `Recorder::clear`.
+    pub fn recorder(&mut self, recorder: Recorder) -> &mut Self {
+        self.options.recorder = Some(recorder);
+        self
+    }
+
+    /// Resolve host names passed to `Client::connect_host` with `resolver`
+    ///
+    /// Defaults to `resolve::SystemResolver`.
+    pub fn resolver(&mut self, resolver: Arc<dyn Resolve>) -> &mut Self {
+        self.options.resolver = Some(resolver);
+        self
+    }
+
+    /// Set the ALPN tokens offered for HTTP/3, in order of descending preference
+    ///
+    /// Defaults to `ALPN`. The negotiated token is available from `Connection::protocol()`.
+    pub fn protocols(&mut self, protocols: &[&[u8]]) -> &mut Self {
+        self.client_config.protocols(protocols);
+        self.protocols = protocols.iter().map(|x| x.to_vec()).collect();
+        self
+    }
+
+    /// Trust `cert` when verifying the server's certificate chain
+    pub fn add_certificate_authority(
+        &mut self,
+        cert: Certificate,
+    ) -> Result<&mut Self, webpki::Error> {
+        self.client_config.add_certificate_authority(cert)?;
+        Ok(self)
+    }
+
+    /// Log TLS secrets to the file named by the `SSLKEYLOGFILE` environment variable
+    ///
+    /// Lets protocol analyzers such as Wireshark decrypt captured traffic. Does nothing if the
+    /// variable is unset.
+    pub fn enable_keylog(&mut self) -> &mut Self {
+        self.client_config.enable_keylog();
+        self
+    }
+
+    /// Check the Certificate Transparency proofs sent by servers against `logs`
+    ///
+    /// Servers sending signed certificate timestamps none of which verify are rejected; servers
+    /// sending none are accepted. `None` disables the check.
+    pub fn ct_logs(&mut self, logs: Option<&'static [&'static sct::Log<'static>]>) -> &mut Self {
+        self.client_config.ct_logs(logs);
+        self
+    }
+
+    /// Restrict the TLS 1.3 cipher suites offered, in order of descending preference
+    ///
+    /// Fails if `suites` is empty or contains an unsupported suite.
+    pub fn cipher_suites(&mut self, suites: &[CipherSuite]) -> Result<&mut Self, TLSError> {
+        self.client_config.cipher_suites(suites)?;
+        Ok(self)
+    }
+
+    /// Accept any server certificate, without verifying it
+    ///
+    /// This makes connections vulnerable to man-in-the-middle attacks and is only meant for
+    /// testing against servers with self-signed certificates. Every handshake logs a warning.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn accept_insecure_certs(&mut self) -> &mut Self {
+        tracing::warn!("server certificates will not be verified");
+        self.certificate_verifier(Arc::new(InsecureVerifier))
+    }
+
+    /// Verify server certificates with a custom verifier, e.g. for certificate pinning
+    ///
+    /// The verifier is passed the authorities trusted by this builder, which it may ignore.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn certificate_verifier(
+        &mut self,
+        verifier: Arc<dyn rustls::ServerCertVerifier>,
+    ) -> &mut Self {
+        self.client_config.certificate_verifier(verifier);
+        self
+    }
+
+    /// Trust the certificate authorities in the host OS certificate store
+    ///
+    /// Replaces the trusted authorities, so call this before `add_certificate_authority`. Fails
+    /// if the store can't be read.
+    #[cfg(feature = "native-certs")]
+    pub fn with_native_roots(&mut self) -> Result<&mut Self, io::Error> {
+        self.client_config.with_native_roots()?;
+        Ok(self)
+    }
+
+    /// Trust the Mozilla root certificates bundled with the `webpki-roots` crate
+    ///
+    /// For environments without a readable OS certificate store, such as containers. Replaces
+    /// the trusted authorities, so call this before `add_certificate_authority`.
+    #[cfg(feature = "webpki-roots")]
+    pub fn with_webpki_roots(&mut self) -> &mut Self {
+        self.client_config.with_webpki_roots();
+        self
+    }
+
+    /// Present this certificate chain to servers requesting client authentication
+    pub fn certificate(
+        &mut self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<&mut Self, TLSError> {
+        self.client_config.certificate(cert_chain, key)?;
+        Ok(self)
+    }
+
+    /// Store session tickets in `cache` to resume sessions and send 0-RTT requests
+    ///
+    /// Defaults to an in-memory cache. See `quinn::FileSessionCache` for keeping tickets across
+    /// restarts, and `Connecting::into_0rtt`.
+    pub fn session_cache(&mut self, cache: Arc<dyn StoresClientSessions>) -> &mut Self {
+        self.client_config.session_cache(cache);
+        self
+    }
+
+    /// Set the transport parameters of connections made by the client
+    ///
+    /// Setting `TransportConfig::keep_alive_interval` keeps idle pooled connections open.
+    pub fn transport_config(&mut self, config: Arc<TransportConfig>) -> &mut Self {
+        self.client_config.transport_config(config);
+        self
+    }
+
+    /// Bind a new endpoint configured with this builder's TLS settings
+    pub fn bind(self, addr: &SocketAddr) -> Result<(EndpointDriver, Client), EndpointError> {
+        let mut endpoint = Endpoint::builder();
+        endpoint.default_client_config(self.client_config.build());
+        let (driver, endpoint, _) = endpoint.bind(addr)?;
+        Ok((
+            driver,
+            Client {
+                endpoint,
+                settings: self.settings,
+                options: self.options,
+                protocols: self.protocols,
+            },
+        ))
+    }
+
+    /// Build a client over an existing endpoint
+    ///
+    /// Connections use `endpoint`'s default client configuration, TLS settings from this
+    /// builder are ignored.
     pub fn endpoint(self, endpoint: Endpoint) -> Client {
         Client {
             endpoint,
             settings: self.settings,
+            options: self.options,
+            protocols: self.protocols,
         }
     }
 }
 
+/// Server certificate verifier accepting anything, installed by `Builder::accept_insecure_certs`
+#[cfg(feature = "dangerous_configuration")]
+struct InsecureVerifier;
+
+#[cfg(feature = "dangerous_configuration")]
+impl rustls::ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _: &rustls::RootCertStore,
+        _: &[rustls::Certificate],
+        name: webpki::DNSNameRef<'_>,
+        _: &[u8],
+    ) -> Result<rustls::ServerCertVerified, TLSError> {
+        let name: &str = name.into();
+        tracing::warn!("accepting the certificate of {} without verification", name);
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
 pub struct Client {
     endpoint: Endpoint,
     settings: Settings,
+    options: Options,
+    protocols: Vec<Vec<u8>>,
 }
 
 impl Client {
@@ -63,14 +294,268 @@ impl Client {
     ) -> Result<Connecting, quinn::ConnectError> {
         Ok(Connecting {
             settings: self.settings.clone(),
+            options: self.options.clone(),
             connecting: self.endpoint.connect(addr, server_name)?,
         })
     }
+
+    /// Connect to whichever of `addrs` completes a handshake first
+    ///
+    /// Implements Happy Eyeballs (RFC 8305): addresses are tried alternating between IPv6 and
+    /// IPv4, starting with the family of the first one, and a new attempt is started every
+    /// `CONNECTION_ATTEMPT_DELAY` or as soon as the previous one fails. Attempts still running
+    /// when one succeeds are abandoned. The endpoint should be bound to an IPv6 wildcard address
+    /// to reach both families.
+    pub async fn connect_any(
+        &self,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+        server_name: &str,
+    ) -> Result<(quinn::ConnectionDriver, ConnectionDriver, Connection), Error> {
+        let mut pending = interleave_families(addrs).into_iter();
+        let runtime = self.endpoint.runtime();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+        // Each iteration starts an attempt, after the last one failed or took too long
+        loop {
+            if let Some(addr) = pending.next() {
+                match self.connect(&addr, server_name) {
+                    Ok(connecting) => attempts.push(connecting),
+                    Err(e) => last_error = Some(Error::Connect(e)),
+                }
+            }
+            if attempts.is_empty() {
+                if pending.len() == 0 {
+                    return Err(last_error.unwrap_or(Error::NoAddresses));
+                }
+                continue;
+            }
+
+            let delay = runtime.new_timer(Instant::now() + CONNECTION_ATTEMPT_DELAY);
+            match future::select(attempts.next(), delay).await {
+                Either::Left((Some(Ok(conn)), _)) => return Ok(conn),
+                Either::Left((Some(Err(e)), _)) => {
+                    tracing::debug!("connection attempt failed: {}", e);
+                    last_error = Some(e);
+                }
+                Either::Left((None, _)) => unreachable!(),
+                Either::Right(_) => {}
+            }
+        }
+    }
+
+    /// Resolve `host` and connect to any of its addresses with `connect_any`
+    pub async fn connect_host(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<(quinn::ConnectionDriver, ConnectionDriver, Connection), Error> {
+        let addrs = match self.options.resolver {
+            Some(ref resolver) => resolver.resolve(host, port).await,
+            None => SystemResolver.resolve(host, port).await,
+        }?;
+        self.connect_any(addrs, host).await
+    }
+
+    /// Connect over HTTP/3 to `origin` if it is known to support it
+    ///
+    /// Returns `None` when `cache` holds no fresh `alt-svc` advertisement for `origin`, in which
+    /// case the request should go through a TCP-based client whose responses feed
+    /// `AltSvcCache::update`. Failing to connect within `timeout` marks the origin as broken so
+    /// that subsequent requests fall back immediately.
+    pub async fn connect_alt_svc(
+        &self,
+        cache: &AltSvcCache,
+        origin: &str,
+        ip: IpAddr,
+        timeout: Duration,
+    ) -> Result<Option<(quinn::ConnectionDriver, ConnectionDriver, Connection)>, Error> {
+        let port = match cache.lookup(origin, &self.protocols) {
+            Some(port) => port,
+            None => return Ok(None),
+        };
+        let host = origin.rsplitn(2, ':').last().unwrap_or(origin);
+        let connecting = self
+            .connect(&SocketAddr::new(ip, port), host)
+            .map_err(Error::Connect)?;
+        let deadline = self.endpoint.runtime().new_timer(Instant::now() + timeout);
+        let result = match future::select(connecting, deadline).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(Error::Timeout),
+        };
+        if result.is_err() {
+            cache.mark_broken(origin);
+        }
+        result.map(Some)
+    }
+}
+
+/// Delay before `Client::connect_any` tries the next address, as recommended by RFC 8305
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Order `addrs` so that address families alternate, starting with the family of the first one
+fn interleave_families(addrs: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let mut addrs = addrs.into_iter().peekable();
+    let first_v6 = match addrs.peek() {
+        Some(addr) => addr.is_ipv6(),
+        None => return Vec::new(),
+    };
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.partition(|addr| addr.is_ipv6() == first_v6);
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let mut other = other.into_iter();
+    for addr in preferred {
+        ordered.push(addr);
+        ordered.extend(other.next());
+    }
+    ordered.extend(other);
+    ordered
+}
+
+/// Remembers which origins advertised HTTP/3 support through `alt-svc` headers
+#[derive(Debug, Default)]
+pub struct AltSvcCache {
+    entries: Mutex<HashMap<String, AltSvcEntry>>,
+}
+
+#[derive(Debug)]
+enum AltSvcEntry {
+    Available(Vec<(Vec<u8>, u16, Instant)>),
+    Broken { until: Instant },
+}
+
+impl AltSvcCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the `alt-svc` header of a response received from `origin` over TCP
+    ///
+    /// Only same-host alternatives are taken into account.
+    pub fn update(&self, origin: &str, value: &HeaderValue) {
+        let value = match value.to_str() {
+            Ok(x) => x.trim(),
+            Err(_) => return,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if value == "clear" {
+            entries.remove(origin);
+            return;
+        }
+        if let Some(AltSvcEntry::Broken { until }) = entries.get(origin) {
+            if *until > Instant::now() {
+                return;
+            }
+        }
+        let now = Instant::now();
+        let alternatives = value
+            .split(',')
+            .filter_map(parse_alt_svc)
+            .map(|(protocol, port, max_age)| (protocol, port, now + max_age))
+            .collect::<Vec<_>>();
+        if !alternatives.is_empty() {
+            entries.insert(origin.into(), AltSvcEntry::Available(alternatives));
+        }
+    }
+
+    /// The port advertised by `origin` for any of `protocols`, if known and fresh
+    pub fn lookup(&self, origin: &str, protocols: &[Vec<u8>]) -> Option<u16> {
+        let now = Instant::now();
+        match self.entries.lock().unwrap().get(origin) {
+            Some(AltSvcEntry::Available(alternatives)) => alternatives
+                .iter()
+                .find(|(protocol, _, expires)| *expires > now && protocols.contains(protocol))
+                .map(|(_, port, _)| *port),
+            _ => None,
+        }
+    }
+
+    /// Stop using HTTP/3 for `origin` until it is advertised again after `BROKEN_DURATION`
+    pub fn mark_broken(&self, origin: &str) {
+        self.entries.lock().unwrap().insert(
+            origin.into(),
+            AltSvcEntry::Broken {
+                until: Instant::now() + BROKEN_DURATION,
+            },
+        );
+    }
+}
+
+/// How long an origin which failed to connect over HTTP/3 is ignored
+pub const BROKEN_DURATION: Duration = Duration::from_secs(300);
+
+/// Parse a single `alt-svc` alternative, yielding the protocol, port and freshness lifetime
+fn parse_alt_svc(alternative: &str) -> Option<(Vec<u8>, u16, Duration)> {
+    let mut params = alternative.split(';').map(str::trim);
+    let mut alt = params.next()?.splitn(2, '=');
+    let protocol = percent_decode(alt.next()?)?;
+    let authority = alt.next()?.trim_matches('"');
+    let port = match authority.rfind(':') {
+        Some(0) => authority[1..].parse().ok()?,
+        _ => return None, // Alternative hosts are not supported
+    };
+    let mut max_age = Duration::from_secs(24 * 60 * 60);
+    for param in params {
+        let mut kv = param.splitn(2, '=');
+        if kv.next() == Some("ma") {
+            max_age = Duration::from_secs(kv.next()?.trim_matches('"').parse().ok()?);
+        }
+    }
+    Some((protocol, port, max_age))
+}
+
+fn percent_decode(token: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(token.len());
+    let mut bytes = token.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    Some(decoded)
 }
 
 pub struct Connection(ConnectionRef);
 
 impl Connection {
+    /// Run HTTP/3 over a connection established outside of this crate
+    ///
+    /// The connection should have negotiated an HTTP/3 ALPN token.
+    pub fn from_quinn(
+        new_conn: quinn::NewConnection,
+        settings: Settings,
+    ) -> Result<(quinn::ConnectionDriver, ConnectionDriver, Self), Error> {
+        Self::new(new_conn, settings, Options::default())
+    }
+
+    fn new(
+        new_conn: quinn::NewConnection,
+        settings: Settings,
+        options: Options,
+    ) -> Result<(quinn::ConnectionDriver, ConnectionDriver, Self), Error> {
+        let quinn::NewConnection {
+            driver,
+            connection,
+            uni_streams,
+            bi_streams,
+            ..
+        } = new_conn;
+        let conn_ref = ConnectionRef::new(
+            connection,
+            Side::Client,
+            uni_streams,
+            bi_streams,
+            settings,
+            options,
+        )?;
+        Ok((
+            driver,
+            ConnectionDriver::new(conn_ref.clone(), None),
+            Connection(conn_ref),
+        ))
+    }
+
     pub fn request<T: Into<Body>>(&self, request: Request<T>) -> RequestBuilder<T> {
         RequestBuilder {
             request,
@@ -79,6 +564,37 @@ impl Connection {
         }
     }
 
+    pub fn authentication_data(&self) -> AuthenticationData {
+        self.0.quic.authentication_data()
+    }
+
+    /// The settings sent by the server, once they have been received
+    pub fn peer_settings(&self) -> Option<Settings> {
+        self.0.h3.lock().unwrap().inner.remote_settings().clone()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.0.stats()
+    }
+
+    /// Accept unidirectional streams of extension type `ty` opened by the server
+    ///
+    /// Streams of types which aren't being accepted are discarded. Fails if streams of type `ty`
+    /// are already being accepted.
+    pub fn accept_uni(&self, ty: u64) -> Result<UniStreams, Error> {
+        self.0.accept_uni(ty)
+    }
+
+    /// Open a unidirectional stream of extension type `ty`
+    pub async fn open_uni(&self, ty: u64) -> Result<SendStream, Error> {
+        self.0.open_uni(ty).await
+    }
+
+    /// The ALPN token negotiated for this connection
+    pub fn protocol(&self) -> Option<Box<[u8]>> {
+        self.0.quic.protocol()
+    }
+
     pub fn close(self) {
         self.0
             .quic
@@ -89,31 +605,58 @@ impl Connection {
 pub struct Connecting {
     connecting: quinn::Connecting,
     settings: Settings,
+    options: Options,
+}
+
+impl Connecting {
+    /// Start sending requests before the handshake completes, using a cached session ticket
+    ///
+    /// Returns `self` unchanged when no ticket allowing 0-RTT is cached for the server. Requests
+    /// sent before `ZeroRttAccepted` resolves may be replayed by an attacker, so only idempotent
+    /// requests should be sent until then. If it resolves to `false`, the server rejected the
+    /// early data and those requests fail.
+    #[allow(clippy::type_complexity, clippy::result_large_err)]
+    pub fn into_0rtt(
+        self,
+    ) -> Result<
+        Result<
+            (
+                quinn::ConnectionDriver,
+                ConnectionDriver,
+                Connection,
+                ZeroRttAccepted,
+            ),
+            Error,
+        >,
+        Self,
+    > {
+        let Connecting {
+            connecting,
+            settings,
+            options,
+        } = self;
+        match connecting.into_0rtt() {
+            Ok((new_conn, accepted)) => Ok(Connection::new(new_conn, settings, options)
+                .map(|(quic_driver, driver, conn)| (quic_driver, driver, conn, accepted))),
+            Err(connecting) => Err(Connecting {
+                connecting,
+                settings,
+                options,
+            }),
+        }
+    }
 }
 
 impl Future for Connecting {
     type Output = Result<(quinn::ConnectionDriver, ConnectionDriver, Connection), Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let quinn::NewConnection {
-            driver,
-            connection,
-            uni_streams,
-            bi_streams,
-            ..
-        } = ready!(Pin::new(&mut self.connecting).poll(cx))?;
-        let conn_ref = ConnectionRef::new(
-            connection,
-            Side::Client,
-            uni_streams,
-            bi_streams,
+        let new_conn = ready!(Pin::new(&mut self.connecting).poll(cx))?;
+        Poll::Ready(Connection::new(
+            new_conn,
             self.settings.clone(),
-        )?;
-        Poll::Ready(Ok((
-            driver,
-            ConnectionDriver(conn_ref.clone()),
-            Connection(conn_ref),
-        )))
+            self.options.clone(),
+        ))
     }
 }
 
@@ -152,9 +695,17 @@ where
             body,
         ) = self.request.into_parts();
         let (conn, trailers) = (self.conn, self.trailers);
+        let body = body.into();
+        let recording = conn.start_recording(&method, &uri, &headers, &body);
         let (send, recv) = conn.quic.open_bi().await?;
 
         let stream_id = send.id();
+        if let Some(ref recording) = recording {
+            recording.stream(stream_id.0);
+        }
+        let span = conn.request_span();
+        record_stream(&span, stream_id);
+        record_request(&span, &method, &uri);
         let send = SendHeaders::new(
             Header::request(method, uri, headers),
             &conn,
@@ -163,17 +714,23 @@ where
         )?
         .await?;
 
-        let recv = RecvResponse::new(FrameDecoder::stream(recv), conn.clone(), stream_id);
-        match body.into() {
+        let recv = RecvResponse::new(
+            conn.frame_stream(recv),
+            conn.clone(),
+            stream_id,
+            span.clone(),
+            recording,
+        );
+        match body {
             Body::Buf(payload) => {
-                let send = WriteFrame::new(send, DataFrame { payload }).await?;
+                let send = conn.write_data(send, payload).await?;
                 Ok((
-                    BodyWriter::new(send, conn, stream_id, trailers, false),
+                    BodyWriter::new(send, conn, stream_id, span, trailers, false, None),
                     recv,
                 ))
             }
             Body::None => Ok((
-                BodyWriter::new(send, conn.clone(), stream_id, trailers, false),
+                BodyWriter::new(send, conn.clone(), stream_id, span, trailers, false, None),
                 recv,
             )),
         }
@@ -199,6 +756,8 @@ pub struct SendRequest {
     conn: ConnectionRef,
     stream_id: Option<StreamId>,
     recv: Option<FrameStream>,
+    span: Span,
+    recording: Option<Recording>,
 }
 
 impl SendRequest {
@@ -210,6 +769,7 @@ impl SendRequest {
     ) -> Self {
         if conn.h3.lock().unwrap().inner.is_closing() {
             return Self {
+                span: conn.request_span(),
                 conn,
                 header: None,
                 body: None,
@@ -217,6 +777,7 @@ impl SendRequest {
                 recv: None,
                 state: SendRequestState::Aborted,
                 trailers: trailers.map(Header::trailer),
+                recording: None,
             };
         }
 
@@ -229,11 +790,17 @@ impl SendRequest {
             },
             body,
         ) = req.into_parts();
+        let body = body.into();
+        let recording = conn.start_recording(&method, &uri, &headers, &body);
+        let span = conn.request_span();
+        record_request(&span, &method, &uri);
 
         Self {
+            span,
             conn,
             header: Some(Header::request(method, uri, headers)),
-            body: Some(body.into()),
+            body: Some(body),
+            recording,
             trailers: trailers.map(Header::trailer),
             state: SendRequestState::Opening(open_bi),
             stream_id: None,
@@ -247,10 +814,13 @@ impl SendRequest {
             self.conn.clone(),
             try_take(&mut self.recv, "recv is none")?,
             try_take(&mut self.stream_id, "stream is none")?,
+            self.span.clone(),
+            self.recording.take(),
         )
     }
 
     pub fn cancel(mut self) {
+        self.conn.request_cancelled();
         match self.state {
             SendRequestState::Sending(send) => {
                 send.reset(ErrorCode::REQUEST_CANCELLED);
@@ -277,11 +847,17 @@ impl Future for SendRequest {
     type Output = Result<Response<RecvBody>, Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let span = self.span.clone();
+        let _guard = span.enter();
         loop {
             match self.state {
                 SendRequestState::Aborted => return Poll::Ready(Err(Error::Aborted)),
                 SendRequestState::Opening(ref mut o) => {
                     let (send, recv) = ready!(Pin::new(o).poll(cx))?;
+                    record_stream(&span, send.id());
+                    if let Some(ref recording) = self.recording {
+                        recording.stream(send.id().0);
+                    }
 
                     self.conn
                         .h3
@@ -290,7 +866,7 @@ impl Future for SendRequest {
                         .inner
                         .request_initiated(send.id());
 
-                    self.recv = Some(FrameDecoder::stream(recv));
+                    self.recv = Some(self.conn.frame_stream(recv));
                     self.stream_id = Some(send.id());
                     self.state = SendRequestState::Sending(SendHeaders::new(
                         try_take(&mut self.header, "header none")?,
@@ -302,10 +878,9 @@ impl Future for SendRequest {
                 SendRequestState::Sending(ref mut send) => {
                     let send = ready!(Pin::new(send).poll(cx))?;
                     self.state = match self.body.take() {
-                        Some(Body::Buf(payload)) => SendRequestState::SendingBody(WriteFrame::new(
-                            send,
-                            DataFrame { payload },
-                        )),
+                        Some(Body::Buf(payload)) => {
+                            SendRequestState::SendingBody(self.conn.write_data(send, payload))
+                        }
                         _ => {
                             let recv = try_take(&mut self.recv, "Invalid receive state")?;
                             SendRequestState::Receiving(recv)
@@ -335,7 +910,12 @@ impl Future for SendRequest {
                 }
                 SendRequestState::Receiving(ref mut frames) => {
                     match ready!(Pin::new(frames).poll_next(cx)) {
-                        None => return Poll::Ready(Err(Error::peer("received an empty response"))),
+                        None => {
+                            return Poll::Ready(Err(Error::peer(
+                                ErrorCode::REQUEST_INCOMPLETE,
+                                "received an empty response",
+                            )))
+                        }
                         Some(Err(e)) => return Poll::Ready(Err(e.into())),
                         Some(Ok(f)) => match f {
                             HttpFrame::Headers(h) => {
@@ -349,6 +929,7 @@ impl Future for SendRequest {
                                     self.recv = Some(frames);
                                 }
                             }
+                            HttpFrame::Extension(_) => (),
                             _ => {
                                 match mem::replace(&mut self.state, SendRequestState::Finished) {
                                     SendRequestState::Receiving(recv) => {
@@ -356,13 +937,25 @@ impl Future for SendRequest {
                                     }
                                     _ => unreachable!(),
                                 }
-                                return Poll::Ready(Err(Error::peer("first frame is not headers")));
+                                return Poll::Ready(Err(Error::peer(
+                                    ErrorCode::FRAME_UNEXPECTED,
+                                    "first frame is not headers",
+                                )));
                             }
                         },
                     }
                 }
                 SendRequestState::Decoding(ref mut decode) => {
-                    let header = ready!(Pin::new(decode).poll(cx))?;
+                    let header = match ready!(Pin::new(decode).poll(cx)) {
+                        Ok(header) => header,
+                        Err(e) => {
+                            self.state = SendRequestState::Finished;
+                            if let Some(recv) = self.recv.take() {
+                                recv.reset(e.code().unwrap_or(ErrorCode::REQUEST_CANCELLED));
+                            }
+                            return Poll::Ready(Err(e));
+                        }
+                    };
                     self.state = SendRequestState::Finished;
                     return Poll::Ready(Ok(self.build_response(header)?));
                 }
@@ -376,7 +969,9 @@ pub struct RecvResponse {
     state: RecvResponseState,
     conn: ConnectionRef,
     stream_id: StreamId,
+    span: Span,
     recv: Option<FrameStream>,
+    recording: Option<Recording>,
 }
 
 enum RecvResponseState {
@@ -386,16 +981,25 @@ enum RecvResponseState {
 }
 
 impl RecvResponse {
-    pub(crate) fn new(recv: FrameStream, conn: ConnectionRef, stream_id: StreamId) -> Self {
+    pub(crate) fn new(
+        recv: FrameStream,
+        conn: ConnectionRef,
+        stream_id: StreamId,
+        span: Span,
+        recording: Option<Recording>,
+    ) -> Self {
         Self {
             conn,
             stream_id,
+            span,
+            recording,
             recv: None,
             state: RecvResponseState::Receiving(recv),
         }
     }
 
     pub fn cancel(self) {
+        self.conn.request_cancelled();
         if let RecvResponseState::Receiving(recv) = self.state {
             recv.reset(ErrorCode::REQUEST_CANCELLED);
         }
@@ -406,6 +1010,8 @@ impl Future for RecvResponse {
     type Output = Result<Response<RecvBody>, crate::Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let span = self.span.clone();
+        let _guard = span.enter();
         loop {
             match self.state {
                 RecvResponseState::Finished => {
@@ -415,7 +1021,12 @@ impl Future for RecvResponse {
                 }
                 RecvResponseState::Receiving(ref mut recv) => {
                     match ready!(Pin::new(recv).poll_next(cx)) {
-                        None => return Poll::Ready(Err(Error::peer("received an empty response"))),
+                        None => {
+                            return Poll::Ready(Err(Error::peer(
+                                ErrorCode::REQUEST_INCOMPLETE,
+                                "received an empty response",
+                            )))
+                        }
                         Some(Err(e)) => return Poll::Ready(Err(e.into())),
                         Some(Ok(f)) => match f {
                             HttpFrame::Headers(h) => {
@@ -429,6 +1040,7 @@ impl Future for RecvResponse {
                                     _ => unreachable!(),
                                 };
                             }
+                            HttpFrame::Extension(_) => (),
                             _ => {
                                 match mem::replace(&mut self.state, RecvResponseState::Finished) {
                                     RecvResponseState::Receiving(recv) => {
@@ -437,7 +1049,10 @@ impl Future for RecvResponse {
                                     _ => unreachable!(),
                                 }
 
-                                return Poll::Ready(Err(Error::peer("first frame is not headers")));
+                                return Poll::Ready(Err(Error::peer(
+                                    ErrorCode::FRAME_UNEXPECTED,
+                                    "first frame is not headers",
+                                )));
                             }
                         },
                     }
@@ -449,6 +1064,8 @@ impl Future for RecvResponse {
                         self.conn.clone(),
                         self.recv.take().unwrap(),
                         self.stream_id,
+                        self.span.clone(),
+                        self.recording.take(),
                     );
                     match response {
                         Err(e) => return Poll::Ready(Err(e)),
@@ -468,13 +1085,86 @@ fn build_response(
     conn: ConnectionRef,
     recv: FrameStream,
     stream_id: StreamId,
+    span: Span,
+    mut recording: Option<Recording>,
 ) -> Result<Response<RecvBody>, Error> {
     let (status, headers) = header.into_response_parts()?;
+    if let Some(ref mut recording) = recording {
+        recording.response(status, &headers);
+    }
+    let request_id = conn.request_id(stream_id);
     let mut response = Response::builder()
         .status(status)
         .version(http::version::Version::HTTP_3)
-        .body(RecvBody::new(recv, conn, stream_id, true))
+        .body(RecvBody::new(recv, conn, stream_id, span, true, recording))
         .unwrap();
     *response.headers_mut() = headers;
+    response.extensions_mut().insert(request_id);
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave() {
+        let addrs = [
+            "[::1]:1",
+            "[::2]:1",
+            "[::3]:1",
+            "127.0.0.1:1",
+            "127.0.0.2:1",
+        ]
+        .iter()
+        .map(|x| x.parse::<SocketAddr>().unwrap())
+        .collect::<Vec<_>>();
+        let order = |x: &[usize]| x.iter().map(|&i| addrs[i]).collect::<Vec<_>>();
+        assert_eq!(interleave_families(addrs.clone()), order(&[0, 3, 1, 4, 2]));
+        assert_eq!(
+            interleave_families(order(&[3, 0, 4, 1, 2])),
+            order(&[3, 0, 4, 1, 2])
+        );
+        assert_eq!(interleave_families(order(&[4, 3])), order(&[4, 3]));
+        assert!(interleave_families(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn alt_svc_cache() {
+        let h3 = [crate::ALPN.to_vec()];
+        let cache = AltSvcCache::new();
+        cache.update("a.test", &HeaderValue::from_static("h2=\":443\""));
+        assert_eq!(cache.lookup("a.test", &h3), None);
+        cache.update(
+            "a.test",
+            &HeaderValue::from_static("h2=\":443\", h3-20=\":4433\"; ma=60"),
+        );
+        assert_eq!(cache.lookup("a.test", &h3), Some(4433));
+        assert_eq!(cache.lookup("a.test", &[b"h2".to_vec()]), Some(443));
+        assert_eq!(cache.lookup("a.test", &[b"h3-29".to_vec()]), None);
+        assert_eq!(cache.lookup("b.test", &h3), None);
+
+        cache.update("a.test", &HeaderValue::from_static("clear"));
+        assert_eq!(cache.lookup("a.test", &h3), None);
+
+        cache.update("a.test", &HeaderValue::from_static("h3-20=\":4433\""));
+        cache.mark_broken("a.test");
+        assert_eq!(cache.lookup("a.test", &h3), None);
+        cache.update("a.test", &HeaderValue::from_static("h3-20=\":4433\""));
+        assert_eq!(cache.lookup("a.test", &h3), None);
+    }
+
+    #[test]
+    fn parse_alternatives() {
+        assert_eq!(
+            parse_alt_svc("h3-20=\":443\"; ma=3600; persist=1"),
+            Some((b"h3-20".to_vec(), 443, Duration::from_secs(3600)))
+        );
+        assert_eq!(
+            parse_alt_svc(" w%3Dx=\":443\""),
+            Some((b"w=x".to_vec(), 443, Duration::from_secs(86400)))
+        );
+        assert_eq!(parse_alt_svc("h3-20=\"alt.test:443\""), None);
+        assert_eq!(parse_alt_svc("h3-20=\":443\"; ma=soon"), None);
+    }
+}
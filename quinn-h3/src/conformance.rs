@@ -0,0 +1,297 @@
+//! Self-test of the protocol error handling of `server`
+//!
+//! `Harness` runs a server on a loopback endpoint and connects to it with a bare QUIC client
+//! scripted to violate HTTP/3 in a specific way. Compare the `Outcome` of each `Misbehavior`
+//! with the `expected` one to check that violations are answered with the right error codes.
+//!
+//! Requires the `conformance` feature.
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{
+    future::{self, Either},
+    pin_mut, StreamExt, TryFutureExt,
+};
+use http::{HeaderMap, HeaderValue, Method, Response, StatusCode, Uri};
+use quinn::{
+    Certificate, CertificateChain, PrivateKey, ReadError, ReadToEndError, RecvStream, Runtime,
+};
+use quinn_proto::StreamId;
+
+use crate::{
+    proto::{
+        connection::Connection,
+        frame::{DataFrame, HttpFrame},
+        headers::Header,
+        ErrorCode, StreamType,
+    },
+    server, Error, Settings,
+};
+
+/// Largest header list accepted by the server of a `Harness`
+pub const MAX_HEADER_LIST_SIZE: u64 = 4096;
+
+/// Largest response read by the client, which only expects empty ones
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+/// How long to wait for the server to react to a misbehavior
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A protocol violation committed by the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// Send a DATA frame as the first frame of a request
+    DataBeforeHeaders,
+    /// Send request headers larger than the server's `max_header_list_size`
+    OversizedHeaders,
+    /// Send a second SETTINGS frame on the control stream
+    DuplicateSettings,
+    /// Send a MAX_PUSH_ID frame before SETTINGS on the control stream
+    MissingSettings,
+}
+
+impl Misbehavior {
+    pub const ALL: [Misbehavior; 4] = [
+        Misbehavior::DataBeforeHeaders,
+        Misbehavior::OversizedHeaders,
+        Misbehavior::DuplicateSettings,
+        Misbehavior::MissingSettings,
+    ];
+
+    /// The reaction of a conforming server
+    pub fn expected(self) -> Outcome {
+        match self {
+            Misbehavior::DataBeforeHeaders => Outcome::StreamReset(ErrorCode::FRAME_UNEXPECTED),
+            Misbehavior::OversizedHeaders => {
+                Outcome::StreamReset(ErrorCode::GENERAL_PROTOCOL_ERROR)
+            }
+            Misbehavior::DuplicateSettings => {
+                Outcome::ConnectionClosed(ErrorCode::FRAME_UNEXPECTED)
+            }
+            Misbehavior::MissingSettings => Outcome::ConnectionClosed(ErrorCode::MISSING_SETTINGS),
+        }
+    }
+
+    fn on_request(self) -> bool {
+        match self {
+            Misbehavior::DataBeforeHeaders | Misbehavior::OversizedHeaders => true,
+            Misbehavior::DuplicateSettings | Misbehavior::MissingSettings => false,
+        }
+    }
+}
+
+/// How the server reacted to a misbehavior
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The request stream was reset with this code
+    StreamReset(ErrorCode),
+    /// The connection was closed with this code
+    ConnectionClosed(ErrorCode),
+    /// The request was answered normally
+    Finished,
+    /// Nothing happened in time
+    Ignored,
+}
+
+/// A server and a misbehaving client connected to it
+///
+/// The endpoints and the server's tasks run on quinn's default runtime, so this must be created
+/// from within it.
+pub struct Harness {
+    client: quinn::Endpoint,
+    server_addr: SocketAddr,
+    server_name: String,
+}
+
+impl Harness {
+    /// Start a server presenting the self-signed `cert`, valid for `server_name`
+    pub fn new(cert: Certificate, key: PrivateKey, server_name: &str) -> Result<Self, Error> {
+        let mut config = quinn::ClientConfigBuilder::default();
+        config
+            .protocols(&[crate::ALPN])
+            .add_certificate_authority(cert.clone())
+            .map_err(|e| Error::internal(format!("invalid certificate: {}", e)))?;
+        let mut client = quinn::Endpoint::builder();
+        client.default_client_config(config.build());
+        let (driver, client, _) = client
+            .bind(&"127.0.0.1:0".parse().unwrap())
+            .map_err(|e| Error::internal(format!("failed to bind client: {}", e)))?;
+        let runtime = client.runtime();
+        runtime.spawn(Box::pin(driver.unwrap_or_else(|_| ())));
+
+        let mut server = server::Builder::new(quinn::Endpoint::builder());
+        server
+            .settings(Settings {
+                max_header_list_size: MAX_HEADER_LIST_SIZE,
+                ..Settings::default()
+            })
+            .certificate(CertificateChain::from_certs(vec![cert]), key)
+            .map_err(|e| Error::internal(format!("invalid certificate: {}", e)))?;
+        let (driver, server, mut incoming) = server
+            .bind(&"127.0.0.1:0".parse().unwrap())
+            .map_err(|e| Error::internal(format!("failed to bind server: {}", e)))?;
+        runtime.spawn(Box::pin(driver.unwrap_or_else(|_| ())));
+        let server_runtime = runtime.clone();
+        runtime.spawn(Box::pin(async move {
+            while let Some(connecting) = incoming.accept().await {
+                let runtime = server_runtime.clone();
+                server_runtime.spawn(Box::pin(serve(runtime, connecting)));
+            }
+        }));
+
+        Ok(Self {
+            client,
+            server_addr: server.local_addr(),
+            server_name: server_name.into(),
+        })
+    }
+
+    /// Commit `misbehavior` on a new connection and report how the server reacted
+    pub async fn run(&self, misbehavior: Misbehavior) -> Result<Outcome, Error> {
+        let quinn::NewConnection {
+            driver,
+            connection,
+            mut uni_streams,
+            ..
+        } = self
+            .client
+            .connect(&self.server_addr, &self.server_name)?
+            .await?;
+        let runtime = self.client.runtime();
+        runtime.spawn(Box::pin(driver.unwrap_or_else(|_| ())));
+
+        let mut buf = BytesMut::new();
+        StreamType::CONTROL.encode(&mut buf);
+        let settings = HttpFrame::Settings(Settings::default());
+        match misbehavior {
+            Misbehavior::DuplicateSettings => {
+                settings.encode(&mut buf);
+                settings.encode(&mut buf);
+            }
+            Misbehavior::MissingSettings => HttpFrame::MaxPushId(0).encode(&mut buf),
+            _ => settings.encode(&mut buf),
+        }
+        // The control stream must stay open as long as the connection
+        let mut control = connection.open_uni().await?;
+        control.write_all(&buf).await?;
+
+        let outcome = if misbehavior.on_request() {
+            let (mut send, recv) = connection.open_bi().await?;
+            let mut buf = BytesMut::new();
+            match misbehavior {
+                Misbehavior::DataBeforeHeaders => HttpFrame::Data(DataFrame {
+                    payload: Bytes::from_static(b"hello"),
+                })
+                .encode(&mut buf),
+                _ => oversized_request(send.id())?.encode(&mut buf),
+            }
+            // Writes fail if the server stops the stream early, which `recv` reports as well
+            let _ = send.write_all(&buf).await;
+            timeout(&*runtime, request_outcome(recv)).await
+        } else {
+            // Keep the server's streams open, so it doesn't see its critical streams closed
+            let mut streams = Vec::new();
+            timeout(&*runtime, async {
+                while let Some(stream) = uni_streams.next().await {
+                    match stream {
+                        Ok(stream) => streams.push(stream),
+                        Err(e) => return closed(e),
+                    }
+                }
+                Ok(Outcome::Ignored)
+            })
+            .await
+        };
+
+        connection.close(0u32.into(), b"");
+        outcome.unwrap_or(Ok(Outcome::Ignored))
+    }
+}
+
+async fn serve(runtime: Arc<dyn Runtime>, connecting: server::Connecting) {
+    let (quic_driver, h3_driver, mut incoming) = match connecting.await {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    runtime.spawn(Box::pin(quic_driver.unwrap_or_else(|_| ())));
+    runtime.spawn(Box::pin(h3_driver.unwrap_or_else(|_| ())));
+    while let Some(request) = incoming.next().await {
+        runtime.spawn(Box::pin(async move {
+            if let Ok((_, sender)) = request.await {
+                let response = Response::builder()
+                    .status(StatusCode::OK)
+                    .body(())
+                    .expect("invalid response");
+                let _ = sender.response(response).send().await;
+            }
+        }));
+    }
+}
+
+/// Wait for `future` for at most `TIMEOUT`, or `None` when it took longer
+async fn timeout<T>(runtime: &dyn Runtime, future: impl Future<Output = T>) -> Option<T> {
+    let deadline = runtime.new_timer(Instant::now() + TIMEOUT);
+    pin_mut!(future);
+    match future::select(future, deadline).await {
+        Either::Left((x, _)) => Some(x),
+        Either::Right(_) => None,
+    }
+}
+
+fn oversized_request(stream_id: StreamId) -> Result<HttpFrame, Error> {
+    let mut fields = HeaderMap::new();
+    let padding = "a".repeat(2 * MAX_HEADER_LIST_SIZE as usize);
+    fields.insert("x-padding", HeaderValue::from_str(&padding).unwrap());
+    let header = Header::request(Method::GET, Uri::from_static("https://localhost/"), fields);
+    // A fresh encoder, which doesn't know about the server's limit
+    let mut conn = Connection::with_settings(Settings::default())?;
+    Ok(HttpFrame::Headers(conn.encode_header(stream_id, header)?))
+}
+
+async fn request_outcome(recv: RecvStream) -> Result<Outcome, Error> {
+    match recv.read_to_end(MAX_RESPONSE_SIZE).await {
+        Ok(_) => Ok(Outcome::Finished),
+        Err(ReadToEndError::Read(ReadError::Reset { error_code })) => {
+            match ErrorCode::from_varint(error_code) {
+                Some(code) => Ok(Outcome::StreamReset(code)),
+                None => Err(Error::internal(format!(
+                    "invalid reset code {}",
+                    error_code
+                ))),
+            }
+        }
+        Err(ReadToEndError::Read(ReadError::ConnectionClosed(e))) => closed(e),
+        Err(e) => Err(Error::internal(format!("reading response failed: {}", e))),
+    }
+}
+
+fn closed(e: quinn::ConnectionError) -> Result<Outcome, Error> {
+    let e = Error::from(e);
+    match e.code() {
+        Some(code) => Ok(Outcome::ConnectionClosed(code)),
+        None => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn misbehaviors() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let key = PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+        let cert = Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+        let harness = Harness::new(cert, key, "localhost").unwrap();
+        for &misbehavior in Misbehavior::ALL.iter() {
+            let outcome = harness.run(misbehavior).await.unwrap();
+            assert_eq!(outcome, misbehavior.expected(), "{:?}", misbehavior);
+        }
+    }
+}
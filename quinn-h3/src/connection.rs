@@ -1,55 +1,262 @@
 use std::{
     collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
     future::Future,
     io::{self, Cursor},
     mem,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll, Waker},
+    time::Duration,
 };
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use futures::{io::AsyncRead, Stream};
+use http::{HeaderMap, Method, Uri};
 use quinn::{IncomingBiStreams, IncomingUniStreams, RecvStream, SendStream};
-use quinn_proto::{Side, StreamId};
+use quinn_proto::{Side, StreamId, VarInt};
+use rand::Rng;
+use tracing::{field, Span};
 
 use crate::{
-    frame::{self, FrameStream},
+    body::Body,
+    frame::{self, FrameDecoder, FrameStream, WriteFrame},
+    har::{Recorder, Recording},
+    metrics::Metrics,
     proto::{
         self,
         connection::{
             Connection, DecodeResult, Error as ProtoError, Error as ConnectionError,
-            PendingStreamType,
+            PendingStreamType, Stats,
         },
-        frame::{HeadersFrame, HttpFrame},
+        frame::{DataFrame, FrameStats, HeadersFrame, HttpFrame},
         ErrorCode, StreamType,
     },
+    qlog::{Qlog, QlogFactory},
+    resolve::Resolve,
     streams::{NewUni, RecvUni, SendUni},
     Error, Settings,
 };
 
-pub struct ConnectionDriver(pub(crate) ConnectionRef);
+pub struct ConnectionDriver {
+    conn: ConnectionRef,
+    registration: Option<Registration>,
+}
+
+impl ConnectionDriver {
+    pub(crate) fn new(conn: ConnectionRef, registration: Option<Registration>) -> Self {
+        if let Some(ref metrics) = conn.metrics {
+            metrics.connection_opened();
+        }
+        Self { conn, registration }
+    }
+}
+
+impl Drop for ConnectionDriver {
+    fn drop(&mut self) {
+        if let Some(ref metrics) = self.conn.metrics {
+            metrics.connection_closed();
+        }
+    }
+}
 
 impl Future for ConnectionDriver {
     type Output = Result<(), Error>;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let res = self.0.h3.lock().unwrap().drive(cx);
-        match res {
-            Ok(false) => Poll::Pending,
-            Ok(true) => Poll::Ready(Ok(())),
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let span = self.conn.span.clone();
+        let _guard = span.enter();
+        let res = self.conn.h3.lock().unwrap().drive(cx);
+        let res = match res {
+            Ok(false) => return Poll::Pending,
+            Ok(true) => Ok(()),
             Err(DriverError(err, code, msg)) => {
-                self.0.quic.close(code.into(), msg.as_bytes());
-                Poll::Ready(Err(err))
+                tracing::debug!("closing connection: {:?} {}", code, msg);
+                self.conn.quic.close(code.into(), msg.as_bytes());
+                Err(err)
+            }
+        };
+        self.registration = None;
+        Poll::Ready(res)
+    }
+}
+
+/// Set of connections tracked while their driver is running
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionSet(Arc<Mutex<ConnectionSetInner>>);
+
+#[derive(Default)]
+pub(crate) struct ConnectionSetInner {
+    conns: HashMap<u64, ConnectionRef>,
+}
+
+impl ConnectionSet {
+    pub fn insert(&self, conn: ConnectionRef) -> Registration {
+        let id = conn.id;
+        self.0.lock().unwrap().conns.insert(id, conn);
+        Registration {
+            set: self.clone(),
+            id,
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<ConnectionRef> {
+        self.0.lock().unwrap().conns.get(&id).cloned()
+    }
+
+    pub fn all(&self) -> Vec<(u64, ConnectionRef)> {
+        let inner = self.0.lock().unwrap();
+        inner.conns.iter().map(|(&id, c)| (id, c.clone())).collect()
+    }
+}
+
+/// Removes a connection from its `ConnectionSet` when dropped
+pub(crate) struct Registration {
+    set: ConnectionSet,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // Bind the connection so it is dropped after the lock is released
+        let _removed = self.set.0.lock().unwrap().conns.remove(&self.id);
+    }
+}
+
+/// Drives both the QUIC connection and HTTP/3 on top of it
+///
+/// Completes once the QUIC connection has been closed, yielding the first error encountered.
+/// Spawning this in place of the separate drivers makes it impossible to forget one of them.
+#[must_use = "connection drivers must be spawned for their connections to function"]
+pub struct Driver {
+    quic: Option<quinn::ConnectionDriver>,
+    h3: Option<ConnectionDriver>,
+    error: Option<Error>,
+}
+
+impl Driver {
+    pub fn new(quic: quinn::ConnectionDriver, h3: ConnectionDriver) -> Self {
+        Self {
+            quic: Some(quic),
+            h3: Some(h3),
+            error: None,
+        }
+    }
+}
+
+impl Future for Driver {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if let Some(ref mut h3) = this.h3 {
+            if let Poll::Ready(res) = Pin::new(h3).poll(cx) {
+                this.h3 = None;
+                if let Err(e) = res {
+                    this.error.get_or_insert(e);
+                }
+            }
+        }
+        // Keep the QUIC connection going after an HTTP/3 error so it can close gracefully
+        if let Some(ref mut quic) = this.quic {
+            if let Poll::Ready(res) = Pin::new(quic).poll(cx) {
+                this.quic = None;
+                if let Err(e) = res {
+                    this.error.get_or_insert(e.into());
+                }
             }
         }
+        // HTTP/3 can't make progress without the QUIC connection
+        if this.quic.is_some() {
+            return Poll::Pending;
+        }
+        match this.error.take() {
+            Some(e) => Poll::Ready(Err(e)),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// Local connection behavior which isn't negotiated with the peer
+#[derive(Clone)]
+pub(crate) struct Options {
+    pub grease: f64,
+    pub qpack_blocked_timeout: Option<Duration>,
+    pub metrics: Option<Arc<dyn Metrics>>,
+    pub qlog: Option<QlogFactory>,
+    pub recorder: Option<Recorder>,
+    pub resolver: Option<Arc<dyn Resolve>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            grease: crate::DEFAULT_GREASE,
+            qpack_blocked_timeout: None,
+            metrics: None,
+            qlog: None,
+            recorder: None,
+            resolver: None,
+        }
     }
 }
 
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("grease", &self.grease)
+            .field("qpack_blocked_timeout", &self.qpack_blocked_timeout)
+            .field("metrics", &self.metrics.is_some())
+            .field("qlog", &self.qlog.is_some())
+            .field("recorder", &self.recorder.is_some())
+            .field("resolver", &self.resolver.is_some())
+            .finish()
+    }
+}
+
+/// Identifies a request for correlating logs, and is unique within the process
+///
+/// Available from the extensions of requests received by a server and responses received by a
+/// client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId {
+    connection: u64,
+    stream: u64,
+}
+
+impl RequestId {
+    /// Process-wide identifier of the connection carrying the request
+    pub fn connection(&self) -> u64 {
+        self.connection
+    }
+
+    /// QUIC stream ID of the request
+    pub fn stream(&self) -> u64 {
+        self.stream
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.connection, self.stream)
+    }
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Clone)]
 pub(crate) struct ConnectionRef {
     pub h3: Arc<Mutex<ConnectionInner>>,
     pub quic: quinn::Connection,
+    pub id: u64,
+    pub span: Span,
+    pub metrics: Option<Arc<dyn Metrics>>,
+    pub qlog: Option<Qlog>,
+    pub recorder: Option<Recorder>,
+    frame_stats: Arc<Mutex<FrameStats>>,
 }
 
 impl ConnectionRef {
@@ -59,30 +266,215 @@ impl ConnectionRef {
         uni_streams: IncomingUniStreams,
         bi_streams: IncomingBiStreams,
         settings: Settings,
+        options: Options,
     ) -> Result<Self, ProtoError> {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let qlog = match options.qlog {
+            Some(ref factory) => factory(id).map(|sink| Qlog::new(sink, side, id)),
+            None => None,
+        };
+        let mut rng = rand::thread_rng();
+        let grease_uni = if rng.gen_bool(options.grease) {
+            let mut send = SendUni::new(
+                StreamType(proto::frame::grease_type(&mut rng)),
+                quic.open_uni(),
+                qlog.clone(),
+            );
+            send.push(Bytes::copy_from_slice(&rng.gen::<[u8; 4]>()));
+            Some(send)
+        } else {
+            None
+        };
+        let frame_stats = Arc::new(Mutex::new(FrameStats::default()));
+        let span = tracing::info_span!("h3", id, side = ?side);
         Ok(Self {
+            id,
+            span,
+            metrics: options.metrics,
+            recorder: options.recorder,
+            qlog: qlog.clone(),
             quic: quic.clone(),
+            frame_stats: frame_stats.clone(),
             h3: Arc::new(Mutex::new(ConnectionInner {
                 side,
                 driver: None,
                 incoming_bi: bi_streams,
                 incoming_uni: uni_streams,
                 pending_uni: VecDeque::with_capacity(3),
-                inner: Connection::with_settings(settings)?,
+                inner: Connection::new(settings, rng.gen_bool(options.grease))?,
                 requests: VecDeque::with_capacity(16),
                 requests_task: None,
                 recv_control: None,
                 recv_encoder: None,
                 recv_decoder: None,
                 blocked_streams: BTreeMap::new(),
+                qpack_blocked_timeout: options.qpack_blocked_timeout,
                 send_unis: [
-                    SendUni::new(StreamType::CONTROL, quic.open_uni()),
-                    SendUni::new(StreamType::ENCODER, quic.open_uni()),
-                    SendUni::new(StreamType::DECODER, quic.open_uni()),
+                    SendUni::new(StreamType::CONTROL, quic.open_uni(), qlog.clone()),
+                    SendUni::new(StreamType::ENCODER, quic.open_uni(), qlog.clone()),
+                    SendUni::new(StreamType::DECODER, quic.open_uni(), qlog.clone()),
                 ],
+                grease_uni,
+                extension_unis: HashMap::new(),
+                frame_stats,
+                qlog,
             })),
         })
     }
+
+    pub fn request_id(&self, stream_id: StreamId) -> RequestId {
+        RequestId {
+            connection: self.id,
+            stream: stream_id.0,
+        }
+    }
+
+    /// Span covering a request's lifetime, with its fields recorded once known
+    pub fn request_span(&self) -> Span {
+        tracing::info_span!(
+            parent: &self.span,
+            "request",
+            stream = field::Empty,
+            method = field::Empty,
+            path = field::Empty
+        )
+    }
+
+    /// Record an outgoing request, if a `Recorder` is installed
+    pub fn start_recording(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: &Body,
+    ) -> Option<Recording> {
+        let recording = self.recorder.as_ref()?.start(self.id, method, uri, headers);
+        if let Body::Buf(ref payload) = body {
+            recording.request_body(payload);
+        }
+        Some(recording)
+    }
+
+    /// Decode frames from a request stream, counting them in the connection stats
+    pub fn frame_stream(&self, recv: RecvStream) -> FrameStream {
+        let stream_id = recv.id();
+        let mut stream = FrameDecoder::stream(recv);
+        stream.decoder_mut().count_frames(self.frame_stats.clone());
+        if let Some(ref qlog) = self.qlog {
+            stream.decoder_mut().qlog_frames(qlog.clone(), stream_id);
+        }
+        stream
+    }
+
+    /// Start sending `payload` in a DATA frame
+    pub fn write_data(&self, send: SendStream, payload: Bytes) -> WriteFrame {
+        if let Some(ref qlog) = self.qlog {
+            qlog.frame_created(send.id(), "data", payload.len());
+        }
+        WriteFrame::new(send, DataFrame { payload })
+    }
+
+    pub fn stats(&self) -> Stats {
+        let stats = self.h3.lock().unwrap().inner.stats();
+        Stats {
+            frames_received: *self.frame_stats.lock().unwrap(),
+            ..stats
+        }
+    }
+
+    pub fn request_cancelled(&self) {
+        self.h3.lock().unwrap().inner.request_cancelled();
+    }
+
+    pub fn accept_uni(&self, ty: u64) -> Result<UniStreams, Error> {
+        if !is_extension_stream_type(ty) {
+            return Err(Error::internal("invalid extension stream type"));
+        }
+        let mut conn = self.h3.lock().unwrap();
+        if conn.extension_unis.contains_key(&ty) {
+            return Err(Error::internal("extension stream type already accepted"));
+        }
+        conn.extension_unis.insert(ty, ExtensionUnis::default());
+        Ok(UniStreams {
+            conn: self.clone(),
+            ty,
+        })
+    }
+
+    pub async fn open_uni(&self, ty: u64) -> Result<SendStream, Error> {
+        if !is_extension_stream_type(ty) {
+            return Err(Error::internal("invalid extension stream type"));
+        }
+        let mut send = self.quic.open_uni().await?;
+        if let Some(ref qlog) = self.qlog {
+            qlog.stream_type_set(send.id(), true, StreamType(ty));
+        }
+        send.write_all(&StreamType(ty).encoded()).await?;
+        Ok(send)
+    }
+}
+
+/// Record the stream carrying a request in a span from `ConnectionRef::request_span`
+pub(crate) fn record_stream(span: &Span, stream_id: StreamId) {
+    let stream = field::display(stream_id.0);
+    span.record("stream", &stream);
+}
+
+/// Record the method and path of a request in a span from `ConnectionRef::request_span`
+pub(crate) fn record_request(span: &Span, method: &Method, uri: &Uri) {
+    let (method, path) = (field::display(method), field::display(uri.path()));
+    span.record("method", &method);
+    span.record("path", &path);
+}
+
+fn is_extension_stream_type(ty: u64) -> bool {
+    ty > StreamType::DECODER.0 && VarInt::from_u64(ty).is_ok()
+}
+
+/// Unidirectional streams of an extension type opened by the peer
+pub struct UniStreams {
+    conn: ConnectionRef,
+    ty: u64,
+}
+
+impl Stream for UniStreams {
+    type Item = Result<RecvStream, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut conn = self.conn.h3.lock().unwrap();
+        let unis = match conn.extension_unis.get_mut(&self.ty) {
+            Some(unis) => unis,
+            None => {
+                return Poll::Ready(Some(Err(Error::internal(
+                    "extension stream type no longer accepted",
+                ))));
+            }
+        };
+        match unis.streams.pop_front() {
+            Some(recv) => Poll::Ready(Some(Ok(recv))),
+            None => {
+                unis.task = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for UniStreams {
+    fn drop(&mut self) {
+        let mut conn = self.conn.h3.lock().unwrap();
+        if let Some(unis) = conn.extension_unis.remove(&self.ty) {
+            for mut recv in unis.streams {
+                let _ = recv.stop(ErrorCode::STREAM_CREATION_ERROR.into());
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct ExtensionUnis {
+    streams: VecDeque<RecvStream>,
+    task: Option<Waker>,
 }
 
 pub(crate) struct ConnectionInner {
@@ -98,7 +490,12 @@ pub(crate) struct ConnectionInner {
     recv_encoder: Option<(RecvStream, BytesMut)>,
     recv_decoder: Option<(RecvStream, BytesMut)>,
     blocked_streams: BTreeMap<usize, HashMap<StreamId, Waker>>,
+    pub qpack_blocked_timeout: Option<Duration>,
     send_unis: [SendUni; 3],
+    grease_uni: Option<SendUni>,
+    extension_unis: HashMap<u64, ExtensionUnis>,
+    frame_stats: Arc<Mutex<FrameStats>>,
+    qlog: Option<Qlog>,
 }
 
 impl ConnectionInner {
@@ -136,7 +533,7 @@ impl ConnectionInner {
     ) -> Result<DecodeResult, Error> {
         self.inner
             .decode_header(stream_id, header)
-            .map_err(|e| Error::peer(format!("decoding header failed: {:?}", e)))
+            .map_err(Error::Header)
             .map(|r| {
                 match &r {
                     DecodeResult::Decoded(_, true) => self.wake(), // send header acknowledgement
@@ -151,6 +548,30 @@ impl ConnectionInner {
                 };
                 r
             })
+            .and_then(|r| {
+                let blocked = self
+                    .blocked_streams
+                    .values()
+                    .map(|x| x.len())
+                    .sum::<usize>();
+                if blocked as u64 > self.inner.local_settings().qpack_blocked_streams {
+                    self.unblock(stream_id);
+                    return Err(Error::peer(
+                        ErrorCode::QPACK_DECOMPRESSION_FAILED,
+                        "too many blocked streams",
+                    ));
+                }
+                Ok(r)
+            })
+    }
+
+    /// Stop waiting for dynamic table updates to decode the headers of `stream_id`
+    pub fn unblock(&mut self, stream_id: StreamId) {
+        for streams in self.blocked_streams.values_mut() {
+            streams.remove(&stream_id);
+        }
+        self.blocked_streams
+            .retain(|_, streams| !streams.is_empty());
     }
 
     fn poll_incoming_bi(&mut self, cx: &mut Context) -> Result<(), DriverError> {
@@ -235,7 +656,13 @@ impl ConnectionInner {
                     let msg = format!("{:?}", e);
                     return Err(DriverError::new(e, ErrorCode::STREAM_CREATION_ERROR, msg));
                 }
-                Ok(n) => self.on_uni_resolved(n)?,
+                Ok(n) => {
+                    if let Some(ref qlog) = self.qlog {
+                        let (stream_id, ty) = n.stream();
+                        qlog.stream_type_set(stream_id, false, ty);
+                    }
+                    self.on_uni_resolved(n)?
+                }
             }
         }
         Ok(())
@@ -243,8 +670,13 @@ impl ConnectionInner {
 
     fn on_uni_resolved(&mut self, new_stream: NewUni) -> Result<(), DriverError> {
         match new_stream {
-            NewUni::Control(stream) => match self.recv_control {
+            NewUni::Control(mut stream) => match self.recv_control {
                 None => {
+                    stream.decoder_mut().count_frames(self.frame_stats.clone());
+                    if let Some(ref qlog) = self.qlog {
+                        let stream_id = stream.get_ref().0.id();
+                        stream.decoder_mut().qlog_frames(qlog.clone(), stream_id);
+                    }
                     self.recv_control = Some(stream);
                     Ok(())
                 }
@@ -277,6 +709,22 @@ impl ConnectionInner {
             },
             NewUni::Push(_) => {
                 println!("push stream ignored");
+                self.inner.push_stream_received();
+                Ok(())
+            }
+            NewUni::Unknown(ty, mut recv) => {
+                match self.extension_unis.get_mut(&ty.0) {
+                    Some(unis) => {
+                        unis.streams.push_back(recv);
+                        if let Some(t) = unis.task.take() {
+                            t.wake();
+                        }
+                    }
+                    None => {
+                        // Reserved stream types are used for greasing, others are ignored as well
+                        let _ = recv.stop(ErrorCode::STREAM_CREATION_ERROR.into());
+                    }
+                }
                 Ok(())
             }
         }
@@ -306,6 +754,7 @@ impl ConnectionInner {
                         (_, _, HttpFrame::Settings(s)) => {
                             self.inner.set_remote_settings(s)?;
                         }
+                        (true, _, HttpFrame::Extension(_)) => (),
                         (true, Side::Client, HttpFrame::Goaway(id)) => {
                             self.inner.leave(StreamId(id));
                         }
@@ -422,6 +871,12 @@ impl ConnectionInner {
                 Poll::Pending => return Ok(()),
             }
         }
+        if let Some(ref mut grease) = self.grease_uni {
+            // The stream is finished once dropped, failures are of no consequence
+            if Pin::new(grease).poll(cx).is_ready() {
+                self.grease_uni = None;
+            }
+        }
         Ok(())
     }
 }
@@ -435,7 +890,7 @@ impl DriverError {
 
     fn peer<T: Into<String>>(code: ErrorCode, msg: T) -> Self {
         let msg = msg.into();
-        DriverError(Error::Peer(msg.clone()), code, msg)
+        DriverError(Error::peer(code, msg.clone()), code, msg)
     }
 
     fn internal<T: Into<String>>(msg: T) -> Self {
@@ -449,7 +904,7 @@ impl DriverError {
 
 impl From<quinn::ConnectionError> for DriverError {
     fn from(err: quinn::ConnectionError) -> DriverError {
-        DriverError::new(Error::Quic(err), ErrorCode::INTERNAL_ERROR, "")
+        DriverError::new(Error::Transport(err), ErrorCode::INTERNAL_ERROR, "")
     }
 }
 
@@ -1,24 +1,38 @@
 use std::{
     future::Future,
-    io, mem,
+    io,
     pin::Pin,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
 };
 
 use bytes::{Buf, Bytes, BytesMut};
-use futures::{io::AsyncWrite, ready};
+use futures::{io::AsyncRead, ready};
 use quinn::{RecvStream, SendStream, VarInt};
-use tokio::io::AsyncRead;
+use quinn_proto::StreamId;
 use tokio_util::codec::{Decoder, FramedRead};
 
-use super::proto::frame::{self, FrameHeader, HttpFrame, IntoPayload, PartialData};
-use crate::{proto::ErrorCode, streams::Reset};
+use super::proto::frame::{self, FrameHeader, FrameStats, HttpFrame, IntoPayload, PartialData};
+use crate::{proto::ErrorCode, qlog::Qlog, streams::Reset};
 
-pub type FrameStream = FramedRead<RecvStream, FrameDecoder>;
+pub type FrameStream = FramedRead<FrameRecv, FrameDecoder>;
 
 impl Reset for FrameStream {
     fn reset(self, error_code: ErrorCode) {
-        let _ = self.into_inner().stop(error_code.0.into());
+        let _ = self.into_inner().0.stop(error_code.0.into());
+    }
+}
+
+/// A `RecvStream` implementing tokio's `AsyncRead` for `FramedRead`, whichever runtime drives it
+pub struct FrameRecv(pub RecvStream);
+
+impl tokio::io::AsyncRead for FrameRecv {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncRead::poll_read(Pin::new(&mut self.0), cx, buf)
     }
 }
 
@@ -26,18 +40,41 @@ impl Reset for FrameStream {
 pub struct FrameDecoder {
     partial: Option<PartialData>,
     expected: Option<usize>,
+    stats: Option<Arc<Mutex<FrameStats>>>,
+    qlog: Option<(Qlog, StreamId)>,
 }
 
 impl FrameDecoder {
-    pub fn stream<T: AsyncRead>(stream: T) -> FramedRead<T, Self> {
+    pub fn stream(stream: RecvStream) -> FrameStream {
         FramedRead::new(
-            stream,
+            FrameRecv(stream),
             FrameDecoder {
                 expected: None,
                 partial: None,
+                stats: None,
+                qlog: None,
             },
         )
     }
+
+    /// Count the frames decoded from now on into `stats`
+    pub(crate) fn count_frames(&mut self, stats: Arc<Mutex<FrameStats>>) {
+        self.stats = Some(stats);
+    }
+
+    /// Record the frames decoded from now on in `qlog`
+    pub(crate) fn qlog_frames(&mut self, qlog: Qlog, stream_id: StreamId) {
+        self.qlog = Some((qlog, stream_id));
+    }
+
+    fn count(&self, frame: &HttpFrame) {
+        if let Some(ref stats) = self.stats {
+            stats.lock().unwrap().count(frame);
+        }
+        if let Some((ref qlog, stream_id)) = self.qlog {
+            qlog.frame_parsed(stream_id, frame);
+        }
+    }
 }
 
 macro_rules! decode {
@@ -53,65 +90,82 @@ impl Decoder for FrameDecoder {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            return Ok(None);
-        }
+        loop {
+            if src.is_empty() {
+                return Ok(None);
+            }
 
-        if let Some(ref mut partial) = self.partial {
-            let (pos, frame) = decode!(src, |cur| HttpFrame::Data(partial.decode_data(cur)));
-            src.advance(pos);
+            if let Some(ref mut partial) = self.partial {
+                let frame = HttpFrame::Data(partial.decode_data(src));
+                if partial.remaining() == 0 {
+                    self.partial = None;
+                }
 
-            if partial.remaining() == 0 {
-                self.partial = None;
+                return Ok(Some(frame));
             }
 
-            return Ok(Some(frame));
-        }
-
-        if let Some(min) = self.expected {
-            if src.len() < min {
-                return Ok(None);
+            if let Some(min) = self.expected {
+                if src.len() < min {
+                    return Ok(None);
+                }
             }
-        }
 
-        let (pos, decoded) = decode!(src, |cur| HttpFrame::decode(cur));
-
-        match decoded {
-            Err(frame::Error::IncompleteData) => {
-                let (pos, decoded) = decode!(src, |cur| PartialData::decode(cur));
-                let (partial, frame) = decoded?;
-                src.advance(pos);
-                self.expected = None;
-                self.partial = Some(partial);
-                if frame.len() > 0 {
-                    Ok(Some(HttpFrame::Data(frame)))
-                } else {
+            let (pos, decoded) = decode!(src, |cur| HttpFrame::decode(cur));
+
+            return match decoded {
+                Err(frame::Error::IncompleteData) => {
+                    let (pos, decoded) = decode!(src, |cur| PartialData::decode(cur));
+                    let mut partial = decoded?;
+                    src.advance(pos);
+                    self.expected = None;
+                    let frame = partial.decode_data(src);
+                    self.partial = Some(partial);
+                    let empty = frame.len() == 0;
+                    let frame = HttpFrame::Data(frame);
+                    self.count(&frame);
+                    if !empty {
+                        Ok(Some(frame))
+                    } else {
+                        Ok(None)
+                    }
+                }
+                Err(frame::Error::Incomplete(min)) => {
+                    self.expected = Some(min);
                     Ok(None)
                 }
+                Err(e) => Err(e.into()),
+                Ok(HttpFrame::Reserved) => {
+                    // Reserved frame types are used for greasing and must be ignored
+                    self.count(&HttpFrame::Reserved);
+                    src.advance(pos);
+                    self.expected = None;
+                    continue;
+                }
+                Ok(frame) => {
+                    self.count(&frame);
+                    src.advance(pos);
+                    self.expected = None;
+                    Ok(Some(frame))
+                }
+            };
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            // The stream ended in the middle of a frame
+            None if !src.is_empty() || self.partial.is_some() => {
+                Err(frame::Error::UnexpectedEnd.into())
             }
-            Err(frame::Error::Incomplete(min)) => {
-                self.expected = Some(min);
-                Ok(None)
-            }
-            Err(e) => Err(e.into()),
-            Ok(frame) => {
-                src.advance(pos);
-                self.expected = None;
-                Ok(Some(frame))
-            }
+            None => Ok(None),
         }
     }
 }
 
 pub struct WriteFrame {
-    state: WriteFrameState,
-    payload: Option<Bytes>,
-}
-
-enum WriteFrameState {
-    Header(SendStream, Bytes),
-    Payload(SendStream, Bytes),
-    Finished,
+    send: Option<SendStream>,
+    bufs: [Bytes; 2],
 }
 
 impl WriteFrame {
@@ -123,13 +177,13 @@ impl WriteFrame {
         frame.encode_header(&mut buf);
 
         Self {
-            payload: Some(frame.into_payload()),
-            state: WriteFrameState::Header(send, buf.into()),
+            send: Some(send),
+            bufs: [buf.into(), frame.into_payload()],
         }
     }
 
     pub fn reset(self, err_code: ErrorCode) {
-        if let WriteFrameState::Header(mut s, _) | WriteFrameState::Payload(mut s, _) = self.state {
+        if let Some(mut s) = self.send {
             s.reset(err_code.into());
         }
     }
@@ -139,33 +193,21 @@ impl Future for WriteFrame {
     type Output = Result<SendStream, io::Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
         loop {
-            match self.state {
-                WriteFrameState::Finished => panic!("polled after finished"),
-                WriteFrameState::Header(ref mut send, ref mut h) => {
-                    let wrote = ready!(Pin::new(send).poll_write(cx, h))?;
-                    h.advance(wrote);
-                    if h.is_empty() {
-                        self.state = match mem::replace(&mut self.state, WriteFrameState::Finished)
-                        {
-                            WriteFrameState::Header(s, _) => {
-                                WriteFrameState::Payload(s, self.payload.take().unwrap())
-                            }
-                            _ => unreachable!(),
-                        }
-                    }
-                }
-                WriteFrameState::Payload(ref mut send, ref mut p) => {
-                    let wrote = ready!(Pin::new(send).poll_write(cx, p))?;
-                    p.advance(wrote);
-                    let send = match mem::replace(&mut self.state, WriteFrameState::Finished) {
-                        WriteFrameState::Payload(s, _) => s,
-                        _ => unreachable!(),
-                    };
-                    self.state = WriteFrameState::Finished;
-                    return Poll::Ready(Ok(send));
+            let send = match this.send {
+                Some(ref mut send) => send,
+                None => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "polled after finished",
+                    )));
                 }
+            };
+            if this.bufs.iter().all(|x| x.is_empty()) {
+                return Poll::Ready(Ok(this.send.take().unwrap()));
             }
+            ready!(send.poll_write_chunks(cx, &mut this.bufs))?;
         }
     }
 }
@@ -254,4 +296,49 @@ mod tests {
         assert_matches!(decoder.decode(&mut buf), Ok(Some(HttpFrame::Data(_))));
         assert_matches!(decoder.decode(&mut buf), Ok(None));
     }
+
+    #[test]
+    fn partial_data() {
+        let frame = HttpFrame::Data(frame::DataFrame {
+            payload: b"hello world"[..].into(),
+        });
+        let mut encoded = BytesMut::with_capacity(16);
+        frame.encode(&mut encoded);
+
+        let mut decoder = FrameDecoder::default();
+        let mut buf = encoded.split_to(7);
+        assert_matches!(
+            decoder.decode(&mut buf),
+            Ok(Some(HttpFrame::Data(ref f))) if f.payload == b"hello"[..]
+        );
+        assert!(buf.is_empty());
+        assert_matches!(decoder.decode(&mut buf), Ok(None));
+        assert_matches!(
+            decoder.decode_eof(&mut buf),
+            Err(Error::Proto(frame::Error::UnexpectedEnd))
+        );
+
+        buf.extend_from_slice(&encoded);
+        assert_matches!(
+            decoder.decode(&mut buf),
+            Ok(Some(HttpFrame::Data(ref f))) if f.payload == b" world"[..]
+        );
+        assert_matches!(decoder.decode_eof(&mut buf), Ok(None));
+    }
+
+    #[test]
+    fn many_reserved_frames() {
+        let mut buf = BytesMut::new();
+        for _ in 0..100_000 {
+            // Empty frame of the first reserved type
+            buf.extend_from_slice(&[0x21, 0x00]);
+        }
+        HttpFrame::Headers(frame::HeadersFrame {
+            encoded: b"header"[..].into(),
+        })
+        .encode(&mut buf);
+
+        let mut decoder = FrameDecoder::default();
+        assert_matches!(decoder.decode(&mut buf), Ok(Some(HttpFrame::Headers(_))));
+    }
 }
@@ -0,0 +1,368 @@
+//! Recording of requests and responses for debugging
+//!
+//! Install a `Recorder` with `client::Builder::recorder` to capture the headers and timings of
+//! every request sent, and optionally the bodies, then serialize them in the HTTP Archive (HAR)
+//! format understood by browser developer tools.
+
+use std::{
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use http::{HeaderMap, Method, StatusCode, Uri};
+
+/// Collects the exchanges of all connections it is installed on
+#[derive(Clone, Default)]
+pub struct Recorder(Arc<Mutex<RecorderInner>>);
+
+#[derive(Default)]
+struct RecorderInner {
+    entries: Vec<Entry>,
+    /// Number of entries discarded by `clear`
+    cleared: usize,
+    body_limit: Option<usize>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also capture the first `limit` bytes of request and response bodies
+    ///
+    /// Only bodies sent as a whole and responses read with `RecvBody::read_to_end` are captured.
+    pub fn capture_bodies(&self, limit: usize) -> &Self {
+        self.0.lock().unwrap().body_limit = Some(limit);
+        self
+    }
+
+    /// The exchanges recorded so far
+    pub fn entries(&self) -> Vec<Entry> {
+        self.0.lock().unwrap().entries.clone()
+    }
+
+    /// Discard the exchanges recorded so far
+    pub fn clear(&self) {
+        let inner = &mut *self.0.lock().unwrap();
+        inner.cleared += inner.entries.len();
+        inner.entries.clear();
+    }
+
+    /// Serialize the recorded exchanges as an HTTP Archive
+    pub fn to_har(&self) -> String {
+        let inner = self.0.lock().unwrap();
+        let mut out = String::new();
+        write!(
+            out,
+            "{{\"log\":{{\"version\":\"1.2\",\
+             \"creator\":{{\"name\":\"quinn-h3\",\"version\":\"{}\"}},\"entries\":[",
+            env!("CARGO_PKG_VERSION")
+        )
+        .unwrap();
+        for (i, entry) in inner.entries.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            entry.write_har(&mut out);
+        }
+        out.push_str("]}}");
+        out
+    }
+
+    pub(crate) fn start(
+        &self,
+        connection: u64,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+    ) -> Recording {
+        let mut inner = self.0.lock().unwrap();
+        inner.entries.push(Entry {
+            connection,
+            stream: None,
+            started: SystemTime::now(),
+            method: method.clone(),
+            uri: uri.clone(),
+            request_headers: headers.clone(),
+            request_body: None,
+            status: None,
+            response_headers: HeaderMap::new(),
+            response_body: None,
+            wait: None,
+            receive: None,
+        });
+        Recording {
+            recorder: self.clone(),
+            index: inner.cleared + inner.entries.len() - 1,
+            start: Instant::now(),
+            response: None,
+        }
+    }
+}
+
+/// A recorded request and its response
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Identifier of the connection, see `RequestId::connection`
+    pub connection: u64,
+    /// QUIC stream ID, once the stream was opened
+    pub stream: Option<u64>,
+    pub started: SystemTime,
+    pub method: Method,
+    pub uri: Uri,
+    pub request_headers: HeaderMap,
+    pub request_body: Option<Bytes>,
+    /// Status of the response, if any was received
+    pub status: Option<StatusCode>,
+    pub response_headers: HeaderMap,
+    pub response_body: Option<Bytes>,
+    /// Time from starting the request to receiving the response headers
+    pub wait: Option<Duration>,
+    /// Time from receiving the response headers to receiving the whole body
+    pub receive: Option<Duration>,
+}
+
+impl Entry {
+    fn write_har(&self, out: &mut String) {
+        let wait = self.wait.map_or(-1.0, ms);
+        let receive = self.receive.map_or(-1.0, ms);
+        let time = wait.max(0.0) + receive.max(0.0);
+        write!(
+            out,
+            "{{\"startedDateTime\":\"{}\",\"time\":{},\"connection\":\"{}\",\"_stream\":{},",
+            iso8601(self.started),
+            time,
+            self.connection,
+            self.stream.map_or(-1, |x| x as i64),
+        )
+        .unwrap();
+
+        write!(
+            out,
+            "\"request\":{{\"method\":\"{}\",\"url\":{},\"httpVersion\":\"HTTP/3\",\
+             \"cookies\":[],\"headers\":",
+            self.method,
+            json_string(&self.uri.to_string()),
+        )
+        .unwrap();
+        write_headers(out, &self.request_headers);
+        write!(out, ",\"queryString\":[],\"headersSize\":-1,\"bodySize\":").unwrap();
+        match self.request_body {
+            Some(ref body) => write!(
+                out,
+                "{},\"postData\":{{\"mimeType\":\"\",\"text\":{}}}}},",
+                body.len(),
+                json_string(&String::from_utf8_lossy(body))
+            )
+            .unwrap(),
+            None => out.push_str("-1},"),
+        }
+
+        write!(
+            out,
+            "\"response\":{{\"status\":{},\"statusText\":\"{}\",\"httpVersion\":\"HTTP/3\",\
+             \"cookies\":[],\"headers\":",
+            self.status.map_or(0, |x| x.as_u16()),
+            self.status.and_then(|x| x.canonical_reason()).unwrap_or(""),
+        )
+        .unwrap();
+        write_headers(out, &self.response_headers);
+        let (size, text) = match self.response_body {
+            Some(ref body) => (
+                body.len() as i64,
+                format!(",\"text\":{}", json_string(&String::from_utf8_lossy(body))),
+            ),
+            None => (-1, String::new()),
+        };
+        write!(
+            out,
+            ",\"content\":{{\"size\":{},\"mimeType\":\"\"{}}},\"redirectURL\":\"\",\
+             \"headersSize\":-1,\"bodySize\":{}}},",
+            size, text, size
+        )
+        .unwrap();
+
+        write!(
+            out,
+            "\"cache\":{{}},\"timings\":{{\"send\":0,\"wait\":{},\"receive\":{}}}}}",
+            wait, receive
+        )
+        .unwrap();
+    }
+}
+
+/// Updates an `Entry` as the exchange progresses
+pub(crate) struct Recording {
+    recorder: Recorder,
+    index: usize,
+    start: Instant,
+    response: Option<Instant>,
+}
+
+impl Recording {
+    fn update(&self, f: impl FnOnce(&mut Entry, Option<usize>)) {
+        let inner = &mut *self.recorder.0.lock().unwrap();
+        // The entry is gone if the recorder was cleared in the meantime
+        if self.index < inner.cleared {
+            return;
+        }
+        f(
+            &mut inner.entries[self.index - inner.cleared],
+            inner.body_limit,
+        );
+    }
+
+    pub fn stream(&self, id: u64) {
+        self.update(|entry, _| entry.stream = Some(id));
+    }
+
+    pub fn request_body(&self, body: &Bytes) {
+        self.update(|entry, limit| entry.request_body = limit.map(|x| truncate(body, x)));
+    }
+
+    pub fn response(&mut self, status: StatusCode, headers: &HeaderMap) {
+        let now = Instant::now();
+        let wait = now - self.start;
+        self.response = Some(now);
+        self.update(|entry, _| {
+            entry.status = Some(status);
+            entry.response_headers = headers.clone();
+            entry.wait = Some(wait);
+        });
+    }
+
+    pub fn response_body(&self, body: &Bytes) {
+        let receive = self.response.map(|x| x.elapsed());
+        self.update(|entry, limit| {
+            entry.response_body = limit.map(|x| truncate(body, x));
+            entry.receive = receive;
+        });
+    }
+}
+
+fn truncate(body: &Bytes, limit: usize) -> Bytes {
+    body.slice(..body.len().min(limit))
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+fn write_headers(out: &mut String, headers: &HeaderMap) {
+    out.push('[');
+    for (i, (name, value)) in headers.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write!(
+            out,
+            "{{\"name\":\"{}\",\"value\":{}}}",
+            name,
+            json_string(&String::from_utf8_lossy(value.as_bytes()))
+        )
+        .unwrap();
+    }
+    out.push(']');
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format `time` as an ISO 8601 UTC timestamp with millisecond precision
+fn iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Convert days since the epoch to a civil date, after Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp() {
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_582_934_400_123);
+        assert_eq!(iso8601(time), "2020-02-29T00:00:00.123Z");
+        let time = UNIX_EPOCH + Duration::from_secs(1_577_836_799);
+        assert_eq!(iso8601(time), "2019-12-31T23:59:59.000Z");
+    }
+
+    #[test]
+    fn json_escape() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn record() {
+        let recorder = Recorder::new();
+        recorder.capture_bodies(4);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-test", "1".parse().unwrap());
+        let uri = "https://localhost/a?b".parse::<Uri>().unwrap();
+        let mut recording = recorder.start(3, &Method::POST, &uri, &headers);
+        recording.stream(8);
+        recording.request_body(&Bytes::from_static(b"hello"));
+        recording.response(StatusCode::NOT_FOUND, &HeaderMap::new());
+        recording.response_body(&Bytes::from_static(b"gone"));
+
+        let entries = recorder.entries();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.connection, 3);
+        assert_eq!(entry.stream, Some(8));
+        assert_eq!(entry.request_body.as_ref().unwrap(), &b"hell"[..]);
+        assert_eq!(entry.status, Some(StatusCode::NOT_FOUND));
+        assert!(entry.wait.is_some() && entry.receive.is_some());
+
+        let har = recorder.to_har();
+        assert!(har.starts_with("{\"log\":{\"version\":\"1.2\""));
+        assert!(har.contains("\"method\":\"POST\",\"url\":\"https://localhost/a?b\""));
+        assert!(har.contains("\"headers\":[{\"name\":\"x-test\",\"value\":\"1\"}]"));
+        assert!(har.contains("\"postData\":{\"mimeType\":\"\",\"text\":\"hell\"}"));
+        assert!(har.contains("\"status\":404,\"statusText\":\"Not Found\""));
+        assert!(har.contains("\"content\":{\"size\":4,\"mimeType\":\"\",\"text\":\"gone\"}"));
+        assert!(har.ends_with("}]}}"));
+
+        recorder.clear();
+        recording.response_body(&Bytes::new());
+        assert!(recorder.entries().is_empty());
+    }
+}
@@ -2,10 +2,12 @@ use std::{
     future::Future,
     pin::Pin,
     task::{Context, Poll},
+    time::Instant,
 };
 
-use quinn::SendStream;
+use quinn::{AsyncTimer, SendStream};
 use quinn_proto::StreamId;
+use tracing::trace;
 
 use crate::{
     connection::ConnectionRef,
@@ -18,6 +20,13 @@ pub struct DecodeHeaders {
     frame: Option<HeadersFrame>,
     conn: ConnectionRef,
     stream_id: StreamId,
+    blocked: Option<Blocked>,
+}
+
+/// Waiting for dynamic table updates
+struct Blocked {
+    since: Instant,
+    timeout: Option<Pin<Box<dyn AsyncTimer>>>,
 }
 
 impl DecodeHeaders {
@@ -26,6 +35,7 @@ impl DecodeHeaders {
             conn,
             stream_id,
             frame: Some(frame),
+            blocked: None,
         }
     }
 }
@@ -33,23 +43,66 @@ impl DecodeHeaders {
 impl Future for DecodeHeaders {
     type Output = Result<Header, Error>;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        match self.frame {
-            None => Poll::Ready(Err(crate::Error::internal("frame none"))),
-            Some(ref frame) => {
-                let mut conn = self.conn.h3.lock().unwrap();
-                let result = conn.decode_header(cx, self.stream_id, frame);
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let frame = match this.frame {
+            None => return Poll::Ready(Err(crate::Error::internal("frame none"))),
+            Some(ref frame) => frame,
+        };
+        let mut conn = this.conn.h3.lock().unwrap();
+        let result = conn.decode_header(cx, this.stream_id, frame);
 
-                match result {
-                    Ok(DecodeResult::MissingRefs(_)) => Poll::Pending,
-                    Ok(DecodeResult::Decoded(decoded, _)) => Poll::Ready(Ok(decoded)),
-                    Err(e) => Poll::Ready(Err(e.into())),
+        match result {
+            Ok(DecodeResult::MissingRefs(_)) => {
+                let timeout = conn.qpack_blocked_timeout;
+                let quic = &this.conn.quic;
+                let blocked = this.blocked.get_or_insert_with(|| {
+                    trace!("headers blocked on dynamic table updates");
+                    Blocked {
+                        since: Instant::now(),
+                        timeout: timeout.map(|t| quic.runtime().new_timer(Instant::now() + t)),
+                    }
+                });
+                let timed_out = match blocked.timeout {
+                    Some(ref mut delay) => delay.as_mut().poll(cx).is_ready(),
+                    None => false,
+                };
+                if !timed_out {
+                    return Poll::Pending;
                 }
+                trace!("timed out waiting for dynamic table updates");
+                conn.unblock(this.stream_id);
+                conn.inner.header_blocked_timeout();
+                this.blocked = None;
+                this.frame = None;
+                Poll::Ready(Err(Error::Timeout))
+            }
+            Ok(DecodeResult::Decoded(decoded, _)) => {
+                if let Some(blocked) = this.blocked.take() {
+                    let elapsed = blocked.since.elapsed();
+                    trace!("headers unblocked after {:?}", elapsed);
+                    conn.inner.header_unblocked(elapsed);
+                }
+                trace!("decoded headers");
+                Poll::Ready(Ok(decoded))
+            }
+            Err(e) => {
+                trace!("failed to decode headers: {}", e);
+                this.blocked = None;
+                Poll::Ready(Err(e))
             }
         }
     }
 }
 
+impl Drop for DecodeHeaders {
+    fn drop(&mut self) {
+        if self.blocked.is_some() {
+            self.conn.h3.lock().unwrap().unblock(self.stream_id);
+        }
+    }
+}
+
 pub(crate) struct SendHeaders(WriteFrame);
 
 impl SendHeaders {
@@ -59,9 +112,15 @@ impl SendHeaders {
         send: SendStream,
         stream_id: StreamId,
     ) -> Result<Self, Error> {
-        let conn = &mut conn.h3.lock().unwrap();
-        let frame = conn.inner.encode_header(stream_id, header)?;
-        conn.wake();
+        let frame = {
+            let conn = &mut conn.h3.lock().unwrap();
+            let frame = conn.inner.encode_header(stream_id, header)?;
+            conn.wake();
+            frame
+        };
+        if let Some(ref qlog) = conn.qlog {
+            qlog.frame_created(stream_id, "headers", frame.encoded.len());
+        }
 
         Ok(Self(WriteFrame::new(send, frame)))
     }
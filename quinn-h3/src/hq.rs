@@ -0,0 +1,106 @@
+//! HTTP/0.9 over QUIC, as spoken by the QUIC interop runner's transfer tests
+//!
+//! Each request takes a bidirectional stream carrying `GET /path\r\n`, answered with the bare
+//! response body. Servers can advertise `ALPN` next to HTTP/3 and serve the connections handed
+//! back by `server::Connecting::route()` with `read_request`.
+
+use std::str;
+
+use bytes::Bytes;
+use quinn::{ReadToEndError, RecvStream, SendStream};
+
+use crate::{proto::ErrorCode, Error};
+
+/// ALPN token of the interop runner's HTTP/0.9 variant
+pub const ALPN: &[u8] = b"hq-interop";
+
+/// Longest request line accepted by `read_request`
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// Request `path` on a new stream of `conn`, returning the response body
+///
+/// Fails with `Error::BodyTooLarge` if the response is longer than `max_size` bytes.
+pub async fn get(conn: &quinn::Connection, path: &str, max_size: usize) -> Result<Bytes, Error> {
+    let (mut send, recv) = conn.open_bi().await?;
+    send.write_all(format!("GET {}\r\n", path).as_bytes())
+        .await?;
+    send.finish().await?;
+    let body = recv
+        .read_to_end(max_size)
+        .await
+        .map_err(|e| read_error(e, max_size))?;
+    Ok(body.into())
+}
+
+/// Read the request sent on a bidirectional stream accepted by a server
+pub async fn read_request(stream: (SendStream, RecvStream)) -> Result<Request, Error> {
+    let (send, recv) = stream;
+    let line = recv
+        .read_to_end(MAX_REQUEST_LEN)
+        .await
+        .map_err(|e| read_error(e, MAX_REQUEST_LEN))?;
+    match parse_request(&line) {
+        Some(path) => Ok(Request {
+            path: path.into(),
+            send,
+        }),
+        None => Err(Error::peer(
+            ErrorCode::GENERAL_PROTOCOL_ERROR,
+            "malformed request line",
+        )),
+    }
+}
+
+/// A request received by a server, to be answered with `respond`
+pub struct Request {
+    path: String,
+    send: SendStream,
+}
+
+impl Request {
+    /// The requested path, starting with a `/`
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Send `body` and finish the stream
+    pub async fn respond(mut self, body: &[u8]) -> Result<(), Error> {
+        self.send.write_all(body).await?;
+        self.send.finish().await?;
+        Ok(())
+    }
+}
+
+fn parse_request(line: &[u8]) -> Option<&str> {
+    let line = str::from_utf8(line).ok()?.trim_end();
+    if !line.starts_with("GET ") {
+        return None;
+    }
+    let path = &line[4..];
+    if !path.starts_with('/') || path.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(path)
+}
+
+fn read_error(e: ReadToEndError, limit: usize) -> Error {
+    match e {
+        ReadToEndError::TooLong => Error::BodyTooLarge { limit },
+        ReadToEndError::Read(e) => Error::Io(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_line() {
+        assert_eq!(parse_request(b"GET /index.html\r\n"), Some("/index.html"));
+        assert_eq!(parse_request(b"GET /a/b"), Some("/a/b"));
+        assert_eq!(parse_request(b"GET index.html\r\n"), None);
+        assert_eq!(parse_request(b"POST /index.html\r\n"), None);
+        assert_eq!(parse_request(b"GET /a b\r\n"), None);
+        assert_eq!(parse_request(b"GET /\xff\r\n"), None);
+    }
+}
@@ -13,10 +13,17 @@ pub use body::Body;
 
 pub mod body;
 pub mod client;
+#[cfg(feature = "conformance")]
+pub mod conformance;
 pub mod connection;
+pub mod har;
 pub mod headers;
+pub mod hq;
+pub mod metrics;
 pub mod proto;
+pub mod qlog;
 pub mod qpack;
+pub mod resolve;
 pub mod server;
 
 mod frame;
@@ -28,61 +35,94 @@ use proto::{frame::SettingsFrame, ErrorCode};
 
 pub type Settings = SettingsFrame;
 
+/// Default fraction of connections on which reserved protocol elements are sent
+pub const DEFAULT_GREASE: f64 = 1.0;
+
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error(display = "Connection is closing, resquest aborted")]
+    /// The connection is closing, so the request was not sent
+    #[error(display = "connection is closing, request aborted")]
     Aborted,
-    #[error(display = "H3 protocol error: {:?}", _0)]
-    Proto(proto::connection::Error),
-    #[error(display = "QUIC protocol error: {}", _0)]
-    Quic(quinn::ConnectionError),
-    #[error(display = "QUIC write error: {}", _0)] // TODO to be refined
-    Write(quinn::WriteError),
-    #[error(display = "Internal error: {}", _0)]
-    Internal(String),
-    #[error(display = "Incorrect peer behavior: {}", _0)]
-    Peer(String),
+    /// The QUIC connection was lost
+    #[error(display = "QUIC connection error: {}", _0)]
+    Transport(#[source] quinn::ConnectionError),
+    /// The QUIC connection could not be initiated
+    #[error(display = "QUIC connect error: {}", _0)]
+    Connect(#[source] quinn::ConnectError),
+    /// Writing to a QUIC stream failed
+    #[error(display = "QUIC write error: {}", _0)]
+    Write(#[source] quinn::WriteError),
+    /// The peer violated the protocol
+    #[error(display = "peer error {:?}: {}", code, reason)]
+    Peer { code: ErrorCode, reason: String },
+    /// Headers could not be encoded or decoded
+    #[error(display = "header error: {:?}", _0)]
+    Header(proto::connection::Error),
+    /// The connection was refused by the admission filter
+    #[error(display = "connection refused")]
+    Refused,
+    /// An operation did not complete in time
+    #[error(display = "timed out")]
+    Timeout,
+    /// There were no addresses to connect to
+    #[error(display = "no addresses to connect to")]
+    NoAddresses,
     #[error(display = "unknown stream type {}", _0)]
     UnknownStream(u64),
     #[error(display = "IO error: {}", _0)]
-    Io(std::io::Error),
-    #[error(display = "Overflow max data size")]
-    Overflow,
-    #[error(display = "Polled after finished")]
+    Io(#[source] std::io::Error),
+    /// A body exceeded the size limit given to `RecvBody::read_to_end`
+    #[error(display = "body larger than {} bytes", limit)]
+    BodyTooLarge { limit: usize },
+    #[error(display = "polled after finished")]
     Poll,
+    #[error(display = "internal error: {}", _0)]
+    Internal(String),
 }
 
 impl Error {
-    pub fn peer<T: Into<String>>(msg: T) -> Self {
-        Error::Peer(msg.into())
+    pub fn peer<T: Into<String>>(code: ErrorCode, reason: T) -> Self {
+        Error::Peer {
+            code,
+            reason: reason.into(),
+        }
     }
 
     pub fn internal<T: Into<String>>(msg: T) -> Self {
         Error::Internal(msg.into())
     }
-}
-
-impl From<proto::connection::Error> for Error {
-    fn from(err: proto::connection::Error) -> Error {
-        Error::Proto(err)
-    }
-}
 
-impl From<quinn::ConnectionError> for Error {
-    fn from(err: quinn::ConnectionError) -> Error {
-        Error::Quic(err)
-    }
-}
-
-impl From<quinn::WriteError> for Error {
-    fn from(err: quinn::WriteError) -> Error {
-        Error::Write(err)
+    /// The HTTP/3 error code describing this error, if any
+    ///
+    /// For errors caused by the peer closing the connection or stopping a stream, this is the
+    /// code it supplied.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::Peer { code, .. } => Some(*code),
+            Error::Header(proto::connection::Error::DecodeError { .. }) => {
+                Some(ErrorCode::QPACK_DECOMPRESSION_FAILED)
+            }
+            Error::Header(_) => Some(ErrorCode::GENERAL_PROTOCOL_ERROR),
+            Error::Transport(e) | Error::Write(quinn::WriteError::ConnectionClosed(e)) => match e {
+                quinn::ConnectionError::ApplicationClosed { reason } => {
+                    ErrorCode::from_varint(reason.error_code)
+                }
+                _ => None,
+            },
+            Error::Write(quinn::WriteError::Stopped { error_code }) => {
+                ErrorCode::from_varint(*error_code)
+            }
+            Error::Refused => Some(ErrorCode::EXCESSIVE_LOAD),
+            Error::UnknownStream(_) => Some(ErrorCode::STREAM_CREATION_ERROR),
+            Error::Internal(_) => Some(ErrorCode::INTERNAL_ERROR),
+            _ => None,
+        }
     }
 }
 
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Error {
-        Error::Io(err)
+impl From<proto::connection::Error> for Error {
+    fn from(err: proto::connection::Error) -> Error {
+        Error::Header(err)
     }
 }
 
@@ -90,14 +130,14 @@ impl From<frame::Error> for Error {
     fn from(err: frame::Error) -> Error {
         match err {
             frame::Error::Io(e) => Error::Io(e),
-            e => Error::Peer(format!("received an invalid frame: {:?}", e)),
+            e => Error::peer(e.code(), format!("received an invalid frame: {:?}", e)),
         }
     }
 }
 
 impl From<proto::headers::Error> for Error {
     fn from(err: proto::headers::Error) -> Error {
-        Error::Peer(format!("invalid headers: {:?}", err))
+        Error::Header(err.into())
     }
 }
 
@@ -118,7 +158,11 @@ impl From<frame::Error> for (ErrorCode, String, Error) {
             ),
             frame::Error::Proto(e) => {
                 let msg = format!("Parse frame error: {:?}", e);
-                (ErrorCode::FRAME_ERROR, msg.clone(), Error::Peer(msg))
+                (
+                    ErrorCode::FRAME_ERROR,
+                    msg.clone(),
+                    Error::peer(ErrorCode::FRAME_ERROR, msg),
+                )
             }
         }
     }
@@ -0,0 +1,235 @@
+//! Server metrics
+//!
+//! Install an implementation of `Metrics` with `server::Builder::metrics` to record connection
+//! and request activity. `Prometheus` keeps the counts in memory and renders them in the
+//! Prometheus text exposition format, to be served on a scrape endpoint.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use http::{Method, StatusCode};
+
+/// Receives server events as they happen
+pub trait Metrics: Send + Sync {
+    /// A connection started serving HTTP/3
+    fn connection_opened(&self);
+    /// The `ConnectionDriver` of a connection finished or was dropped
+    fn connection_closed(&self);
+    /// The headers of a request were received, `header_size` being the encoded size
+    fn request(&self, method: &Method, header_size: usize);
+    /// A response was sent, or abandoned after sending its headers
+    fn response(&self, response: &ResponseInfo);
+}
+
+/// Summary of a response given to `Metrics::response`
+#[derive(Debug, Clone)]
+pub struct ResponseInfo {
+    pub status: StatusCode,
+    /// Bytes of DATA frame payload sent
+    pub body_size: u64,
+    /// Time since the request headers were received
+    pub duration: Duration,
+}
+
+/// Upper bounds of the request duration histogram buckets, in seconds
+const DURATION_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/// In-memory metrics rendered in the Prometheus text exposition format
+#[derive(Default)]
+pub struct Prometheus(Mutex<Counts>);
+
+#[derive(Default)]
+struct Counts {
+    connections: u64,
+    active_connections: u64,
+    requests: BTreeMap<String, u64>,
+    request_header_bytes: u64,
+    responses: BTreeMap<u16, u64>,
+    response_body_bytes: u64,
+    durations: [u64; DURATION_BUCKETS.len()],
+    duration_count: u64,
+    duration_sum: f64,
+}
+
+impl Prometheus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render the current values
+    pub fn render(&self) -> String {
+        let counts = self.0.lock().unwrap();
+        let mut buf = String::new();
+        let out = &mut buf;
+
+        single(out, "h3_connections_total", "counter", counts.connections);
+        single(
+            out,
+            "h3_connections_active",
+            "gauge",
+            counts.active_connections,
+        );
+
+        family(out, "h3_requests_total", "counter");
+        for (method, n) in &counts.requests {
+            writeln!(out, "h3_requests_total{{method=\"{}\"}} {}", method, n).unwrap();
+        }
+        let header_bytes = counts.request_header_bytes;
+        single(
+            out,
+            "h3_request_header_bytes_total",
+            "counter",
+            header_bytes,
+        );
+
+        family(out, "h3_responses_total", "counter");
+        for (status, n) in &counts.responses {
+            writeln!(out, "h3_responses_total{{status=\"{}\"}} {}", status, n).unwrap();
+        }
+        let body_bytes = counts.response_body_bytes;
+        single(out, "h3_response_body_bytes_total", "counter", body_bytes);
+
+        let name = "h3_request_duration_seconds";
+        family(out, name, "histogram");
+        let mut cumulative = 0;
+        for (bound, n) in DURATION_BUCKETS.iter().zip(counts.durations.iter()) {
+            cumulative += n;
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+        }
+        let count = counts.duration_count;
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+        writeln!(out, "{}_sum {}", name, counts.duration_sum).unwrap();
+        writeln!(out, "{}_count {}", name, count).unwrap();
+        buf
+    }
+}
+
+fn family(out: &mut String, name: &str, ty: &str) {
+    writeln!(out, "# TYPE {} {}", name, ty).unwrap();
+}
+
+fn single(out: &mut String, name: &str, ty: &str, value: u64) {
+    family(out, name, ty);
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+impl Metrics for Prometheus {
+    fn connection_opened(&self) {
+        let mut counts = self.0.lock().unwrap();
+        counts.connections += 1;
+        counts.active_connections += 1;
+    }
+
+    fn connection_closed(&self) {
+        self.0.lock().unwrap().active_connections -= 1;
+    }
+
+    fn request(&self, method: &Method, header_size: usize) {
+        let mut counts = self.0.lock().unwrap();
+        *counts.requests.entry(method.to_string()).or_insert(0) += 1;
+        counts.request_header_bytes += header_size as u64;
+    }
+
+    fn response(&self, response: &ResponseInfo) {
+        let mut counts = self.0.lock().unwrap();
+        *counts
+            .responses
+            .entry(response.status.as_u16())
+            .or_insert(0) += 1;
+        counts.response_body_bytes += response.body_size;
+        let secs = response.duration.as_secs_f64();
+        if let Some(i) = DURATION_BUCKETS.iter().position(|&bound| secs <= bound) {
+            counts.durations[i] += 1;
+        }
+        counts.duration_count += 1;
+        counts.duration_sum += secs;
+    }
+}
+
+/// Reports a response to `Metrics` when dropped, if its headers were sent
+pub(crate) struct ResponseTracker {
+    metrics: Arc<dyn Metrics>,
+    start: Instant,
+    status: Option<StatusCode>,
+    body_size: u64,
+}
+
+impl ResponseTracker {
+    pub fn new(metrics: Arc<dyn Metrics>, start: Instant) -> Self {
+        Self {
+            metrics,
+            start,
+            status: None,
+            body_size: 0,
+        }
+    }
+
+    pub fn status(&mut self, status: StatusCode) {
+        self.status = Some(status);
+    }
+
+    pub fn body(&mut self, len: usize) {
+        self.body_size += len as u64;
+    }
+}
+
+impl Drop for ResponseTracker {
+    fn drop(&mut self) {
+        if let Some(status) = self.status {
+            self.metrics.response(&ResponseInfo {
+                status,
+                body_size: self.body_size,
+                duration: self.start.elapsed(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Arc::new(Prometheus::new());
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        metrics.request(&Method::GET, 30);
+        metrics.request(&Method::GET, 20);
+        metrics.request(&Method::POST, 10);
+        metrics.response(&ResponseInfo {
+            status: StatusCode::OK,
+            body_size: 100,
+            duration: Duration::from_millis(20),
+        });
+        let mut tracker = ResponseTracker::new(metrics.clone(), Instant::now());
+        tracker.status(StatusCode::NOT_FOUND);
+        tracker.body(5);
+        drop(tracker);
+        drop(ResponseTracker::new(metrics.clone(), Instant::now()));
+
+        let out = metrics.render();
+        let lines = out.lines().collect::<Vec<_>>();
+        for line in &[
+            "h3_connections_total 2",
+            "h3_connections_active 1",
+            "h3_requests_total{method=\"GET\"} 2",
+            "h3_requests_total{method=\"POST\"} 1",
+            "h3_request_header_bytes_total 60",
+            "h3_responses_total{status=\"200\"} 1",
+            "h3_responses_total{status=\"404\"} 1",
+            "h3_response_body_bytes_total 105",
+            "h3_request_duration_seconds_bucket{le=\"0.01\"} 1",
+            "h3_request_duration_seconds_bucket{le=\"0.025\"} 2",
+            "h3_request_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "h3_request_duration_seconds_count 2",
+        ] {
+            assert!(lines.contains(line), "missing {}", line);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::VecDeque;
+use std::{cmp, collections::VecDeque, time::Duration};
 
 use bytes::{Buf, Bytes, BytesMut};
 use quinn_proto::StreamId;
@@ -6,7 +6,7 @@ use std::convert::TryFrom;
 
 use crate::{
     proto::{
-        frame::{HeadersFrame, HttpFrame},
+        frame::{self, FrameStats, HeadersFrame, HttpFrame},
         headers::{self, Header},
     },
     qpack::{self, DecoderError, DynamicTable, EncoderError, HeaderField},
@@ -38,8 +38,39 @@ impl PendingStreamType {
     }
 }
 
+/// Counters describing the activity on a connection
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    pub requests_started: u64,
+    pub requests_completed: u64,
+    /// Requests cancelled by this endpoint
+    pub requests_cancelled: u64,
+    /// Size of the header fields sent, before compression
+    pub header_bytes_sent: u64,
+    /// Size of the header blocks sent, after compression
+    pub encoded_header_bytes_sent: u64,
+    /// Size of the header fields received, after decompression
+    pub header_bytes_received: u64,
+    /// Size of the header blocks received, before decompression
+    pub encoded_header_bytes_received: u64,
+    /// Current size of the dynamic table used to compress the headers sent
+    pub encoder_table_size: usize,
+    /// Current size of the dynamic table used to decompress the headers received
+    pub decoder_table_size: usize,
+    /// Push streams opened by the peer, which are not supported and get ignored
+    pub push_streams: u64,
+    /// Header blocks which had to wait for dynamic table updates before being decoded
+    pub headers_blocked: u64,
+    /// Total time header blocks spent waiting for dynamic table updates
+    pub headers_blocked_time: Duration,
+    /// Longest time a header block spent waiting for dynamic table updates
+    pub max_header_blocked_time: Duration,
+    /// Header blocks abandoned after waiting longer than the configured timeout
+    pub headers_blocked_timeouts: u64,
+    pub frames_received: FrameStats,
+}
+
 pub struct Connection {
-    #[allow(dead_code)]
     local_settings: Settings,
     remote_settings: Option<Settings>,
     decoder_table: DynamicTable,
@@ -47,16 +78,29 @@ pub struct Connection {
     pending_streams: [BytesMut; 3],
     requests_in_flight: VecDeque<StreamId>,
     go_away: bool,
+    stats: Stats,
 }
 
 impl Connection {
     pub fn with_settings(settings: Settings) -> Result<Self> {
+        Self::new(settings, false)
+    }
+
+    /// When `grease` is set, reserved SETTINGS identifiers and frame types are sent on the
+    /// control stream
+    pub fn new(settings: Settings, grease: bool) -> Result<Self> {
         let mut decoder_table = DynamicTable::new();
         decoder_table.set_max_blocked(settings.qpack_blocked_streams as usize)?;
         decoder_table.set_max_size(settings.qpack_max_table_capacity as usize)?;
 
         let mut pending_control = BytesMut::with_capacity(128);
-        settings.encode(&mut pending_control);
+        if grease {
+            let mut rng = rand::thread_rng();
+            settings.encode_greased(&mut rng, &mut pending_control);
+            frame::encode_grease_frame(&mut rng, &mut pending_control);
+        } else {
+            settings.encode(&mut pending_control);
+        }
         let pending_streams = [
             pending_control,
             BytesMut::with_capacity(2048),
@@ -71,6 +115,7 @@ impl Connection {
             encoder_table: DynamicTable::new(),
             requests_in_flight: VecDeque::with_capacity(32),
             go_away: false,
+            stats: Stats::default(),
         })
     }
 
@@ -82,12 +127,17 @@ impl Connection {
         }
 
         let mut block = BytesMut::with_capacity(512);
+        let mut size = 0;
         qpack::encode(
             &mut self.encoder_table.encoder(stream_id.0),
             &mut block,
             &mut self.pending_streams[PendingStreamType::Encoder as usize],
-            headers.into_iter().map(HeaderField::from),
+            headers.into_iter().map(HeaderField::from).inspect(|f| {
+                size += f.mem_size();
+            }),
         )?;
+        self.stats.header_bytes_sent += size as u64;
+        self.stats.encoded_header_bytes_sent += block.len() as u64;
 
         Ok(HeadersFrame {
             encoded: block.freeze(),
@@ -112,6 +162,12 @@ impl Connection {
                         &mut self.pending_streams[PendingStreamType::Decoder as usize],
                     );
                 }
+                let size = decoded.iter().map(|f| f.mem_size() as u64).sum::<u64>();
+                if size > self.local_settings.max_header_list_size {
+                    return Err(Error::HeaderListTooLarge);
+                }
+                self.stats.header_bytes_received += size;
+                self.stats.encoded_header_bytes_received += header.encoded.len() as u64;
                 Ok(DecodeResult::Decoded(Header::try_from(decoded)?, had_refs))
             }
         }
@@ -129,6 +185,10 @@ impl Connection {
         Ok(qpack::on_decoder_recv(&mut self.encoder_table, read)?)
     }
 
+    pub fn local_settings(&self) -> &Settings {
+        &self.local_settings
+    }
+
     pub fn remote_settings(&self) -> &Option<Settings> {
         &self.remote_settings
     }
@@ -155,17 +215,46 @@ impl Connection {
     }
 
     pub fn request_initiated(&mut self, id: StreamId) {
+        self.stats.requests_started += 1;
         if !self.go_away {
             self.requests_in_flight.push_back(id);
         }
     }
 
     pub fn request_finished(&mut self, id: StreamId) {
+        self.stats.requests_completed += 1;
         if !self.go_away {
             self.requests_in_flight.push_back(id);
         }
     }
 
+    pub fn request_cancelled(&mut self) {
+        self.stats.requests_cancelled += 1;
+    }
+
+    pub fn push_stream_received(&mut self) {
+        self.stats.push_streams += 1;
+    }
+
+    /// Record a header block decoded after waiting `duration` for dynamic table updates
+    pub fn header_unblocked(&mut self, duration: Duration) {
+        self.stats.headers_blocked += 1;
+        self.stats.headers_blocked_time += duration;
+        self.stats.max_header_blocked_time = cmp::max(self.stats.max_header_blocked_time, duration);
+    }
+
+    pub fn header_blocked_timeout(&mut self) {
+        self.stats.headers_blocked_timeouts += 1;
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            encoder_table_size: self.encoder_table.size(),
+            decoder_table_size: self.decoder_table.size(),
+            ..self.stats.clone()
+        }
+    }
+
     pub fn requests_in_flight(&self) -> usize {
         self.requests_in_flight.len()
     }
@@ -258,6 +347,7 @@ mod tests {
                 ],
                 requests_in_flight: VecDeque::with_capacity(32),
                 go_away: false,
+                stats: Stats::default(),
             }
         }
     }
@@ -309,6 +399,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn decode_too_large() {
+        let mut header_map = HeaderMap::new();
+        header_map.append("hello", HeaderValue::from_static("text/html"));
+        let header = Header::request(Method::GET, Uri::default(), header_map);
+        let encoded = Connection::default()
+            .encode_header(StreamId(1), header)
+            .expect("encoding failed");
+
+        let mut server = Connection::with_settings(Settings {
+            max_header_list_size: 64,
+            ..Settings::default()
+        })
+        .unwrap();
+        assert_matches!(
+            server.decode_header(StreamId(1), &encoded),
+            Err(Error::HeaderListTooLarge)
+        );
+    }
+
     #[test]
     fn decode_header() {
         let mut header_map = HeaderMap::new();
@@ -364,4 +474,45 @@ mod tests {
         );
         assert!(server.pending_streams[PendingStreamType::Decoder as usize].is_empty());
     }
+
+    #[test]
+    fn header_stats() {
+        let mut header_map = HeaderMap::new();
+        header_map.append("hello", HeaderValue::from_static("text/html"));
+        let header = Header::request(Method::GET, Uri::default(), header_map);
+
+        let mut client = Connection::default();
+        let encoded = client
+            .encode_header(StreamId(1), header)
+            .expect("encoding failed");
+        let sent = client.stats();
+        assert!(sent.header_bytes_sent > 0);
+        assert_eq!(sent.encoded_header_bytes_sent, encoded.encoded.len() as u64);
+
+        let mut server = Connection::default();
+        assert_matches!(
+            server.decode_header(StreamId(1), &encoded),
+            Ok(DecodeResult::Decoded(_, _))
+        );
+        let received = server.stats();
+        assert_eq!(received.header_bytes_received, sent.header_bytes_sent);
+        assert_eq!(
+            received.encoded_header_bytes_received,
+            sent.encoded_header_bytes_sent
+        );
+    }
+
+    #[test]
+    fn blocked_header_stats() {
+        let mut conn = Connection::default();
+        conn.header_unblocked(Duration::from_millis(10));
+        conn.header_unblocked(Duration::from_millis(30));
+        conn.header_blocked_timeout();
+
+        let stats = conn.stats();
+        assert_eq!(stats.headers_blocked, 2);
+        assert_eq!(stats.headers_blocked_time, Duration::from_millis(40));
+        assert_eq!(stats.max_header_blocked_time, Duration::from_millis(30));
+        assert_eq!(stats.headers_blocked_timeouts, 1);
+    }
 }
@@ -1,10 +1,11 @@
-use std::mem::size_of;
+use std::{cmp, collections::BTreeMap, mem::size_of};
 
-use bytes::{buf::ext::BufExt as _, Buf, BufMut, Bytes};
+use bytes::{buf::ext::BufExt as _, Buf, BufMut, Bytes, BytesMut};
 use quinn_proto::{
     coding::{BufExt, BufMutExt, Codec, UnexpectedEnd},
     VarInt,
 };
+use rand::Rng;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -27,6 +28,7 @@ pub enum HttpFrame {
     Goaway(u64),
     MaxPushId(u64),
     DuplicatePush(u64),
+    Extension(ExtensionFrame),
     Reserved,
 }
 
@@ -42,6 +44,7 @@ impl HttpFrame {
             HttpFrame::Goaway(id) => simple_frame_encode(Type::GOAWAY, *id, buf),
             HttpFrame::MaxPushId(id) => simple_frame_encode(Type::MAX_PUSH_ID, *id, buf),
             HttpFrame::DuplicatePush(id) => simple_frame_encode(Type::DUPLICATE_PUSH, *id, buf),
+            HttpFrame::Extension(f) => f.encode(buf),
             HttpFrame::Reserved => (),
         }
     }
@@ -72,12 +75,54 @@ impl HttpFrame {
             Type::GOAWAY => Ok(HttpFrame::Goaway(payload.get_var()?)),
             Type::MAX_PUSH_ID => Ok(HttpFrame::MaxPushId(payload.get_var()?)),
             Type::DUPLICATE_PUSH => Ok(HttpFrame::DuplicatePush(payload.get_var()?)),
-            t if t.0 > 0x21 && (t.0 - 0x21) % 0x1f == 0 => Ok(HttpFrame::Reserved),
+            t if t.0 >= 0x21 && (t.0 - 0x21) % 0x1f == 0 => {
+                payload.advance(len as usize);
+                Ok(HttpFrame::Reserved)
+            }
+            t if t.is_extension() => Ok(HttpFrame::Extension(ExtensionFrame {
+                ty: t.0,
+                payload: payload.to_bytes(),
+            })),
             _ => Err(Error::UnsupportedFrame),
         }
     }
 }
 
+/// Number of frames of each type
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub data: u64,
+    pub headers: u64,
+    pub priority: u64,
+    pub cancel_push: u64,
+    pub settings: u64,
+    pub push_promise: u64,
+    pub goaway: u64,
+    pub max_push_id: u64,
+    pub duplicate_push: u64,
+    pub extension: u64,
+    pub reserved: u64,
+}
+
+impl FrameStats {
+    pub(crate) fn count(&mut self, frame: &HttpFrame) {
+        let counter = match frame {
+            HttpFrame::Data(_) => &mut self.data,
+            HttpFrame::Headers(_) => &mut self.headers,
+            HttpFrame::Priority(_) => &mut self.priority,
+            HttpFrame::CancelPush(_) => &mut self.cancel_push,
+            HttpFrame::Settings(_) => &mut self.settings,
+            HttpFrame::PushPromise(_) => &mut self.push_promise,
+            HttpFrame::Goaway(_) => &mut self.goaway,
+            HttpFrame::MaxPushId(_) => &mut self.max_push_id,
+            HttpFrame::DuplicatePush(_) => &mut self.duplicate_push,
+            HttpFrame::Extension(_) => &mut self.extension,
+            HttpFrame::Reserved => &mut self.reserved,
+        };
+        *counter += 1;
+    }
+}
+
 macro_rules! frame_types {
     {$($name:ident = $val:expr,)*} => {
         impl Type {
@@ -101,6 +146,42 @@ frame_types! {
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub(crate) struct Type(u64);
 
+impl Type {
+    /// Whether the type is neither defined by HTTP/3 nor reserved from HTTP/2
+    fn is_extension(self) -> bool {
+        self.0 > 0xE
+            || ![
+                Self::DATA,
+                Self::HEADERS,
+                Self::PRIORITY,
+                Self::CANCEL_PUSH,
+                Self::SETTINGS,
+                Self::PUSH_PROMISE,
+                Self::GOAWAY,
+                Self::MAX_PUSH_ID,
+                Self::DUPLICATE_PUSH,
+                Type(0x6),
+                Type(0x8),
+                Type(0x9),
+            ]
+            .contains(&self)
+    }
+}
+
+/// A random frame or stream type reserved for greasing, of the form `0x1f * N + 0x21`
+pub(crate) fn grease_type<R: Rng>(rng: &mut R) -> u64 {
+    0x1f * rng.gen_range(0, 0x1000) + 0x21
+}
+
+/// Encode a frame of reserved type carrying a few random bytes, which the peer must ignore
+pub(crate) fn encode_grease_frame<R: Rng, B: BufMut>(rng: &mut R, buf: &mut B) {
+    let payload = rng.gen::<[u8; 4]>();
+    let len = rng.gen_range(0, payload.len() + 1);
+    Type(grease_type(rng)).encode(buf);
+    buf.write_var(len as u64);
+    buf.put_slice(&payload[..len]);
+}
+
 impl Codec for Type {
     fn decode<B: Buf>(buf: &mut B) -> Result<Self, UnexpectedEnd> {
         Ok(Type(buf.get_var()?))
@@ -153,24 +234,20 @@ pub struct PartialData {
 }
 
 impl PartialData {
-    pub fn decode<B: Buf>(buf: &mut B) -> Result<(Self, DataFrame), Error> {
+    /// Decode the header of a DATA frame whose payload is yet to be received
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, Error> {
         if Type::DATA != Type::decode(buf)? {
             panic!("can only decode Data frames");
         }
 
-        let len = buf.get_var()? as usize;
-        let payload = buf.to_bytes();
-
-        Ok((
-            Self {
-                remaining: len - payload.len(),
-            },
-            DataFrame { payload },
-        ))
+        Ok(Self {
+            remaining: buf.get_var()? as usize,
+        })
     }
 
-    pub fn decode_data<B: Buf>(&mut self, buf: &mut B) -> DataFrame {
-        let payload = buf.take(self.remaining).to_bytes();
+    /// Take the part of the payload available in `buf`
+    pub fn decode_data(&mut self, buf: &mut BytesMut) -> DataFrame {
+        let payload = buf.split_to(cmp::min(self.remaining, buf.len())).freeze();
         self.remaining -= payload.len();
         DataFrame { payload }
     }
@@ -335,12 +412,36 @@ impl FrameHeader for PriorityFrame {
     }
 }
 
+/// A frame of a type not defined by HTTP/3, for use by protocol extensions
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExtensionFrame {
+    pub ty: u64,
+    pub payload: Bytes,
+}
+
+impl ExtensionFrame {
+    /// Whether `ty` may be used by an extension frame
+    pub fn is_valid_type(ty: u64) -> bool {
+        VarInt::from_u64(ty).is_ok() && Type(ty).is_extension()
+    }
+
+    pub fn encode<T: BufMut>(&self, buf: &mut T) {
+        buf.write_var(self.ty);
+        buf.write_var(self.payload.len() as u64);
+        buf.put_slice(&self.payload);
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct SettingsFrame {
     pub num_placeholders: u64,
     pub max_header_list_size: u64,
     pub qpack_max_table_capacity: u64,
     pub qpack_blocked_streams: u64,
+    /// Settings defined by extensions, by identifier
+    ///
+    /// Identifiers defined or reserved by HTTP/3 are not sent.
+    pub extensions: BTreeMap<u64, u64>,
 }
 
 impl Default for SettingsFrame {
@@ -350,6 +451,7 @@ impl Default for SettingsFrame {
             max_header_list_size: 65536,
             qpack_max_table_capacity: 4096,
             qpack_blocked_streams: 128,
+            extensions: BTreeMap::new(),
         }
     }
 }
@@ -357,6 +459,22 @@ impl Default for SettingsFrame {
 impl SettingsFrame {
     pub fn encode<T: BufMut>(&self, buf: &mut T) {
         self.encode_header(buf);
+        self.encode_settings(buf);
+    }
+
+    /// Encode with an additional reserved setting, which the peer must ignore
+    pub(crate) fn encode_greased<R: Rng, T: BufMut>(&self, rng: &mut R, buf: &mut T) {
+        let n = rng.gen::<u8>() as u64;
+        let id = SettingId(0x0a0a | (n & 0xf0) << 8 | (n & 0x0f) << 4);
+        let value = rng.gen_range(0, 1 << 30);
+        Type::SETTINGS.encode(buf);
+        buf.write_var((self.len() + sz(id.0) + sz(value)) as u64);
+        self.encode_settings(buf);
+        id.encode(buf);
+        buf.write_var(value);
+    }
+
+    fn encode_settings<T: BufMut>(&self, buf: &mut T) {
         SettingId::NUM_PLACEHOLDERS.encode(buf);
         buf.write_var(self.num_placeholders);
         SettingId::MAX_HEADER_LIST_SIZE.encode(buf);
@@ -365,6 +483,16 @@ impl SettingsFrame {
         buf.write_var(self.qpack_max_table_capacity);
         SettingId::QPACK_BLOCKED_STREAMS.encode(buf);
         buf.write_var(self.qpack_blocked_streams);
+        for (&id, &value) in self.sent_extensions() {
+            buf.write_var(id);
+            buf.write_var(value);
+        }
+    }
+
+    fn sent_extensions(&self) -> impl Iterator<Item = (&u64, &u64)> {
+        self.extensions
+            .iter()
+            .filter(|(&id, _)| SettingId(id).is_extension())
     }
 
     fn decode<T: Buf>(buf: &mut T) -> Result<SettingsFrame, Error> {
@@ -390,6 +518,9 @@ impl SettingsFrame {
                 SettingId::QPACK_BLOCKED_STREAMS => {
                     settings.qpack_blocked_streams = value;
                 }
+                id if id.is_extension() => {
+                    settings.extensions.insert(id.0, value);
+                }
                 _ => continue,
             }
         }
@@ -400,9 +531,6 @@ impl SettingsFrame {
 impl FrameHeader for SettingsFrame {
     const TYPE: Type = Type::SETTINGS;
     fn len(&self) -> usize {
-        fn sz(x: u64) -> usize {
-            VarInt::from_u64(x).unwrap().size()
-        }
         sz(SettingId::NUM_PLACEHOLDERS.0)
             + sz(self.num_placeholders)
             + sz(SettingId::MAX_HEADER_LIST_SIZE.0)
@@ -411,12 +539,27 @@ impl FrameHeader for SettingsFrame {
             + sz(self.qpack_max_table_capacity)
             + sz(SettingId::QPACK_BLOCKED_STREAMS.0)
             + sz(self.qpack_blocked_streams)
+            + self
+                .sent_extensions()
+                .map(|(&id, &value)| sz(id) + sz(value))
+                .sum::<usize>()
     }
 }
 
+fn sz(x: u64) -> usize {
+    VarInt::from_u64(x).unwrap().size()
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 struct SettingId(u64);
 
+impl SettingId {
+    /// Whether the identifier is neither defined by HTTP/3, reserved from HTTP/2 nor greasing
+    fn is_extension(self) -> bool {
+        self.0 > 0x8 && self.0 & 0x0f0f != 0x0a0a && VarInt::from_u64(self.0).is_ok()
+    }
+}
+
 impl Codec for SettingId {
     fn decode<B: Buf>(buf: &mut B) -> Result<Self, UnexpectedEnd> {
         Ok(SettingId(buf.get_var()?))
@@ -460,11 +603,24 @@ mod tests {
 
     #[test]
     fn unknown_frame_type() {
-        let mut buf = Cursor::new(&[0x2f, 4, 0, 255, 128, 0]);
+        let mut buf = Cursor::new(&[0x8, 4, 0, 255, 128, 0]);
         let decoded = HttpFrame::decode(&mut buf);
         assert_eq!(decoded, Err(Error::UnsupportedFrame));
     }
 
+    #[test]
+    fn extension_frame() {
+        codec_frame_check(
+            HttpFrame::Extension(ExtensionFrame {
+                ty: 0x2f,
+                payload: Bytes::from("foo"),
+            }),
+            &[0x2f, 3, 102, 111, 111],
+        );
+        assert!(!ExtensionFrame::is_valid_type(0x9));
+        assert!(ExtensionFrame::is_valid_type(0xa));
+    }
+
     #[test]
     fn buffer_too_short() {
         let mut buf = Cursor::new(&[04, 0x4, 0, 255, 128]);
@@ -537,6 +693,7 @@ mod tests {
                 max_header_list_size: 0xfad1,
                 qpack_max_table_capacity: 0xfad2,
                 qpack_blocked_streams: 0xfad3,
+                extensions: BTreeMap::new(),
             }),
             &[
                 4, 20, 8, 128, 0, 250, 218, 6, 128, 0, 250, 209, 1, 128, 0, 250, 210, 7, 128, 0,
@@ -545,6 +702,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn settings_extensions() {
+        let mut settings = SettingsFrame::default();
+        settings.extensions.insert(0x2b, 0xfad4);
+        settings.extensions.insert(0x6, 0);
+        settings.extensions.insert(0x1a2a, 0);
+        let mut buf = Vec::new();
+        settings.encode(&mut buf);
+
+        let mut expected = SettingsFrame::default();
+        expected.extensions.insert(0x2b, 0xfad4);
+        let mut read = Cursor::new(&buf);
+        assert_eq!(
+            HttpFrame::decode(&mut read),
+            Ok(HttpFrame::Settings(expected))
+        );
+    }
+
     #[test]
     fn data_frame() {
         codec_frame_check(
@@ -600,5 +775,30 @@ mod tests {
         let mut buf = Cursor::new(&raw);
         let decoded = HttpFrame::decode(&mut buf);
         assert_eq!(decoded, Ok(HttpFrame::Reserved));
+        assert!(!buf.has_remaining());
+    }
+
+    #[test]
+    fn grease_ignored() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let settings = SettingsFrame {
+                num_placeholders: 0xfada,
+                ..SettingsFrame::default()
+            };
+            let mut buf = Vec::new();
+            settings.encode_greased(&mut rng, &mut buf);
+            encode_grease_frame(&mut rng, &mut buf);
+            HttpFrame::Goaway(2).encode(&mut buf);
+
+            let mut read = Cursor::new(&buf);
+            assert_eq!(
+                HttpFrame::decode(&mut read),
+                Ok(HttpFrame::Settings(settings))
+            );
+            assert_eq!(HttpFrame::decode(&mut read), Ok(HttpFrame::Reserved));
+            assert_eq!(HttpFrame::decode(&mut read), Ok(HttpFrame::Goaway(2)));
+            assert!(!read.has_remaining());
+        }
     }
 }
@@ -1,3 +1,5 @@
+use std::convert::TryFrom;
+
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use quinn_proto::{
     coding::{BufExt, BufMutExt, UnexpectedEnd},
@@ -8,7 +10,7 @@ pub mod connection;
 pub mod frame;
 pub mod headers;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct StreamType(pub u64);
 
 macro_rules! stream_types {
@@ -36,8 +38,7 @@ impl StreamType {
     }
 
     pub fn encoded(&self) -> Bytes {
-        let var_int = VarInt::from(self.0 as u32);
-        let mut buf = BytesMut::with_capacity(var_int.size());
+        let mut buf = BytesMut::with_capacity(VarInt::MAX_SIZE);
         self.encode(&mut buf);
         buf.freeze()
     }
@@ -77,6 +78,12 @@ error_codes! {
     QPACK_DECODER_STREAM_ERROR = 0x202,
 }
 
+impl ErrorCode {
+    pub(crate) fn from_varint(code: VarInt) -> Option<Self> {
+        u32::try_from(code.into_inner()).ok().map(ErrorCode)
+    }
+}
+
 impl From<ErrorCode> for VarInt {
     fn from(error: ErrorCode) -> VarInt {
         error.0.into()
@@ -0,0 +1,205 @@
+//! qlog output of HTTP/3 events
+//!
+//! Configure a sink with `client::Builder::qlog` or `server::Builder::qlog` to write a qlog
+//! trace per connection, which can be visualized with qvis. Frames parsed and created on request
+//! and control streams and the types of unidirectional streams are recorded.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use quinn_proto::{Side, StreamId};
+
+use crate::proto::{frame::HttpFrame, StreamType};
+
+/// Creates the qlog sink of a connection, given its `RequestId::connection` identifier
+pub(crate) type QlogFactory = Arc<dyn Fn(u64) -> Option<Box<dyn Write + Send>> + Send + Sync>;
+
+/// Write the trace of each connection to `<dir>/<connection id>.qlog`
+pub fn dir(dir: impl Into<PathBuf>) -> impl Fn(u64) -> Option<Box<dyn Write + Send>> {
+    let dir = dir.into();
+    move |id| {
+        let path = dir.join(format!("{}.qlog", id));
+        match File::create(&path) {
+            Ok(file) => Some(Box::new(BufWriter::new(file)) as Box<dyn Write + Send>),
+            Err(e) => {
+                tracing::warn!("failed to create {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// Handle to the qlog trace of a connection
+#[derive(Clone)]
+pub(crate) struct Qlog(Arc<Mutex<Writer>>);
+
+impl Qlog {
+    pub fn new(sink: Box<dyn Write + Send>, side: Side, id: u64) -> Self {
+        let mut writer = Writer {
+            sink,
+            start: Instant::now(),
+            first: true,
+        };
+        let reference_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis())
+            .unwrap_or(0);
+        let vantage_point = match side {
+            Side::Client => "client",
+            Side::Server => "server",
+        };
+        writer.write(&format!(
+            "{{\"qlog_version\":\"draft-01\",\"title\":\"quinn-h3 connection {}\",\
+             \"traces\":[{{\"vantage_point\":{{\"type\":\"{}\"}},\
+             \"common_fields\":{{\"reference_time\":\"{}\"}},\
+             \"event_fields\":[\"relative_time\",\"category\",\"event\",\"data\"],\
+             \"events\":[",
+            id, vantage_point, reference_time
+        ));
+        Qlog(Arc::new(Mutex::new(writer)))
+    }
+
+    pub fn frame_parsed(&self, stream_id: StreamId, frame: &HttpFrame) {
+        let (ty, len) = frame_info(frame);
+        self.frame("frame_parsed", stream_id, ty, len);
+    }
+
+    pub fn frame_created(&self, stream_id: StreamId, ty: &str, len: usize) {
+        self.frame("frame_created", stream_id, ty, Some(len));
+    }
+
+    fn frame(&self, event: &str, stream_id: StreamId, ty: &str, len: Option<usize>) {
+        let length = match len {
+            Some(len) => format!(",\"byte_length\":\"{}\"", len),
+            None => String::new(),
+        };
+        self.event(
+            event,
+            &format!(
+                "{{\"stream_id\":\"{}\",\"frame\":{{\"frame_type\":\"{}\"}}{}}}",
+                stream_id.0, ty, length
+            ),
+        );
+    }
+
+    pub fn stream_type_set(&self, stream_id: StreamId, local: bool, ty: StreamType) {
+        let owner = if local { "local" } else { "remote" };
+        let ty = match ty {
+            StreamType::CONTROL => "control",
+            StreamType::PUSH => "push",
+            StreamType::ENCODER => "qpack_encode",
+            StreamType::DECODER => "qpack_decode",
+            _ => "unknown",
+        };
+        self.event(
+            "stream_type_set",
+            &format!(
+                "{{\"stream_id\":\"{}\",\"owner\":\"{}\",\"new\":\"{}\"}}",
+                stream_id.0, owner, ty
+            ),
+        );
+    }
+
+    fn event(&self, event: &str, data: &str) {
+        let mut writer = self.0.lock().unwrap();
+        let time = writer.start.elapsed().as_micros() as f64 / 1000.0;
+        let sep = if writer.first { "" } else { "," };
+        writer.first = false;
+        writer.write(&format!(
+            "{}[\"{}\",\"http\",\"{}\",{}]",
+            sep, time, event, data
+        ));
+    }
+}
+
+fn frame_info(frame: &HttpFrame) -> (&'static str, Option<usize>) {
+    match frame {
+        HttpFrame::Data(f) => ("data", Some(f.payload.len())),
+        HttpFrame::Headers(f) => ("headers", Some(f.encoded.len())),
+        HttpFrame::Priority(_) => ("priority", None),
+        HttpFrame::CancelPush(_) => ("cancel_push", None),
+        HttpFrame::Settings(_) => ("settings", None),
+        HttpFrame::PushPromise(_) => ("push_promise", None),
+        HttpFrame::Goaway(_) => ("goaway", None),
+        HttpFrame::MaxPushId(_) => ("max_push_id", None),
+        HttpFrame::DuplicatePush(_) => ("duplicate_push", None),
+        HttpFrame::Extension(f) => ("unknown", Some(f.payload.len())),
+        HttpFrame::Reserved => ("reserved", None),
+    }
+}
+
+struct Writer {
+    sink: Box<dyn Write + Send>,
+    start: Instant,
+    first: bool,
+}
+
+impl Writer {
+    fn write(&mut self, s: &str) {
+        if let Err(e) = self.sink.write_all(s.as_bytes()) {
+            tracing::warn!("failed to write qlog: {}", e);
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.write("]}]}");
+        let _ = self.sink.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::frame::DataFrame;
+    use bytes::Bytes;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace() {
+        let out = Shared::default();
+        let qlog = Qlog::new(Box::new(out.clone()), Side::Server, 7);
+        qlog.stream_type_set(StreamId(3), true, StreamType::CONTROL);
+        qlog.frame_parsed(
+            StreamId(0),
+            &HttpFrame::Data(DataFrame {
+                payload: Bytes::from_static(b"hello"),
+            }),
+        );
+        qlog.frame_created(StreamId(0), "headers", 12);
+        drop(qlog);
+
+        let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert!(out.starts_with("{\"qlog_version\":\"draft-01\""));
+        assert!(out.contains("\"vantage_point\":{\"type\":\"server\"}"));
+        assert!(out.contains(
+            ",\"http\",\"stream_type_set\",\
+             {\"stream_id\":\"3\",\"owner\":\"local\",\"new\":\"control\"}]"
+        ));
+        assert!(out.contains(
+            ",\"http\",\"frame_parsed\",\
+             {\"stream_id\":\"0\",\"frame\":{\"frame_type\":\"data\"},\"byte_length\":\"5\"}]"
+        ));
+        assert!(out.contains("\"frame_created\""));
+        assert_eq!(out.matches("],[").count(), 2);
+        assert!(out.ends_with("]]}]}"));
+    }
+}
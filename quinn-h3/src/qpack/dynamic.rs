@@ -325,6 +325,10 @@ impl DynamicTable {
         DynamicTable::default()
     }
 
+    pub fn size(&self) -> usize {
+        self.curr_size
+    }
+
     pub fn decoder(&self, base: usize) -> DynamicTableDecoder {
         DynamicTableDecoder { table: self, base }
     }
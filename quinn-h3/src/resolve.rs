@@ -0,0 +1,113 @@
+//! Host name resolution for `Client::connect_host`
+//!
+//! The default `SystemResolver` uses the operating system's resolver on a thread of its own, so
+//! it blocks no runtime's event loop. Implement `Resolve` to use another resolver, or install a
+//! `StaticResolver` to override the addresses of some hosts.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    pin::Pin,
+    sync::Arc,
+    thread,
+};
+
+use futures::channel::oneshot;
+
+/// Future returned by `Resolve::resolve`
+pub type Resolving<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Resolves host names to the addresses to connect to
+pub trait Resolve: Send + Sync {
+    /// Look up the addresses of `host`, all with the given `port`
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a>;
+}
+
+/// Resolves names with the operating system's resolver, like `std::net::ToSocketAddrs`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a> {
+        let (send, recv) = oneshot::channel();
+        let host = host.to_owned();
+        thread::spawn(move || {
+            let _ = send.send((&host[..], port).to_socket_addrs().map(|x| x.collect()));
+        });
+        Box::pin(async move {
+            recv.await.unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "resolver thread panicked",
+                ))
+            })
+        })
+    }
+}
+
+/// Resolves some hosts to fixed addresses, delegating others to a fallback resolver
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<SocketAddr>>,
+    fallback: Arc<dyn Resolve>,
+}
+
+impl StaticResolver {
+    /// Override hosts, resolving unknown ones with `SystemResolver`
+    pub fn new() -> Self {
+        Self::with_fallback(Arc::new(SystemResolver))
+    }
+
+    /// Override hosts, resolving unknown ones with `fallback`
+    pub fn with_fallback(fallback: Arc<dyn Resolve>) -> Self {
+        Self {
+            hosts: HashMap::new(),
+            fallback,
+        }
+    }
+
+    /// Connect to `addrs` instead of the addresses of `host`, whatever the requested port
+    pub fn add(&mut self, host: &str, addrs: Vec<SocketAddr>) -> &mut Self {
+        self.hosts.insert(host.to_ascii_lowercase(), addrs);
+        self
+    }
+}
+
+impl Default for StaticResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolve for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a> {
+        match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some(addrs) => {
+                let addrs = addrs.clone();
+                Box::pin(async move { Ok(addrs) })
+            }
+            None => self.fallback.resolve(host, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve() {
+        let addr = "[::1]:4433".parse::<SocketAddr>().unwrap();
+        let mut resolver = StaticResolver::new();
+        resolver.add("Example.com", vec![addr]);
+        assert_eq!(
+            resolver.resolve("example.com", 443).await.unwrap(),
+            vec![addr]
+        );
+        assert_eq!(
+            resolver.resolve("127.0.0.1", 443).await.unwrap(),
+            vec!["127.0.0.1:443".parse().unwrap()]
+        );
+    }
+}
@@ -1,26 +1,33 @@
 use std::{
     future::Future,
-    mem,
+    io, mem,
     net::SocketAddr,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use futures::{ready, Stream};
-use http::{response, HeaderMap, Request, Response};
-use quinn::{EndpointBuilder, EndpointDriver, EndpointError, RecvStream, SendStream};
+use futures::{future, ready, Stream};
+use http::{response, HeaderMap, HeaderValue, Request, Response};
+use quinn::{
+    AuthenticationData, Certificate, CertificateChain, EndpointBuilder, EndpointDriver,
+    EndpointError, PrivateKey, RecvStream, SendStream, ServerConfigBuilder,
+};
 use quinn_proto::{Side, StreamId};
+use rustls::{CipherSuite, ProducesTickets, ResolvesServerCert, TLSError};
+use tracing::Span;
 
 use crate::{
     body::{Body, BodyWriter, RecvBody},
-    connection::{ConnectionDriver, ConnectionRef},
-    frame::{FrameDecoder, FrameStream, WriteFrame},
-    headers::{DecodeHeaders, SendHeaders},
-    proto::{
-        frame::{DataFrame, HttpFrame},
-        headers::Header,
-        ErrorCode,
+    connection::{
+        record_request, record_stream, ConnectionDriver, ConnectionRef, ConnectionSet, Options,
+        UniStreams,
     },
+    frame::{FrameStream, WriteFrame},
+    headers::{DecodeHeaders, SendHeaders},
+    metrics::{Metrics, ResponseTracker},
+    proto::{connection::Stats, frame::HttpFrame, headers::Header, ErrorCode},
     streams::Reset,
     try_take, Error, Settings,
 };
@@ -28,6 +35,9 @@ use crate::{
 pub struct Builder {
     endpoint: EndpointBuilder,
     settings: Settings,
+    options: Options,
+    server_config: Option<ServerConfigBuilder>,
+    protocols: Vec<Vec<u8>>,
 }
 
 impl Builder {
@@ -35,6 +45,9 @@ impl Builder {
         Self {
             endpoint,
             settings: Settings::default(),
+            options: Options::default(),
+            server_config: None,
+            protocols: vec![crate::ALPN.into()],
         }
     }
 
@@ -43,74 +56,613 @@ impl Builder {
         self
     }
 
+    /// Fraction of connections on which reserved settings, frames and stream types are sent
+    ///
+    /// Greasing keeps peers and middleboxes from relying on the absence of extensions. Defaults
+    /// to `DEFAULT_GREASE`. Panics if `rate` is not within `0.0..=1.0`.
+    pub fn grease(&mut self, rate: f64) -> &mut Self {
+        assert!((0.0..=1.0).contains(&rate), "grease rate out of range");
+        self.options.grease = rate;
+        self
+    }
+
+    /// Give up on header blocks still waiting for dynamic table updates after `timeout`
+    ///
+    /// The request stream is then reset and `Error::Timeout` returned. By default, header blocks
+    /// wait until the connection closes.
+    pub fn qpack_blocked_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.options.qpack_blocked_timeout = timeout;
+        self
+    }
+
+    /// Write a qlog trace of each connection to the sink returned by `sink`
+    ///
+    /// `sink` is called with the connection's identifier, see `RequestId::connection`. Returning
+    /// `None` disables tracing of that connection. See `qlog::dir` for writing to files.
+    pub fn qlog<F>(&mut self, sink: F) -> &mut Self
+    where
+        F: Fn(u64) -> Option<Box<dyn io::Write + Send>> + Send + Sync + 'static,
+    {
+        self.options.qlog = Some(Arc::new(sink));
+        self
+    }
+
+    /// Record connections, requests and responses in `metrics`
+    ///
+    /// See `metrics::Prometheus` for an implementation.
+    pub fn metrics(&mut self, metrics: Arc<dyn Metrics>) -> &mut Self {
+        self.options.metrics = Some(metrics);
+        self
+    }
+
+    /// Set the ALPN tokens served as HTTP/3, in order of descending preference
+    ///
+    /// Defaults to `ALPN`. These are advertised by the TLS configuration of this builder, if
+    /// any, and in `alt-svc` values. Connections negotiating other protocols are handed back by
+    /// `Connecting::route()`.
+    pub fn protocols(&mut self, protocols: &[&[u8]]) -> &mut Self {
+        self.protocols = protocols.iter().map(|x| x.to_vec()).collect();
+        if let Some(ref mut config) = self.server_config {
+            config.protocols(protocols);
+        }
+        self
+    }
+
+    /// Log TLS secrets to the file named by the `SSLKEYLOGFILE` environment variable
+    ///
+    /// Lets protocol analyzers such as Wireshark decrypt captured traffic. Does nothing if the
+    /// variable is unset. TLS settings made on this builder replace the server configuration of
+    /// the endpoint.
+    pub fn enable_keylog(&mut self) -> &mut Self {
+        self.server_config().enable_keylog();
+        self
+    }
+
+    /// Set the certificate chain presented to clients
+    ///
+    /// TLS settings made on this builder replace the server configuration of the endpoint.
+    pub fn certificate(
+        &mut self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<&mut Self, TLSError> {
+        self.server_config().certificate(cert_chain, key)?;
+        Ok(self)
+    }
+
+    /// Set the certificate chain presented to clients, with Certificate Transparency proofs
+    ///
+    /// `scts` is an encoded `SignedCertificateTimestampList` (RFC 6962). TLS settings made on this
+    /// builder replace the server configuration of the endpoint.
+    pub fn certificate_with_scts(
+        &mut self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+        scts: Vec<u8>,
+    ) -> Result<&mut Self, TLSError> {
+        self.server_config()
+            .certificate_with_scts(cert_chain, key, scts)?;
+        Ok(self)
+    }
+
+    /// Issue self-contained session tickets encrypted by `ticketer`
+    ///
+    /// See `quinn::TicketKeys`. TLS settings made on this builder replace the server
+    /// configuration of the endpoint.
+    pub fn session_ticketer(&mut self, ticketer: Arc<dyn ProducesTickets>) -> &mut Self {
+        self.server_config().session_ticketer(ticketer);
+        self
+    }
+
+    /// Restrict the TLS 1.3 cipher suites that may be negotiated
+    ///
+    /// Fails if `suites` is empty or contains an unsupported suite. TLS settings made on this
+    /// builder replace the server configuration of the endpoint.
+    pub fn cipher_suites(&mut self, suites: &[CipherSuite]) -> Result<&mut Self, TLSError> {
+        self.server_config().cipher_suites(suites)?;
+        Ok(self)
+    }
+
+    /// Select the certificate chain presented to each client, e.g. based on SNI
+    ///
+    /// See `quinn::CertificateResolver`.
+    pub fn certificate_resolver(&mut self, resolver: Arc<dyn ResolvesServerCert>) -> &mut Self {
+        self.server_config().certificate_resolver(resolver);
+        self
+    }
+
+    /// Require clients to present a certificate signed by one of `roots`
+    ///
+    /// The verified chain is available to request handlers through the `AuthenticationData`
+    /// request extension.
+    pub fn require_client_certificate(
+        &mut self,
+        roots: impl IntoIterator<Item = Certificate>,
+    ) -> Result<&mut Self, webpki::Error> {
+        self.server_config().require_client_certificate(roots)?;
+        Ok(self)
+    }
+
+    /// Ask clients for a certificate signed by one of `roots`, accepting anonymous clients too
+    pub fn request_client_certificate(
+        &mut self,
+        roots: impl IntoIterator<Item = Certificate>,
+    ) -> Result<&mut Self, webpki::Error> {
+        self.server_config().request_client_certificate(roots)?;
+        Ok(self)
+    }
+
+    /// Verify client certificates with a custom verifier
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn client_certificate_verifier(
+        &mut self,
+        verifier: Arc<dyn rustls::ClientCertVerifier>,
+    ) -> &mut Self {
+        self.server_config().client_certificate_verifier(verifier);
+        self
+    }
+
+    fn server_config(&mut self) -> &mut ServerConfigBuilder {
+        let protocols = &self.protocols;
+        self.server_config.get_or_insert_with(|| {
+            let mut config = ServerConfigBuilder::default();
+            config.protocols(&protocols.iter().map(|x| &x[..]).collect::<Vec<_>>());
+            config
+        })
+    }
+
     pub fn bind(
         self,
         addr: &SocketAddr,
     ) -> Result<(EndpointDriver, Server, IncomingConnection), EndpointError> {
-        let (endpoint_driver, _endpoint, incoming) = self.endpoint.bind(addr)?;
+        let socket = std::net::UdpSocket::bind(addr).map_err(EndpointError::Socket)?;
+        self.with_socket(socket)
+    }
+
+    /// Serve on a pre-configured socket
+    pub fn with_socket(
+        mut self,
+        socket: std::net::UdpSocket,
+    ) -> Result<(EndpointDriver, Server, IncomingConnection), EndpointError> {
+        if let Some(config) = self.server_config.take() {
+            self.endpoint.listen(config.build());
+        }
+        let (endpoint_driver, endpoint, incoming) = self.endpoint.with_socket(socket)?;
+        let protocols = Arc::new(self.protocols);
+        let connections = ConnectionSet::default();
         Ok((
             endpoint_driver,
-            Server,
+            Server {
+                local_addr: endpoint.local_addr().map_err(EndpointError::Socket)?,
+                protocols: protocols.clone(),
+                connections: connections.clone(),
+            },
             IncomingConnection {
                 incoming,
                 settings: self.settings.clone(),
+                options: self.options,
+                protocols,
+                connections,
+                admission: None,
             },
         ))
     }
 }
 
-pub struct Server;
+/// Handle to a running server
+///
+/// Keeps track of the connections whose `ConnectionDriver` is running.
+#[derive(Clone)]
+pub struct Server {
+    local_addr: SocketAddr,
+    protocols: Arc<Vec<Vec<u8>>>,
+    connections: ConnectionSet,
+}
+
+impl Server {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Value for an `alt-svc` header advertising this server, for use by TCP-based servers
+    ///
+    /// Clients will consider the advertisement fresh for `max_age`.
+    pub fn alt_svc(&self, max_age: Duration) -> HeaderValue {
+        let protocols = self.protocols.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        alt_svc(self.local_addr.port(), &protocols, max_age)
+    }
+
+    /// Snapshot of the active connections
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .all()
+            .into_iter()
+            .map(|(id, conn)| ConnectionInfo {
+                id: ConnectionId(id),
+                remote_address: conn.quic.remote_address(),
+                stats: conn.stats(),
+            })
+            .collect()
+    }
+
+    /// Close the connection identified by `id`
+    ///
+    /// Returns `false` if the connection is not active anymore.
+    pub fn close_connection(&self, id: ConnectionId, error_code: ErrorCode, reason: &[u8]) -> bool {
+        match self.connections.get(id.0) {
+            Some(conn) => {
+                conn.quic.close(error_code.into(), reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close every active connection
+    pub fn close(&self, error_code: ErrorCode, reason: &[u8]) {
+        for (_, conn) in self.connections.all() {
+            conn.quic.close(error_code.into(), reason);
+        }
+    }
+}
+
+/// Identifies a connection to a `Server`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ConnectionId(u64);
+
+/// Description of a connection to a `Server`
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub remote_address: SocketAddr,
+    pub stats: Stats,
+}
+
+/// Build an `alt-svc` header value advertising HTTP/3 on `port` of the same host
+pub fn alt_svc(port: u16, protocols: &[&[u8]], max_age: Duration) -> HeaderValue {
+    let mut value = String::new();
+    for protocol in protocols {
+        if !value.is_empty() {
+            value.push_str(", ");
+        }
+        // ALPN protocol ids are percent-encoded unless they are valid tokens (RFC 7838 §3)
+        for &b in protocol.iter() {
+            if b != b'%' && is_tchar(b) {
+                value.push(b as char);
+            } else {
+                value.push_str(&format!("%{:02X}", b));
+            }
+        }
+        value.push_str(&format!("=\":{}\"; ma={}", port, max_age.as_secs()));
+    }
+    HeaderValue::from_str(&value).expect("alt-svc value is visible ASCII")
+}
+
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
 
 pub struct IncomingConnection {
     incoming: quinn::Incoming,
     settings: Settings,
+    options: Options,
+    protocols: Arc<Vec<Vec<u8>>>,
+    connections: ConnectionSet,
+    admission: Option<AdmissionFilter>,
+}
+
+impl IncomingConnection {
+    /// Wait for the next incoming connection
+    ///
+    /// Returns `None` once the endpoint stops accepting connections.
+    pub async fn accept(&mut self) -> Option<Connecting> {
+        future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    /// Only accept connections for which `filter` returns `true`
+    ///
+    /// The filter is called once with the peer's address when a connection comes in, and once
+    /// more with its authentication data after the handshake. Connections refused before the
+    /// handshake are dropped silently, those refused after it are closed with `EXCESSIVE_LOAD`.
+    /// In both cases, no HTTP/3 state is allocated.
+    pub fn admit<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(&Admission) -> bool + Send + Sync + 'static,
+    {
+        self.admission = Some(Arc::new(filter));
+        self
+    }
 }
 
 impl Stream for IncomingConnection {
     type Item = Connecting;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        Poll::Ready(
-            ready!(Pin::new(&mut self.incoming).poll_next(cx)).map(|c| Connecting {
-                connecting: c,
+        loop {
+            let connecting = match ready!(Pin::new(&mut self.incoming).poll_next(cx)) {
+                Some(c) => c,
+                None => return Poll::Ready(None),
+            };
+            if let Some(ref filter) = self.admission {
+                let admission = Admission {
+                    remote_address: connecting.remote_address(),
+                    authentication_data: None,
+                };
+                if !filter(&admission) {
+                    continue;
+                }
+            }
+            return Poll::Ready(Some(Connecting {
+                connecting,
                 settings: self.settings.clone(),
-            }),
-        )
+                options: self.options.clone(),
+                protocols: self.protocols.clone(),
+                connections: self.connections.clone(),
+                admission: self.admission.clone(),
+            }));
+        }
+    }
+}
+
+type AdmissionFilter = Arc<dyn Fn(&Admission) -> bool + Send + Sync>;
+
+/// Information available to an `IncomingConnection::admit` filter
+pub struct Admission<'a> {
+    pub remote_address: SocketAddr,
+    /// Available once the handshake has completed
+    pub authentication_data: Option<&'a AuthenticationData>,
+}
+
+/// Run the admission filter, if any, on an established connection
+fn admit(filter: &Option<AdmissionFilter>, new_conn: &quinn::NewConnection) -> Result<(), Error> {
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return Ok(()),
+    };
+    let conn = &new_conn.connection;
+    let admission = Admission {
+        remote_address: conn.remote_address(),
+        authentication_data: Some(&conn.authentication_data()),
+    };
+    if filter(&admission) {
+        return Ok(());
     }
+    conn.close(ErrorCode::EXCESSIVE_LOAD.into(), b"connection refused");
+    Err(Error::Refused)
 }
 
 pub struct Connecting {
     connecting: quinn::Connecting,
     settings: Settings,
+    options: Options,
+    protocols: Arc<Vec<Vec<u8>>>,
+    connections: ConnectionSet,
+    admission: Option<AdmissionFilter>,
+}
+
+impl Connecting {
+    /// The peer's UDP address
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connecting.remote_address()
+    }
+
+    /// Use `settings` for this connection instead of those of the `Builder`
+    pub fn settings(&mut self, settings: Settings) -> &mut Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Complete the handshake, handing back connections which did not negotiate HTTP/3
+    ///
+    /// Allows serving HTTP/3 and other protocols from the same endpoint, dispatching on ALPN.
+    pub fn route(self) -> Route {
+        Route(self)
+    }
+
+    /// Complete the handshake without starting HTTP/3 yet
+    ///
+    /// The resulting `NewConnection` exposes the handshake data, such as the server name
+    /// requested by the client, to choose the `Settings` before HTTP/3 is set up.
+    pub fn handshake(self) -> Handshake {
+        Handshake(Some(self))
+    }
+}
+
+pub struct Handshake(Option<Connecting>);
+
+impl Future for Handshake {
+    type Output = Result<NewConnection, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let connecting = match self.0 {
+            Some(ref mut connecting) => connecting,
+            None => return Poll::Ready(Err(Error::Poll)),
+        };
+        let new_conn = ready!(Pin::new(&mut connecting.connecting).poll(cx))?;
+        admit(&connecting.admission, &new_conn)?;
+        let Connecting {
+            settings,
+            options,
+            connections,
+            ..
+        } = self.0.take().unwrap();
+        Poll::Ready(Ok(NewConnection {
+            new_conn,
+            settings,
+            options,
+            connections,
+        }))
+    }
+}
+
+/// An established connection on which HTTP/3 hasn't been started yet
+pub struct NewConnection {
+    new_conn: quinn::NewConnection,
+    settings: Settings,
+    options: Options,
+    connections: ConnectionSet,
+}
+
+impl NewConnection {
+    /// The peer's UDP address
+    pub fn remote_address(&self) -> SocketAddr {
+        self.new_conn.connection.remote_address()
+    }
+
+    /// Cryptographic identity of the peer, including the server name it requested
+    pub fn authentication_data(&self) -> AuthenticationData {
+        self.new_conn.connection.authentication_data()
+    }
+
+    /// The ALPN token negotiated for this connection
+    pub fn protocol(&self) -> Option<Box<[u8]>> {
+        self.new_conn.connection.protocol()
+    }
+
+    /// Use `settings` for this connection instead of those of the `Builder`
+    pub fn settings(&mut self, settings: Settings) -> &mut Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Set up HTTP/3 on the connection
+    pub fn start(
+        self,
+    ) -> Result<(quinn::ConnectionDriver, ConnectionDriver, IncomingRequest), Error> {
+        IncomingRequest::new(
+            self.new_conn,
+            self.settings,
+            self.options,
+            Some(&self.connections),
+        )
+    }
+}
+
+pub enum Routed {
+    H3(quinn::ConnectionDriver, ConnectionDriver, IncomingRequest),
+    Other(quinn::NewConnection),
+}
+
+pub struct Route(Connecting);
+
+impl Future for Route {
+    type Output = Result<Routed, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let new_conn = ready!(Pin::new(&mut self.0.connecting).poll(cx))?;
+        admit(&self.0.admission, &new_conn)?;
+        let is_h3 = match new_conn.connection.protocol() {
+            Some(protocol) => self.0.protocols.iter().any(|x| x[..] == protocol[..]),
+            None => false,
+        };
+        if !is_h3 {
+            return Poll::Ready(Ok(Routed::Other(new_conn)));
+        }
+        let (driver, h3_driver, incoming) = IncomingRequest::new(
+            new_conn,
+            self.0.settings.clone(),
+            self.0.options.clone(),
+            Some(&self.0.connections),
+        )?;
+        Poll::Ready(Ok(Routed::H3(driver, h3_driver, incoming)))
+    }
 }
 
 impl Future for Connecting {
     type Output = Result<(quinn::ConnectionDriver, ConnectionDriver, IncomingRequest), Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let new_conn = ready!(Pin::new(&mut self.connecting).poll(cx))?;
+        admit(&self.admission, &new_conn)?;
+        Poll::Ready(IncomingRequest::new(
+            new_conn,
+            self.settings.clone(),
+            self.options.clone(),
+            Some(&self.connections),
+        ))
+    }
+}
+
+pub struct IncomingRequest(ConnectionRef);
+
+impl IncomingRequest {
+    /// The ALPN token negotiated for this connection
+    pub fn protocol(&self) -> Option<Box<[u8]>> {
+        self.0.quic.protocol()
+    }
+
+    /// Serve HTTP/3 over a connection accepted outside of this crate
+    ///
+    /// The connection should have negotiated an HTTP/3 ALPN token.
+    pub fn from_quinn(
+        new_conn: quinn::NewConnection,
+        settings: Settings,
+    ) -> Result<(quinn::ConnectionDriver, ConnectionDriver, Self), Error> {
+        Self::new(new_conn, settings, Options::default(), None)
+    }
+
+    fn new(
+        new_conn: quinn::NewConnection,
+        settings: Settings,
+        options: Options,
+        connections: Option<&ConnectionSet>,
+    ) -> Result<(quinn::ConnectionDriver, ConnectionDriver, Self), Error> {
         let quinn::NewConnection {
             driver,
             connection,
-            bi_streams,
             uni_streams,
+            bi_streams,
             ..
-        } = ready!(Pin::new(&mut self.connecting).poll(cx))?;
+        } = new_conn;
         let conn_ref = ConnectionRef::new(
             connection,
             Side::Server,
             uni_streams,
             bi_streams,
-            self.settings.clone(),
+            settings,
+            options,
         )?;
-        Poll::Ready(Ok((
+        Ok((
             driver,
-            ConnectionDriver(conn_ref.clone()),
+            ConnectionDriver::new(
+                conn_ref.clone(),
+                connections.map(|x| x.insert(conn_ref.clone())),
+            ),
             IncomingRequest(conn_ref),
-        )))
+        ))
     }
-}
 
-pub struct IncomingRequest(ConnectionRef);
+    pub fn authentication_data(&self) -> AuthenticationData {
+        self.0.quic.authentication_data()
+    }
+
+    /// The settings sent by the client, once they have been received
+    pub fn peer_settings(&self) -> Option<Settings> {
+        self.0.h3.lock().unwrap().inner.remote_settings().clone()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.0.stats()
+    }
+
+    /// Accept unidirectional streams of extension type `ty` opened by the client
+    ///
+    /// Streams of types which aren't being accepted are discarded. Fails if streams of type `ty`
+    /// are already being accepted.
+    pub fn accept_uni(&self, ty: u64) -> Result<UniStreams, Error> {
+        self.0.accept_uni(ty)
+    }
+
+    /// Open a unidirectional stream of extension type `ty`
+    pub async fn open_uni(&self, ty: u64) -> Result<SendStream, Error> {
+        self.0.open_uni(ty).await
+    }
+
+    /// Wait for the next request on this connection
+    pub async fn next(&mut self) -> Option<RecvRequest> {
+        future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
 
 impl Stream for IncomingRequest {
     type Item = RecvRequest;
@@ -140,16 +692,24 @@ pub struct RecvRequest {
     state: RecvRequestState,
     conn: ConnectionRef,
     stream_id: StreamId,
+    span: Span,
     streams: Option<(FrameStream, SendStream)>,
+    header_size: usize,
+    tracker: Option<ResponseTracker>,
 }
 
 impl RecvRequest {
     fn new(recv: RecvStream, send: SendStream, conn: ConnectionRef) -> Self {
+        let span = conn.request_span();
+        record_stream(&span, send.id());
         Self {
-            conn,
             stream_id: send.id(),
+            span,
             streams: None,
-            state: RecvRequestState::Receiving(FrameDecoder::stream(recv), send),
+            header_size: 0,
+            tracker: None,
+            state: RecvRequestState::Receiving(conn.frame_stream(recv), send),
+            conn,
         }
     }
 
@@ -159,6 +719,10 @@ impl RecvRequest {
         recv: FrameStream,
     ) -> Result<Request<RecvBody>, Error> {
         let (method, uri, headers) = headers.into_request_parts()?;
+        record_request(&self.span, &method, &uri);
+        if let Some(ref metrics) = self.conn.metrics {
+            metrics.request(&method, self.header_size);
+        }
         let mut request = Request::builder()
             .method(method)
             .uri(uri)
@@ -167,10 +731,18 @@ impl RecvRequest {
                 recv,
                 self.conn.clone(),
                 self.stream_id,
+                self.span.clone(),
                 false,
+                None,
             ))
             .unwrap();
         *request.headers_mut() = headers;
+        request
+            .extensions_mut()
+            .insert(self.conn.quic.authentication_data());
+        request
+            .extensions_mut()
+            .insert(self.conn.request_id(self.stream_id));
         Ok(request)
     }
 
@@ -187,12 +759,25 @@ impl Future for RecvRequest {
     type Output = Result<(Request<RecvBody>, Sender), Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let span = self.span.clone();
+        let _guard = span.enter();
         loop {
             match self.state {
                 RecvRequestState::Receiving(ref mut frames, _) => {
                     match ready!(Pin::new(frames).poll_next(cx)) {
-                        None => return Poll::Ready(Err(Error::peer("received an empty request"))),
+                        None => {
+                            return Poll::Ready(Err(Error::peer(
+                                ErrorCode::REQUEST_INCOMPLETE,
+                                "received an empty request",
+                            )))
+                        }
                         Some(Ok(HttpFrame::Headers(f))) => {
+                            self.header_size = f.encoded.len();
+                            self.tracker = self
+                                .conn
+                                .metrics
+                                .clone()
+                                .map(|m| ResponseTracker::new(m, Instant::now()));
                             let decode = DecodeHeaders::new(f, self.conn.clone(), self.stream_id);
                             match mem::replace(&mut self.state, RecvRequestState::Decoding(decode))
                             {
@@ -200,12 +785,16 @@ impl Future for RecvRequest {
                                 _ => unreachable!("Invalid state"),
                             }
                         }
+                        Some(Ok(HttpFrame::Extension(_))) => (),
                         Some(x) => {
                             let (code, error) = match x {
                                 Err(e) => (e.code(), e.into()),
                                 Ok(_) => (
                                     ErrorCode::FRAME_UNEXPECTED,
-                                    Error::peer("first frame is not headers"),
+                                    Error::peer(
+                                        ErrorCode::FRAME_UNEXPECTED,
+                                        "first frame is not headers",
+                                    ),
                                 ),
                             };
                             match mem::replace(&mut self.state, RecvRequestState::Finished) {
@@ -217,7 +806,18 @@ impl Future for RecvRequest {
                     }
                 }
                 RecvRequestState::Decoding(ref mut decode) => {
-                    let header = ready!(Pin::new(decode).poll(cx))?;
+                    let header = match ready!(Pin::new(decode).poll(cx)) {
+                        Ok(header) => header,
+                        Err(e) => {
+                            self.state = RecvRequestState::Finished;
+                            if let Some((recv, mut send)) = self.streams.take() {
+                                let code = e.code().unwrap_or(ErrorCode::REQUEST_CANCELLED);
+                                recv.reset(code);
+                                send.reset(code.into());
+                            }
+                            return Poll::Ready(Err(e));
+                        }
+                    };
                     self.state = RecvRequestState::Finished;
                     let (recv, send) = try_take(&mut self.streams, "Recv request invalid state")?;
                     return Poll::Ready(Ok((
@@ -226,11 +826,13 @@ impl Future for RecvRequest {
                             send,
                             stream_id: self.stream_id,
                             conn: self.conn.clone(),
+                            span: self.span.clone(),
+                            tracker: self.tracker.take(),
                         },
                     )));
                 }
                 RecvRequestState::Finished => {
-                    return Poll::Ready(Err(Error::peer("polled after ready")));
+                    return Poll::Ready(Err(Error::Poll));
                 }
             };
         }
@@ -241,6 +843,8 @@ pub struct Sender {
     send: SendStream,
     conn: ConnectionRef,
     stream_id: StreamId,
+    span: Span,
+    tracker: Option<ResponseTracker>,
 }
 
 impl Sender {
@@ -277,8 +881,19 @@ where
             send,
             stream_id,
             conn,
+            span,
+            tracker,
         } = self.sender;
-        SendResponse::new(self.response, self.trailers, send, stream_id, conn)?.await?;
+        SendResponse::new(
+            self.response,
+            self.trailers,
+            send,
+            stream_id,
+            conn,
+            span,
+            tracker,
+        )?
+        .await?;
         Ok(())
     }
 
@@ -287,6 +902,8 @@ where
             send,
             stream_id,
             conn,
+            span,
+            mut tracker,
         } = self.sender;
 
         let (
@@ -297,14 +914,24 @@ where
         ) = self.response.into_parts();
 
         let trailers = self.trailers;
+        if let Some(ref mut tracker) = tracker {
+            tracker.status(status);
+        }
 
         let send =
             SendHeaders::new(Header::response(status, headers), &conn, send, stream_id)?.await?;
         let send = match body.into() {
             Body::None => send,
-            Body::Buf(payload) => WriteFrame::new(send, DataFrame { payload }).await?,
+            Body::Buf(payload) => {
+                if let Some(ref mut tracker) = tracker {
+                    tracker.body(payload.len());
+                }
+                conn.write_data(send, payload).await?
+            }
         };
-        Ok(BodyWriter::new(send, conn, stream_id, trailers, true))
+        Ok(BodyWriter::new(
+            send, conn, stream_id, span, trailers, true, tracker,
+        ))
     }
 }
 
@@ -321,6 +948,8 @@ pub struct SendResponse {
     trailer: Option<Header>,
     conn: ConnectionRef,
     stream_id: StreamId,
+    span: Span,
+    _tracker: Option<ResponseTracker>,
 }
 
 impl SendResponse {
@@ -330,6 +959,8 @@ impl SendResponse {
         send: SendStream,
         stream_id: StreamId,
         conn: ConnectionRef,
+        span: Span,
+        mut tracker: Option<ResponseTracker>,
     ) -> Result<Self, Error> {
         let (
             response::Parts {
@@ -337,6 +968,13 @@ impl SendResponse {
             },
             body,
         ) = response.into_parts();
+        let body = body.into();
+        if let Some(ref mut tracker) = tracker {
+            tracker.status(status);
+            if let Body::Buf(ref payload) = body {
+                tracker.body(payload.len());
+            }
+        }
 
         let headers = Header::response(status, headers);
         let state =
@@ -346,12 +984,15 @@ impl SendResponse {
             conn,
             state,
             stream_id,
-            body: Some(body.into()),
+            span,
+            body: Some(body),
+            _tracker: tracker,
             trailer: trailers.map(Header::trailer),
         })
     }
 
     pub fn cancel(mut self) {
+        self.conn.request_cancelled();
         let state = mem::replace(&mut self.state, SendResponseState::Finished);
         match state {
             SendResponseState::SendingHeader(send) => {
@@ -372,9 +1013,11 @@ impl Future for SendResponse {
     type Output = Result<(), Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let span = self.span.clone();
+        let _guard = span.enter();
         loop {
             match self.state {
-                SendResponseState::Finished => panic!("polled after finished"),
+                SendResponseState::Finished => return Poll::Ready(Err(Error::Poll)),
                 SendResponseState::SendingTrailers(ref mut write) => {
                     ready!(Pin::new(write).poll(cx))?; // drop send
                     self.state = SendResponseState::Finished;
@@ -384,10 +1027,8 @@ impl Future for SendResponse {
                     let send = ready!(Pin::new(write).poll(cx))?;
                     match self.body.take() {
                         Some(Body::Buf(payload)) => {
-                            self.state = SendResponseState::SendingBody(WriteFrame::new(
-                                send,
-                                DataFrame { payload },
-                            ));
+                            self.state =
+                                SendResponseState::SendingBody(self.conn.write_data(send, payload));
                         }
                         _ => {
                             self.state = SendResponseState::Finished;
@@ -427,3 +1068,20 @@ impl Drop for SendResponse {
             .request_finished(self.stream_id);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alt_svc_value() {
+        assert_eq!(
+            alt_svc(4433, &[b"h3-20"], Duration::from_secs(3600)),
+            "h3-20=\":4433\"; ma=3600"
+        );
+        assert_eq!(
+            alt_svc(443, &[b"h3", b"w=x:y%"], Duration::from_secs(60)),
+            "h3=\":443\"; ma=60, w%3Dx%3Ay%25=\":443\"; ma=60"
+        );
+    }
+}
@@ -4,20 +4,19 @@ use std::{
     future::Future,
     io, mem,
     pin::Pin,
+    slice,
     task::{Context, Poll},
 };
 
-use bytes::{Buf, Bytes};
-use futures::{
-    io::{AsyncRead, AsyncWrite},
-    ready,
-};
+use bytes::Bytes;
+use futures::{io::AsyncRead, ready};
 use quinn::{OpenUni, RecvStream, SendStream};
-use quinn_proto::VarInt;
+use quinn_proto::{StreamId, VarInt};
 
 use crate::{
     frame::{FrameDecoder, FrameStream},
     proto::{ErrorCode, StreamType},
+    qlog::Qlog,
     Error,
 };
 
@@ -26,6 +25,7 @@ pub enum NewUni {
     Push(PushStream),
     Encoder(RecvStream),
     Decoder(RecvStream),
+    Unknown(StreamType, RecvStream),
 }
 
 impl TryFrom<(StreamType, RecvStream)> for NewUni {
@@ -37,11 +37,23 @@ impl TryFrom<(StreamType, RecvStream)> for NewUni {
             StreamType::PUSH => NewUni::Push(PushStream(FrameDecoder::stream(recv))),
             StreamType::ENCODER => NewUni::Encoder(recv),
             StreamType::DECODER => NewUni::Decoder(recv),
-            _ => return Err(Error::UnknownStream(ty.0)),
+            _ => NewUni::Unknown(ty, recv),
         })
     }
 }
 
+impl NewUni {
+    pub fn stream(&self) -> (StreamId, StreamType) {
+        match self {
+            NewUni::Control(s) => (s.get_ref().0.id(), StreamType::CONTROL),
+            NewUni::Push(PushStream(s)) => (s.get_ref().0.id(), StreamType::PUSH),
+            NewUni::Encoder(s) => (s.id(), StreamType::ENCODER),
+            NewUni::Decoder(s) => (s.id(), StreamType::DECODER),
+            NewUni::Unknown(ty, s) => (s.id(), *ty),
+        }
+    }
+}
+
 pub struct RecvUni {
     inner: Option<(RecvStream, [u8; VarInt::MAX_SIZE], usize, usize)>,
 }
@@ -64,8 +76,9 @@ impl Future for RecvUni {
                 Some((ref mut recv, ref mut buf, ref mut expected, ref mut len)) => {
                     match ready!(Pin::new(recv).poll_read(cx, &mut buf[*len..*expected]))? {
                         0 => {
-                            return Poll::Ready(Err(Error::Peer(
-                                "Uni stream closed before type received".into(),
+                            return Poll::Ready(Err(Error::peer(
+                                ErrorCode::STREAM_CREATION_ERROR,
+                                "Uni stream closed before type received",
                             )))
                         }
                         read => {
@@ -98,14 +111,16 @@ pub struct SendUni {
     ty: StreamType,
     state: SendUniState,
     data: VecDeque<Bytes>,
+    qlog: Option<Qlog>,
 }
 
 impl SendUni {
-    pub(super) fn new(ty: StreamType, open_uni: quinn::OpenUni) -> Self {
+    pub(super) fn new(ty: StreamType, open_uni: quinn::OpenUni, qlog: Option<Qlog>) -> Self {
         Self {
             ty,
             state: SendUniState::Opening(open_uni),
             data: VecDeque::with_capacity(2),
+            qlog,
         }
     }
 }
@@ -135,6 +150,9 @@ impl Future for SendUni {
                         return Poll::Ready(Ok(()));
                     }
                     let send = ready!(Pin::new(o).poll(cx))?;
+                    if let Some(ref qlog) = self.qlog {
+                        qlog.stream_type_set(send.id(), true, self.ty);
+                    }
                     self.state = SendUniState::Sending(send, self.ty.encoded());
                 }
                 SendUniState::Idle(_) => match self.data.pop_front() {
@@ -145,8 +163,7 @@ impl Future for SendUni {
                     None => return Poll::Ready(Ok(())),
                 },
                 SendUniState::Sending(ref mut send, ref mut data) => {
-                    let wrote = ready!(Pin::new(send).poll_write(cx, data))?;
-                    data.advance(wrote);
+                    ready!(send.poll_write_chunks(cx, slice::from_mut(data)))?;
                     if data.is_empty() {
                         self.state = match mem::replace(&mut self.state, SendUniState::Transitive) {
                             SendUniState::Sending(s, _) => match self.data.pop_front() {
@@ -0,0 +1,126 @@
+//! Support for the ACK frequency extension, which lets a sender ask its peer to acknowledge less
+//! often
+
+use std::time::{Duration, Instant};
+
+use tracing::trace;
+
+use crate::frame::AckFrequency;
+
+/// How often to acknowledge ack-eliciting packets in the Data space
+///
+/// Follows the local `TransportConfig` until the peer requests otherwise with an `ACK_FREQUENCY`
+/// frame.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct AckFrequencyState {
+    /// Sequence number of the most recent request, if any
+    sequence: Option<u64>,
+    /// Number of ack-eliciting packets to receive before acknowledging
+    packet_tolerance: u64,
+    /// Longest time an acknowledgement may be delayed
+    max_ack_delay: Duration,
+    /// Whether reordered packets are acknowledged like any other
+    ignore_order: bool,
+    /// Number of ack-eliciting packets received since the last acknowledgement was sent
+    unacked: u64,
+    /// Whether the most recently received packet arrived out of order
+    reordered: bool,
+    /// When a delayed acknowledgement must be sent
+    deadline: Option<Instant>,
+}
+
+impl AckFrequencyState {
+    pub(crate) fn new(packet_tolerance: u64, max_ack_delay: Duration) -> Self {
+        Self {
+            sequence: None,
+            packet_tolerance,
+            max_ack_delay,
+            ignore_order: false,
+            unacked: 0,
+            reordered: false,
+            deadline: None,
+        }
+    }
+
+    /// Apply a request from the peer, unless a newer one was already applied
+    pub(crate) fn on_frame(&mut self, frame: &AckFrequency) {
+        if self.sequence.map_or(false, |x| frame.sequence <= x) {
+            trace!(sequence = frame.sequence, "ignoring stale ACK_FREQUENCY");
+            return;
+        }
+        self.sequence = Some(frame.sequence);
+        self.packet_tolerance = frame.packet_tolerance;
+        self.max_ack_delay = Duration::from_micros(frame.update_max_ack_delay);
+        self.ignore_order = frame.ignore_order;
+    }
+
+    /// A packet numbered `number` was received, the largest previously received being `largest`
+    pub(crate) fn on_packet(&mut self, number: u64, largest: u64) {
+        self.reordered = number < largest || number > largest + 1;
+    }
+
+    /// An ack-eliciting packet was received
+    ///
+    /// Returns the time by which an acknowledgement must be sent, or `None` if it should be sent
+    /// immediately.
+    pub(crate) fn on_ack_eliciting(&mut self, now: Instant, immediate: bool) -> Option<Instant> {
+        self.unacked += 1;
+        if immediate
+            || self.unacked >= self.packet_tolerance
+            || (self.reordered && !self.ignore_order)
+        {
+            return None;
+        }
+        Some(*self.deadline.get_or_insert(now + self.max_ack_delay))
+    }
+
+    /// An acknowledgement was sent
+    pub(crate) fn on_ack_sent(&mut self) {
+        self.unacked = 0;
+        self.deadline = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tolerance() {
+        let now = Instant::now();
+        let mut state = AckFrequencyState::new(1, Duration::from_millis(25));
+        state.on_packet(0, 0);
+        assert_eq!(state.on_ack_eliciting(now, false), None);
+        state.on_ack_sent();
+
+        state.on_frame(&AckFrequency {
+            sequence: 1,
+            packet_tolerance: 3,
+            update_max_ack_delay: 10_000,
+            ignore_order: false,
+        });
+        // Stale requests are ignored
+        state.on_frame(&AckFrequency {
+            sequence: 0,
+            packet_tolerance: 1,
+            update_max_ack_delay: 0,
+            ignore_order: false,
+        });
+        let deadline = now + Duration::from_millis(10);
+        state.on_packet(1, 0);
+        assert_eq!(state.on_ack_eliciting(now, false), Some(deadline));
+        state.on_packet(2, 1);
+        let later = now + Duration::from_millis(1);
+        assert_eq!(state.on_ack_eliciting(later, false), Some(deadline));
+        state.on_packet(3, 2);
+        assert_eq!(state.on_ack_eliciting(later, false), None);
+        state.on_ack_sent();
+
+        // Reordering and IMMEDIATE_ACK are acknowledged at once
+        state.on_packet(5, 3);
+        assert_eq!(state.on_ack_eliciting(later, false), None);
+        state.on_ack_sent();
+        state.on_packet(6, 5);
+        assert_eq!(state.on_ack_eliciting(later, true), None);
+    }
+}
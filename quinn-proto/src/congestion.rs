@@ -0,0 +1,107 @@
+//! Logic for controlling the rate at which data is sent
+//!
+//! A `Controller` is created for each network path of a connection by the `ControllerFactory` set
+//! in `TransportConfig::congestion_controller_factory`. The default is `NewReno`.
+
+use std::{
+    cmp, fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{connection::RttEstimator, ConfigError, TransportConfig};
+
+mod bbr;
+mod cubic;
+mod new_reno;
+
+pub use bbr::{Bbr, BbrFactory};
+pub use cubic::{Cubic, CubicConfig};
+pub use new_reno::{NewReno, NewRenoFactory};
+
+/// Common interface for congestion control algorithms
+pub trait Controller: Send {
+    /// A packet counting towards the window, of `bytes` bytes, was sent
+    ///
+    /// `bytes_in_flight` is the connection's total of bytes in flight, including this packet.
+    fn on_sent(&mut self, now: Instant, bytes: u64, bytes_in_flight: u64) {
+        let _ = (now, bytes, bytes_in_flight);
+    }
+
+    /// A packet of `bytes` bytes sent at `sent` was acknowledged
+    ///
+    /// `app_limited` indicates that the window wasn't filled when the packet was acknowledged, so
+    /// the acknowledgement says nothing about the capacity of the path. `bytes_in_flight` is the
+    /// connection's total of bytes still in flight, no longer including this packet.
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        bytes: u64,
+        app_limited: bool,
+        rtt: &RttEstimator,
+        bytes_in_flight: u64,
+    );
+
+    /// Packets were lost or marked with ECN-CE, the newest of them sent at `sent`
+    ///
+    /// `lost_bytes` no longer count towards the window; it is 0 for ECN-CE marks. `mtu_probe`
+    /// indicates that the packets were MTU probes, whose loss is no sign of congestion.
+    /// `persistent_congestion` indicates that every packet sent over a period of several PTOs was
+    /// lost.
+    fn on_loss(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        lost_bytes: u64,
+        mtu_probe: bool,
+        persistent_congestion: bool,
+    );
+
+    /// Number of bytes that may be in flight
+    fn window(&self) -> u64;
+
+    /// Rate at which packets should be sent, in bytes per second
+    ///
+    /// Defaults to 1.25 windows per round trip, so that pacing doesn't hold back growth of the
+    /// window.
+    fn pacing_rate(&self, rtt: &RttEstimator) -> u64 {
+        let rtt = rtt.smoothed().unwrap_or_else(|| rtt.latest());
+        let micros = cmp::max(rtt.as_micros() as u64, 1);
+        self.window().saturating_mul(1_250_000) / micros
+    }
+
+    /// Snapshot of the controller's state, for diagnostics
+    fn state(&self) -> ControllerState {
+        ControllerState {
+            window: self.window(),
+            ..ControllerState::default()
+        }
+    }
+
+    /// Duplicate the controller's state, for a path which is probably the same as this one
+    fn clone_box(&self) -> Box<dyn Controller>;
+}
+
+/// Constructs controllers on demand
+pub trait ControllerFactory: fmt::Debug + Send + Sync {
+    /// Construct a fresh `Controller` for a new path
+    fn build(&self, now: Instant, config: &TransportConfig) -> Box<dyn Controller>;
+
+    /// Check the factory's parameters, as part of validating the `TransportConfig` it belongs to
+    fn validate(&self) -> Result<(), ConfigError> {
+        Ok(())
+    }
+}
+
+/// Snapshot of a `Controller`'s state, see `Connection::congestion`
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ControllerState {
+    /// Number of bytes that may be in flight
+    pub window: u64,
+    /// Phase of the algorithm, for those which have distinct phases
+    pub phase: Option<&'static str>,
+    /// Estimated bottleneck bandwidth in bytes per second, for model-based algorithms
+    pub bandwidth: Option<u64>,
+    /// Estimated round trip time of the path when empty, for model-based algorithms
+    pub min_rtt: Option<Duration>,
+}
@@ -0,0 +1,482 @@
+use std::{
+    cmp,
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::{Controller, ControllerFactory, ControllerState};
+use crate::{connection::RttEstimator, TransportConfig};
+
+/// Constructs `Bbr` controllers
+#[derive(Debug, Default, Copy, Clone)]
+pub struct BbrFactory;
+
+impl ControllerFactory for BbrFactory {
+    fn build(&self, now: Instant, config: &TransportConfig) -> Box<dyn Controller> {
+        Box::new(Bbr::new(now, config))
+    }
+}
+
+/// Version 1 of the Bottleneck Bandwidth and Round-trip propagation time algorithm
+///
+/// Sends at the rate of a model of the path built from the bottleneck bandwidth and the minimum
+/// RTT, rather than backing off on loss, which keeps throughput high on long fat networks with
+/// random loss. Follows draft-cardwell-iccrg-bbr-congestion-control-00. Reacts to loss only on
+/// persistent congestion.
+#[derive(Debug, Clone)]
+pub struct Bbr {
+    max_datagram_size: u64,
+    initial_window: u64,
+    minimum_window: u64,
+    mode: Mode,
+    window: u64,
+    /// Window to restore when leaving `ProbeRtt`
+    prior_window: u64,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+    /// Total bytes acknowledged
+    delivered: u64,
+    /// When `delivered` last increased
+    delivered_time: Instant,
+    /// Delivery state when each packet in flight was sent, oldest first
+    sent: VecDeque<SentState>,
+    /// Maximum delivery rate over the last `BANDWIDTH_WINDOW` rounds
+    bandwidth: MaxFilter,
+    /// Number of round trips so far
+    round: u64,
+    /// Acknowledging a packet sent after this time starts a new round trip
+    round_start: Instant,
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Instant,
+    /// Bandwidth when growth was last observed during startup
+    full_bandwidth: u64,
+    /// Rounds since growth was last observed during startup
+    full_bandwidth_rounds: u32,
+    filled_pipe: bool,
+    cycle_index: usize,
+    cycle_start: Instant,
+    /// When to leave `ProbeRtt`, once the window was drained
+    probe_rtt_done: Option<Instant>,
+}
+
+impl Bbr {
+    /// Construct a controller starting with `config.initial_window`
+    pub fn new(now: Instant, config: &TransportConfig) -> Self {
+        Self {
+            max_datagram_size: config.max_datagram_size,
+            initial_window: config.initial_window,
+            minimum_window: config.minimum_window,
+            mode: Mode::Startup,
+            window: config.initial_window,
+            prior_window: config.initial_window,
+            pacing_gain: HIGH_GAIN,
+            cwnd_gain: HIGH_GAIN,
+            delivered: 0,
+            delivered_time: now,
+            sent: VecDeque::new(),
+            bandwidth: MaxFilter::default(),
+            round: 0,
+            round_start: now,
+            min_rtt: None,
+            min_rtt_stamp: now,
+            full_bandwidth: 0,
+            full_bandwidth_rounds: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_start: now,
+            probe_rtt_done: None,
+        }
+    }
+
+    /// Estimated bandwidth-delay product scaled by `gain`, if a model was built yet
+    fn bdp(&self, gain: f64) -> Option<u64> {
+        let bandwidth = self.bandwidth.get();
+        let min_rtt = self.min_rtt?;
+        if bandwidth == 0 {
+            return None;
+        }
+        let bdp = bandwidth as u128 * min_rtt.as_micros() / 1_000_000;
+        Some((bdp as f64 * gain) as u64)
+    }
+
+    /// Find the delivery state when the packet sent at `sent` was sent
+    fn take_sent_state(&mut self, sent: Instant) -> Option<SentState> {
+        // Older entries belong to packets which were lost, or acknowledged out of order
+        while let Some(state) = self.sent.pop_front() {
+            if state.time == sent {
+                return Some(state);
+            }
+            if state.time > sent {
+                self.sent.push_front(state);
+                break;
+            }
+        }
+        None
+    }
+
+    fn check_full_pipe(&mut self) {
+        let bandwidth = self.bandwidth.get();
+        if bandwidth as f64 >= self.full_bandwidth as f64 * FULL_BANDWIDTH_GROWTH {
+            self.full_bandwidth = bandwidth;
+            self.full_bandwidth_rounds = 0;
+            return;
+        }
+        self.full_bandwidth_rounds += 1;
+        if self.full_bandwidth_rounds >= FULL_BANDWIDTH_ROUNDS {
+            self.filled_pipe = true;
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.mode = Mode::ProbeBw;
+        self.cwnd_gain = CWND_GAIN;
+        // Start with the cruising phase rather than the draining one
+        self.cycle_index = 2;
+        self.cycle_start = now;
+        self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+    }
+
+    fn update_cycle(&mut self, now: Instant, in_flight: u64) {
+        let min_rtt = match self.min_rtt {
+            Some(x) => x,
+            None => return,
+        };
+        let elapsed = now - self.cycle_start > min_rtt;
+        let advance = if self.pacing_gain > 1.0 {
+            elapsed && self.bdp(self.pacing_gain).map_or(true, |x| in_flight >= x)
+        } else if self.pacing_gain < 1.0 {
+            elapsed || self.bdp(1.0).map_or(true, |x| in_flight <= x)
+        } else {
+            elapsed
+        };
+        if advance {
+            self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+            self.cycle_start = now;
+            self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+        }
+    }
+
+    fn update_probe_rtt(&mut self, now: Instant, min_rtt_expired: bool, in_flight: u64) {
+        if min_rtt_expired && self.mode != Mode::ProbeRtt {
+            self.mode = Mode::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.prior_window = self.window;
+            self.probe_rtt_done = None;
+        }
+        if self.mode != Mode::ProbeRtt {
+            return;
+        }
+        match self.probe_rtt_done {
+            None if in_flight <= self.probe_rtt_window() => {
+                self.probe_rtt_done = Some(now + PROBE_RTT_DURATION);
+            }
+            Some(done) if now >= done => {
+                self.min_rtt_stamp = now;
+                self.window = cmp::max(self.window, self.prior_window);
+                if self.filled_pipe {
+                    self.enter_probe_bw(now);
+                } else {
+                    self.mode = Mode::Startup;
+                    self.pacing_gain = HIGH_GAIN;
+                    self.cwnd_gain = HIGH_GAIN;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn probe_rtt_window(&self) -> u64 {
+        cmp::max(
+            MIN_PIPE_PACKETS * self.max_datagram_size,
+            self.minimum_window,
+        )
+    }
+
+    fn update_window(&mut self, bytes: u64) {
+        if self.mode == Mode::ProbeRtt {
+            self.window = cmp::min(self.window, self.probe_rtt_window());
+            return;
+        }
+        let target = match self.bdp(self.cwnd_gain) {
+            // Unless sends are paced, the queue built during startup only drains through the window
+            Some(bdp) if self.mode == Mode::Drain => bdp,
+            Some(bdp) => bdp + 3 * self.max_datagram_size,
+            None => self.initial_window,
+        };
+        if self.filled_pipe {
+            self.window = cmp::min(self.window + bytes, target);
+        } else if self.window < target || self.delivered < self.initial_window {
+            self.window += bytes;
+        }
+        self.window = cmp::max(self.window, self.minimum_window);
+    }
+}
+
+impl Controller for Bbr {
+    fn on_sent(&mut self, now: Instant, bytes: u64, bytes_in_flight: u64) {
+        if bytes_in_flight == bytes {
+            // Don't count idle time against the delivery rate
+            self.delivered_time = now;
+        }
+        self.sent.push_back(SentState {
+            time: now,
+            delivered: self.delivered,
+            delivered_time: self.delivered_time,
+        });
+    }
+
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        bytes: u64,
+        app_limited: bool,
+        rtt: &RttEstimator,
+        bytes_in_flight: u64,
+    ) {
+        self.delivered += bytes;
+        self.delivered_time = now;
+
+        let round_start = sent > self.round_start;
+        if round_start {
+            self.round += 1;
+            self.round_start = now;
+        }
+
+        if let Some(state) = self.take_sent_state(sent) {
+            let delivered = u128::from(self.delivered - state.delivered) * 1_000_000;
+            let interval = (now - state.delivered_time).as_micros();
+            if let Some(rate) = delivered.checked_div(interval) {
+                let rate = rate as u64;
+                if !app_limited || rate > self.bandwidth.get() {
+                    self.bandwidth.update(self.round, rate);
+                }
+            }
+        }
+
+        let min_rtt_expired = now - self.min_rtt_stamp > MIN_RTT_EXPIRY;
+        let sample = rtt.latest();
+        if self.min_rtt.map_or(true, |x| sample <= x) || min_rtt_expired {
+            self.min_rtt = Some(sample);
+            self.min_rtt_stamp = now;
+        }
+
+        if round_start && !self.filled_pipe && !app_limited {
+            self.check_full_pipe();
+        }
+        match self.mode {
+            Mode::Startup if self.filled_pipe => {
+                self.mode = Mode::Drain;
+                self.pacing_gain = 1.0 / HIGH_GAIN;
+                self.cwnd_gain = 1.0;
+            }
+            Mode::ProbeBw => self.update_cycle(now, bytes_in_flight),
+            _ => {}
+        }
+        if self.mode == Mode::Drain && self.bdp(1.0).map_or(false, |x| bytes_in_flight <= x) {
+            self.enter_probe_bw(now);
+        }
+        self.update_probe_rtt(now, min_rtt_expired, bytes_in_flight);
+        self.update_window(bytes);
+    }
+
+    fn on_loss(
+        &mut self,
+        _now: Instant,
+        _sent: Instant,
+        _lost_bytes: u64,
+        _mtu_probe: bool,
+        persistent: bool,
+    ) {
+        if persistent {
+            self.prior_window = self.window;
+            self.window = self.minimum_window;
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn pacing_rate(&self, rtt: &RttEstimator) -> u64 {
+        match self.bandwidth.get() {
+            0 => {
+                let rtt = rtt.smoothed().unwrap_or_else(|| rtt.latest());
+                let micros = cmp::max(rtt.as_micros() as u64, 1);
+                (self.window as f64 * self.pacing_gain) as u64 * 1_000_000 / micros
+            }
+            bandwidth => (bandwidth as f64 * self.pacing_gain) as u64,
+        }
+    }
+
+    fn state(&self) -> ControllerState {
+        ControllerState {
+            window: self.window,
+            phase: Some(self.mode.name()),
+            bandwidth: Some(self.bandwidth.get()).filter(|&x| x > 0),
+            min_rtt: self.min_rtt,
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Mode {
+    /// Doubling the sending rate every round trip to find the bottleneck bandwidth
+    Startup,
+    /// Draining the queue built during startup
+    Drain,
+    /// Cycling the pacing gain to probe for more bandwidth and drain queues
+    ProbeBw,
+    /// Reducing the window to measure the minimum RTT again
+    ProbeRtt,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Startup => "startup",
+            Mode::Drain => "drain",
+            Mode::ProbeBw => "probe_bw",
+            Mode::ProbeRtt => "probe_rtt",
+        }
+    }
+}
+
+/// Delivery state when a packet was sent
+#[derive(Debug, Copy, Clone)]
+struct SentState {
+    time: Instant,
+    delivered: u64,
+    delivered_time: Instant,
+}
+
+/// Maximum of the values seen over the last `BANDWIDTH_WINDOW` rounds
+#[derive(Debug, Clone, Default)]
+struct MaxFilter {
+    /// Candidate maxima with their round, in decreasing order
+    samples: VecDeque<(u64, u64)>,
+}
+
+impl MaxFilter {
+    fn update(&mut self, round: u64, value: u64) {
+        while self.samples.back().map_or(false, |&(_, x)| x <= value) {
+            self.samples.pop_back();
+        }
+        self.samples.push_back((round, value));
+        while self
+            .samples
+            .front()
+            .map_or(false, |&(r, _)| r + BANDWIDTH_WINDOW <= round)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    fn get(&self) -> u64 {
+        self.samples.front().map_or(0, |&(_, x)| x)
+    }
+}
+
+/// Gain doubling the sending rate every round trip during startup, 2/ln(2)
+const HIGH_GAIN: f64 = 2.885;
+/// Gain applied to the bandwidth-delay product for the window after startup
+const CWND_GAIN: f64 = 2.0;
+/// Pacing gains cycled through in `ProbeBw`, one round trip each
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// Number of round trips over which the maximum bandwidth is tracked
+const BANDWIDTH_WINDOW: u64 = 10;
+/// How long a minimum RTT sample stays valid
+const MIN_RTT_EXPIRY: Duration = Duration::from_secs(10);
+/// Time spent with a minimal window in `ProbeRtt`
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+/// Growth in bandwidth per round trip below which startup is considered done
+const FULL_BANDWIDTH_GROWTH: f64 = 1.25;
+/// Round trips without growth after which startup is considered done
+const FULL_BANDWIDTH_ROUNDS: u32 = 3;
+/// Size of the window in `ProbeRtt`, in packets
+const MIN_PIPE_PACKETS: u64 = 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_filter() {
+        let mut filter = MaxFilter::default();
+        filter.update(0, 10);
+        filter.update(1, 5);
+        assert_eq!(filter.get(), 10);
+        filter.update(BANDWIDTH_WINDOW, 3);
+        assert_eq!(filter.get(), 5);
+        filter.update(BANDWIDTH_WINDOW + 1, 8);
+        assert_eq!(filter.get(), 8);
+    }
+
+    #[test]
+    fn finds_bottleneck() {
+        // A 10Mbps bottleneck with a 50ms RTT
+        const BANDWIDTH: u64 = 1_250_000;
+        const SIZE: u64 = 1200;
+        let delay = Duration::from_millis(25);
+        let transmission = Duration::from_micros(SIZE * 1_000_000 / BANDWIDTH);
+
+        let config = TransportConfig::default();
+        let start = Instant::now();
+        let mut cc = Bbr::new(start, &config);
+        let mut rtt = RttEstimator::new();
+        let mut now = start;
+        let mut link_free = start;
+        let mut in_flight = 0;
+        let mut acks = VecDeque::new();
+        while now < start + Duration::from_secs(5) {
+            while in_flight + SIZE <= cc.window() {
+                in_flight += SIZE;
+                cc.on_sent(now, SIZE, in_flight);
+                link_free = cmp::max(link_free, now + delay) + transmission;
+                acks.push_back((link_free + delay, now));
+            }
+            let (ack, sent) = acks.pop_front().unwrap();
+            now = ack;
+            in_flight -= SIZE;
+            rtt.update(Duration::from_millis(0), now - sent);
+            cc.on_ack(now, sent, SIZE, false, &rtt, in_flight);
+        }
+
+        let state = cc.state();
+        assert_eq!(state.phase, Some("probe_bw"));
+        let bandwidth = state.bandwidth.unwrap();
+        assert!(bandwidth > BANDWIDTH * 9 / 10 && bandwidth <= BANDWIDTH * 11 / 10);
+        assert!(state.min_rtt.unwrap() < Duration::from_millis(55));
+        // The window stays close to twice the bandwidth-delay product
+        assert!(cc.window() < 4 * BANDWIDTH / 20);
+    }
+
+    #[test]
+    fn leaves_probe_rtt() {
+        const SIZE: u64 = 1200;
+        let config = TransportConfig::default();
+        let start = Instant::now();
+        let mut cc = Bbr::new(start, &config);
+        let mut rtt = RttEstimator::new();
+        rtt.update(Duration::from_millis(0), Duration::from_millis(50));
+        // Packets whose space is later discarded are never acknowledged or declared lost
+        for i in 1..=10 {
+            cc.on_sent(start, SIZE, i * SIZE);
+        }
+        cc.on_sent(start, SIZE, 11 * SIZE);
+        cc.on_ack(start, start, SIZE, false, &rtt, 0);
+
+        let now = start + MIN_RTT_EXPIRY + Duration::from_secs(1);
+        cc.on_sent(now, SIZE, SIZE);
+        cc.on_ack(now, now, SIZE, false, &rtt, 0);
+        assert_eq!(cc.state().phase, Some("probe_rtt"));
+        let now = now + PROBE_RTT_DURATION;
+        cc.on_sent(now, SIZE, SIZE);
+        cc.on_ack(now, now, SIZE, false, &rtt, 0);
+        assert_ne!(cc.state().phase, Some("probe_rtt"));
+    }
+}
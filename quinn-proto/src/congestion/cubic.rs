@@ -0,0 +1,270 @@
+use std::{
+    cmp,
+    time::{Duration, Instant},
+};
+
+use super::{Controller, ControllerFactory, ControllerState};
+use crate::{connection::RttEstimator, ConfigError, TransportConfig};
+
+/// Constants of the `Cubic` algorithm, which constructs `Cubic` controllers
+///
+/// The defaults are those recommended by RFC 8312.
+#[derive(Debug, Copy, Clone)]
+pub struct CubicConfig {
+    /// Multiplicative decrease factor applied to the window on loss, strictly between 0 and 1
+    pub beta: f64,
+    /// Scaling constant of the cubic function, in packets per second cubed. Must be positive.
+    pub c: f64,
+    /// Whether to release bandwidth faster when the available bandwidth shrinks
+    pub fast_convergence: bool,
+    /// Whether to grow the window at least as fast as standard TCP would
+    pub tcp_friendly: bool,
+}
+
+impl Default for CubicConfig {
+    fn default() -> Self {
+        Self {
+            beta: 0.7,
+            c: 0.4,
+            fast_convergence: true,
+            tcp_friendly: true,
+        }
+    }
+}
+
+impl ControllerFactory for CubicConfig {
+    fn build(&self, now: Instant, config: &TransportConfig) -> Box<dyn Controller> {
+        Box::new(Cubic::new(*self, now, config))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(self.beta > 0.0 && self.beta < 1.0) {
+            return Err(ConfigError::IllegalValue(
+                "cubic beta must be between 0 and 1",
+            ));
+        }
+        if !(self.c > 0.0 && self.c.is_finite()) {
+            return Err(ConfigError::IllegalValue(
+                "cubic c must be positive and finite",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The CUBIC congestion control algorithm of RFC 8312, used by most TCP stacks
+///
+/// Grows the window as a cubic function of the time since the last congestion event, which
+/// makes growth independent of the RTT and quickly returns to the window at which loss occurred.
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    config: CubicConfig,
+    max_datagram_size: u64,
+    minimum_window: u64,
+    window: u64,
+    /// Slow start threshold in bytes
+    ssthresh: u64,
+    /// Packets sent before this time were sent before the last congestion event
+    recovery_start_time: Instant,
+    /// Window before the last reduction, in packets
+    w_max: f64,
+    /// Time for the window to grow back to `w_max` after a reduction
+    k: Duration,
+    /// Start of the current congestion avoidance epoch
+    epoch_start: Option<Instant>,
+    /// Estimate of the window standard TCP would have, in packets
+    w_est: f64,
+}
+
+impl Cubic {
+    /// Construct a controller starting with `config.initial_window`
+    pub fn new(cubic: CubicConfig, now: Instant, config: &TransportConfig) -> Self {
+        Self {
+            config: cubic,
+            max_datagram_size: config.max_datagram_size,
+            minimum_window: config.minimum_window,
+            window: config.initial_window,
+            ssthresh: u64::max_value(),
+            recovery_start_time: now,
+            w_max: 0.0,
+            k: Duration::new(0, 0),
+            epoch_start: None,
+            w_est: 0.0,
+        }
+    }
+
+    fn packets(&self, bytes: u64) -> f64 {
+        bytes as f64 / self.max_datagram_size as f64
+    }
+
+    /// Window in packets `t` after the start of the epoch, following the cubic function
+    fn w_cubic(&self, t: Duration) -> f64 {
+        let t = t.as_secs_f64() - self.k.as_secs_f64();
+        self.config.c * t * t * t + self.w_max
+    }
+
+    fn congestion_avoidance(&mut self, now: Instant, bytes: u64, rtt: &RttEstimator) {
+        let window = self.packets(self.window);
+        let epoch_start = match self.epoch_start {
+            Some(x) => x,
+            None => {
+                // First acknowledgement since slow start or a reduction
+                if self.w_max < window {
+                    self.w_max = window;
+                    self.k = Duration::new(0, 0);
+                }
+                self.w_est = window;
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+        let rtt = rtt.min();
+        let t = now - epoch_start;
+        let target = self.w_cubic(t + rtt);
+
+        let acked = self.packets(bytes);
+        let beta = self.config.beta;
+        self.w_est += 3.0 * (1.0 - beta) / (1.0 + beta) * acked / window;
+        let target = if self.config.tcp_friendly && target < self.w_est {
+            self.w_est
+        } else {
+            target
+        };
+        if target > window {
+            let increase = (target - window) / window * bytes as f64;
+            self.window += increase as u64;
+        }
+    }
+}
+
+impl Controller for Cubic {
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        bytes: u64,
+        app_limited: bool,
+        rtt: &RttEstimator,
+        _bytes_in_flight: u64,
+    ) {
+        if app_limited || sent <= self.recovery_start_time {
+            return;
+        }
+        if self.window < self.ssthresh {
+            self.window += bytes;
+        } else {
+            self.congestion_avoidance(now, bytes, rtt);
+        }
+    }
+
+    fn on_loss(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        _lost_bytes: u64,
+        mtu_probe: bool,
+        persistent_congestion: bool,
+    ) {
+        if mtu_probe {
+            return;
+        }
+        if sent > self.recovery_start_time {
+            self.recovery_start_time = now;
+            self.epoch_start = None;
+            let window = self.packets(self.window);
+            let beta = self.config.beta;
+            self.w_max = if self.config.fast_convergence && window < self.w_max {
+                window * (1.0 + beta) / 2.0
+            } else {
+                window
+            };
+            // Capped so that a tiny `c` can't overflow the `Duration`
+            let k = (self.w_max * (1.0 - beta) / self.config.c).cbrt();
+            self.k = Duration::from_secs_f64(k.min(f64::from(u32::max_value())));
+            self.window = cmp::max((self.window as f64 * beta) as u64, self.minimum_window);
+            self.ssthresh = self.window;
+        }
+        if persistent_congestion {
+            self.epoch_start = None;
+            self.window = self.minimum_window;
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn state(&self) -> ControllerState {
+        let phase = if self.window < self.ssthresh {
+            "slow_start"
+        } else {
+            "congestion_avoidance"
+        };
+        ControllerState {
+            window: self.window,
+            phase: Some(phase),
+            ..ControllerState::default()
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cubic() {
+        let config = TransportConfig::default();
+        let start = Instant::now();
+        let mut rtt = RttEstimator::new();
+        rtt.update(Duration::new(0, 0), Duration::from_millis(100));
+        let mut cc = Cubic::new(CubicConfig::default(), start, &config);
+        let now = start + Duration::from_millis(100);
+        for _ in 0..90 {
+            cc.on_ack(now, now, 1200, false, &rtt, 0);
+        }
+        assert_eq!(cc.window(), 100 * 1200);
+        assert_eq!(cc.state().phase, Some("slow_start"));
+
+        cc.on_loss(now, now, 1200, false, false);
+        assert_eq!(cc.window(), 70 * 1200);
+        assert_eq!(cc.state().phase, Some("congestion_avoidance"));
+        // K = cbrt(100 * 0.3 / 0.4) seconds
+        assert_eq!(cc.k.as_millis(), 4217);
+
+        // The window grows back to its previous maximum after K, then beyond
+        let mut now = now;
+        let mut windows = Vec::new();
+        for _ in 0..60 {
+            now += Duration::from_millis(100);
+            let acks = cc.window() / 1200;
+            for _ in 0..acks {
+                cc.on_ack(now, now, 1200, false, &rtt, 0);
+            }
+            windows.push(cc.window() / 1200);
+        }
+        // Concave growth, which slows down approaching the previous maximum
+        assert!(windows[10] - windows[0] > windows[30] - windows[20]);
+        assert!(windows[38] >= 98 && windows[38] <= 102);
+        // Convex growth past it
+        assert!(windows[59] - windows[49] > windows[49] - windows[39]);
+    }
+
+    #[test]
+    fn validate() {
+        assert!(CubicConfig::default().validate().is_ok());
+        for &(beta, c) in &[(1.5, 0.4), (0.0, 0.4), (0.7, 0.0), (std::f64::NAN, 0.4)] {
+            assert!(CubicConfig {
+                beta,
+                c,
+                ..CubicConfig::default()
+            }
+            .validate()
+            .is_err());
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::{cmp, time::Instant};
+
+use super::{Controller, ControllerFactory};
+use crate::{connection::RttEstimator, TransportConfig};
+
+/// Constructs `NewReno` controllers
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NewRenoFactory;
+
+impl ControllerFactory for NewRenoFactory {
+    fn build(&self, now: Instant, config: &TransportConfig) -> Box<dyn Controller> {
+        Box::new(NewReno::new(now, config))
+    }
+}
+
+/// The loss-based controller described in the QUIC recovery draft
+///
+/// Configured through `TransportConfig`'s `initial_window`, `minimum_window`,
+/// `loss_reduction_factor` and `max_datagram_size`.
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    max_datagram_size: u64,
+    minimum_window: u64,
+    loss_reduction_factor: u16,
+    /// Maximum number of bytes in flight that may be sent.
+    window: u64,
+    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is
+    /// slow start and the window grows by the number of bytes acknowledged.
+    ssthresh: u64,
+    /// The time when QUIC first detects a loss, causing it to enter recovery. When a packet sent
+    /// after this time is acknowledged, QUIC exits recovery.
+    recovery_start_time: Instant,
+}
+
+impl NewReno {
+    /// Construct a controller starting with `config.initial_window`
+    pub fn new(now: Instant, config: &TransportConfig) -> Self {
+        Self {
+            max_datagram_size: config.max_datagram_size,
+            minimum_window: config.minimum_window,
+            loss_reduction_factor: config.loss_reduction_factor,
+            window: config.initial_window,
+            ssthresh: u64::max_value(),
+            recovery_start_time: now,
+        }
+    }
+}
+
+impl Controller for NewReno {
+    fn on_ack(
+        &mut self,
+        _now: Instant,
+        sent: Instant,
+        bytes: u64,
+        app_limited: bool,
+        _rtt: &RttEstimator,
+        _bytes_in_flight: u64,
+    ) {
+        // Do not increase congestion window in recovery period, or if we weren't sending at max
+        // rate.
+        if app_limited || sent <= self.recovery_start_time {
+            return;
+        }
+        if self.window < self.ssthresh {
+            // Slow start.
+            self.window += bytes;
+        } else {
+            // Congestion avoidance.
+            self.window += self.max_datagram_size * bytes / self.window;
+        }
+    }
+
+    fn on_loss(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        _lost_bytes: u64,
+        mtu_probe: bool,
+        persistent_congestion: bool,
+    ) {
+        if mtu_probe {
+            return;
+        }
+        // Start a new recovery epoch if the lost packet is larger than the end of the
+        // previous recovery epoch.
+        if sent > self.recovery_start_time {
+            self.recovery_start_time = now;
+            // *= factor
+            self.window = (self.window * u64::from(self.loss_reduction_factor)) >> 16;
+            self.window = cmp::max(self.window, self.minimum_window);
+            self.ssthresh = self.window;
+        }
+        if persistent_congestion {
+            self.window = self.minimum_window;
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn new_reno() {
+        let config = TransportConfig::default();
+        let start = Instant::now();
+        let rtt = RttEstimator::new();
+        let mut cc = NewReno::new(start, &config);
+        assert_eq!(cc.window(), config.initial_window);
+
+        // Slow start, unless application limited
+        let now = start + Duration::from_millis(100);
+        cc.on_ack(now, now, 1200, true, &rtt, 0);
+        assert_eq!(cc.window(), config.initial_window);
+        cc.on_ack(now, now, 1200, false, &rtt, 0);
+        assert_eq!(cc.window(), config.initial_window + 1200);
+
+        // Losses within a recovery period only reduce the window once
+        let window = cc.window();
+        let later = now + Duration::from_millis(100);
+        cc.on_loss(later, now, 1200, false, false);
+        assert_eq!(cc.window(), window / 2);
+        cc.on_loss(later, now, 1200, false, false);
+        assert_eq!(cc.window(), window / 2);
+        cc.on_ack(later, now, 1200, false, &rtt, 0);
+        assert_eq!(cc.window(), window / 2);
+
+        cc.on_loss(later, later, 1200, false, true);
+        assert_eq!(cc.window(), config.minimum_window);
+    }
+}
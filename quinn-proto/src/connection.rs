@@ -9,27 +9,31 @@ use std::{
 
 use bytes::{Bytes, BytesMut};
 use err_derive::Error;
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand::{rngs::StdRng, Rng};
 use tracing::{debug, error, info, trace, trace_span, warn};
 
 use crate::{
+    ack_frequency::AckFrequencyState,
     coding::BufMutExt,
+    congestion,
     crypto::{self, HeaderKeys, Keys},
     frame,
     frame::{Close, Datagram, FrameStruct},
+    mtud::MtuDiscovery,
+    pacing::Pacer,
     packet::{Header, LongType, Packet, PacketNumber, PartialDecode, SpaceId},
     range_set::RangeSet,
     shared::{
-        ConnectionEvent, ConnectionEventInner, ConnectionId, EcnCodepoint, EndpointConfig,
-        EndpointEvent, EndpointEventInner, IssuedCid, ServerConfig, TransportConfig,
+        is_reserved_version, reserved_version, ConnectionEvent, ConnectionEventInner, ConnectionId,
+        EcnCodepoint, EndpointConfig, EndpointEvent, EndpointEventInner, IssuedCid, ServerConfig,
+        TransportConfig,
     },
     spaces::{CryptoSpace, PacketSpace, Retransmits, SentPacket},
     streams::{self, FinishError, ReadError, Streams, UnknownStream, WriteError},
     timer::{Timer, TimerKind, TimerTable},
-    transport_parameters::{self, TransportParameters},
+    transport_parameters::{self, PeerParams, TransportParameters},
     Dir, Frame, Side, StreamId, Transmit, TransportError, TransportErrorCode, VarInt,
-    MAX_STREAM_COUNT, MIN_INITIAL_SIZE, MIN_MTU, REM_CID_COUNT, RESET_TOKEN_SIZE,
-    TIMER_GRANULARITY,
+    MAX_STREAM_COUNT, MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE, TIMER_GRANULARITY,
 };
 
 /// Protocol state and logic for a single QUIC connection
@@ -52,19 +56,28 @@ where
     rem_cid: ConnectionId,
     /// The CID the peer initially chose, for use during the handshake
     rem_handshake_cid: ConnectionId,
+    /// QUIC version in use, which a client may change in response to version negotiation
+    version: u32,
     rem_cid_seq: u64,
     path: PathData,
     prev_path: Option<PathData>,
     state: State,
     side: Side,
-    mtu: u16,
     /// Whether or not 0-RTT was enabled during the handshake. Does not imply acceptance.
     zero_rtt_enabled: bool,
     /// Set if 0-RTT is supported, then cleared when no longer needed.
     zero_rtt_crypto: Option<CryptoSpace<S::Keys>>,
     key_phase: bool,
+    /// Packets and bytes sent under the current 1-RTT keys
+    key_phase_packets: u64,
+    key_phase_bytes: u64,
+    /// Number of key updates initiated locally and by the peer
+    local_key_updates: u64,
+    remote_key_updates: u64,
     /// Transport parameters set by the peer
     params: TransportParameters,
+    /// Whether `params` came from the peer (or a previous session) rather than defaults
+    params_set: bool,
     /// Streams on which writing was blocked on *connection-level* flow or congestion control
     blocked_streams: HashSet<StreamId>,
     /// Limit on outgoing data, dictated by peer
@@ -81,6 +94,10 @@ where
     orig_rem_cid: Option<ConnectionId>,
     /// Total number of outgoing packets that have been deemed lost
     lost_packets: u64,
+    /// Cumulative counters reported by `stats`
+    stats: ConnectionStats,
+    /// Recorded if `TransportConfig::telemetry` is set
+    telemetry: VecDeque<TelemetryEvent>,
     io: IoQueue,
     events: VecDeque<Event>,
     endpoint_events: VecDeque<EndpointEventInner>,
@@ -107,10 +124,15 @@ where
     /// per directionality
     stream_opened: [bool; 2],
     accepted_0rtt: bool,
+    rejected_0rtt: bool,
+    /// Bytes of 0-RTT packets sent
+    zero_rtt_bytes: u64,
     /// Whether the idle timer should be reset the next time an ack-eliciting packet is transmitted.
     permit_idle_reset: bool,
     /// Negotiated idle timeout
     idle_timeout: u64,
+    /// Limit on the idle timeout set by the application, or 0 for none
+    idle_timeout_override: u64,
     /// Number of the first 1-RTT packet transmitted
     first_1rtt_sent: Option<u64>,
     /// Sequence number of the first remote CID that we haven't been asked to retire
@@ -136,13 +158,23 @@ where
     //
     /// Summary statistics of packets that have been sent, but not yet acked or deemed lost
     in_flight: InFlight,
-    /// The time when QUIC first detects a loss, causing it to enter recovery. When a packet sent
-    /// after this time is acknowledged, QUIC exits recovery.
-    recovery_start_time: Instant,
     /// Explicit congestion notification (ECN) counters
     ecn_counters: frame::EcnCounts,
     /// Whether the most recently received packet had an ECN codepoint set
     receiving_ecn: bool,
+
+    //
+    // ACK frequency
+    //
+    /// How often to acknowledge
+    ack_frequency: AckFrequencyState,
+    /// Sequence number of the next `ACK_FREQUENCY` frame to send
+    ack_frequency_sequence: u64,
+    /// Delay most recently requested of the peer through `ACK_FREQUENCY`
+    requested_max_ack_delay: Option<Duration>,
+    /// Whether to send an `IMMEDIATE_ACK` frame with the next 1-RTT packet
+    immediate_ack_pending: bool,
+
     remote_validated: bool,
     /// Total UDP datagram bytes received, tracked for handshake anti-amplification
     total_recvd: u64,
@@ -153,6 +185,10 @@ where
     rem_cids: Vec<IssuedCid>,
     /// State of the unreliable datagram extension
     datagrams: DatagramState,
+    #[cfg(feature = "qlog")]
+    qlog: Option<crate::qlog::QlogStream>,
+    #[cfg(feature = "packet-tap")]
+    packet_tap: Option<Box<dyn crate::tap::PacketTap>>,
 }
 
 impl<S> Connection<S>
@@ -169,6 +205,7 @@ where
         remote: SocketAddr,
         tls: S,
         now: Instant,
+        mut rng: StdRng,
     ) -> Self {
         let side = if server_config.is_some() {
             Side::Server
@@ -183,11 +220,22 @@ where
             rem_cid_set: side.is_server(),
             token: None,
             client_hello: None,
+            version_negotiated: false,
         });
-        let mut rng = StdRng::from_entropy();
+        // A server adopts the version of the client's first Initial
+        let version = if side.is_client() && endpoint_config.grease_version {
+            reserved_version(&mut rng)
+        } else {
+            endpoint_config.supported_versions[0]
+        };
         let remote_validated = server_config
             .as_ref()
             .map_or(false, |c| c.use_stateless_retry);
+        // The endpoint issues the preferred address's CID along with the handshake CID
+        let cids_issued = server_config
+            .as_ref()
+            .map_or(false, |c| c.has_preferred_address(&endpoint_config))
+            as u64;
         let mut this = Self {
             endpoint_config,
             server_config,
@@ -195,22 +243,33 @@ where
             handshake_cid: loc_cid,
             rem_cid,
             rem_handshake_cid: rem_cid,
+            version,
             rem_cid_seq: 0,
             path: PathData {
                 remote,
                 rtt: RttEstimator::new(),
-                congestion_window: config.initial_window,
-                ssthresh: u64::max_value(),
-                sending_ecn: true,
+                congestion: config.congestion_controller_factory.build(now, &config),
+                pacing: Pacer::new(now, config.initial_mtu),
+                mtud: MtuDiscovery::new(config.initial_mtu, config.max_udp_payload_size),
+                sending_ecn: config.allow_ecn,
+                // The handshake is subject to its own anti-amplification limit
+                validated: true,
+                total_sent: 0,
+                total_recvd: 0,
+                lost_packets: 0,
             },
             prev_path: None,
             side,
             state,
-            mtu: MIN_MTU,
             zero_rtt_enabled: false,
             zero_rtt_crypto: None,
             key_phase: false,
+            key_phase_packets: 0,
+            key_phase_bytes: 0,
+            local_key_updates: 0,
+            remote_key_updates: 0,
             params: TransportParameters::default(),
+            params_set: false,
             blocked_streams: HashSet::new(),
             max_data: 0,
             data_sent: 0,
@@ -219,10 +278,12 @@ where
             unacked_data: 0,
             orig_rem_cid: None,
             lost_packets: 0,
+            stats: ConnectionStats::default(),
+            telemetry: VecDeque::new(),
             io: IoQueue::new(),
             events: VecDeque::new(),
             endpoint_events: VecDeque::new(),
-            cids_issued: 0,
+            cids_issued,
             spin_enabled: config.allow_spin && rng.gen_ratio(7, 8),
             spin: false,
             spaces: [initial_space, PacketSpace::new(now), PacketSpace::new(now)],
@@ -232,8 +293,11 @@ where
             path_challenge: None,
             stream_opened: [false, false],
             accepted_0rtt: false,
+            rejected_0rtt: false,
+            zero_rtt_bytes: 0,
             permit_idle_reset: true,
             idle_timeout: config.idle_timeout,
+            idle_timeout_override: 0,
             first_1rtt_sent: None,
             first_unretired_cid: 0,
 
@@ -244,19 +308,41 @@ where
             pto_count: 0,
 
             in_flight: InFlight::new(),
-            recovery_start_time: now,
             ecn_counters: frame::EcnCounts::ZERO,
             receiving_ecn: false,
+            ack_frequency: AckFrequencyState::new(
+                config.ack_eliciting_threshold,
+                Duration::from_micros(config.max_ack_delay),
+            ),
+            ack_frequency_sequence: 0,
+            requested_max_ack_delay: None,
+            immediate_ack_pending: false,
             remote_validated,
             total_recvd: 0,
             total_sent: 0,
 
             streams: Streams::new(side, config.stream_window_uni, config.stream_window_bidi),
             datagrams: DatagramState::new(),
+            #[cfg(feature = "qlog")]
+            qlog: config
+                .qlog
+                .as_ref()
+                .and_then(|factory| factory.writer(side, &init_cid))
+                .and_then(|writer| {
+                    crate::qlog::QlogStream::new(writer, side, &init_cid, now)
+                        .map_err(|e| warn!("failed to start qlog trace: {}", e))
+                        .ok()
+                }),
+            #[cfg(feature = "packet-tap")]
+            packet_tap: config
+                .packet_tap
+                .as_ref()
+                .and_then(|factory| factory.build(side, &init_cid)),
             config,
             rem_cids: Vec::new(),
             rng,
         };
+        this.streams.record_closed = this.config.telemetry;
         if side.is_client() {
             // Kick off the connection
             this.write_tls();
@@ -294,12 +380,110 @@ where
         }
 
         if let Some(x) = self.events.pop_front() {
+            if let Event::ConnectionLost { ref reason } = x {
+                self.record(TelemetryEvent::Closed {
+                    reason: reason.clone(),
+                });
+            }
             return Some(x);
         }
 
         None
     }
 
+    /// Returns telemetry events, if enabled by `TransportConfig::telemetry`
+    pub fn poll_telemetry(&mut self) -> Option<TelemetryEvent> {
+        for id in self.streams.closed.drain(..) {
+            self.telemetry
+                .push_back(TelemetryEvent::StreamClosed { id });
+        }
+        self.telemetry.pop_front()
+    }
+
+    fn record(&mut self, event: TelemetryEvent) {
+        if self.config.telemetry {
+            self.telemetry.push_back(event);
+        }
+    }
+
+    /// Write to the qlog trace, if any, abandoning it if that fails
+    #[cfg(feature = "qlog")]
+    fn qlog(&mut self, f: impl FnOnce(&mut crate::qlog::QlogStream, &Self) -> io::Result<()>) {
+        if let Some(mut qlog) = self.qlog.take() {
+            match f(&mut qlog, self) {
+                Ok(()) => self.qlog = Some(qlog),
+                Err(e) => warn!("abandoning qlog trace: {}", e),
+            }
+        }
+    }
+
+    /// Whether the plaintext of packets is wanted by `observe_packet`
+    #[cfg(feature = "packet-tap")]
+    fn observing_packets(&self) -> bool {
+        #[cfg(feature = "qlog")]
+        {
+            if self.qlog.is_some() {
+                return true;
+            }
+        }
+        self.packet_tap.is_some()
+    }
+
+    /// Report a packet sent or received to the qlog trace and packet tap, if any
+    #[cfg(feature = "packet-tap")]
+    fn observe_packet(
+        &mut self,
+        now: Instant,
+        sent: bool,
+        header: &Header,
+        number: Option<u64>,
+        len: usize,
+        payload: Bytes,
+    ) {
+        // Only numbered packets carry frames
+        let payload = if number.is_some() {
+            payload
+        } else {
+            Bytes::new()
+        };
+        let direction = if sent {
+            crate::tap::Direction::Sent
+        } else {
+            crate::tap::Direction::Received
+        };
+        let summary = crate::tap::PacketSummary::new(now, direction, header, number, len, payload);
+        #[cfg(feature = "qlog")]
+        self.qlog(|qlog, _| qlog.packet(&summary));
+        if let Some(ref mut tap) = self.packet_tap {
+            tap.packet(&summary);
+        }
+    }
+
+    /// Show a datagram sent or received to the packet tap, if any
+    #[cfg(feature = "packet-tap")]
+    fn tap_datagram(&mut self, now: Instant, sent: bool, contents: &[u8]) {
+        if let Some(ref mut tap) = self.packet_tap {
+            let direction = if sent {
+                crate::tap::Direction::Sent
+            } else {
+                crate::tap::Direction::Received
+            };
+            tap.datagram(now, direction, contents);
+        }
+    }
+
+    #[cfg(feature = "qlog")]
+    fn qlog_metrics(&mut self, now: Instant) {
+        self.qlog(|qlog, this| {
+            qlog.metrics_updated(
+                now,
+                &this.path.rtt,
+                &this.path.congestion.state(),
+                this.in_flight.bytes,
+            )
+        });
+    }
+
     /// Return endpoint-facing events
     pub fn poll_endpoint_events(&mut self) -> Option<EndpointEvent> {
         self.endpoint_events.pop_front().map(EndpointEvent)
@@ -319,12 +503,17 @@ where
         } = packet;
 
         self.in_flight.insert(&packet);
+        self.stats.packets_sent += 1;
         self.space_mut(space)
             .sent_packets
             .insert(packet_number, packet);
         self.reset_keep_alive(now);
         if size != 0 {
             if ack_eliciting {
+                self.path
+                    .congestion
+                    .on_sent(now, u64::from(size), self.in_flight.bytes);
+                self.path.pacing.on_transmit(size);
                 self.space_mut(space).time_of_last_sent_ack_eliciting_packet = Some(now);
                 if self.permit_idle_reset {
                     self.reset_idle_timeout(now);
@@ -378,7 +567,10 @@ where
             if let Some(info) = self.space_mut(space).sent_packets.remove(&packet) {
                 self.space_mut(space).pending_acks.subtract(&info.acks);
                 ack_eliciting_acked |= info.ack_eliciting;
-                self.on_packet_acked(info);
+                if space == SpaceId::Data {
+                    self.path.mtud.on_acked(now, packet, info.size);
+                }
+                self.on_packet_acked(now, info);
             }
         }
 
@@ -401,6 +593,9 @@ where
         {
             // Received first acknowledgment of 1-RTT packet
             self.discard_space(SpaceId::Handshake);
+            if self.side.is_client() {
+                self.migrate_to_preferred_address(now);
+            }
         }
 
         // Must be called before crypto/pto_count are clobbered
@@ -428,6 +623,8 @@ where
         }
 
         self.set_loss_detection_timer();
+        #[cfg(feature = "qlog")]
+        self.qlog_metrics(now);
         if was_blocked && !self.blocked() {
             for stream in self.blocked_streams.drain() {
                 self.events.push_back(Event::StreamWritable { stream });
@@ -455,31 +652,28 @@ where
             }
             Ok(false) => {}
             Ok(true) => {
-                self.congestion_event(now, largest_sent_time);
+                self.path
+                    .congestion
+                    .on_loss(now, largest_sent_time, 0, false, false);
             }
         }
     }
 
     // Not timing-aware, so it's safe to call this for inferred acks, such as arise from
     // high-latency handshakes
-    fn on_packet_acked(&mut self, info: SentPacket) {
+    fn on_packet_acked(&mut self, now: Instant, info: SentPacket) {
         let was_congestion_blocked = self.congestion_blocked();
         self.in_flight.remove(&info);
-        if info.ack_eliciting {
-            // Congestion control
-            // Do not increase congestion window in recovery period or while migrating, or if we
-            // weren't sending at max rate.
-            if !self.in_recovery(info.time_sent) && !self.migrating() && was_congestion_blocked {
-                if self.path.congestion_window < self.path.ssthresh {
-                    // Slow start.
-                    self.path.congestion_window += u64::from(info.size);
-                } else {
-                    // Congestion avoidance.
-                    self.path.congestion_window += self.config.max_datagram_size
-                        * u64::from(info.size)
-                        / self.path.congestion_window;
-                }
-            }
+        // Do not increase congestion window while migrating
+        if info.ack_eliciting && !self.migrating() {
+            self.path.congestion.on_ack(
+                now,
+                info.time_sent,
+                u64::from(info.size),
+                !was_congestion_blocked,
+                &self.path.rtt,
+                self.in_flight.bytes,
+            );
         }
 
         // Update state for confirmed delivery of frames
@@ -538,6 +732,18 @@ where
                 trace!("sending keep-alive");
                 self.ping();
             }
+            TimerKind::CidRotation => {
+                // The old CID is retired, so the peer issues a replacement
+                if let Some(cid) = self.rem_cids.pop() {
+                    self.update_rem_cid(cid);
+                }
+                self.reset_cid_rotation(now);
+            }
+            TimerKind::Pacing => trace!("pacing timer expired"),
+            TimerKind::MaxAckDelay => {
+                trace!("sending delayed ACK");
+                self.space_mut(SpaceId::Data).permit_ack_only = true;
+            }
             TimerKind::LossDetection => {
                 self.on_loss_detection_timeout(now);
             }
@@ -591,7 +797,11 @@ where
             "PTO fired"
         );
         self.space_mut(space).loss_probes = self.space(space).loss_probes.saturating_add(2);
+        // Don't let a delayed acknowledgement hold up the probes
+        self.immediate_ack_pending |=
+            space == SpaceId::Data && self.requested_max_ack_delay.is_some();
         self.pto_count = self.pto_count.saturating_add(1);
+        self.stats.pto_count += 1;
         self.set_loss_detection_timer();
     }
 
@@ -666,7 +876,12 @@ where
             let old_bytes_in_flight = self.in_flight.bytes;
             let largest_lost_sent = self.space(pn_space).sent_packets[&largest_lost].time_sent;
             self.lost_packets += lost_packets.len() as u64;
+            self.stats.packets_lost += lost_packets.len() as u64;
+            self.path.lost_packets += lost_packets.len() as u64;
             trace!("packets lost: {:?}", lost_packets);
+            let mut lost_probe_bytes = 0;
+            let mut largest_lost_probe_sent = None;
+            let mut largest_lost_size = 0;
             for packet in &lost_packets {
                 let info = self
                     .space_mut(pn_space)
@@ -674,10 +889,22 @@ where
                     .remove(&packet)
                     .unwrap(); // safe: lost_packets is populated just above
                 self.in_flight.remove(&info);
+                self.stats.bytes_lost += u64::from(info.size);
+                #[cfg(feature = "qlog")]
+                self.qlog(|qlog, _| qlog.packet_lost(now, pn_space, *packet));
+                if pn_space == SpaceId::Data {
+                    if self.path.mtud.on_lost(now, *packet) {
+                        lost_probe_bytes += u64::from(info.size);
+                        largest_lost_probe_sent = Some(info.time_sent);
+                    } else {
+                        largest_lost_size = cmp::max(largest_lost_size, info.size);
+                    }
+                }
                 self.space_mut(pn_space).pending += info.retransmits;
             }
-            // Don't apply congestion penalty for lost ack-only packets
-            let lost_ack_eliciting = old_bytes_in_flight != self.in_flight.bytes;
+            self.path.mtud.on_loss_event(now, largest_lost_size);
+            // Don't apply congestion penalty for lost ack-only packets or MTU probes
+            let lost_bytes = old_bytes_in_flight - self.in_flight.bytes - lost_probe_bytes;
 
             // InPersistentCongestion: Determine if all packets in the time period before the newest
             // lost packet, including the edges, are marked lost
@@ -685,34 +912,25 @@ where
             let in_persistent_congestion = self.space(pn_space).largest_acked_packet_sent
                 < largest_lost_sent - congestion_period;
 
-            if lost_ack_eliciting {
-                self.congestion_event(now, largest_lost_sent);
-                if in_persistent_congestion {
-                    self.path.congestion_window = self.config.minimum_window;
-                }
+            if lost_bytes != 0 {
+                self.path.congestion.on_loss(
+                    now,
+                    largest_lost_sent,
+                    lost_bytes,
+                    false,
+                    in_persistent_congestion,
+                );
             }
+            if let Some(sent) = largest_lost_probe_sent {
+                self.path
+                    .congestion
+                    .on_loss(now, sent, lost_probe_bytes, true, false);
+            }
+            #[cfg(feature = "qlog")]
+            self.qlog_metrics(now);
         }
     }
 
-    fn congestion_event(&mut self, now: Instant, sent_time: Instant) {
-        // Start a new recovery epoch if the lost packet is larger than the end of the
-        // previous recovery epoch.
-        if self.in_recovery(sent_time) {
-            return;
-        }
-        self.recovery_start_time = now;
-        // *= factor
-        self.path.congestion_window =
-            (self.path.congestion_window * u64::from(self.config.loss_reduction_factor)) >> 16;
-        self.path.congestion_window =
-            cmp::max(self.path.congestion_window, self.config.minimum_window);
-        self.path.ssthresh = self.path.congestion_window;
-    }
-
-    fn in_recovery(&self, sent_time: Instant) -> bool {
-        sent_time <= self.recovery_start_time
-    }
-
     fn earliest_time_and_space(
         &self,
         get: impl Fn(&PacketSpace<S::Keys>) -> Option<Instant>,
@@ -751,7 +969,11 @@ where
         if let Some((sent_time, _)) =
             self.earliest_time_and_space(|x| x.time_of_last_sent_ack_eliciting_packet)
         {
-            let timeout = self.pto() * 2u32.pow(cmp::min(self.pto_count, MAX_BACKOFF_EXPONENT));
+            let backoff = self
+                .config
+                .pto_backoff
+                .saturating_pow(cmp::min(self.pto_count, MAX_BACKOFF_EXPONENT));
+            let timeout = self.pto() * backoff;
             self.io
                 .timer_start(TimerKind::LossDetection, sent_time + timeout);
         }
@@ -802,6 +1024,9 @@ where
             }
         }
         let space = &mut self.spaces[space_id as usize];
+        if space_id == SpaceId::Data {
+            self.ack_frequency.on_packet(packet, space.rx_packet);
+        }
         space.pending_acks.insert_one(packet);
         if space.pending_acks.len() > MAX_ACK_BLOCKS {
             space.pending_acks.pop_min();
@@ -809,13 +1034,17 @@ where
         if packet >= space.rx_packet {
             space.rx_packet = packet;
             space.rx_packet_time = now;
-            // Update outgoing spin bit, inverting iff we're the client
-            self.spin = self.side.is_client() ^ spin;
+            // Update outgoing spin bit, inverting iff we're the client. Only short headers carry
+            // the bit, so other packets mustn't disturb it.
+            if is_1rtt {
+                self.spin = self.side.is_client() ^ spin;
+            }
         }
     }
 
     fn reset_idle_timeout(&mut self, now: Instant) {
         if self.idle_timeout == 0 {
+            self.io.timer_stop(TimerKind::Idle);
             return;
         }
         if self.state.is_closed() {
@@ -836,6 +1065,16 @@ where
         );
     }
 
+    fn reset_cid_rotation(&mut self, now: Instant) {
+        if self.config.cid_rotation_interval == 0 || !self.state.is_established() {
+            return;
+        }
+        self.io.timer_start(
+            TimerKind::CidRotation,
+            now + Duration::from_millis(self.config.cid_rotation_interval),
+        );
+    }
+
     fn queue_stream_data(&mut self, stream: StreamId, data: Bytes) -> Result<(), WriteError> {
         let ss = self
             .streams
@@ -915,6 +1154,11 @@ where
             .push((stream_id, error_code));
     }
 
+    /// Note that the client's address was validated by a stateless retry
+    pub(crate) fn remote_address_validated(&mut self) {
+        self.remote_validated = true;
+    }
+
     /// Handle the already-decrypted first packet from the client
     ///
     /// Decrypting the first packet in the `Endpoint` allows stateless packet handling to be more
@@ -933,6 +1177,17 @@ where
         debug_assert!(self.side.is_server());
         let len = packet.header_data.len() + packet.payload.len();
         self.total_recvd = len as u64;
+        // The packet was decrypted by the endpoint, so its tag has already been stripped
+        let tag_len = self.spaces[SpaceId::Initial as usize]
+            .crypto
+            .as_ref()
+            .map_or(0, |x| x.packet.tag_len());
+        self.stats.datagrams_received += 1;
+        self.stats.bytes_received +=
+            (len + tag_len + remaining.as_ref().map_or(0, |x| x.len())) as u64;
+        if let Header::Initial { version, .. } = packet.header {
+            self.version = version;
+        }
 
         self.on_packet_authenticated(
             now,
@@ -942,6 +1197,14 @@ where
             false,
             false,
         );
+        #[cfg(feature = "packet-tap")]
+        {
+            if self.observing_packets() {
+                let payload = packet.payload.clone().freeze();
+                let number = Some(packet_number);
+                self.observe_packet(now, false, &packet.header, number, len + tag_len, payload);
+            }
+        }
         self.process_decrypted_packet(now, remote, Some(packet_number), packet)?;
         if let Some(data) = remaining {
             self.handle_coalesced(now, remote, ecn, data);
@@ -955,10 +1218,10 @@ where
             None => return,
         };
         if self.side.is_client() {
-            match self.tls.transport_parameters() {
+            match self.peer_transport_params() {
                 Ok(params) => {
-                    let params =
-                        params.expect("rustls didn't supply transport parameters with ticket");
+                    let params = params
+                        .expect("crypto session didn't supply transport parameters with ticket");
                     // Certain values must not be cached
                     let params = TransportParameters {
                         original_connection_id: None,
@@ -966,6 +1229,7 @@ where
                         stateless_reset_token: None,
                         ack_delay_exponent: TransportParameters::default().ack_delay_exponent,
                         active_connection_id_limit: 0,
+                        min_ack_delay: None,
                         ..params
                     };
                     self.set_params(params);
@@ -1080,6 +1344,40 @@ where
         }
     }
 
+    /// Resend a client's first flight after a Retry or version negotiation
+    fn restart_handshake(&mut self, now: Instant, client_hello: Bytes) {
+        let space = self.space_mut(SpaceId::Initial);
+        if let Some(info) = space.sent_packets.remove(&0) {
+            space.pending_acks.subtract(&info.acks);
+            self.on_packet_acked(now, info);
+        };
+
+        self.discard_space(SpaceId::Initial); // Make sure we clean up after any retransmitted Initials
+        self.spaces[0] = PacketSpace {
+            crypto: Some(CryptoSpace::new(S::Keys::new_initial(
+                &self.rem_cid,
+                self.side,
+            ))),
+            next_packet_number: self.spaces[0].next_packet_number,
+            crypto_offset: client_hello.len() as u64,
+            ..PacketSpace::new(now)
+        };
+        self.spaces[0].pending.crypto.push_back(frame::Crypto {
+            offset: 0,
+            data: client_hello,
+        });
+
+        // Retransmit all 0-RTT data
+        let zero_rtt = mem::replace(
+            &mut self.space_mut(SpaceId::Data).sent_packets,
+            BTreeMap::new(),
+        );
+        for (_, info) in zero_rtt {
+            self.in_flight.remove(&info);
+            self.space_mut(SpaceId::Data).pending += info.retransmits;
+        }
+    }
+
     fn discard_space(&mut self, space: SpaceId) {
         trace!("discarding {:?} keys", space);
         let space = self.space_mut(space);
@@ -1117,12 +1415,30 @@ where
                     return;
                 }
 
+                let len = first_decode.len() + remaining.as_ref().map_or(0, |x| x.len());
                 self.total_recvd = self.total_recvd.wrapping_add(first_decode.len() as u64);
+                self.stats.datagrams_received += 1;
+                self.stats.bytes_received += len as u64;
+                #[cfg(feature = "packet-tap")]
+                {
+                    if self.packet_tap.is_some() {
+                        let mut contents = first_decode.data().to_vec();
+                        if let Some(ref data) = remaining {
+                            contents.extend_from_slice(data);
+                        }
+                        self.tap_datagram(now, false, &contents);
+                    }
+                }
 
                 self.handle_decode(now, remote, ecn, first_decode);
                 if let Some(data) = remaining {
                     self.handle_coalesced(now, remote, ecn, data);
                 }
+                // Counted after handling, so a datagram which caused a migration counts towards
+                // the new path
+                if remote == self.path.remote {
+                    self.path.total_recvd = self.path.total_recvd.saturating_add(len as u64);
+                }
             }
             NewIdentifiers(ids) => {
                 ids.into_iter().for_each(|frame| {
@@ -1143,7 +1459,11 @@ where
         self.total_recvd = self.total_recvd.wrapping_add(data.len() as u64);
         let mut remaining = Some(data);
         while let Some(data) = remaining {
-            match PartialDecode::new(data, self.endpoint_config.local_cid_len) {
+            match PartialDecode::new(
+                data,
+                self.endpoint_config.local_cid_len,
+                &self.endpoint_config.supported_versions,
+            ) {
                 Ok((partial_decode, rest)) => {
                     remaining = rest;
                     self.handle_decode(now, remote, ecn, partial_decode);
@@ -1201,10 +1521,12 @@ where
         ecn: Option<EcnCodepoint>,
         mut packet: Packet,
     ) {
+        // Including the authentication tag, which decryption strips
+        let len = packet.header_data.len() + packet.payload.len();
         trace!(
             "got {:?} packet ({} bytes) from {} using id {}",
             packet.header.space(),
-            packet.payload.len() + packet.header_data.len(),
+            len,
             remote,
             packet.header.dst_cid(),
         );
@@ -1269,6 +1591,13 @@ where
                             packet.header.is_1rtt(),
                         );
                     }
+                    #[cfg(feature = "packet-tap")]
+                    {
+                        if self.observing_packets() {
+                            let payload = packet.payload.clone().freeze();
+                            self.observe_packet(now, false, &packet.header, number, len, payload);
+                        }
+                    }
                     self.process_decrypted_packet(now, remote, number, packet)
                 }
             }
@@ -1348,44 +1677,17 @@ where
                         }
                         trace!("retrying with CID {}", rem_cid);
                         let client_hello = state.client_hello.take().unwrap();
+                        let version_negotiated = state.version_negotiated;
                         self.orig_rem_cid = Some(self.rem_cid);
                         self.rem_cid = rem_cid;
                         self.rem_handshake_cid = rem_cid;
-
-                        let space = self.space_mut(SpaceId::Initial);
-                        if let Some(info) = space.sent_packets.remove(&0) {
-                            space.pending_acks.subtract(&info.acks);
-                            self.on_packet_acked(info);
-                        };
-
-                        self.discard_space(SpaceId::Initial); // Make sure we clean up after any retransmitted Initials
-                        self.spaces[0] = PacketSpace {
-                            crypto: Some(CryptoSpace::new(S::Keys::new_initial(
-                                &rem_cid, self.side,
-                            ))),
-                            next_packet_number: self.spaces[0].next_packet_number,
-                            crypto_offset: client_hello.len() as u64,
-                            ..PacketSpace::new(now)
-                        };
-                        self.spaces[0].pending.crypto.push_back(frame::Crypto {
-                            offset: 0,
-                            data: client_hello,
-                        });
-
-                        // Retransmit all 0-RTT data
-                        let zero_rtt = mem::replace(
-                            &mut self.space_mut(SpaceId::Data).sent_packets,
-                            BTreeMap::new(),
-                        );
-                        for (_, info) in zero_rtt {
-                            self.in_flight.remove(&info);
-                            self.space_mut(SpaceId::Data).pending += info.retransmits;
-                        }
+                        self.restart_handshake(now, client_hello);
 
                         self.state = State::Handshake(state::Handshake {
                             token: Some(packet.payload.freeze()),
                             rem_cid_set: false,
                             client_hello: None,
+                            version_negotiated,
                         });
                         Ok(())
                     }
@@ -1421,8 +1723,7 @@ where
                         if self.side.is_client() {
                             // Client-only beceause server params were set from the client's Initial
                             let params =
-                                self.tls
-                                    .transport_parameters()?
+                                self.peer_transport_params()?
                                     .ok_or_else(|| TransportError {
                                         code: TransportErrorCode::crypto(0x6d),
                                         frame: None,
@@ -1446,17 +1747,21 @@ where
                             }
                             self.validate_params(&params)?;
                             self.set_params(params);
-                            if params.active_connection_id_limit != 0 {
+                            if self.params.active_connection_id_limit != 0 {
                                 self.endpoint_events.push_back(
                                     EndpointEventInner::NeedIdentifiers(
-                                        params.active_connection_id_limit,
+                                        self.params.active_connection_id_limit,
                                     ),
                                 );
                             }
                         }
 
                         self.events.push_back(Event::Connected);
+                        self.record(TelemetryEvent::HandshakeComplete);
+                        self.endpoint_events
+                            .push_back(EndpointEventInner::Established);
                         self.state = State::Established;
+                        self.reset_cid_rotation(now);
                         trace!("established");
                         Ok(())
                     }
@@ -1486,8 +1791,7 @@ where
                             && self.highest_space != SpaceId::Initial
                         {
                             let params =
-                                self.tls
-                                    .transport_parameters()?
+                                self.peer_transport_params()?
                                     .ok_or_else(|| TransportError {
                                         code: TransportErrorCode::crypto(0x6d),
                                         frame: None,
@@ -1495,10 +1799,10 @@ where
                                     })?;
                             self.validate_params(&params)?;
                             self.set_params(params);
-                            if params.active_connection_id_limit != 0 {
+                            if self.params.active_connection_id_limit != 0 {
                                 self.endpoint_events.push_back(
                                     EndpointEventInner::NeedIdentifiers(
-                                        params.active_connection_id_limit,
+                                        self.params.active_connection_id_limit,
                                     ),
                                 );
                             }
@@ -1519,8 +1823,43 @@ where
                         Ok(())
                     }
                     Header::VersionNegotiate { .. } => {
-                        debug!("remote doesn't support our version");
-                        Err(ConnectionError::VersionMismatch)
+                        if self.side.is_server()
+                            || state.rem_cid_set
+                            || state.version_negotiated
+                            || self.orig_rem_cid.is_some()
+                        {
+                            // Only valid in response to a client's first Initial, and acted on at
+                            // most once, so that a forged packet can't switch versions again
+                            return Ok(());
+                        }
+                        let offered = packet
+                            .payload
+                            .chunks_exact(4)
+                            .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+                            .filter(|&x| !is_reserved_version(x))
+                            .collect::<Vec<_>>();
+                        if offered.contains(&self.version) {
+                            debug!("ignoring version negotiation offering the version in use");
+                            return Ok(());
+                        }
+                        let version = self
+                            .endpoint_config
+                            .supported_versions
+                            .iter()
+                            .find(|x| offered.contains(x));
+                        let version = match version {
+                            Some(&x) => x,
+                            None => {
+                                debug!("remote doesn't support our version");
+                                return Err(ConnectionError::VersionMismatch);
+                            }
+                        };
+                        debug!(version = %format_args!("{:#x}", version), "switching version");
+                        self.version = version;
+                        state.version_negotiated = true;
+                        let client_hello = state.client_hello.clone().unwrap();
+                        self.restart_handshake(now, client_hello);
+                        Ok(())
                     }
                     Header::Short { .. } => unreachable!(
                         "short packets received during handshake are discarded in handle_packet"
@@ -1611,6 +1950,8 @@ where
     ) -> Result<(), TransportError> {
         let is_0rtt = self.space(SpaceId::Data).crypto.is_none();
         let mut is_probing_packet = true;
+        let mut ack_eliciting = false;
+        let mut immediate_ack = false;
         for frame in frame::Iter::new(payload) {
             let span = match frame {
                 Frame::Padding => None,
@@ -1631,7 +1972,7 @@ where
             match frame {
                 Frame::Ack(_) | Frame::Padding | Frame::Close(_) => {}
                 _ => {
-                    self.space_mut(SpaceId::Data).permit_ack_only = true;
+                    ack_eliciting = true;
                 }
             }
             // Check whether this could be a probing packet
@@ -1714,6 +2055,7 @@ where
                     trace!("path validated");
                     self.io.timer_stop(TimerKind::PathValidation);
                     self.path_challenge = None;
+                    self.path.validated = true;
                 }
                 Frame::MaxData(bytes) => {
                     let was_blocked = self.blocked();
@@ -1801,6 +2143,7 @@ where
 
                     // Notify application
                     self.on_stream_frame(true, id);
+                    self.record(TelemetryEvent::StreamReset { id, error_code });
                 }
                 Frame::DataBlocked { offset } => {
                     debug!(offset, "peer claims to be blocked at connection level");
@@ -1897,7 +2240,7 @@ where
                         self.update_rem_cid(issued);
                     } else {
                         // Reasonable limit to bound memory use
-                        if (self.rem_cids.len() as u64) < REM_CID_COUNT {
+                        if (self.rem_cids.len() as u64) < self.config.active_connection_id_limit {
                             self.rem_cids.push(issued);
                         }
                     }
@@ -1941,19 +2284,33 @@ where
                     }
                     self.datagrams.recv_buffered += datagram.data.len();
                     self.datagrams.incoming.push_back(datagram);
+                    self.stats.app_datagrams_received += 1;
+                }
+                Frame::AckFrequency(frame) => {
+                    // Our min_ack_delay is 0, so any requested delay is acceptable
+                    self.ack_frequency.on_frame(&frame);
                 }
+                Frame::ImmediateAck => {
+                    immediate_ack = true;
+                }
+            }
+        }
+
+        if ack_eliciting {
+            match self.ack_frequency.on_ack_eliciting(now, immediate_ack) {
+                None => self.space_mut(SpaceId::Data).permit_ack_only = true,
+                Some(deadline) => self.io.timer_start(TimerKind::MaxAckDelay, deadline),
             }
         }
 
-        if remote != self.path.remote
+        // Clients only change paths on their own initiative, e.g. for a preferred address
+        if self.side.is_server()
+            && remote != self.path.remote
             && !is_probing_packet
             && number == self.space(SpaceId::Data).rx_packet
         {
             debug_assert!(
-                self.server_config
-                    .as_ref()
-                    .expect("packets from unknown remote should be dropped by clients")
-                    .migration,
+                self.server_config.as_ref().unwrap().migration,
                 "migration-initiating packets should have been dropped immediately"
             );
             self.migrate(now, remote);
@@ -1977,8 +2334,12 @@ where
         }
         let next = &mut self.streams.next_remote[stream.dir() as usize];
         if stream.index() >= *next {
-            *next = stream.index() + 1;
+            let first = mem::replace(next, stream.index() + 1);
             self.stream_opened[stream.dir() as usize] = true;
+            for index in first..=stream.index() {
+                let id = StreamId::new(stream.initiator(), stream.dir(), index);
+                self.record(TelemetryEvent::StreamOpened { id });
+            }
         } else if notify_readable {
             self.events.push_back(Event::StreamReadable { stream });
         }
@@ -1989,8 +2350,29 @@ where
         self.path_challenge.is_some()
     }
 
+    /// Number of bytes which may still be sent on the current path, if limited
+    ///
+    /// Until a client's new address is validated, it might be spoofed, so a server sends at most
+    /// `ServerConfig::amplification_factor` times as much data as it received from there.
+    fn path_budget(&self) -> Option<u64> {
+        if self.path.validated || self.side.is_client() {
+            return None;
+        }
+        Some(
+            (self.amplification_factor() * self.path.total_recvd)
+                .saturating_sub(self.path.total_sent),
+        )
+    }
+
+    fn amplification_factor(&self) -> u64 {
+        self.server_config
+            .as_ref()
+            .map_or(3, |x| x.amplification_factor.into())
+    }
+
     fn migrate(&mut self, now: Instant, remote: SocketAddr) {
         trace!(%remote, "migration initiated");
+        self.record(TelemetryEvent::Migrated { remote });
         // Reset rtt/congestion state for new path unless it looks like a NAT rebinding.
         let maybe_rebinding = remote.is_ipv4() && remote.ip() == self.path.remote.ip();
         // Note that the congestion window will not grow until validation terminates. Helps mitigate
@@ -2002,18 +2384,26 @@ where
             } else {
                 RttEstimator::new()
             },
-            congestion_window: if maybe_rebinding {
-                self.path.congestion_window
+            congestion: if maybe_rebinding {
+                self.path.congestion.clone_box()
             } else {
-                self.config.initial_window
+                self.config
+                    .congestion_controller_factory
+                    .build(now, &self.config)
             },
-            ssthresh: if maybe_rebinding {
-                self.path.ssthresh
-            } else {
-                u64::max_value()
+            pacing: Pacer::new(now, self.config.initial_mtu),
+            mtud: {
+                let mut mtud =
+                    MtuDiscovery::new(self.config.initial_mtu, self.config.max_udp_payload_size);
+                mtud.set_peer_max(self.params.max_packet_size);
+                mtud
             },
             // Try ECN on the new path if it's probably not the same as an old broken path.
-            sending_ecn: self.path.sending_ecn || !maybe_rebinding,
+            sending_ecn: self.config.allow_ecn && (self.path.sending_ecn || !maybe_rebinding),
+            validated: false,
+            total_sent: 0,
+            total_recvd: 0,
+            lost_packets: 0,
         };
         let prev = Some(mem::replace(&mut self.path, new_path));
         // Don't clobber the original path if the previous one hasn't been validated yet
@@ -2033,6 +2423,36 @@ where
         self.path_challenge_pending = true;
     }
 
+    /// Move to the server's preferred address, if it advertised one we can reach
+    fn migrate_to_preferred_address(&mut self, now: Instant) {
+        let preferred = match self.params.preferred_address {
+            Some(x) => x,
+            None => return,
+        };
+        let remote = match self.path.remote {
+            SocketAddr::V4(_) => preferred.address_v4.map(SocketAddr::V4),
+            SocketAddr::V6(remote) => preferred.address_v6.map(SocketAddr::V6).or_else(|| {
+                // Dual-stack sockets reach IPv4 peers through mapped addresses
+                let mapped = remote.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff];
+                preferred
+                    .address_v4
+                    .filter(|_| mapped)
+                    .map(|x| SocketAddr::new(x.ip().to_ipv6_mapped().into(), x.port()))
+            }),
+        };
+        let remote = match remote {
+            Some(x) if x != self.path.remote => x,
+            _ => return,
+        };
+        debug!(%remote, "migrating to preferred address");
+        self.migrate(now, remote);
+        self.update_rem_cid(IssuedCid {
+            sequence: 1,
+            id: preferred.connection_id,
+            reset_token: preferred.stateless_reset_token,
+        });
+    }
+
     fn update_rem_cid(&mut self, new: IssuedCid) {
         trace!("switching to remote CID {}: {}", new.sequence, new.id);
         let retired = self.rem_cid_seq;
@@ -2062,12 +2482,35 @@ where
         if self.state.is_handshake()
             && !self.remote_validated
             && self.side.is_server()
-            && self.total_recvd * 3 < self.total_sent + u64::from(self.mtu)
+            && self.total_recvd * self.amplification_factor()
+                < self.total_sent + u64::from(self.mtu())
         {
-            trace!("blocked by anti-amplification");
+            trace!(
+                allowed = self.total_recvd * self.amplification_factor(),
+                sent = self.total_sent,
+                "blocked by anti-amplification"
+            );
+            return None;
+        }
+        let path_budget = self.path_budget();
+        if path_budget.map_or(false, |x| x < MIN_PACKET_SPACE as u64) {
+            trace!(
+                allowed = self.amplification_factor() * self.path.total_recvd,
+                sent = self.path.total_sent,
+                "blocked by anti-amplification on unvalidated path"
+            );
             return None;
         }
 
+        if self.key_update_due() {
+            trace!(
+                packets = self.key_phase_packets,
+                bytes = self.key_phase_bytes,
+                "rotating keys"
+            );
+            self.update_keys(None, false);
+        }
+
         // If we need to send a probe, make sure we have something to send.
         for space in SpaceId::iter() {
             if self.space(space).loss_probes != 0 {
@@ -2075,6 +2518,10 @@ where
             }
         }
 
+        if let Some(transmit) = self.poll_mtu_probe(now) {
+            return Some(transmit);
+        }
+
         // Select the set of spaces that have data to send so we can try to coalesce them
         let (spaces, close) = match self.state {
             State::Drained => {
@@ -2102,8 +2549,20 @@ where
             ),
         };
 
-        let mut buf = Vec::with_capacity(self.mtu as usize);
+        // Tail loss probes use the initial MTU, so they get through even if the path MTU shrank
+        let mtu = if self.space(SpaceId::Data).loss_probes != 0 {
+            self.config.initial_mtu
+        } else {
+            self.mtu()
+        };
+        let mtu = match path_budget {
+            Some(budget) => cmp::min(u64::from(mtu), budget) as u16,
+            None => mtu,
+        };
+        let mut buf = Vec::with_capacity(mtu as usize);
         let mut coalesce = spaces.len() > 1;
+        #[cfg(feature = "packet-tap")]
+        let observing = self.observing_packets();
         let pad_space = if self.side.is_client() && spaces.first() == Some(&SpaceId::Initial) {
             spaces.last().cloned()
         } else {
@@ -2122,6 +2581,12 @@ where
                 {
                     continue;
                 }
+                if ack_eliciting && self.space(space_id).loss_probes == 0 {
+                    if let Some(resume) = self.pacing_delay(now) {
+                        self.io.timer_start(TimerKind::Pacing, resume);
+                        continue;
+                    }
+                }
             }
 
             //
@@ -2158,18 +2623,21 @@ where
                     key_phase: self.key_phase,
                 },
                 SpaceId::Data => Header::Long {
+                    version: self.version,
                     ty: LongType::ZeroRtt,
                     src_cid: self.handshake_cid,
                     dst_cid: self.rem_cid,
                     number,
                 },
                 SpaceId::Handshake => Header::Long {
+                    version: self.version,
                     ty: LongType::Handshake,
                     src_cid: self.handshake_cid,
                     dst_cid: self.rem_cid,
                     number,
                 },
                 SpaceId::Initial => Header::Initial {
+                    version: self.version,
                     src_cid: self.handshake_cid,
                     dst_cid: self.rem_cid,
                     token: match self.state {
@@ -2184,6 +2652,11 @@ where
             let partial_encode = header.encode(&mut buf);
             coalesce = coalesce && !header.is_short();
 
+            // Datagrams validating a path must be padded, to check that the path supports
+            // the minimum MTU
+            let path_frames = space_id == SpaceId::Data
+                && (self.path_challenge.is_some() || self.path_response.is_some());
+
             let sent = if close {
                 trace!("sending CONNECTION_CLOSE");
                 let max_len = buf.capacity()
@@ -2205,7 +2678,7 @@ where
                 coalesce = false;
                 None
             } else {
-                Some(self.populate_packet(space_id, &mut buf))
+                Some(self.populate_packet(now, space_id, &mut buf))
             };
 
             let space = &mut self.spaces[space_id as usize];
@@ -2226,6 +2699,11 @@ where
                 // Initial-bearing packets MUST be padded
                 buf.resize(MIN_INITIAL_SIZE - crypto.packet.tag_len(), 0);
                 true
+            } else if path_frames && buf.len() < MIN_INITIAL_SIZE - crypto.packet.tag_len() {
+                // Unless the anti-amplification limit forbids it
+                let size = cmp::min(MIN_INITIAL_SIZE, buf.capacity());
+                buf.resize(size - crypto.packet.tag_len(), 0);
+                true
             } else {
                 false
             };
@@ -2246,20 +2724,38 @@ where
                 buf.resize(buf.len() + padding, 0);
             }
 
+            #[cfg(feature = "packet-tap")]
+            let plaintext = if observing {
+                let payload_start = partial_encode.start + partial_encode.header_len;
+                Some(Bytes::copy_from_slice(&buf[payload_start..]))
+            } else {
+                None
+            };
             buf.resize(buf.len() + crypto.packet.tag_len(), 0);
-            debug_assert!(buf.len() < self.mtu as usize);
+            debug_assert!(buf.len() < self.path.mtud.current_mtu() as usize);
             let packet_buf = &mut buf[partial_encode.start..];
+            let packet_len = packet_buf.len() as u64;
             partial_encode.finish(
                 packet_buf,
                 &crypto.header,
                 Some((exact_number, &crypto.packet)),
             );
+            if space_id == SpaceId::Data && space.crypto.is_none() {
+                self.zero_rtt_bytes += packet_len;
+            } else if space_id == SpaceId::Data {
+                self.key_phase_packets += 1;
+                self.key_phase_bytes += packet_len;
+            }
 
             if let Some((sent, acks)) = sent {
                 // If we sent any acks, don't immediately resend them. Setting this even if ack_only is
                 // false needlessly prevents us from ACKing the next packet if it's ACK-only, but saves
                 // the need for subtler logic to avoid double-transmitting acks all the time.
                 space.permit_ack_only &= acks.is_empty();
+                if space_id == SpaceId::Data && !acks.is_empty() {
+                    self.ack_frequency.on_ack_sent();
+                    self.io.timer_stop(TimerKind::MaxAckDelay);
+                }
 
                 self.on_packet_sent(
                     now,
@@ -2278,6 +2774,13 @@ where
                     },
                 );
             }
+            #[cfg(feature = "packet-tap")]
+            {
+                if let Some(payload) = plaintext {
+                    let (number, len) = (Some(exact_number), packet_len as usize);
+                    self.observe_packet(now, true, &header, number, len, payload);
+                }
+            }
 
             if !coalesce || buf.capacity() - buf.len() < MIN_PACKET_SPACE {
                 break;
@@ -2290,6 +2793,11 @@ where
 
         trace!("sending {} byte datagram", buf.len());
         self.total_sent = self.total_sent.wrapping_add(buf.len() as u64);
+        self.path.total_sent = self.path.total_sent.saturating_add(buf.len() as u64);
+        self.stats.datagrams_sent += 1;
+        self.stats.bytes_sent += buf.len() as u64;
+        #[cfg(feature = "packet-tap")]
+        self.tap_datagram(now, true, &buf);
 
         Some(Transmit {
             destination: self.path.remote,
@@ -2302,7 +2810,117 @@ where
         })
     }
 
-    fn populate_packet(&mut self, space_id: SpaceId, buf: &mut Vec<u8>) -> (Retransmits, RangeSet) {
+    /// Build a padded PING probing whether a larger MTU gets through, if one is due
+    fn poll_mtu_probe(&mut self, now: Instant) -> Option<Transmit> {
+        // Wait for the handshake to be confirmed, lest the peer drop probes for lack
+        // of keys
+        if !self.state.is_established()
+            || self.migrating()
+            || self.space(SpaceId::Handshake).crypto.is_some()
+            || self.space(SpaceId::Data).crypto.is_none()
+            || self.space(SpaceId::Data).loss_probes != 0
+            || self.congestion_blocked()
+        {
+            return None;
+        }
+        let size = self.path.mtud.poll_probe(now)?;
+        if let Some(resume) = self.pacing_delay(now) {
+            self.io.timer_start(TimerKind::Pacing, resume);
+            return None;
+        }
+        if let Some(ref mut prev) = self.prev_crypto {
+            prev.update_unacked = false;
+        }
+
+        #[cfg(feature = "packet-tap")]
+        let observing = self.observing_packets();
+        let space = &mut self.spaces[SpaceId::Data as usize];
+        let exact_number = space.get_tx_number();
+        let span = trace_span!("send", space = ?SpaceId::Data, pn = exact_number);
+        let _guard = span.enter();
+        let header = Header::Short {
+            dst_cid: self.rem_cid,
+            number: PacketNumber::new(exact_number, space.largest_acked_packet.unwrap_or(0)),
+            spin: if self.spin_enabled {
+                self.spin
+            } else {
+                self.rng.gen()
+            },
+            key_phase: self.key_phase,
+        };
+        let mut buf = Vec::with_capacity(size as usize);
+        let partial_encode = header.encode(&mut buf);
+        let crypto = space.crypto.as_ref().unwrap();
+        trace!(size, "PING (MTU probe)");
+        buf.write(frame::Type::PING);
+        buf.resize(size as usize, 0);
+        #[cfg(feature = "packet-tap")]
+        let plaintext = if observing {
+            let payload_start = partial_encode.start + partial_encode.header_len;
+            let tag_len = crypto.packet.tag_len();
+            Some(Bytes::copy_from_slice(
+                &buf[payload_start..buf.len() - tag_len],
+            ))
+        } else {
+            None
+        };
+        let packet_buf = &mut buf[partial_encode.start..];
+        partial_encode.finish(
+            packet_buf,
+            &crypto.header,
+            Some((exact_number, &crypto.packet)),
+        );
+
+        self.key_phase_packets += 1;
+        self.key_phase_bytes += u64::from(size);
+        #[cfg(feature = "packet-tap")]
+        {
+            if let Some(payload) = plaintext {
+                let number = Some(exact_number);
+                self.observe_packet(now, true, &header, number, size as usize, payload);
+            }
+        }
+        self.on_packet_sent(
+            now,
+            SpaceId::Data,
+            exact_number,
+            SentPacket {
+                acks: RangeSet::new(),
+                time_sent: now,
+                size,
+                ack_eliciting: true,
+                retransmits: Retransmits::default(),
+            },
+        );
+        self.path.mtud.on_probe_sent(exact_number, size);
+        self.total_sent = self.total_sent.wrapping_add(buf.len() as u64);
+        self.path.total_sent = self.path.total_sent.saturating_add(buf.len() as u64);
+        self.stats.datagrams_sent += 1;
+        self.stats.bytes_sent += buf.len() as u64;
+        #[cfg(feature = "packet-tap")]
+        self.tap_datagram(now, true, &buf);
+
+        Some(Transmit {
+            destination: self.path.remote,
+            contents: buf.into(),
+            ecn: if self.path.sending_ecn {
+                Some(EcnCodepoint::ECT0)
+            } else {
+                None
+            },
+        })
+    }
+
+    fn populate_packet(
+        &mut self,
+        now: Instant,
+        space_id: SpaceId,
+        buf: &mut Vec<u8>,
+    ) -> (Retransmits, RangeSet) {
+        let max_datagram = match space_id {
+            SpaceId::Data => self.max_datagram_size(),
+            _ => None,
+        };
         let space = &mut self.spaces[space_id as usize];
         let mut sent = Retransmits::default();
         let zero_rtt_crypto = self.zero_rtt_crypto.as_ref();
@@ -2339,12 +2957,57 @@ where
             } else {
                 None
             };
-            frame::Ack::encode(0, &space.pending_acks, ecn, buf);
+            // Only acknowledgements in the Data space may be delayed
+            let delay = if space_id == SpaceId::Data {
+                let delay = now - space.rx_packet_time;
+                delay.as_micros() as u64 >> TransportParameters::default().ack_delay_exponent
+            } else {
+                0
+            };
+            frame::Ack::encode(delay, &space.pending_acks, ecn, buf);
             space.pending_acks.clone()
         } else {
             RangeSet::new()
         };
 
+        // IMMEDIATE_ACK
+        if self.immediate_ack_pending && space_id == SpaceId::Data && !is_0rtt {
+            trace!("IMMEDIATE_ACK");
+            self.immediate_ack_pending = false;
+            buf.write(frame::Type::IMMEDIATE_ACK);
+        }
+
+        // ACK_FREQUENCY
+        if space.pending.ack_frequency
+            && space_id == SpaceId::Data
+            && buf.len() + frame::AckFrequency::SIZE_BOUND < max_size
+        {
+            space.pending.ack_frequency = false;
+            if let (Some(config), Some(min_ack_delay)) =
+                (self.config.ack_frequency, self.params.min_ack_delay)
+            {
+                let frame = frame::AckFrequency {
+                    sequence: self.ack_frequency_sequence,
+                    packet_tolerance: config.ack_eliciting_threshold,
+                    update_max_ack_delay: cmp::max(
+                        config.max_ack_delay,
+                        min_ack_delay.into_inner(),
+                    ),
+                    ignore_order: config.ignore_order,
+                };
+                trace!(
+                    sequence = frame.sequence,
+                    tolerance = frame.packet_tolerance,
+                    "ACK_FREQUENCY"
+                );
+                frame.encode(buf);
+                sent.ack_frequency = true;
+                self.ack_frequency_sequence += 1;
+                self.requested_max_ack_delay =
+                    Some(Duration::from_micros(frame.update_max_ack_delay));
+            }
+        }
+
         // PATH_CHALLENGE
         if buf.len() + 9 < max_size && space_id == SpaceId::Data {
             // Transmit challenges with every outgoing frame on an unvalidated path
@@ -2521,11 +3184,23 @@ where
         }
 
         // DATAGRAM
-        while buf.len() + Datagram::SIZE_BOUND < max_size {
+        while buf.len() + Datagram::SIZE_BOUND < max_size && space_id == SpaceId::Data {
             let datagram = match self.datagrams.outgoing.pop_front() {
                 Some(x) => x,
                 None => break,
             };
+            if max_datagram.map_or(false, |max| datagram.data.len() > max) {
+                // Queued before the path MTU estimate or the peer's limit shrank, so it can never
+                // be sent
+                trace!(len = datagram.data.len(), "dropping oversized datagram");
+                if self.datagrams.outgoing_total >= self.config.datagram_send_buffer_size {
+                    self.events.push_back(Event::DatagramSendUnblocked);
+                }
+                self.datagrams.outgoing_total -= datagram.data.len();
+                self.datagrams.dropped += 1;
+                self.stats.app_datagrams_dropped += 1;
+                continue;
+            }
             if buf.len() + datagram.size(true) > max_size {
                 // Future work: we could be more clever about cramming small datagrams into
                 // mostly-full packets when a larger one is queued first
@@ -2537,6 +3212,7 @@ where
             }
             self.datagrams.outgoing_total -= datagram.data.len();
             datagram.encode(true, buf);
+            self.stats.app_datagrams_sent += 1;
         }
 
         // STREAM
@@ -2590,6 +3266,9 @@ where
             self.state = State::Closed(state::Closed {
                 reason: Close::Application(frame::ApplicationClose { error_code, reason }),
             });
+            self.record(TelemetryEvent::Closed {
+                reason: ConnectionError::LocallyClosed,
+            });
         }
     }
 
@@ -2615,6 +3294,13 @@ where
                 "original CID mismatch",
             ));
         }
+        if params.preferred_address.map_or(false, |x| {
+            x.connection_id.is_empty() || self.rem_cid.is_empty()
+        }) {
+            return Err(TransportError::TRANSPORT_PARAMETER_ERROR(
+                "preferred address without connection IDs",
+            ));
+        }
         if params.initial_max_streams_bidi > MAX_STREAM_COUNT
             || params.initial_max_streams_uni > MAX_STREAM_COUNT
         {
@@ -2626,6 +3312,14 @@ where
         Ok(())
     }
 
+    /// Decode the transport parameters conveyed by the crypto session, if any
+    fn peer_transport_params(&self) -> Result<Option<TransportParameters>, TransportError> {
+        match self.tls.transport_parameters() {
+            None => Ok(None),
+            Some(mut buf) => Ok(Some(TransportParameters::read(self.side, &mut buf)?)),
+        }
+    }
+
     fn set_params(&mut self, params: TransportParameters) {
         self.streams.max[Dir::Bi as usize] = params.initial_max_streams_bidi;
         self.streams.max[Dir::Uni as usize] = params.initial_max_streams_uni;
@@ -2635,12 +3329,14 @@ where
             self.streams.send_mut(id).unwrap().max_data =
                 params.initial_max_stream_data_bidi_local as u64;
         }
-        self.idle_timeout = if self.config.idle_timeout == 0 || params.idle_timeout == 0 {
-            cmp::max(self.config.idle_timeout, params.idle_timeout)
-        } else {
-            cmp::min(self.config.idle_timeout, params.idle_timeout)
-        };
+        let negotiated = negotiate_idle_timeout(self.config.idle_timeout, params.idle_timeout);
+        self.idle_timeout = negotiate_idle_timeout(negotiated, self.idle_timeout_override);
+        self.path.mtud.set_peer_max(params.max_packet_size);
+        if self.config.ack_frequency.is_some() && params.min_ack_delay.is_some() {
+            self.space_mut(SpaceId::Data).pending.ack_frequency = true;
+        }
         self.params = params;
+        self.params_set = true;
     }
 
     /// Open a single stream if possible
@@ -2651,6 +3347,7 @@ where
             return None;
         }
         let id = self.streams.open(self.side, dir)?;
+        self.record(TelemetryEvent::StreamOpened { id });
         // TODO: Queue STREAM_ID_BLOCKED if None
         self.streams.send_mut(id).unwrap().max_data = match dir {
             Dir::Uni => self.params.initial_max_stream_data_uni,
@@ -2666,6 +3363,17 @@ where
         self.spaces[self.highest_space as usize].ping_pending = true;
     }
 
+    /// Notify the connection that the local address changed, e.g. due to switching networks
+    ///
+    /// Switches to a fresh remote connection ID, if one is available, so that observers can't link
+    /// the new path to the old one, and pings the peer so that it learns of the new path promptly.
+    pub fn local_address_changed(&mut self) {
+        if let Some(cid) = self.rem_cids.pop() {
+            self.update_rem_cid(cid);
+        }
+        self.ping();
+    }
+
     /// Permit an additional remote `ty` stream.
     fn alloc_remote_stream(&mut self, dir: Dir) {
         let space = &mut self.spaces[SpaceId::Data as usize];
@@ -2753,8 +3461,18 @@ where
         Ok(())
     }
 
+    /// When the pacer permits sending the next 1-RTT packet, or `None` if now
+    fn pacing_delay(&mut self, now: Instant) -> Option<Instant> {
+        // Pace only once the RTT is known, rather than spreading the first flight over a guess
+        if !self.config.pacing || self.path.rtt.smoothed().is_none() {
+            return None;
+        }
+        let rate = self.path.congestion.pacing_rate(&self.path.rtt);
+        self.path.pacing.delay(now, rate, self.mtu())
+    }
+
     fn congestion_blocked(&self) -> bool {
-        self.in_flight.bytes + u64::from(self.mtu) >= self.path.congestion_window
+        self.in_flight.bytes + u64::from(self.mtu()) >= self.path.congestion.window()
     }
 
     fn blocked(&self) -> bool {
@@ -2847,10 +3565,98 @@ where
         self.update_keys(None, false);
     }
 
+    /// Switch to fresh 1-RTT keys for outgoing packets
+    ///
+    /// Returns false if a key update isn't currently permitted: before the handshake is confirmed,
+    /// or while the keys replaced by a previous update are still retained. Keys can also be
+    /// rotated automatically, see `TransportConfig::key_update_packets`.
+    pub fn force_key_update(&mut self) -> bool {
+        if !self.can_update_keys() {
+            return false;
+        }
+        self.update_keys(None, false);
+        true
+    }
+
+    /// Fill `output` with keying material derived from the connection's secrets (RFC 5705)
+    ///
+    /// Both endpoints obtain the same value for the same `label` and `context`, so it can be used
+    /// to bind application-level authentication to this connection. Fails until the handshake has
+    /// completed.
+    pub fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), crypto::ExportKeyingMaterialError> {
+        self.tls.export_keying_material(output, label, context)
+    }
+
+    /// Statistics on the 1-RTT keys used by this connection
+    pub fn key_phase_stats(&self) -> KeyPhaseStats {
+        KeyPhaseStats {
+            local_updates: self.local_key_updates,
+            remote_updates: self.remote_key_updates,
+            packets_sent: self.key_phase_packets,
+            bytes_sent: self.key_phase_bytes,
+        }
+    }
+
+    fn can_update_keys(&self) -> bool {
+        self.state.is_established()
+            && self.space(SpaceId::Handshake).crypto.is_none()
+            && self.space(SpaceId::Data).crypto.is_some()
+            && self.prev_crypto.is_none()
+    }
+
+    /// Whether enough has been sent under the current keys to rotate them automatically
+    fn key_update_due(&self) -> bool {
+        let packets = self.config.key_update_packets;
+        let bytes = self.config.key_update_bytes;
+        ((packets != 0 && self.key_phase_packets >= packets)
+            || (bytes != 0 && self.key_phase_bytes >= bytes))
+            && self.can_update_keys()
+    }
+
     /// Send data on the given stream
     ///
     /// Returns the number of bytes successfully written.
     pub fn write(&mut self, stream: StreamId, data: &[u8]) -> Result<usize, WriteError> {
+        let n = self.write_limit(stream, data.len())?;
+        self.queue_stream_data(stream, Bytes::copy_from_slice(&data[0..n]))?;
+        trace!(%stream, "wrote {} bytes", n);
+        Ok(n)
+    }
+
+    /// Send owned chunks of data on the given stream without copying them
+    ///
+    /// Chunks are written in order, as far as flow and congestion control allow. Written data is
+    /// split off the front of `data`, leaving fully written chunks empty. Returns the total number
+    /// of bytes written.
+    pub fn write_chunks(
+        &mut self,
+        stream: StreamId,
+        data: &mut [Bytes],
+    ) -> Result<usize, WriteError> {
+        let len = data.iter().map(|x| x.len()).sum();
+        let n = self.write_limit(stream, len)?;
+        let mut remaining = n;
+        for chunk in data.iter_mut() {
+            if remaining == 0 {
+                break;
+            }
+            let chunk = chunk.split_to(remaining.min(chunk.len()));
+            remaining -= chunk.len();
+            if !chunk.is_empty() {
+                self.queue_stream_data(stream, chunk)?;
+            }
+        }
+        trace!(%stream, "wrote {} bytes", n);
+        Ok(n)
+    }
+
+    /// Compute how many of `len` bytes may currently be written to `stream`
+    fn write_limit(&mut self, stream: StreamId, len: usize) -> Result<usize, WriteError> {
         assert!(stream.dir() == Dir::Bi || stream.initiator() == self.side);
         if self.state.is_closed() {
             trace!(%stream, "write blocked; connection draining");
@@ -2890,10 +3696,7 @@ where
             self.max_data - self.data_sent,
             self.config.send_window - self.unacked_data,
         );
-        let n = conn_budget.min(stream_budget).min(data.len() as u64) as usize;
-        self.queue_stream_data(stream, Bytes::copy_from_slice(&data[0..n]))?;
-        trace!(%stream, "wrote {} bytes", n);
-        Ok(n)
+        Ok(conn_budget.min(stream_budget).min(len as u64) as usize)
     }
 
     /// Prepare to transmit an unreliable, unordered datagram
@@ -2906,10 +3709,17 @@ where
     ///
     /// If `Err(SendDatagramError::Blocked)` is returned, `Event::DatagramSendUnblocked` may be
     /// emitted in the future.
+    ///
+    /// Datagrams already queued which no longer fit in a packet, e.g. because the path MTU estimate
+    /// shrank, are dropped. The next call then returns `Err(SendDatagramError::Dropped)`.
     pub fn send_datagram(&mut self) -> Result<DatagramSender<'_, S>, SendDatagramError> {
         if self.config.datagram_receive_buffer_size.is_none() {
             return Err(SendDatagramError::Disabled);
         }
+        if self.datagrams.dropped != 0 {
+            let count = mem::replace(&mut self.datagrams.dropped, 0);
+            return Err(SendDatagramError::Dropped(count));
+        }
         let max = self
             .max_datagram_size()
             .ok_or(SendDatagramError::UnsupportedByPeer)?;
@@ -2937,7 +3747,7 @@ where
     /// Not necessarily the maximum size of received datagrams.
     pub fn max_datagram_size(&self) -> Option<usize> {
         // This is usually 1182 bytes, but we shouldn't document that without a doctest.
-        let max_size = self.mtu as usize
+        let max_size = self.mtu() as usize
             - 1                 // flags byte
             - self.rem_cid.len()
             - 4                 // worst-case packet number size
@@ -2967,6 +3777,14 @@ where
             update_unacked: remote,
         });
         self.key_phase = !self.key_phase;
+        self.key_phase_packets = 0;
+        self.key_phase_bytes = 0;
+        if remote {
+            self.remote_key_updates += 1;
+        } else {
+            self.local_key_updates += 1;
+        }
+        self.record(TelemetryEvent::KeyUpdate { remote });
     }
 
     /// If the connection is currently handshaking
@@ -2996,6 +3814,48 @@ where
         self.zero_rtt_enabled
     }
 
+    /// Override the idle timeout of this connection, in milliseconds
+    ///
+    /// The timeout can only be shortened from the value negotiated during the handshake, since the
+    /// peer discards the connection after that long regardless; configure a generous
+    /// `TransportConfig::idle_timeout` and shorten it for connections that warrant it. 0 restores
+    /// the negotiated value. Returns the timeout now in effect.
+    ///
+    /// If called during the handshake, the override also applies once the timeout is negotiated.
+    pub fn set_idle_timeout(&mut self, now: Instant, timeout: u64) -> u64 {
+        self.idle_timeout_override = timeout;
+        let negotiated = negotiate_idle_timeout(self.config.idle_timeout, self.params.idle_timeout);
+        self.idle_timeout = negotiate_idle_timeout(negotiated, timeout);
+        self.reset_idle_timeout(now);
+        self.idle_timeout
+    }
+
+    /// The idle timeout currently in effect, in milliseconds, or 0 if disabled
+    pub fn idle_timeout(&self) -> u64 {
+        self.idle_timeout
+    }
+
+    /// Whether 0-RTT data was attempted and what became of it
+    ///
+    /// Servers report `Accepted` whenever they were able to receive 0-RTT data; they can't tell
+    /// whether a client they rejected would have sent any.
+    pub fn zero_rtt_status(&self) -> ZeroRttStatus {
+        if !self.zero_rtt_enabled {
+            ZeroRttStatus::NotAttempted
+        } else if self.side.is_server() || self.accepted_0rtt {
+            ZeroRttStatus::Accepted
+        } else if self.rejected_0rtt {
+            ZeroRttStatus::Rejected
+        } else {
+            ZeroRttStatus::Pending
+        }
+    }
+
+    /// Bytes of 0-RTT packets sent, including those the server went on to reject
+    pub fn zero_rtt_bytes_sent(&self) -> u64 {
+        self.zero_rtt_bytes
+    }
+
     /// Look up whether we're the client or server of this Connection
     pub fn side(&self) -> Side {
         self.side
@@ -3016,6 +3876,13 @@ where
         self.tls.alpn_protocol()
     }
 
+    /// Cryptographic identity and parameters negotiated with the peer
+    ///
+    /// Only complete once the connection has been established.
+    pub fn authentication_data(&self) -> S::AuthenticationData {
+        self.tls.authentication_data()
+    }
+
     /// The number of bytes of packets containing retransmittable frames that have not been
     /// acknowledged or declared lost.
     #[cfg(test)]
@@ -3027,7 +3894,8 @@ where
     #[cfg(test)]
     pub(crate) fn congestion_state(&self) -> u64 {
         self.path
-            .congestion_window
+            .congestion
+            .window()
             .saturating_sub(self.in_flight.bytes)
     }
 
@@ -3038,20 +3906,139 @@ where
         self.tls.sni_hostname()
     }
 
+    /// Whether this connection exposes its round trip time to observers with the spin bit
+    ///
+    /// Permitted by `TransportConfig::allow_spin`, but even then left off on a random one in eight
+    /// connections, so that those can't be told apart from connections that never use it.
+    pub fn spin_enabled(&self) -> bool {
+        self.spin_enabled
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_spin_enabled(&mut self, enabled: bool) {
+        self.spin_enabled = enabled;
+    }
+
+    /// The congestion controller of the current path
+    pub fn congestion(&self) -> &dyn congestion::Controller {
+        &*self.path.congestion
+    }
+
+    /// Current statistics on the connection's performance
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            rtt: self
+                .path
+                .rtt
+                .smoothed
+                .unwrap_or_else(|| Duration::from_micros(self.config.initial_rtt)),
+            rtt_var: self.path.rtt.var,
+            congestion_window: self.path.congestion.window(),
+            congestion: self.path.congestion.state(),
+            bytes_in_flight: self.in_flight.bytes,
+            ..self.stats
+        }
+    }
+
+    /// Statistics on the path currently used to send to the peer
+    pub fn current_path(&self) -> PathStats {
+        self.path
+            .stats(Duration::from_micros(self.config.initial_rtt))
+    }
+
+    /// Statistics on all known paths, starting with the current one
+    ///
+    /// While a migration is being validated, the path it replaced is also reported, since the
+    /// connection falls back to it if validation fails.
+    pub fn path_stats(&self) -> Vec<PathStats> {
+        let initial_rtt = Duration::from_micros(self.config.initial_rtt);
+        let mut paths = vec![self.path.stats(initial_rtt)];
+        if self.migrating() {
+            paths.extend(self.prev_path.as_ref().map(|x| x.stats(initial_rtt)));
+        }
+        paths
+    }
+
     /// Total number of outgoing packets that have been deemed lost
     #[cfg(test)]
     pub(crate) fn lost_packets(&self) -> u64 {
         self.lost_packets
     }
 
-    /// Whether explicit congestion notification is in use on outgoing packets.
-    #[cfg(test)]
-    pub(crate) fn using_ecn(&self) -> bool {
+    /// How much data may be sent to the peer before its address is validated
+    ///
+    /// `None` unless this is a server which hasn't yet confirmed that the client can receive what's
+    /// sent to its address, either during the handshake or after the client migrated. Useful in
+    /// diagnosing slow handshakes, which may be due to the server waiting for more data from the
+    /// client before it can send any more itself.
+    pub fn amplification_limit(&self) -> Option<AmplificationLimit> {
+        let factor = self.amplification_factor();
+        if self.side.is_client() {
+            None
+        } else if self.state.is_handshake() && !self.remote_validated {
+            Some(AmplificationLimit {
+                allowed: factor * self.total_recvd,
+                sent: self.total_sent,
+            })
+        } else if !self.path.validated {
+            Some(AmplificationLimit {
+                allowed: factor * self.path.total_recvd,
+                sent: self.path.total_sent,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Transport parameters advertised by the peer
+    ///
+    /// `None` until the peer's parameters are known: for a server, once the client's first Initial
+    /// is processed; for a client, once the handshake completes, or as soon as the connection is
+    /// created if 0-RTT was attempted using parameters remembered from a previous session.
+    pub fn peer_params(&self) -> Option<PeerParams> {
+        if !self.params_set {
+            return None;
+        }
+        Some(PeerParams::from(&self.params))
+    }
+
+    /// Transport parameters sent by the peer that quinn-proto doesn't interpret itself
+    ///
+    /// Empty until `peer_params` is available. See `TransportConfig::custom_transport_params`.
+    pub fn peer_custom_params(&self) -> &[(u16, Vec<u8>)] {
+        &self.params.custom
+    }
+
+    /// Whether explicit congestion notification is in use on outgoing packets
+    ///
+    /// Enabled by `TransportConfig::allow_ecn` on each new path, and disabled again if the peer's
+    /// acknowledgements show that the marks don't survive the path.
+    pub fn using_ecn(&self) -> bool {
         self.path.sending_ecn
     }
 
+    /// The QUIC version in use
+    ///
+    /// One of `EndpointConfig::supported_versions`, as negotiated with the peer.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Largest UDP payload known to get through the current path
+    ///
+    /// Starts out at the minimum every path must support, and grows as path MTU discovery finds
+    /// that larger packets get through.
+    pub fn mtu(&self) -> u16 {
+        self.path.mtud.current_mtu()
+    }
+
     fn max_ack_delay(&self) -> Duration {
-        Duration::from_micros(self.params.max_ack_delay * 1000)
+        // The peer may delay acknowledgements as long as we asked it to, even before it sees the
+        // request
+        cmp::max(
+            Duration::from_micros(self.params.max_ack_delay * 1000),
+            self.requested_max_ack_delay.unwrap_or_default(),
+        )
     }
 
     fn space(&self, id: SpaceId) -> &PacketSpace<S::Keys> {
@@ -3076,6 +4063,7 @@ where
         debug_assert!(self.side.is_client());
         debug!("0-RTT rejected");
         self.accepted_0rtt = false;
+        self.rejected_0rtt = true;
         self.streams.zero_rtt_rejected(self.side);
         // Discard already-queued frames
         self.space_mut(SpaceId::Data).pending = Retransmits::default();
@@ -3104,6 +4092,7 @@ where
 }
 
 pub fn initial_close<K, R>(
+    version: u32,
     crypto: &K,
     header_crypto: &K::HeaderKeys,
     remote_id: &ConnectionId,
@@ -3117,6 +4106,7 @@ where
 {
     let number = PacketNumber::U8(packet_number);
     let header = Header::Initial {
+        version,
         dst_cid: *remote_id,
         src_cid: *local_id,
         number,
@@ -3263,6 +4253,8 @@ mod state {
         ///
         /// Only set for clients
         pub client_hello: Option<Bytes>,
+        /// Whether a Version Negotiation packet has already been acted on
+        pub version_negotiated: bool,
     }
 
     #[derive(Clone)]
@@ -3320,6 +4312,79 @@ pub struct TimerUpdate {
     pub update: TimerSetting,
 }
 
+/// Statistics on a connection, see `Connection::stats`
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ConnectionStats {
+    /// Smoothed round trip time, or the configured initial estimate before any sample is taken
+    pub rtt: Duration,
+    /// Variation in round trip time samples
+    pub rtt_var: Duration,
+    /// Congestion window of the current path, in bytes
+    pub congestion_window: u64,
+    /// State of the current path's congestion controller, including any bandwidth and minimum
+    /// round trip time estimates
+    pub congestion: congestion::ControllerState,
+    /// Bytes sent in packets that are neither acknowledged nor deemed lost
+    pub bytes_in_flight: u64,
+    /// UDP datagrams sent
+    pub datagrams_sent: u64,
+    /// Bytes sent in UDP datagrams
+    pub bytes_sent: u64,
+    /// UDP datagrams received, including any which turned out to be invalid
+    pub datagrams_received: u64,
+    /// Bytes received in UDP datagrams
+    pub bytes_received: u64,
+    /// QUIC packets sent
+    pub packets_sent: u64,
+    /// Packets deemed lost
+    pub packets_lost: u64,
+    /// Bytes in packets deemed lost
+    pub bytes_lost: u64,
+    /// Number of times the probe timeout fired
+    pub pto_count: u64,
+    /// Application datagrams sent
+    pub app_datagrams_sent: u64,
+    /// Application datagrams received, including any later dropped for lack of buffer space
+    pub app_datagrams_received: u64,
+    /// Application datagrams dropped unsent because they no longer fit in a packet
+    pub app_datagrams_dropped: u64,
+}
+
+/// The effective idle timeout given two limits, either of which may be 0 to impose no limit
+fn negotiate_idle_timeout(x: u64, y: u64) -> u64 {
+    if x == 0 || y == 0 {
+        cmp::max(x, y)
+    } else {
+        cmp::min(x, y)
+    }
+}
+
+/// Outcome of 0-RTT on a connection, see `Connection::zero_rtt_status`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ZeroRttStatus {
+    /// No 0-RTT keys were available, so no early data could be exchanged
+    NotAttempted,
+    /// 0-RTT data may have been sent, but the handshake hasn't revealed whether it was accepted
+    Pending,
+    /// The peer processed any 0-RTT data
+    Accepted,
+    /// The peer discarded all 0-RTT data, which must be retransmitted by the application
+    Rejected,
+}
+
+/// Statistics on the 1-RTT keys of a connection, see `Connection::key_phase_stats`
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct KeyPhaseStats {
+    /// Number of key updates we initiated
+    pub local_updates: u64,
+    /// Number of key updates the peer initiated
+    pub remote_updates: u64,
+    /// Packets sent using the current keys
+    pub packets_sent: u64,
+    /// Bytes sent using the current keys
+    pub bytes_sent: u64,
+}
+
 struct PrevCrypto<K>
 where
     K: crypto::Keys,
@@ -3372,8 +4437,9 @@ impl InFlight {
     }
 }
 
-#[derive(Copy, Clone)]
-struct RttEstimator {
+/// Round trip time estimates of a path
+#[derive(Debug, Copy, Clone)]
+pub struct RttEstimator {
     /// The most recent RTT measurement made when receiving an ack for a previously unacked packet
     latest: Duration,
     /// The smoothed RTT of the connection, computed as described in RFC6298
@@ -3385,7 +4451,7 @@ struct RttEstimator {
 }
 
 impl RttEstimator {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             latest: Duration::new(0, 0),
             smoothed: None,
@@ -3394,7 +4460,7 @@ impl RttEstimator {
         }
     }
 
-    fn update(&mut self, ack_delay: Duration, rtt: Duration) {
+    pub(crate) fn update(&mut self, ack_delay: Duration, rtt: Duration) {
         self.latest = rtt;
         // min_rtt ignores ack delay.
         self.min = cmp::min(self.min, self.latest);
@@ -3416,6 +4482,26 @@ impl RttEstimator {
             self.var = self.latest / 2;
         }
     }
+
+    /// The most recent RTT sample, corrected for the peer's ack delay
+    pub fn latest(&self) -> Duration {
+        self.latest
+    }
+
+    /// The smoothed RTT, once any sample was taken
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// The minimum RTT seen, ignoring ack delay
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// The mean deviation of RTT samples from the smoothed RTT
+    pub fn var(&self) -> Duration {
+        self.var
+    }
 }
 
 /// Events of interest to the application
@@ -3465,6 +4551,48 @@ pub enum Event {
     DatagramSendUnblocked,
 }
 
+/// Structured events describing a connection's lifecycle, for telemetry
+///
+/// Recorded only if `TransportConfig::telemetry` is set, and retrieved with
+/// `Connection::poll_telemetry`.
+#[derive(Debug, Clone)]
+pub enum TelemetryEvent {
+    /// The handshake completed
+    HandshakeComplete,
+    /// A stream was opened by either peer
+    StreamOpened {
+        /// The new stream
+        id: StreamId,
+    },
+    /// Both directions of a stream finished and its state was discarded
+    StreamClosed {
+        /// The closed stream
+        id: StreamId,
+    },
+    /// The peer abandoned sending on a stream
+    StreamReset {
+        /// The reset stream
+        id: StreamId,
+        /// Error code supplied by the peer
+        error_code: VarInt,
+    },
+    /// The peer's address changed and its new path is being validated
+    Migrated {
+        /// The peer's new address
+        remote: SocketAddr,
+    },
+    /// The 1-RTT keys were updated
+    KeyUpdate {
+        /// Whether the peer initiated the update
+        remote: bool,
+    },
+    /// The connection was closed, locally or otherwise
+    Closed {
+        /// Why the connection was closed
+        reason: ConnectionError,
+    },
+}
+
 impl From<ConnectionError> for Event {
     fn from(x: ConnectionError) -> Self {
         Event::ConnectionLost { reason: x }
@@ -3494,13 +4622,66 @@ const MIN_PACKET_SPACE: usize = 40;
 struct PathData {
     remote: SocketAddr,
     rtt: RttEstimator,
-    /// Maximum number of bytes in flight that may be sent.
-    congestion_window: u64,
-    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is
-    /// slow start and the window grows by the number of bytes acknowledged.
-    ssthresh: u64,
+    congestion: Box<dyn congestion::Controller>,
+    pacing: Pacer,
+    mtud: MtuDiscovery,
     /// Whether we're enabling ECN on outgoing packets
     sending_ecn: bool,
+    /// Whether the peer was shown to receive what's sent on the path
+    validated: bool,
+    /// Total UDP datagram bytes sent on the path, tracked for anti-amplification
+    total_sent: u64,
+    /// Total UDP datagram bytes received on the path, tracked for anti-amplification
+    total_recvd: u64,
+    /// Number of packets deemed lost while this was the active path
+    lost_packets: u64,
+}
+
+impl PathData {
+    fn stats(&self, initial_rtt: Duration) -> PathStats {
+        PathStats {
+            remote: self.remote,
+            validated: self.validated,
+            rtt: self.rtt.smoothed.unwrap_or(initial_rtt),
+            mtu: self.mtud.current_mtu(),
+            congestion_window: self.congestion.window(),
+            bytes_sent: self.total_sent,
+            bytes_received: self.total_recvd,
+            lost_packets: self.lost_packets,
+        }
+    }
+}
+
+/// Statistics on a network path, see `Connection::path_stats`
+///
+/// Paths are identified by the peer's address; the local address isn't tracked.
+#[derive(Debug, Copy, Clone)]
+pub struct PathStats {
+    /// The peer's address on this path
+    pub remote: SocketAddr,
+    /// Whether the peer was shown to receive what's sent on the path
+    pub validated: bool,
+    /// Smoothed round trip time, or the configured initial estimate before any sample is taken
+    pub rtt: Duration,
+    /// Current maximum UDP payload size
+    pub mtu: u16,
+    /// Congestion window, in bytes
+    pub congestion_window: u64,
+    /// Bytes sent in UDP datagrams on the path
+    pub bytes_sent: u64,
+    /// Bytes received in UDP datagrams on the path
+    pub bytes_received: u64,
+    /// Packets deemed lost while this was the active path
+    pub lost_packets: u64,
+}
+
+/// Anti-amplification state of a server, see `Connection::amplification_limit`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AmplificationLimit {
+    /// Bytes which may be sent in all, `ServerConfig::amplification_factor` times those received
+    pub allowed: u64,
+    /// Bytes sent so far
+    pub sent: u64,
 }
 
 /// Errors that can arise when sending a datagram
@@ -3515,6 +4696,11 @@ pub enum SendDatagramError {
     /// Datagram support is disabled locally
     #[error(display = "datagram support disabled")]
     Disabled,
+    /// This many previously queued datagrams were dropped because they no longer fit in a packet
+    ///
+    /// Reported once; sending may be retried immediately.
+    #[error(display = "{} queued datagrams dropped", _0)]
+    Dropped(u64),
 }
 
 /// The datagram is larger than the connection can currently accommodate
@@ -3550,6 +4736,8 @@ struct DatagramState {
     incoming: VecDeque<Datagram>,
     outgoing: VecDeque<Datagram>,
     outgoing_total: usize,
+    /// Number of queued datagrams dropped since the application was last told
+    dropped: u64,
 }
 
 impl DatagramState {
@@ -3559,6 +4747,7 @@ impl DatagramState {
             incoming: VecDeque::new(),
             outgoing: VecDeque::new(),
             outgoing_total: 0,
+            dropped: 0,
         }
     }
 }
@@ -5,16 +5,19 @@
 //! abstraction layer as well as a single implementation of these traits that uses
 //! *ring* and rustls to implement the TLS protocol support.
 //!
+//! Other implementations need not depend on quinn-proto internals: transport parameters are
+//! carried as opaque encoded bytes, and are encoded and validated by the connection itself.
+//!
 //! Note that usage of any protocol (version) other than TLS 1.3 does not conform to any
 //! published versions of the specification, and will not be supported in QUIC v1.
 
 use std::str;
 
 use bytes::BytesMut;
+use err_derive::Error;
 
 use crate::{
     shared::{ConfigError, ConnectionId},
-    transport_parameters::TransportParameters,
     ConnectError, Side, TransportError,
 };
 
@@ -27,6 +30,8 @@ pub(crate) mod rustls;
 
 /// A cryptographic session (commonly TLS)
 pub trait Session: Sized {
+    /// Data conveyed by the peer during the handshake, including cryptographic identity
+    type AuthenticationData;
     /// Type used to hold configuration for client sessions
     type ClientConfig: ClientConfig<Self>;
     /// Type used to sign various values
@@ -42,6 +47,11 @@ pub trait Session: Sized {
     /// has been negotiated.
     fn alpn_protocol(&self) -> Option<&[u8]>;
 
+    /// Get the data authenticated by the handshake
+    ///
+    /// Fields may be missing if the handshake has not advanced sufficiently.
+    fn authentication_data(&self) -> Self::AuthenticationData;
+
     /// Get the 0-RTT keys if available (clients only)
     ///
     /// On the client side, this method can be used to see if 0-RTT key material is available
@@ -67,10 +77,11 @@ pub trait Session: Sized {
     /// The SNI hostname sent by the client (server only)
     fn sni_hostname(&self) -> Option<&str>;
 
-    /// The peer's QUIC transport parameters
+    /// The peer's encoded QUIC transport parameters
     ///
-    /// These are only available after the first flight from the peer has been received.
-    fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError>;
+    /// These are only available after the first flight from the peer has been received, or on a
+    /// client attempting 0-RTT, from the session being resumed.
+    fn transport_parameters(&self) -> Option<&[u8]>;
 
     /// Writes handshake bytes into the given buffer and optionally returns the negotiated keys
     ///
@@ -80,8 +91,23 @@ pub trait Session: Sized {
 
     /// Update the given set of keys
     fn update_keys(&self, keys: &Self::Keys) -> Self::Keys;
+
+    /// Fill `output` with keying material derived from the session's secrets (RFC 5705)
+    ///
+    /// Fails if the handshake hasn't completed yet.
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError>;
 }
 
+/// Keying material could not be exported from the session, see `Session::export_keying_material`
+#[derive(Debug, Error, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[error(display = "keying material unavailable")]
+pub struct ExportKeyingMaterialError;
+
 /// Client-side configuration for the crypto protocol
 pub trait ClientConfig<S>
 where
@@ -93,11 +119,9 @@ where
         Self: Sized;
 
     /// Start a client session with this configuration
-    fn start_session(
-        &self,
-        server_name: &str,
-        params: &TransportParameters,
-    ) -> Result<S, ConnectError>;
+    ///
+    /// `params` are the encoded transport parameters to convey to the server.
+    fn start_session(&self, server_name: &str, params: &[u8]) -> Result<S, ConnectError>;
 }
 
 /// Server-side configuration for the crypto protocol
@@ -111,7 +135,9 @@ where
         Self: Sized;
 
     /// Start a server session with this configuration
-    fn start_session(&self, params: &TransportParameters) -> S;
+    ///
+    /// `params` are the encoded transport parameters to convey to the client.
+    fn start_session(&self, params: &[u8]) -> S;
 }
 
 /// Keys used to protect packet payloads
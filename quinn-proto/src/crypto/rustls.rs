@@ -1,5 +1,4 @@
 use std::{
-    io,
     ops::{Deref, DerefMut},
     str,
     sync::Arc,
@@ -16,10 +15,7 @@ use rustls::{
 use webpki::DNSNameRef;
 
 use super::ring::{hkdf_expand, Crypto};
-use crate::{
-    crypto, transport_parameters::TransportParameters, ConnectError, Side, TransportError,
-    TransportErrorCode,
-};
+use crate::{crypto, ConnectError, Side, TransportError, TransportErrorCode};
 
 /// A rustls TLS session
 pub enum TlsSession {
@@ -39,6 +35,7 @@ impl TlsSession {
 }
 
 impl crypto::Session for TlsSession {
+    type AuthenticationData = AuthenticationData;
     type ClientConfig = Arc<rustls::ClientConfig>;
     type HmacKey = hmac::Key;
     type Keys = Crypto;
@@ -48,6 +45,18 @@ impl crypto::Session for TlsSession {
         self.get_alpn_protocol()
     }
 
+    fn authentication_data(&self) -> AuthenticationData {
+        AuthenticationData {
+            peer_certificates: self.get_peer_certificates(),
+            protocol: self.get_alpn_protocol().map(|p| p.into()),
+            server_name: match self {
+                TlsSession::Client(_) => None,
+                TlsSession::Server(session) => session.get_sni_hostname().map(|s| s.into()),
+            },
+            cipher_suite: self.get_negotiated_ciphersuite().map(|suite| suite.suite),
+        }
+    }
+
     fn early_crypto(&self) -> Option<Self::Keys> {
         let secret = self.get_early_secret()?;
         // If an early secret is known, TLS guarantees it's associated with a resumption
@@ -96,14 +105,8 @@ impl crypto::Session for TlsSession {
         }
     }
 
-    fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
-        match self.get_quic_transport_parameters() {
-            None => Ok(None),
-            Some(buf) => match TransportParameters::read(self.side(), &mut io::Cursor::new(buf)) {
-                Ok(params) => Ok(Some(params)),
-                Err(e) => Err(e.into()),
-            },
-        }
+    fn transport_parameters(&self) -> Option<&[u8]> {
+        self.get_quic_transport_parameters()
     }
 
     fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Self::Keys> {
@@ -119,6 +122,16 @@ impl crypto::Session for TlsSession {
         ))
     }
 
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), crypto::ExportKeyingMaterialError> {
+        Session::export_keying_material(&**self, output, label, Some(context))
+            .map_err(|_| crypto::ExportKeyingMaterialError)
+    }
+
     fn update_keys(&self, keys: &Self::Keys) -> Self::Keys {
         let (client_secret, server_secret) = match self.side() {
             Side::Client => (&keys.local_secret, &keys.remote_secret),
@@ -140,6 +153,21 @@ impl crypto::Session for TlsSession {
     }
 }
 
+/// Authentication data for (rustls) TLS session
+#[derive(Debug, Clone)]
+pub struct AuthenticationData {
+    /// The certificate chain presented by the peer, if any
+    pub peer_certificates: Option<Vec<rustls::Certificate>>,
+    /// The negotiated application protocol, if ALPN is in use
+    pub protocol: Option<Vec<u8>>,
+    /// The server name specified by the client, if any
+    ///
+    /// Always `None` for outgoing connections
+    pub server_name: Option<String>,
+    /// The negotiated TLS cipher suite
+    pub cipher_suite: Option<rustls::CipherSuite>,
+}
+
 impl Deref for TlsSession {
     type Target = dyn rustls::Session;
     fn deref(&self) -> &Self::Target {
@@ -167,17 +195,13 @@ impl crypto::ClientConfig<TlsSession> for Arc<rustls::ClientConfig> {
         Arc::new(cfg)
     }
 
-    fn start_session(
-        &self,
-        server_name: &str,
-        params: &TransportParameters,
-    ) -> Result<TlsSession, ConnectError> {
+    fn start_session(&self, server_name: &str, params: &[u8]) -> Result<TlsSession, ConnectError> {
         let pki_server_name = DNSNameRef::try_from_ascii_str(server_name)
             .map_err(|_| ConnectError::InvalidDnsName(server_name.into()))?;
         Ok(TlsSession::Client(rustls::ClientSession::new_quic(
             self,
             pki_server_name,
-            to_vec(params),
+            params.to_vec(),
         )))
     }
 }
@@ -190,8 +214,8 @@ impl crypto::ServerConfig<TlsSession> for Arc<rustls::ServerConfig> {
         Arc::new(cfg)
     }
 
-    fn start_session(&self, params: &TransportParameters) -> TlsSession {
-        TlsSession::Server(rustls::ServerSession::new_quic(self, to_vec(params)))
+    fn start_session(&self, params: &[u8]) -> TlsSession {
+        TlsSession::Server(rustls::ServerSession::new_quic(self, params.to_vec()))
     }
 }
 
@@ -208,9 +232,3 @@ fn update_secrets(hash_alg: HashAlgorithm, client: &hkdf::Prk, server: &hkdf::Pr
         server: hkdf_expand(server, b"quic ku", hkdf_alg),
     }
 }
-
-fn to_vec(params: &TransportParameters) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    params.write(&mut bytes);
-    bytes
-}
@@ -1,10 +1,10 @@
 use std::{
     collections::{HashMap, VecDeque},
     fmt, iter,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     ops::{Index, IndexMut},
     sync::Arc,
-    time::{Duration, Instant, SystemTime},
+    time::{Duration, Instant},
 };
 
 use bytes::{BufMut, BytesMut};
@@ -21,13 +21,13 @@ use crate::{
     },
     packet::{Header, Packet, PacketDecodeError, PartialDecode},
     shared::{
-        ClientConfig, ConfigError, ConnectionEvent, ConnectionEventInner, ConnectionId,
-        EcnCodepoint, EndpointConfig, EndpointEvent, EndpointEventInner, IssuedCid, ResetToken,
-        ServerConfig,
+        reserved_version, ClientConfig, ConfigError, ConnectionEvent, ConnectionEventInner,
+        ConnectionId, EcnCodepoint, EndpointConfig, EndpointEvent, EndpointEventInner, IssuedCid,
+        RefusalReason, ResetToken, ServerConfig,
     },
-    transport_parameters::TransportParameters,
-    Side, Transmit, TransportError, LOC_CID_COUNT, MAX_CID_SIZE, MIN_INITIAL_SIZE,
-    RESET_TOKEN_SIZE, VERSION,
+    transport_parameters::{PreferredAddress, TransportParameters},
+    Side, Transmit, TransportError, TransportErrorCode, LOC_CID_COUNT, MAX_CID_SIZE,
+    MIN_INITIAL_SIZE, RESET_TOKEN_SIZE,
 };
 
 /// The main entry point to the library
@@ -54,6 +54,21 @@ where
     config: Arc<EndpointConfig>,
     server_config: Option<Arc<ServerConfig<S>>>,
     incoming_handshakes: usize,
+    /// Number of incoming connections which haven't completed the handshake yet
+    half_open: usize,
+    /// Number of Retry packets sent
+    retries_sent: u64,
+    /// Number of Version Negotiation packets sent
+    version_negotiations_sent: u64,
+    /// Number of incoming connections whose handshake began
+    connections_accepted: u64,
+    /// Handshakes begun recently, for rate limiting
+    handshake_rate: HandshakeRate,
+    /// Number of incoming connections, in all and by the IP address they began with
+    incoming_connections: usize,
+    incoming_by_ip: HashMap<IpAddr, u32>,
+    /// Number of incoming connections refused due to load or connection limits
+    connections_refused: u64,
     /// Whether incoming connections should be unconditionally rejected by a server
     ///
     /// Equivalent to a `ServerConfig.accept_buffer` of `0`, but can be changed after the endpoint is constructed.
@@ -74,8 +89,15 @@ where
         server_config: Option<Arc<ServerConfig<S>>>,
     ) -> Result<Self, ConfigError> {
         config.validate()?;
+        if let Some(ref server_config) = server_config {
+            server_config.validate()?;
+        }
+        let rng = match config.rng_seed {
+            Some(seed) => StdRng::from_seed(seed),
+            None => StdRng::from_entropy(),
+        };
         Ok(Self {
-            rng: StdRng::from_entropy(),
+            rng,
             transmits: VecDeque::new(),
             connection_ids_initial: HashMap::new(),
             connection_ids: HashMap::new(),
@@ -83,6 +105,14 @@ where
             connection_reset_tokens: ResetTokenTable::default(),
             connections: Slab::new(),
             incoming_handshakes: 0,
+            half_open: 0,
+            retries_sent: 0,
+            version_negotiations_sent: 0,
+            connections_accepted: 0,
+            handshake_rate: HandshakeRate::default(),
+            incoming_connections: 0,
+            incoming_by_ip: HashMap::new(),
+            connections_refused: 0,
             reject_new_connections: false,
             reset_key: S::HmacKey::new(&config.reset_key)?,
             token_key: server_config
@@ -115,10 +145,11 @@ where
         match event.0 {
             NeedIdentifiers(max) => {
                 if self.config.local_cid_len != 0 {
-                    // We've already issued one CID as part of the normal handshake process.
-                    return Some(
-                        self.send_new_identifiers(ch, max.min(LOC_CID_COUNT - 1) as usize),
-                    );
+                    // We've already issued one CID as part of the normal handshake process, and
+                    // perhaps another for the preferred address.
+                    let issued = self.connections[ch].cids_issued;
+                    let num = max.min(LOC_CID_COUNT - 1).saturating_sub(issued);
+                    return Some(self.send_new_identifiers(ch, num as usize));
                 }
             }
             ResetToken(remote, token) => {
@@ -136,8 +167,27 @@ where
                     return Some(self.send_new_identifiers(ch, 1));
                 }
             }
+            Established => {
+                let meta = &mut self.connections[ch];
+                if meta.handshaking {
+                    meta.handshaking = false;
+                    self.half_open -= 1;
+                }
+            }
             Drained => {
                 let conn = self.connections.remove(ch.0);
+                if conn.handshaking {
+                    self.half_open -= 1;
+                }
+                if conn.incoming {
+                    self.incoming_connections -= 1;
+                    let ip = conn.initial_remote.ip();
+                    let count = self.incoming_by_ip.get_mut(&ip).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        self.incoming_by_ip.remove(&ip);
+                    }
+                }
                 if conn.init_cid.len() > 0 {
                     self.connection_ids_initial.remove(&conn.init_cid);
                 }
@@ -162,7 +212,11 @@ where
         data: BytesMut,
     ) -> Option<(ConnectionHandle, DatagramEvent<S>)> {
         let datagram_len = data.len();
-        let (first_decode, remaining) = match PartialDecode::new(data, self.config.local_cid_len) {
+        let (first_decode, remaining) = match PartialDecode::new(
+            data,
+            self.config.local_cid_len,
+            &self.config.supported_versions,
+        ) {
             Ok(x) => x,
             Err(PacketDecodeError::UnsupportedVersion {
                 source,
@@ -181,13 +235,16 @@ where
                     dst_cid: source,
                 }
                 .encode(&mut buf);
-                buf.write::<u32>(0x0a1a_2a3a); // reserved version
-                buf.write(VERSION); // supported version
+                buf.write(reserved_version(&mut self.rng));
+                for &version in &self.config.supported_versions {
+                    buf.write(version);
+                }
                 self.transmits.push_back(Transmit {
                     destination: remote,
                     ecn: None,
                     contents: buf.into(),
                 });
+                self.version_negotiations_sent += 1;
                 return None;
             }
             Err(e) => {
@@ -244,6 +301,15 @@ where
             ));
         }
 
+        if !first_decode.is_initial() && !dst_cid.is_empty() {
+            if let Some(ref generator) = self.config.cid_generator {
+                if !generator.validate(&dst_cid) {
+                    debug!("dropping packet with invalid CID {}", dst_cid);
+                    return None;
+                }
+            }
+        }
+
         //
         // Potentially create a new connection
         //
@@ -354,6 +420,9 @@ where
         if self.is_full() {
             return Err(ConnectError::TooManyConnections);
         }
+        if self.config.local_cid_len == 0 && self.connection_remotes.contains_key(&remote) {
+            return Err(ConnectError::RemoteInUse);
+        }
         config.transport.validate()?;
         let remote_id = ConnectionId::random(&mut self.rng, MAX_CID_SIZE);
         trace!(initial_dcid = %remote_id);
@@ -365,7 +434,7 @@ where
                 config,
                 server_name: server_name.into(),
             },
-            Instant::now(),
+            self.config.clock.now(),
         )?;
         Ok((ch, conn))
     }
@@ -390,7 +459,10 @@ where
 
     fn new_cid(&mut self) -> ConnectionId {
         loop {
-            let cid = ConnectionId::random(&mut self.rng, self.config.local_cid_len);
+            let cid = match self.config.cid_generator {
+                Some(ref generator) => generator.generate_cid(&mut self.rng),
+                None => ConnectionId::random(&mut self.rng, self.config.local_cid_len),
+            };
             if !self.connection_ids.contains_key(&cid) {
                 break cid;
             }
@@ -407,12 +479,14 @@ where
         now: Instant,
     ) -> Result<(ConnectionHandle, Connection<S>), ConnectError> {
         let loc_cid = self.new_cid();
+        let mut preferred_cid = None;
         let (server_config, tls, transport_config) = match opts {
             ConnectionOpts::Client {
                 config,
                 server_name,
             } => {
-                let params = TransportParameters::new::<S>(&config.transport, None);
+                let mut params = Vec::new();
+                TransportParameters::new::<S>(&config.transport, None).write(&mut params);
                 (
                     None,
                     config.crypto.start_session(&server_name, &params)?,
@@ -420,16 +494,35 @@ where
                 )
             }
             ConnectionOpts::Server { orig_dst_cid } => {
-                let config = self.server_config.as_ref().unwrap();
-                let params = TransportParameters::new(&config.transport, Some(config));
+                let config = self.server_config.clone().unwrap();
+                let params = TransportParameters::new(&config.transport, Some(&config));
+                let preferred_address = if config.has_preferred_address(&self.config) {
+                    // Issued as sequence number 1, so the endpoint routes packets carrying it
+                    let cid = self.new_cid();
+                    preferred_cid = Some(cid);
+                    Some(PreferredAddress {
+                        address_v4: config.preferred_address_v4,
+                        address_v6: config.preferred_address_v6,
+                        connection_id: cid,
+                        stateless_reset_token: reset_token_for(&self.reset_key, &cid),
+                    })
+                } else {
+                    None
+                };
                 let server_params = TransportParameters {
                     stateless_reset_token: Some(reset_token_for(&self.reset_key, &loc_cid)),
                     original_connection_id: orig_dst_cid,
+                    preferred_address,
+                    // Without connection IDs, packets from a new address can't be routed
+                    disable_active_migration: params.disable_active_migration
+                        || self.config.local_cid_len == 0,
                     ..params
                 };
+                let mut params = Vec::new();
+                server_params.write(&mut params);
                 (
                     Some(config.clone()),
-                    config.crypto.start_session(&server_params),
+                    config.crypto.start_session(&params),
                     config.transport.clone(),
                 )
             }
@@ -445,18 +538,29 @@ where
             remote,
             tls,
             now,
+            StdRng::from_rng(&mut self.rng).unwrap(),
         );
-        let id = self.connections.insert(ConnectionMeta {
+        let mut meta = ConnectionMeta {
             init_cid,
             cids_issued: 0,
             loc_cids: iter::once((0, loc_cid)).collect(),
             initial_remote: remote,
+            handshaking: false,
+            incoming: false,
             reset_token: None,
-        });
+        };
+        if let Some(cid) = preferred_cid {
+            meta.cids_issued = 1;
+            meta.loc_cids.insert(1, cid);
+        }
+        let id = self.connections.insert(meta);
         let ch = ConnectionHandle(id);
 
         if self.config.local_cid_len > 0 {
             self.connection_ids.insert(loc_cid, ch);
+            if let Some(cid) = preferred_cid {
+                self.connection_ids.insert(cid, ch);
+            }
         } else {
             self.connection_remotes.insert(remote, ch);
         }
@@ -473,13 +577,14 @@ where
         crypto: &S::Keys,
         header_crypto: &<S::Keys as Keys>::HeaderKeys,
     ) -> Option<(ConnectionHandle, Connection<S>)> {
-        let (src_cid, dst_cid, token, packet_number) = match packet.header {
+        let (version, src_cid, dst_cid, token, packet_number) = match packet.header {
             Header::Initial {
+                version,
                 src_cid,
                 dst_cid,
                 ref token,
                 number,
-            } => (src_cid, dst_cid, token.clone(), number),
+            } => (version, src_cid, dst_cid, token.clone(), number),
             _ => panic!("non-initial packet in handle_initial()"),
         };
         let packet_number = packet_number.expand(0);
@@ -504,29 +609,98 @@ where
         // Local CID used for stateless packets
         let temp_loc_cid = self.new_cid();
         let server_config = self.server_config.as_ref().unwrap();
+        let require_retry = server_config.use_stateless_retry
+            || self.half_open
+                >= server_config
+                    .retry_threshold
+                    .map_or(usize::max_value(), |x| x as usize);
 
-        if self.incoming_handshakes == server_config.accept_buffer as usize
+        let refusal = if self.incoming_handshakes == server_config.accept_buffer as usize
             || self.reject_new_connections
             || self.is_full()
         {
-            debug!("rejecting connection due to full accept buffer");
+            debug!(%remote, "rejecting connection due to full accept buffer");
+            Some(RefusalReason::ServerBusy)
+        } else if self.incoming_connections
+            >= server_config
+                .max_connections
+                .map_or(usize::max_value(), |x| x as usize)
+        {
+            debug!(%remote, "refusing connection due to connection limit");
+            Some(RefusalReason::ConnectionLimit)
+        } else if self.incoming_by_ip.get(&remote.ip()).cloned().unwrap_or(0)
+            >= server_config
+                .max_connections_per_ip
+                .unwrap_or(u32::max_value())
+        {
+            debug!(%remote, "refusing connection due to per-IP connection limit");
+            Some(RefusalReason::ConnectionLimitPerIp)
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            self.connections_refused += 1;
+            if let Some(ref observer) = server_config.refusal_observer {
+                observer.refused(remote, reason);
+            }
+            let error = match reason {
+                RefusalReason::ServerBusy => TransportError::SERVER_BUSY(""),
+                _ => TransportErrorCode::CONNECTION_REFUSED.into(),
+            };
             self.transmits.push_back(Transmit {
                 destination: remote,
                 ecn: None,
                 contents: initial_close(
+                    version,
                     crypto,
                     header_crypto,
                     &src_cid,
                     &temp_loc_cid,
                     0,
-                    TransportError::SERVER_BUSY(""),
+                    error,
                 ),
             });
             return None;
         }
 
-        if dst_cid.len() < 8
-            && (!server_config.use_stateless_retry || dst_cid.len() != self.config.local_cid_len)
+        // Tokens from past Retries are honored even once they're no longer required, so clients
+        // that were asked for one can still connect
+        let mut retry_cid = None;
+        let token_data = match server_config.token_format {
+            Some(ref format) => format.check(&remote, &token),
+            None => token::check(self.token_key.as_ref().unwrap(), &remote, &token),
+        };
+        if let Some((token_dst_cid, token_issued)) = token_data {
+            let expires = token_issued + Duration::from_micros(server_config.retry_token_lifetime);
+            if expires > self.config.clock.system_time() {
+                retry_cid = Some(token_dst_cid);
+            } else {
+                trace!("ignoring expired token");
+            }
+        }
+
+        // Clients which have proven their address with a token are exempt from the global limit,
+        // which guards against floods from spoofed addresses
+        self.handshake_rate.roll(now);
+        let rate_limited = self.handshake_rate.for_ip(remote.ip())
+            >= server_config
+                .handshake_rate_limit_per_ip
+                .unwrap_or(u32::max_value())
+            || (self.handshake_rate.total
+                >= server_config
+                    .handshake_rate_limit
+                    .unwrap_or(u32::max_value())
+                && !(server_config.retry_rate_limited && retry_cid.is_some()));
+        if rate_limited && (!server_config.retry_rate_limited || retry_cid.is_some()) {
+            debug!(%remote, "dropping connection attempt due to handshake rate limit");
+            self.connections_refused += 1;
+            if let Some(ref observer) = server_config.refusal_observer {
+                observer.refused(remote, RefusalReason::RateLimit);
+            }
+            return None;
+        }
+
+        if dst_cid.len() < 8 && (retry_cid.is_none() || dst_cid.len() != self.config.local_cid_len)
         {
             debug!(
                 "rejecting connection due to invalid DCID length {}",
@@ -536,6 +710,7 @@ where
                 destination: remote,
                 ecn: None,
                 contents: initial_close(
+                    version,
                     crypto,
                     header_crypto,
                     &src_cid,
@@ -547,53 +722,36 @@ where
             return None;
         }
 
-        let mut retry_cid = None;
-        if server_config.use_stateless_retry {
-            if let Some((token_dst_cid, token_issued)) =
-                token::check(self.token_key.as_ref().unwrap(), &remote, &token)
-            {
-                let expires = token_issued
-                    + Duration::from_micros(
-                        self.server_config.as_ref().unwrap().retry_token_lifetime,
-                    );
-                if expires > SystemTime::now() {
-                    retry_cid = Some(token_dst_cid);
-                } else {
-                    trace!("sending stateless retry due to expired token");
+        if (require_retry || rate_limited) && retry_cid.is_none() {
+            trace!("sending stateless retry");
+            let issued = self.config.clock.system_time();
+            let token = match server_config.token_format {
+                Some(ref format) => format.generate(&remote, &dst_cid, issued),
+                None => {
+                    token::generate(self.token_key.as_ref().unwrap(), &remote, &dst_cid, issued)
                 }
-            } else {
-                trace!("sending stateless retry due to invalid token");
-            }
-            if retry_cid.is_none() {
-                let token = token::generate(
-                    self.token_key.as_ref().unwrap(),
-                    &remote,
-                    &dst_cid,
-                    SystemTime::now(),
-                );
-                let mut buf = Vec::new();
-                let header = Header::Retry {
-                    src_cid: temp_loc_cid,
-                    dst_cid: src_cid,
-                    orig_dst_cid: dst_cid,
-                };
-                let encode = header.encode(&mut buf);
-                encode.finish::<S::Keys, <S::Keys as Keys>::HeaderKeys>(
-                    &mut buf,
-                    header_crypto,
-                    None,
-                );
-                buf.put_slice(&token);
+            };
+            let mut buf = Vec::new();
+            let header = Header::Retry {
+                version,
+                src_cid: temp_loc_cid,
+                dst_cid: src_cid,
+                orig_dst_cid: dst_cid,
+            };
+            let encode = header.encode(&mut buf);
+            encode.finish::<S::Keys, <S::Keys as Keys>::HeaderKeys>(&mut buf, header_crypto, None);
+            buf.put_slice(&token);
 
-                self.transmits.push_back(Transmit {
-                    destination: remote,
-                    ecn: None,
-                    contents: buf.into(),
-                });
-                return None;
-            }
+            self.transmits.push_back(Transmit {
+                destination: remote,
+                ecn: None,
+                contents: buf.into(),
+            });
+            self.retries_sent += 1;
+            return None;
         }
 
+        self.handshake_rate.record(remote.ip());
         let (ch, mut conn) = self
             .add_connection(
                 dst_cid,
@@ -608,10 +766,19 @@ where
         if dst_cid.len() != 0 {
             self.connection_ids_initial.insert(dst_cid, ch);
         }
+        if retry_cid.is_some() {
+            conn.remote_address_validated();
+        }
         match conn.handle_first_packet(now, remote, ecn, packet_number as u64, packet, rest) {
             Ok(()) => {
                 trace!(id = ch.0, icid = %dst_cid, "connection incoming");
                 self.incoming_handshakes += 1;
+                self.connections[ch].handshaking = true;
+                self.connections[ch].incoming = true;
+                self.half_open += 1;
+                self.incoming_connections += 1;
+                self.connections_accepted += 1;
+                *self.incoming_by_ip.entry(remote.ip()).or_insert(0) += 1;
                 Some((ch, conn))
             }
             Err(e) => {
@@ -622,6 +789,7 @@ where
                         destination: remote,
                         ecn: None,
                         contents: initial_close(
+                            version,
                             crypto,
                             header_crypto,
                             &src_cid,
@@ -650,6 +818,41 @@ where
         self.reject_new_connections = true;
     }
 
+    /// Number of incoming connections which haven't completed the handshake yet
+    ///
+    /// Compared against `ServerConfig::retry_threshold`.
+    pub fn half_open_connections(&self) -> usize {
+        self.half_open
+    }
+
+    /// Number of Retry packets sent to validate client addresses
+    pub fn retries_sent(&self) -> u64 {
+        self.retries_sent
+    }
+
+    /// Number of incoming connections refused due to load, connection limits or handshake rate
+    /// limits
+    ///
+    /// Set `ServerConfig::refusal_observer` to learn the address and reason of each.
+    pub fn connections_refused(&self) -> u64 {
+        self.connections_refused
+    }
+
+    /// Number of incoming connections whose handshake was begun
+    pub fn connections_accepted(&self) -> u64 {
+        self.connections_accepted
+    }
+
+    /// Number of Version Negotiation packets sent to clients using unsupported versions
+    pub fn version_negotiations_sent(&self) -> u64 {
+        self.version_negotiations_sent
+    }
+
+    /// Number of connections currently known to the endpoint, including those being drained
+    pub fn open_connections(&self) -> usize {
+        self.connections.len()
+    }
+
     #[cfg(test)]
     pub(crate) fn known_connections(&self) -> usize {
         let x = self.connections.len();
@@ -710,11 +913,47 @@ pub(crate) struct ConnectionMeta {
     /// Only needed to support connections with zero-length CIDs, which cannot migrate, so we don't
     /// bother keeping it up to date.
     initial_remote: SocketAddr,
+    /// Whether this is an incoming connection which hasn't completed the handshake yet
+    handshaking: bool,
+    /// Whether this is an incoming connection, counted against the connection limits
+    incoming: bool,
     /// Reset token provided by the peer for the CID we're currently sending to, and the address
     /// being sent to
     reset_token: Option<(SocketAddr, ResetToken)>,
 }
 
+/// Handshakes begun in the current one-second window
+#[derive(Default)]
+struct HandshakeRate {
+    window_start: Option<Instant>,
+    total: u32,
+    by_ip: HashMap<IpAddr, u32>,
+}
+
+impl HandshakeRate {
+    /// Start a new window if the current one has passed
+    fn roll(&mut self, now: Instant) {
+        let expired = match self.window_start {
+            Some(start) => now.saturating_duration_since(start) >= Duration::from_secs(1),
+            None => true,
+        };
+        if expired {
+            self.window_start = Some(now);
+            self.total = 0;
+            self.by_ip.clear();
+        }
+    }
+
+    fn record(&mut self, ip: IpAddr) {
+        self.total += 1;
+        *self.by_ip.entry(ip).or_insert(0) += 1;
+    }
+
+    fn for_ip(&self, ip: IpAddr) -> u32 {
+        self.by_ip.get(&ip).cloned().unwrap_or(0)
+    }
+}
+
 fn reset_token_for<H>(key: &H, id: &ConnectionId) -> ResetToken
 where
     H: crypto::HmacKey,
@@ -869,6 +1108,12 @@ pub enum ConnectError {
     /// Try a larger `EndpointConfig::local_cid_len`.
     #[error(display = "too many connections")]
     TooManyConnections,
+    /// The endpoint is already connected to the remote address
+    ///
+    /// Without connection IDs, packets of multiple connections to the same remote can't be told
+    /// apart. Try a nonzero `EndpointConfig::local_cid_len`.
+    #[error(display = "already connected to remote address")]
+    RemoteInUse,
     /// The domain name supplied was malformed
     #[error(display = "invalid DNS name: {}", _0)]
     InvalidDnsName(String),
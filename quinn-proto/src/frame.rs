@@ -124,6 +124,8 @@ frame_types! {
     CONNECTION_CLOSE = 0x1c,
     APPLICATION_CLOSE = 0x1d,
     // DATAGRAM
+    IMMEDIATE_ACK = 0xac,
+    ACK_FREQUENCY = 0xaf,
 }
 
 const STREAM_TYS: RangeInclusive<u64> = RangeInclusive::new(0x08, 0x0f);
@@ -151,6 +153,8 @@ pub enum Frame {
     PathResponse(u64),
     Close(Close),
     Datagram(Datagram),
+    AckFrequency(AckFrequency),
+    ImmediateAck,
     Invalid { ty: Type, reason: &'static str },
 }
 
@@ -190,6 +194,8 @@ impl Frame {
             Crypto(_) => Type::CRYPTO,
             NewToken { .. } => Type::NEW_TOKEN,
             Datagram(_) => Type(*DATAGRAM_TYS.start()),
+            AckFrequency(_) => Type::ACK_FREQUENCY,
+            ImmediateAck => Type::IMMEDIATE_ACK,
             Invalid { ty, .. } => ty,
         }
     }
@@ -629,6 +635,17 @@ impl Iter {
             Type::NEW_TOKEN => Frame::NewToken {
                 token: self.take_len()?,
             },
+            Type::ACK_FREQUENCY => Frame::AckFrequency(AckFrequency {
+                sequence: self.bytes.get_var()?,
+                packet_tolerance: self.bytes.get_var()?,
+                update_max_ack_delay: self.bytes.get_var()?,
+                ignore_order: match self.bytes.get::<u8>()? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(IterErr::Malformed),
+                },
+            }),
+            Type::IMMEDIATE_ACK => Frame::ImmediateAck,
             _ => {
                 if let Some(s) = ty.stream() {
                     Frame::Stream(Stream {
@@ -815,6 +832,33 @@ impl Datagram {
     }
 }
 
+/// A request for the peer to change how often it sends acknowledgements
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AckFrequency {
+    /// Orders requests, which may arrive out of order
+    pub sequence: u64,
+    /// Number of ack-eliciting packets to receive before sending an acknowledgement
+    pub packet_tolerance: u64,
+    /// Longest time to delay an acknowledgement, in microseconds
+    pub update_max_ack_delay: u64,
+    /// Whether to delay acknowledgements of reordered packets like any other
+    pub ignore_order: bool,
+}
+
+impl FrameStruct for AckFrequency {
+    const SIZE_BOUND: usize = 2 + 8 + 8 + 8 + 1;
+}
+
+impl AckFrequency {
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        out.write(Type::ACK_FREQUENCY); // 2 bytes
+        out.write_var(self.sequence); // <= 8 bytes
+        out.write_var(self.packet_tolerance); // <= 8 bytes
+        out.write_var(self.update_max_ack_delay); // <= 8 bytes
+        out.write(self.ignore_order as u8); // 1 byte
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -845,4 +889,27 @@ mod test {
             ref x => panic!("incorrect frame {:?}", x),
         }
     }
+
+    #[test]
+    fn ack_frequency_coding() {
+        let frame = AckFrequency {
+            sequence: 3,
+            packet_tolerance: 10,
+            update_max_ack_delay: 25_000,
+            ignore_order: true,
+        };
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        buf.write(Type::IMMEDIATE_ACK);
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        match frames[0] {
+            Frame::AckFrequency(x) => assert_eq!(x, frame),
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+        match frames[1] {
+            Frame::ImmediateAck => {}
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+    }
 }
@@ -17,17 +17,27 @@
 
 use std::{fmt, net::SocketAddr, ops, time::Duration};
 
+mod ack_frequency;
 mod assembler;
 #[doc(hidden)]
 pub mod coding;
+mod mtud;
+mod pacing;
 mod packet;
+#[cfg(feature = "qlog")]
+pub mod qlog;
 mod range_set;
+#[cfg(feature = "sim")]
+pub mod sim;
 mod spaces;
+#[cfg(feature = "packet-tap")]
+pub mod tap;
 #[cfg(all(test, feature = "rustls"))]
 mod tests;
 mod transport_parameters;
 mod varint;
 
+pub use transport_parameters::PeerParams;
 pub use varint::{VarInt, VarIntBoundsExceeded};
 
 mod timer;
@@ -35,10 +45,13 @@ pub use timer::{Timer, TimerTable, TimerTableIter, TimerTableIterMut};
 
 mod connection;
 pub use crate::connection::{
-    ConnectionError, DatagramSender, DatagramTooLarge, Event, SendDatagramError, TimerSetting,
-    TimerUpdate,
+    AmplificationLimit, ConnectionError, ConnectionStats, DatagramSender, DatagramTooLarge, Event,
+    KeyPhaseStats, PathStats, RttEstimator, SendDatagramError, TelemetryEvent, TimerSetting,
+    TimerUpdate, ZeroRttStatus,
 };
 
+pub mod congestion;
+
 pub mod crypto;
 
 mod frame;
@@ -50,8 +63,9 @@ pub use crate::endpoint::{ConnectError, ConnectionHandle, DatagramEvent};
 
 mod shared;
 pub use crate::shared::{
-    ConfigError, ConnectionEvent, ConnectionId, EcnCodepoint, EndpointConfig, EndpointEvent,
-    TransportConfig,
+    AckFrequencyConfig, Clock, ConfigError, ConnectionEvent, ConnectionId, ConnectionIdGenerator,
+    EcnCodepoint, EndpointConfig, EndpointEvent, RefusalObserver, RefusalReason, StdClock,
+    TokenFormat, TransportConfig,
 };
 
 mod streams;
@@ -81,13 +95,15 @@ mod rustls_impls {
     pub type Endpoint = generic::Endpoint<crypto::rustls::TlsSession>;
     /// A `ServerConfig` containing server-side rustls configuration
     pub type ServerConfig = generic::ServerConfig<crypto::rustls::TlsSession>;
+
+    pub use crate::crypto::rustls::AuthenticationData;
 }
 
 #[cfg(feature = "rustls")]
 pub use crate::rustls_impls::*;
 
 /// The QUIC protocol version implemented
-const VERSION: u32 = 0xff00_0018;
+pub const VERSION: u32 = 0xff00_0018;
 
 /// Whether an endpoint was the initiator of a connection
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
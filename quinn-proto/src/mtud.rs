@@ -0,0 +1,295 @@
+//! Datagram packetization layer path MTU discovery (DPLPMTUD), see RFC 8899
+
+use std::{
+    cmp,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, trace};
+
+/// Searches for the largest packet size a path supports, and detects when it shrinks
+///
+/// Starts out from a base size which every path must support, then binary searches up to a limit by
+/// sending padded probe packets. Lost probes don't count as congestion. If larger packets keep
+/// getting lost while smaller ones get through, the path is assumed to have become a black hole
+/// for them and the MTU drops back to the base.
+#[derive(Debug, Clone)]
+pub(crate) struct MtuDiscovery {
+    /// Size every path is assumed to support
+    base_mtu: u16,
+    /// Largest size known to get through
+    current_mtu: u16,
+    /// Largest size to probe for
+    max_mtu: u16,
+    state: State,
+    /// Number of loss events of packets larger than `base_mtu` since one was acknowledged
+    suspicious_losses: u32,
+}
+
+impl MtuDiscovery {
+    /// Construct a search for sizes between `base_mtu` and `max_mtu`
+    pub(crate) fn new(base_mtu: u16, max_mtu: u16) -> Self {
+        let max_mtu = cmp::max(base_mtu, max_mtu);
+        Self {
+            base_mtu,
+            current_mtu: base_mtu,
+            max_mtu,
+            state: State::Searching(Search::new(max_mtu)),
+            suspicious_losses: 0,
+        }
+    }
+
+    /// Largest size known to get through the path
+    pub(crate) fn current_mtu(&self) -> u16 {
+        self.current_mtu
+    }
+
+    /// Lower the limit of the search, as the peer won't accept packets larger than `max`
+    pub(crate) fn set_peer_max(&mut self, max: u64) {
+        let max = cmp::min(max, u64::from(u16::max_value())) as u16;
+        self.max_mtu = cmp::max(self.base_mtu, cmp::min(self.max_mtu, max));
+        if let State::Searching(ref mut search) = self.state {
+            search.upper = cmp::min(search.upper, u32::from(self.max_mtu) + 1);
+        }
+    }
+
+    /// The size of the probe to send now, if any
+    pub(crate) fn poll_probe(&mut self, now: Instant) -> Option<u16> {
+        if let State::Idle { next_search } = self.state {
+            if now < next_search {
+                return None;
+            }
+            trace!(
+                from = self.current_mtu,
+                to = self.max_mtu,
+                "searching for MTU"
+            );
+            self.state = State::Searching(Search::new(self.max_mtu));
+        }
+        let candidate = match self.state {
+            State::Searching(ref search) if search.in_flight.is_none() => {
+                search.candidate(self.current_mtu, self.max_mtu)
+            }
+            _ => return None,
+        };
+        if candidate <= self.current_mtu {
+            // The peer's limit leaves nothing to search for
+            self.state = State::Idle {
+                next_search: now + SEARCH_INTERVAL,
+            };
+            return None;
+        }
+        Some(candidate)
+    }
+
+    /// A probe of `size` bytes was sent as packet `number`
+    pub(crate) fn on_probe_sent(&mut self, number: u64, size: u16) {
+        if let State::Searching(ref mut search) = self.state {
+            search.in_flight = Some((number, size));
+        }
+    }
+
+    /// Packet `number` of `size` bytes was acknowledged
+    pub(crate) fn on_acked(&mut self, now: Instant, number: u64, size: u16) {
+        if size > self.base_mtu {
+            self.suspicious_losses = 0;
+        }
+        let search = match self.state {
+            State::Searching(ref mut search) => search,
+            State::Idle { .. } => return,
+        };
+        match search.in_flight {
+            Some((probe, size)) if probe == number => {
+                trace!(size, "MTU probe acknowledged");
+                self.current_mtu = size;
+                search.in_flight = None;
+                search.lost = 0;
+            }
+            _ => return,
+        }
+        self.continue_search(now);
+    }
+
+    /// Packet `number` was lost, returning whether it was a probe
+    pub(crate) fn on_lost(&mut self, now: Instant, number: u64) -> bool {
+        let search = match self.state {
+            State::Searching(ref mut search) => search,
+            State::Idle { .. } => return false,
+        };
+        match search.in_flight {
+            Some((probe, size)) if probe == number => {
+                trace!(size, "MTU probe lost");
+                search.in_flight = None;
+                search.lost += 1;
+                if search.lost >= MAX_PROBES {
+                    search.upper = u32::from(size);
+                    search.lost = 0;
+                    self.continue_search(now);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Packets other than probes were lost, the largest being `largest_lost` bytes
+    pub(crate) fn on_loss_event(&mut self, now: Instant, largest_lost: u16) {
+        if largest_lost <= self.base_mtu {
+            return;
+        }
+        self.suspicious_losses += 1;
+        if self.suspicious_losses < BLACK_HOLE_THRESHOLD {
+            return;
+        }
+        debug!(
+            mtu = self.current_mtu,
+            "black hole detected, falling back to base MTU"
+        );
+        self.current_mtu = self.base_mtu;
+        self.suspicious_losses = 0;
+        self.state = State::Idle {
+            next_search: now + SEARCH_INTERVAL,
+        };
+    }
+
+    fn continue_search(&mut self, now: Instant) {
+        if let State::Searching(ref search) = self.state {
+            if search.upper - u32::from(self.current_mtu) > u32::from(MIN_SEARCH_STEP) {
+                return;
+            }
+        }
+        debug!(mtu = self.current_mtu, "MTU search complete");
+        self.state = State::Idle {
+            next_search: now + SEARCH_INTERVAL,
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum State {
+    /// Waiting for the next search, which raises the MTU if the path changed to permit it
+    Idle {
+        next_search: Instant,
+    },
+    Searching(Search),
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Search {
+    /// Smallest size assumed not to get through
+    ///
+    /// Wider than a size, as every size up to `u16::max_value()` may get through.
+    upper: u32,
+    /// Packet number and size of the outstanding probe
+    in_flight: Option<(u64, u16)>,
+    /// Number of probes of the current candidate size which were lost
+    lost: u32,
+}
+
+impl Search {
+    /// Start a search up to and including `max`
+    fn new(max: u16) -> Self {
+        Self {
+            upper: u32::from(max) + 1,
+            in_flight: None,
+            lost: 0,
+        }
+    }
+
+    /// The size to probe next
+    ///
+    /// Tries `max` first, as paths commonly support it, then halfway between the known good and
+    /// bad sizes.
+    fn candidate(&self, current: u16, max: u16) -> u16 {
+        if self.upper > u32::from(max) {
+            return max;
+        }
+        current + ((self.upper - u32::from(current)) / 2) as u16
+    }
+}
+
+/// Number of lost probes of a size after which it is deemed too large
+const MAX_PROBES: u32 = 3;
+/// Search range below which the search stops
+const MIN_SEARCH_STEP: u16 = 20;
+/// Number of loss events of large packets, without any large packet being acknowledged, after
+/// which larger packets are assumed not to get through anymore
+const BLACK_HOLE_THRESHOLD: u32 = 3;
+/// Time between searches, which find increases in the path MTU
+const SEARCH_INTERVAL: Duration = Duration::from_secs(600);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a search over a path which drops packets larger than `path_mtu`
+    fn search(mtud: &mut MtuDiscovery, now: Instant, path_mtu: u16) -> u32 {
+        let mut number = 0;
+        while let Some(size) = mtud.poll_probe(now) {
+            mtud.on_probe_sent(number, size);
+            if size <= path_mtu {
+                mtud.on_acked(now, number, size);
+            } else {
+                assert!(mtud.on_lost(now, number));
+            }
+            number += 1;
+        }
+        number as u32
+    }
+
+    #[test]
+    fn binary_search() {
+        let now = Instant::now();
+        let mut mtud = MtuDiscovery::new(1200, 1500);
+        let probes = search(&mut mtud, now, 1400);
+        assert!(mtud.current_mtu() <= 1400 && mtud.current_mtu() > 1400 - MIN_SEARCH_STEP);
+        assert!(probes < 20, "{} probes sent", probes);
+        // No new search until the interval passes
+        assert_eq!(mtud.poll_probe(now + Duration::from_secs(1)), None);
+        assert!(mtud.poll_probe(now + SEARCH_INTERVAL).is_some());
+    }
+
+    #[test]
+    fn peer_limit() {
+        let now = Instant::now();
+        let mut mtud = MtuDiscovery::new(1200, 1500);
+        mtud.set_peer_max(1300);
+        search(&mut mtud, now, 1500);
+        assert_eq!(mtud.current_mtu(), 1300);
+    }
+
+    #[test]
+    fn largest_size() {
+        let now = Instant::now();
+        let mut mtud = MtuDiscovery::new(1200, u16::max_value());
+        mtud.set_peer_max(u64::from(u16::max_value()));
+        search(&mut mtud, now, u16::max_value());
+        assert_eq!(mtud.current_mtu(), u16::max_value());
+    }
+
+    #[test]
+    fn black_hole() {
+        let now = Instant::now();
+        let mut mtud = MtuDiscovery::new(1200, 1500);
+        search(&mut mtud, now, 1500);
+        assert_eq!(mtud.current_mtu(), 1500);
+
+        // Losses of small packets are no cause for suspicion
+        for _ in 0..BLACK_HOLE_THRESHOLD {
+            mtud.on_loss_event(now, 1200);
+        }
+        assert_eq!(mtud.current_mtu(), 1500);
+        // Nor are losses of large packets while others get through
+        for _ in 0..BLACK_HOLE_THRESHOLD {
+            mtud.on_loss_event(now, 1500);
+            mtud.on_acked(now, 100, 1500);
+        }
+        assert_eq!(mtud.current_mtu(), 1500);
+
+        for _ in 0..BLACK_HOLE_THRESHOLD {
+            mtud.on_loss_event(now, 1500);
+        }
+        assert_eq!(mtud.current_mtu(), 1200);
+        assert_eq!(mtud.poll_probe(now), None);
+    }
+}
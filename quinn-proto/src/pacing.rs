@@ -0,0 +1,117 @@
+//! Pacing of packet transmissions
+
+use std::{
+    cmp,
+    time::{Duration, Instant},
+};
+
+/// Spreads the transmission of packets over time, at a rate set by the congestion controller
+///
+/// Implemented as a token bucket holding up to a few milliseconds' worth of data, so that short
+/// bursts are still sent at once.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Pacer {
+    /// Number of bytes which may be sent without waiting
+    tokens: u64,
+    /// When `tokens` was last refilled
+    last_refill: Instant,
+}
+
+impl Pacer {
+    /// Construct a pacer permitting an initial burst of `MIN_BURST_SIZE` packets
+    pub(crate) fn new(now: Instant, mtu: u16) -> Self {
+        Self {
+            tokens: MIN_BURST_SIZE * u64::from(mtu),
+            last_refill: now,
+        }
+    }
+
+    /// When a packet of `size` bytes may be sent at `rate` bytes per second, or `None` if now
+    pub(crate) fn delay(&mut self, now: Instant, rate: u64, size: u16) -> Option<Instant> {
+        if rate == 0 {
+            return None;
+        }
+        let size = u64::from(size);
+        let capacity = cmp::min(
+            cmp::max(
+                rate.saturating_mul(BURST_INTERVAL.as_micros() as u64) / 1_000_000,
+                MIN_BURST_SIZE * size,
+            ),
+            MAX_BURST_SIZE * size,
+        );
+        let elapsed = if now > self.last_refill {
+            now - self.last_refill
+        } else {
+            Duration::new(0, 0)
+        };
+        let new_tokens = rate.saturating_mul(elapsed.as_micros() as u64) / 1_000_000;
+        // Leave `last_refill` alone until whole tokens accumulate, so slow rates still progress
+        if new_tokens > 0 {
+            self.tokens = self.tokens.saturating_add(new_tokens);
+            self.last_refill = now;
+        }
+        self.tokens = cmp::min(self.tokens, capacity);
+        if self.tokens >= size {
+            return None;
+        }
+
+        let missing = size - self.tokens;
+        // Round up, so the tokens have arrived when the timer fires
+        let wait = Duration::from_micros(missing * 1_000_000 / rate + 1);
+        Some(self.last_refill + wait)
+    }
+
+    /// A packet of `size` bytes was sent
+    pub(crate) fn on_transmit(&mut self, size: u16) {
+        self.tokens = self.tokens.saturating_sub(u64::from(size));
+    }
+}
+
+/// Duration of transmissions which may be sent in a single burst
+const BURST_INTERVAL: Duration = Duration::from_millis(2);
+/// Number of packets which may always be sent in a single burst
+const MIN_BURST_SIZE: u64 = 10;
+/// Number of packets which may never be exceeded by a single burst
+const MAX_BURST_SIZE: u64 = 256;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spreads_packets() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(start, 1200);
+        // 1.2MB/s, one packet per millisecond
+        let rate = 1_200_000;
+        for _ in 0..MIN_BURST_SIZE {
+            assert_eq!(pacer.delay(start, rate, 1200), None);
+            pacer.on_transmit(1200);
+        }
+        assert_eq!(
+            pacer.delay(start, rate, 1200),
+            Some(start + Duration::from_micros(1001))
+        );
+
+        let now = start + Duration::from_millis(1);
+        assert_eq!(pacer.delay(now, rate, 1200), None);
+        pacer.on_transmit(1200);
+        assert!(pacer.delay(now, rate, 1200).is_some());
+
+        // Tokens don't accumulate past the burst capacity
+        let now = now + Duration::from_secs(1);
+        for _ in 0..MIN_BURST_SIZE {
+            assert_eq!(pacer.delay(now, rate, 1200), None);
+            pacer.on_transmit(1200);
+        }
+        assert!(pacer.delay(now, rate, 1200).is_some());
+    }
+
+    #[test]
+    fn unlimited() {
+        let now = Instant::now();
+        let mut pacer = Pacer::new(now, 1200);
+        pacer.on_transmit(12_000);
+        assert_eq!(pacer.delay(now, 0, 1200), None);
+    }
+}
@@ -7,7 +7,7 @@ use crate::{
     coding::{self, BufExt, BufMutExt},
     crypto,
     shared::ConnectionId,
-    MAX_CID_SIZE, VERSION,
+    MAX_CID_SIZE,
 };
 
 // Due to packet number encryption, it is impossible to fully decode a header
@@ -30,9 +30,10 @@ impl PartialDecode {
     pub(crate) fn new(
         bytes: BytesMut,
         local_cid_len: usize,
+        supported_versions: &[u32],
     ) -> Result<(Self, Option<BytesMut>), PacketDecodeError> {
         let mut buf = io::Cursor::new(bytes);
-        let plain_header = PlainHeader::decode(&mut buf, local_cid_len)?;
+        let plain_header = PlainHeader::decode(&mut buf, local_cid_len, supported_versions)?;
         let dgram_len = buf.get_ref().len();
         let packet_len = plain_header
             .payload_len()
@@ -111,6 +112,7 @@ impl PartialDecode {
         } = self;
 
         if let Initial {
+            version,
             dst_cid,
             src_cid,
             token_pos,
@@ -125,6 +127,7 @@ impl PartialDecode {
             let token = header_data.slice(token_pos.start..token_pos.end);
             return Ok(Packet {
                 header: Header::Initial {
+                    version,
                     dst_cid,
                     src_cid,
                     token,
@@ -137,21 +140,25 @@ impl PartialDecode {
 
         let header = match plain_header {
             Long {
+                version,
                 ty,
                 dst_cid,
                 src_cid,
                 ..
             } => Header::Long {
+                version,
                 ty,
                 dst_cid,
                 src_cid,
                 number: Self::decrypt_header(&mut buf, header_crypto.unwrap())?,
             },
             Retry {
+                version,
                 dst_cid,
                 src_cid,
                 orig_dst_cid,
             } => Header::Retry {
+                version,
                 dst_cid,
                 src_cid,
                 orig_dst_cid,
@@ -228,18 +235,21 @@ impl Packet {
 #[derive(Debug, Clone)]
 pub(crate) enum Header {
     Initial {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         token: Bytes,
         number: PacketNumber,
     },
     Long {
+        version: u32,
         ty: LongType,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         number: PacketNumber,
     },
     Retry {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         orig_dst_cid: ConnectionId,
@@ -263,13 +273,14 @@ impl Header {
         let start = w.len();
         match *self {
             Initial {
+                version,
                 ref dst_cid,
                 ref src_cid,
                 ref token,
                 number,
             } => {
                 w.write(u8::from(LongHeaderType::Initial) | number.tag());
-                w.write(VERSION);
+                w.write(version);
                 Self::encode_cids(w, dst_cid, src_cid);
                 w.write_var(token.len() as u64);
                 w.put_slice(token);
@@ -282,13 +293,14 @@ impl Header {
                 }
             }
             Long {
+                version,
                 ty,
                 ref dst_cid,
                 ref src_cid,
                 number,
             } => {
                 w.write(u8::from(LongHeaderType::Standard(ty)) | number.tag());
-                w.write(VERSION);
+                w.write(version);
                 Self::encode_cids(w, dst_cid, src_cid);
                 w.write::<u16>(0); // Placeholder for payload length; see `set_payload_length`
                 number.encode(w);
@@ -299,12 +311,13 @@ impl Header {
                 }
             }
             Retry {
+                version,
                 ref dst_cid,
                 ref src_cid,
                 ref orig_dst_cid,
             } => {
                 w.write(u8::from(LongHeaderType::Retry));
-                w.write(VERSION);
+                w.write(version);
                 Self::encode_cids(w, dst_cid, src_cid);
                 w.write(orig_dst_cid.len() as u8);
                 w.put_slice(orig_dst_cid);
@@ -477,18 +490,21 @@ impl PartialEncode {
 #[derive(Debug)]
 pub(crate) enum PlainHeader {
     Initial {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         token_pos: Range<usize>,
         len: u64,
     },
     Long {
+        version: u32,
         ty: LongType,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         len: u64,
     },
     Retry {
+        version: u32,
         dst_cid: ConnectionId,
         src_cid: ConnectionId,
         orig_dst_cid: ConnectionId,
@@ -528,6 +544,7 @@ impl PlainHeader {
     fn decode(
         buf: &mut io::Cursor<BytesMut>,
         local_cid_len: usize,
+        supported_versions: &[u32],
     ) -> Result<Self, PacketDecodeError> {
         let first = buf.get::<u8>()?;
         if first & LONG_HEADER_FORM == 0 {
@@ -558,7 +575,7 @@ impl PlainHeader {
                 });
             }
 
-            if version != VERSION {
+            if !supported_versions.contains(&version) {
                 return Err(PacketDecodeError::UnsupportedVersion {
                     source: src_cid,
                     destination: dst_cid,
@@ -573,6 +590,7 @@ impl PlainHeader {
 
                     let len = buf.get_var()?;
                     Ok(PlainHeader::Initial {
+                        version,
                         dst_cid,
                         src_cid,
                         token_pos: token_start..token_start + token_len,
@@ -584,12 +602,14 @@ impl PlainHeader {
                     let orig_dst_cid = Self::get_cid(buf, odcil)?;
 
                     Ok(PlainHeader::Retry {
+                        version,
                         dst_cid,
                         src_cid,
                         orig_dst_cid,
                     })
                 }
                 LongHeaderType::Standard(ty) => Ok(PlainHeader::Long {
+                    version,
                     ty,
                     dst_cid,
                     src_cid,
@@ -849,7 +869,7 @@ mod tests {
     fn header_encoding() {
         use crate::{
             crypto::{ring::Crypto, Keys},
-            Side,
+            Side, VERSION,
         };
 
         let dcid = ConnectionId::new(&hex!("06b858ec6f80452b"));
@@ -857,6 +877,7 @@ mod tests {
         let client_header_crypto = client_crypto.header_keys();
         let mut buf = Vec::new();
         let header = Header::Initial {
+            version: VERSION,
             number: PacketNumber::U8(0),
             src_cid: ConnectionId::new(&[]),
             dst_cid: dcid,
@@ -881,7 +902,9 @@ mod tests {
 
         let server_crypto = Crypto::new_initial(&dcid, Side::Server);
         let server_header_crypto = server_crypto.header_keys();
-        let decode = PartialDecode::new(buf.as_slice().into(), 0).unwrap().0;
+        let decode = PartialDecode::new(buf.as_slice().into(), 0, &[VERSION])
+            .unwrap()
+            .0;
         let mut packet = decode.finish(Some(&server_header_crypto)).unwrap();
         assert_eq!(
             packet.header_data[..],
@@ -0,0 +1,291 @@
+//! Tracing of connections in the qlog format, for visualization with tools like qvis
+//!
+//! Enabled by the `qlog` feature. A `QlogFactory` set in `TransportConfig::qlog` supplies a
+//! writer for each new connection, to which events are streamed as JSON text sequences
+//! (`.sqlog`). `QlogDir` writes one file per connection to a directory.
+
+use std::{
+    fmt::{self, Write as _},
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{
+    congestion::ControllerState,
+    connection::RttEstimator,
+    packet::SpaceId,
+    tap::{Direction, FrameSummary, PacketSummary, PacketType},
+    ConnectionId, Side,
+};
+
+/// Supplies the destination of each connection's qlog trace
+pub trait QlogFactory: fmt::Debug + Send + Sync {
+    /// Construct a writer for a new connection, or `None` not to trace it
+    ///
+    /// `initial_dst_cid` is recorded as the trace's ODCID, as in `tap::PacketTapFactory::build`.
+    fn writer(
+        &self,
+        side: Side,
+        initial_dst_cid: &ConnectionId,
+    ) -> Option<Box<dyn io::Write + Send>>;
+}
+
+/// Writes each connection's trace to `<initial_dst_cid>_<side>.sqlog` in a directory
+#[derive(Debug, Clone)]
+pub struct QlogDir(pub PathBuf);
+
+impl QlogFactory for QlogDir {
+    fn writer(
+        &self,
+        side: Side,
+        initial_dst_cid: &ConnectionId,
+    ) -> Option<Box<dyn io::Write + Send>> {
+        let side = match side {
+            Side::Client => "client",
+            Side::Server => "server",
+        };
+        let path = self.0.join(format!("{}_{}.sqlog", initial_dst_cid, side));
+        match fs::File::create(&path) {
+            Ok(file) => Some(Box::new(io::BufWriter::new(file))),
+            Err(e) => {
+                warn!("failed to create qlog file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// Events of a single connection, as they're written
+pub(crate) struct QlogStream {
+    writer: Box<dyn io::Write + Send>,
+    start: Instant,
+    phase: Option<&'static str>,
+    buf: String,
+}
+
+impl QlogStream {
+    pub(crate) fn new(
+        writer: Box<dyn io::Write + Send>,
+        side: Side,
+        initial_dst_cid: &ConnectionId,
+        now: Instant,
+    ) -> io::Result<Self> {
+        let mut this = Self {
+            writer,
+            start: now,
+            phase: None,
+            buf: String::new(),
+        };
+        let side = match side {
+            Side::Client => "client",
+            Side::Server => "server",
+        };
+        let _ = write!(
+            this.buf,
+            "{{\"qlog_version\":\"0.3\",\"qlog_format\":\"JSON-SEQ\",\"title\":\"quinn\",\
+             \"trace\":{{\"vantage_point\":{{\"type\":\"{}\"}},\
+             \"common_fields\":{{\"ODCID\":\"{}\",\"time_format\":\"relative\",\"reference_time\":0}}}}}}",
+            side, initial_dst_cid
+        );
+        this.flush_record()?;
+        Ok(this)
+    }
+
+    /// A packet was sent or received
+    pub(crate) fn packet(&mut self, packet: &PacketSummary) -> io::Result<()> {
+        let name = match packet.direction {
+            Direction::Sent => "transport:packet_sent",
+            Direction::Received => "transport:packet_received",
+        };
+        self.begin(packet.time, name);
+        let _ = write!(
+            self.buf,
+            "{{\"header\":{{\"packet_type\":\"{}\"",
+            packet_type(packet.ty)
+        );
+        if let Some(number) = packet.number {
+            let _ = write!(self.buf, ",\"packet_number\":{}", number);
+        }
+        let _ = write!(
+            self.buf,
+            "}},\"raw\":{{\"length\":{}}},\"frames\":[",
+            packet.size
+        );
+        for (i, frame) in packet.frames.iter().enumerate() {
+            if i != 0 {
+                self.buf.push(',');
+            }
+            write_frame(&mut self.buf, frame);
+        }
+        self.buf.push_str("]}");
+        self.end()
+    }
+
+    /// A packet was declared lost
+    pub(crate) fn packet_lost(
+        &mut self,
+        now: Instant,
+        space: SpaceId,
+        number: u64,
+    ) -> io::Result<()> {
+        self.begin(now, "recovery:packet_lost");
+        let ty = match space {
+            SpaceId::Initial => "initial",
+            SpaceId::Handshake => "handshake",
+            SpaceId::Data => "1RTT",
+        };
+        let _ = write!(
+            self.buf,
+            "{{\"header\":{{\"packet_type\":\"{}\",\"packet_number\":{}}}}}",
+            ty, number
+        );
+        self.end()
+    }
+
+    /// Loss recovery or congestion control state may have changed
+    pub(crate) fn metrics_updated(
+        &mut self,
+        now: Instant,
+        rtt: &RttEstimator,
+        congestion: &ControllerState,
+        bytes_in_flight: u64,
+    ) -> io::Result<()> {
+        self.begin(now, "recovery:metrics_updated");
+        if let Some(smoothed) = rtt.smoothed() {
+            let _ = write!(
+                self.buf,
+                "{{\"min_rtt\":{},\"smoothed_rtt\":{},\"latest_rtt\":{},\"rtt_variance\":{},",
+                millis(rtt.min()),
+                millis(smoothed),
+                millis(rtt.latest()),
+                millis(rtt.var())
+            );
+        } else {
+            self.buf.push('{');
+        }
+        let _ = write!(
+            self.buf,
+            "\"congestion_window\":{},\"bytes_in_flight\":{}}}",
+            congestion.window, bytes_in_flight
+        );
+        self.end()?;
+
+        match congestion.phase {
+            Some(phase) if congestion.phase != self.phase => {
+                self.phase = congestion.phase;
+                self.begin(now, "recovery:congestion_state_updated");
+                let _ = write!(self.buf, "{{\"new\":\"{}\"}}", phase);
+                self.end()?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self, now: Instant, name: &str) {
+        let time = millis(now.saturating_duration_since(self.start));
+        let _ = write!(
+            self.buf,
+            "{{\"time\":{},\"name\":\"{}\",\"data\":",
+            time, name
+        );
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.buf.push('}');
+        self.flush_record()
+    }
+
+    /// Write out the buffered record as an element of a JSON text sequence
+    fn flush_record(&mut self) -> io::Result<()> {
+        self.writer.write_all(b"\x1e")?;
+        self.writer.write_all(self.buf.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl Drop for QlogStream {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+fn packet_type(ty: PacketType) -> &'static str {
+    match ty {
+        PacketType::Initial => "initial",
+        PacketType::Handshake => "handshake",
+        PacketType::ZeroRtt => "0RTT",
+        PacketType::Retry => "retry",
+        PacketType::OneRtt => "1RTT",
+        PacketType::VersionNegotiate => "version_negotiation",
+    }
+}
+
+fn write_frame(out: &mut String, frame: &FrameSummary) {
+    let _ = match *frame {
+        FrameSummary::Padding { length } => {
+            write!(out, "{{\"frame_type\":\"padding\",\"length\":{}}}", length)
+        }
+        FrameSummary::Ack { ref ranges } => {
+            out.push_str("{\"frame_type\":\"ack\",\"acked_ranges\":[");
+            for (i, range) in ranges.iter().enumerate() {
+                if i != 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "[{},{}]", range.start, range.end - 1);
+            }
+            out.push_str("]}");
+            Ok(())
+        }
+        FrameSummary::Crypto { offset, length } => write!(
+            out,
+            "{{\"frame_type\":\"crypto\",\"offset\":{},\"length\":{}}}",
+            offset, length
+        ),
+        FrameSummary::Stream {
+            id,
+            offset,
+            length,
+            fin,
+        } => write!(
+            out,
+            "{{\"frame_type\":\"stream\",\"stream_id\":{},\"offset\":{},\"length\":{},\"fin\":{}}}",
+            id.0, offset, length, fin
+        ),
+        FrameSummary::Datagram { length } => {
+            write!(out, "{{\"frame_type\":\"datagram\",\"length\":{}}}", length)
+        }
+        FrameSummary::ResetStream { id, error_code } => write!(
+            out,
+            "{{\"frame_type\":\"reset_stream\",\"stream_id\":{},\"error_code\":{}}}",
+            id.0, error_code
+        ),
+        FrameSummary::Close {
+            application,
+            error_code,
+        } => {
+            let ty = if application {
+                "application_close"
+            } else {
+                "connection_close"
+            };
+            write!(
+                out,
+                "{{\"frame_type\":\"{}\",\"error_code\":{}}}",
+                ty, error_code
+            )
+        }
+        FrameSummary::Other(ref name) => {
+            write!(out, "{{\"frame_type\":\"{}\"}}", name.to_ascii_lowercase())
+        }
+    };
+}
+
+fn millis(x: Duration) -> f64 {
+    x.as_secs_f64() * 1000.0
+}
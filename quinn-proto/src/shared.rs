@@ -1,4 +1,9 @@
-use std::{cmp, fmt, net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    cmp, fmt,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
 use bytes::BytesMut;
 use err_derive::Error;
@@ -6,9 +11,10 @@ use rand::{Rng, RngCore};
 use tracing::warn;
 
 use crate::{
+    congestion,
     crypto::{self, ClientConfig as _, ServerConfig as _},
     packet::PartialDecode,
-    VarInt, MAX_CID_SIZE, RESET_TOKEN_SIZE,
+    transport_parameters, VarInt, MAX_CID_SIZE, REM_CID_COUNT, RESET_TOKEN_SIZE, VERSION,
 };
 
 /// Parameters governing the core QUIC state machine
@@ -77,25 +83,63 @@ pub struct TransportConfig {
     /// Maximum number of tail loss probes before an RTO fires.
     pub max_tlps: u32,
     /// Maximum reordering in packet number space before FACK style loss detection considers a
-    /// packet lost.
+    /// packet lost. Must be nonzero.
+    ///
+    /// Raise on paths which reorder packets heavily, to avoid spurious retransmissions.
     pub packet_threshold: u32,
     /// Maximum reordering in time space before time based loss detection considers a packet lost.
     /// 0.16 format, added to 1
     pub time_threshold: u16,
-    /// The length of the peer’s delayed ack timer (μs).
-    pub delayed_ack_timeout: u64,
+    /// Factor by which the probe timeout grows after each consecutive probe timeout. Must be
+    /// nonzero.
+    ///
+    /// Defaults to 2. Lower values recover faster from loss bursts on paths with stable RTTs, such
+    /// as within a datacenter; 1 disables the backoff.
+    pub pto_backoff: u32,
+    /// Longest time to delay acknowledging ack-eliciting packets (μs)
+    ///
+    /// Advertised to the peer, rounded up to milliseconds, so it can account for the delay in loss
+    /// recovery. Must be less than 2^14 ms.
+    pub max_ack_delay: u64,
+    /// Number of ack-eliciting packets to receive before acknowledging them without waiting for
+    /// `max_ack_delay`. Must be nonzero.
+    ///
+    /// Defaults to 1, acknowledging every such packet immediately. Higher values reduce the number
+    /// of acknowledgements sent during bulk transfers. The peer may override both settings with the
+    /// ACK frequency extension.
+    pub ack_eliciting_threshold: u64,
+    /// Ask the peer to acknowledge packets less often, if it supports the ACK frequency extension
+    ///
+    /// Fewer acknowledgements save CPU time and bandwidth on the reverse path of bulk transfers, at
+    /// the cost of slower loss detection and window growth. Defaults to `None`, which leaves the
+    /// peer's behavior unchanged.
+    pub ack_frequency: Option<AckFrequencyConfig>,
     /// The RTT used before an RTT sample is taken (μs)
     pub initial_rtt: u64,
 
+    /// Size of the UDP payloads every path is assumed to carry, before path MTU discovery (bytes)
+    ///
+    /// Must be at least 1200, which every QUIC path supports. Defaults to 1232, which fits in the
+    /// smallest IPv6 MTU. Raise it only where every path is known to carry larger packets, as it
+    /// is also what black hole detection falls back to.
+    pub initial_mtu: u16,
+    /// Largest UDP payload to accept from the peer, and to probe for with path MTU discovery (bytes)
+    ///
+    /// Advertised to the peer as the `max_packet_size` transport parameter. Must be at least
+    /// `initial_mtu`. Defaults to 1452, which fits in an Ethernet frame over IPv6. Raise it for
+    /// paths with jumbo frames, or lower it for tunnels with extra overhead.
+    pub max_udp_payload_size: u16,
     /// The sender’s maximum UDP payload size. Does not include UDP or IP overhead.
     ///
     /// Used for calculating initial and minimum congestion windows.
     pub max_datagram_size: u64,
     /// Default limit on the amount of outstanding data in bytes.
     ///
-    /// Recommended value: `min(10 * max_datagram_size, max(2 * max_datagram_size, 14720))`
+    /// Recommended value: `min(10 * max_datagram_size, max(2 * max_datagram_size, 14720))`. Larger
+    /// values shorten slow start on paths with a large bandwidth-delay product, such as satellite
+    /// links.
     pub initial_window: u64,
-    /// Default minimum congestion window.
+    /// Default minimum congestion window. Must be nonzero and at most `initial_window`.
     ///
     /// Recommended value: `2 * max_datagram_size`.
     pub minimum_window: u64,
@@ -103,6 +147,16 @@ pub struct TransportConfig {
     pub loss_reduction_factor: u16,
     /// Number of consecutive PTOs after which network is considered to be experiencing persistent congestion.
     pub persistent_congestion_threshold: u32,
+    /// How to construct the congestion controller of each path
+    ///
+    /// Defaults to `congestion::NewRenoFactory`. Like the other transport parameters, this can be
+    /// set per connection through the `ClientConfig` passed when connecting.
+    pub congestion_controller_factory: Arc<dyn congestion::ControllerFactory>,
+    /// Whether to spread packets over each round trip at the congestion controller's pacing rate
+    ///
+    /// Sending a whole window at once causes bursts of loss on paths with shallow buffers.
+    /// Defaults to true.
+    pub pacing: bool,
     /// Number of milliseconds of inactivity before sending a keep-alive packet
     ///
     /// Keep-alive packets prevent an inactive but otherwise healthy connection from timing out.
@@ -111,13 +165,40 @@ pub struct TransportConfig {
     /// enabled for the connection to be preserved. Must be set lower than the idle_timeout of both
     /// peers to be effective.
     pub keep_alive_interval: u32,
+    /// Number of 1-RTT packets to send before automatically switching to fresh keys
+    ///
+    /// Limits how much data is protected by any one key, for long-lived connections. 0 to disable,
+    /// which is the default.
+    pub key_update_packets: u64,
+    /// Number of 1-RTT bytes to send before automatically switching to fresh keys
+    ///
+    /// Like `key_update_packets`, whichever is reached first. 0 to disable, which is the default.
+    pub key_update_bytes: u64,
+    /// Number of milliseconds after which to switch to a fresh connection ID for outgoing packets
+    ///
+    /// Makes it harder for observers to link the packets of a long-lived connection, e.g. across
+    /// NAT rebindings. Retired IDs are replaced by the peer. 0 to disable, which is the default.
+    pub cid_rotation_interval: u64,
+    /// Maximum number of connection IDs to accept from the peer
+    ///
+    /// Advertised to the peer as the `active_connection_id_limit` transport parameter. Spare IDs
+    /// are used for migration and rotation. Must be at least 2.
+    pub active_connection_id_limit: u64,
     /// Maximum quantity of out-of-order crypto layer data to buffer
     pub crypto_buffer_size: usize,
     /// Whether the implementation is permitted to set the spin bit on this connection
     ///
     /// This allows passive observers to easily judge the round trip time of a connection, which can
-    /// be useful for network administration but sacrifices a small amount of privacy.
+    /// be useful for network administration but sacrifices a small amount of privacy. As the spec
+    /// requires, the bit is still randomized on one in eight connections where it's permitted; see
+    /// `Connection::spin_enabled`. Defaults to true.
     pub allow_spin: bool,
+    /// Whether to use explicit congestion notification (ECN) on outgoing packets
+    ///
+    /// Packets are marked ECT(0), and congestion experienced marks reported by the peer reduce the
+    /// congestion window as loss would, without the retransmission. Use is validated on each path,
+    /// and stops if the peer's counts show that marks are lost or mangled. Defaults to true.
+    pub allow_ecn: bool,
     /// Maximum number of incoming application datagram bytes to buffer, or None to disable
     /// datagrams
     ///
@@ -131,6 +212,27 @@ pub struct TransportConfig {
     /// than the link, or even the underlying hardware, can transmit them. This limits the amount of
     /// memory that may be consumed in that case.
     pub datagram_send_buffer_size: usize,
+    /// Application-defined transport parameters to send to the peer, as (id, value) pairs
+    ///
+    /// Lets extensions which quinn-proto knows nothing about negotiate during the handshake. Ids
+    /// must be unique and must not collide with a parameter quinn-proto interprets itself. Those
+    /// sent by the peer are exposed by `Connection::peer_custom_params`.
+    pub custom_transport_params: Vec<(u16, Vec<u8>)>,
+    /// Whether to record `TelemetryEvent`s, retrieved with `Connection::poll_telemetry`
+    ///
+    /// Events accumulate until polled, so leave this disabled, the default, unless they'll be
+    /// consumed.
+    pub telemetry: bool,
+    /// Where to write a qlog trace of each connection, if anywhere
+    ///
+    /// Tracing serializes every packet, so it's best reserved for debugging. Defaults to `None`.
+    #[cfg(feature = "qlog")]
+    pub qlog: Option<Arc<dyn crate::qlog::QlogFactory>>,
+    /// What to show the packets of each connection to, if anything
+    ///
+    /// Defaults to `None`.
+    #[cfg(feature = "packet-tap")]
+    pub packet_tap: Option<Arc<dyn crate::tap::PacketTapFactory>>,
 }
 
 impl Default for TransportConfig {
@@ -153,9 +255,14 @@ impl Default for TransportConfig {
             max_tlps: 2,
             packet_threshold: 3,
             time_threshold: 0x2000, // 1/8
-            delayed_ack_timeout: 25 * 1000,
+            pto_backoff: 2,
+            max_ack_delay: 25 * 1000,
+            ack_eliciting_threshold: 1,
+            ack_frequency: None,
             initial_rtt: 500 * 1000, // 500ms per spec, intentionally distinct from EXPECTED_RTT
 
+            initial_mtu: 1232,
+            max_udp_payload_size: 1452,
             max_datagram_size: MAX_DATAGRAM_SIZE,
             initial_window: cmp::min(
                 10 * MAX_DATAGRAM_SIZE,
@@ -164,11 +271,24 @@ impl Default for TransportConfig {
             minimum_window: 2 * MAX_DATAGRAM_SIZE,
             loss_reduction_factor: 0x8000, // 1/2
             persistent_congestion_threshold: 3,
+            congestion_controller_factory: Arc::new(congestion::NewRenoFactory),
+            pacing: true,
             keep_alive_interval: 0,
+            key_update_packets: 0,
+            key_update_bytes: 0,
+            cid_rotation_interval: 0,
+            active_connection_id_limit: REM_CID_COUNT,
             crypto_buffer_size: 16 * 1024,
             allow_spin: true,
+            allow_ecn: true,
             datagram_receive_buffer_size: Some(STREAM_RWND as usize),
             datagram_send_buffer_size: 1024 * 1024,
+            custom_transport_params: Vec::new(),
+            telemetry: false,
+            #[cfg(feature = "qlog")]
+            qlog: None,
+            #[cfg(feature = "packet-tap")]
+            packet_tap: None,
         }
     }
 }
@@ -181,17 +301,90 @@ impl TransportConfig {
             ("receive_window", self.receive_window),
             ("stream_receive_window", self.stream_receive_window),
             ("idle_timeout", self.idle_timeout),
+            (
+                "active_connection_id_limit",
+                self.active_connection_id_limit,
+            ),
         ]
         .iter()
         .find(|&&(_, x)| x > VarInt::MAX.into_inner())
         {
             return Err(ConfigError::VarIntBounds(name));
         }
+        if self.initial_mtu < 1200 || self.max_udp_payload_size < self.initial_mtu {
+            return Err(ConfigError::IllegalValue(
+                "initial_mtu must be at least 1200 and at most max_udp_payload_size",
+            ));
+        }
+        if self.minimum_window == 0 || self.minimum_window > self.initial_window {
+            return Err(ConfigError::IllegalValue(
+                "minimum_window must be nonzero and at most initial_window",
+            ));
+        }
+        if self.packet_threshold == 0 {
+            return Err(ConfigError::IllegalValue(
+                "packet_threshold must be nonzero",
+            ));
+        }
+        if self.pto_backoff == 0 {
+            return Err(ConfigError::IllegalValue("pto_backoff must be nonzero"));
+        }
+        if self.max_ack_delay >= (1 << 14) * 1000 {
+            return Err(ConfigError::IllegalValue(
+                "max_ack_delay must be less than 2^14 ms",
+            ));
+        }
+        if self.ack_eliciting_threshold == 0 {
+            return Err(ConfigError::IllegalValue(
+                "ack_eliciting_threshold must be nonzero",
+            ));
+        }
+        if let Some(ref x) = self.ack_frequency {
+            if x.ack_eliciting_threshold == 0 {
+                return Err(ConfigError::IllegalValue(
+                    "ack_eliciting_threshold must be nonzero",
+                ));
+            }
+            if x.max_ack_delay >= (1 << 14) * 1000 {
+                return Err(ConfigError::IllegalValue(
+                    "max_ack_delay must be less than 2^14 ms",
+                ));
+            }
+        }
+        if self.active_connection_id_limit < 2 {
+            return Err(ConfigError::IllegalValue(
+                "active_connection_id_limit must be at least 2",
+            ));
+        }
+        self.congestion_controller_factory.validate()?;
         if self.crypto_buffer_size < 4096 {
             return Err(ConfigError::IllegalValue(
                 "crypto_buffer_size must be at least 4096",
             ));
         }
+        for (i, &(id, _)) in self.custom_transport_params.iter().enumerate() {
+            if transport_parameters::is_known_id(id)
+                || id % 31 == 27
+                || self.custom_transport_params[..i]
+                    .iter()
+                    .any(|&(x, _)| x == id)
+            {
+                return Err(ConfigError::IllegalValue(
+                    "custom transport parameter ids must be unique, unreserved, and not otherwise in use",
+                ));
+            }
+        }
+        if self
+            .custom_transport_params
+            .iter()
+            .map(|(_, value)| 4 + value.len())
+            .sum::<usize>()
+            > 32 * 1024
+        {
+            return Err(ConfigError::IllegalValue(
+                "custom transport parameters must total at most 32KiB",
+            ));
+        }
         if self.idle_timeout != 0 && u64::from(self.keep_alive_interval) >= self.idle_timeout {
             warn!(
                 "keep-alive interval {} is ineffective due to lower idle timeout {}",
@@ -202,6 +395,31 @@ impl TransportConfig {
     }
 }
 
+/// Acknowledgement behavior to request of the peer, see `TransportConfig::ack_frequency`
+#[derive(Debug, Copy, Clone)]
+pub struct AckFrequencyConfig {
+    /// Number of ack-eliciting packets the peer may receive before acknowledging them. Must be
+    /// nonzero.
+    pub ack_eliciting_threshold: u64,
+    /// Longest time the peer may delay an acknowledgement (μs)
+    ///
+    /// Raised to the minimum advertised by the peer if necessary.
+    pub max_ack_delay: u64,
+    /// Whether the peer should delay acknowledgements of reordered packets too, rather than
+    /// acknowledging them immediately to speed up loss detection
+    pub ignore_order: bool,
+}
+
+impl Default for AckFrequencyConfig {
+    fn default() -> Self {
+        Self {
+            ack_eliciting_threshold: 10,
+            max_ack_delay: 25 * 1000,
+            ignore_order: false,
+        }
+    }
+}
+
 /// Global configuration for the endpoint, affecting all connections
 ///
 /// Default values should be suitable for most internet applications.
@@ -215,16 +433,54 @@ pub struct EndpointConfig {
     /// 0, at most 3/4 * 2^(local_cid_len * 8) simultaneous connections can be supported.
     pub local_cid_len: usize,
 
+    /// How to generate local connection IDs, if not at random
+    ///
+    /// Must generate IDs of `local_cid_len` bytes.
+    pub cid_generator: Option<Arc<dyn ConnectionIdGenerator>>,
+
     /// Private key used to send authenticated connection resets to peers who were
     /// communicating with a previous instance of this endpoint.
     pub reset_key: Vec<u8>,
+
+    /// QUIC versions to accept, in order of preference
+    ///
+    /// Clients begin connections with the first, and use version negotiation to fall back to
+    /// another the server supports. Every version listed must share the wire format implemented
+    /// here, so versions other than the default are only useful to smooth over a change of version
+    /// number, such as between an experimental and a final number for the same format.
+    pub supported_versions: Vec<u32>,
+
+    /// Whether clients should begin connections with a reserved version
+    ///
+    /// Servers must answer such a version with version negotiation, so this checks that they
+    /// still do, guarding against ossification at the cost of a round trip per connection.
+    pub grease_version: bool,
+
+    /// Source of the current time, where the caller doesn't supply it
+    ///
+    /// Used to start the timers of outgoing connections and to issue and expire address
+    /// validation tokens. Reads the operating system's clocks by default.
+    pub clock: Arc<dyn Clock>,
+
+    /// Seed for the random numbers used by the endpoint and its connections
+    ///
+    /// Connection IDs, greasing, path challenges and the like are drawn from a generator seeded
+    /// with this, so that fuzzing and simulation runs can be reproduced. Cryptographic keys are
+    /// generated by the crypto session and unaffected. `None`, the default, seeds from the
+    /// operating system's entropy source, which should be kept outside of testing.
+    pub rng_seed: Option<[u8; 32]>,
 }
 
 impl fmt::Debug for EndpointConfig {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt.debug_struct("EndpointConfig")
             .field("local_cid_len", &self.local_cid_len)
+            .field("cid_generator", &self.cid_generator)
             .field("reset_key", &"[ elided ]")
+            .field("supported_versions", &self.supported_versions)
+            .field("grease_version", &self.grease_version)
+            .field("clock", &self.clock)
+            .field("rng_seed", &self.rng_seed.map(|_| "[ elided ]"))
             .finish()
     }
 }
@@ -235,7 +491,12 @@ impl Default for EndpointConfig {
         rand::thread_rng().fill_bytes(&mut reset_key);
         Self {
             local_cid_len: 8,
+            cid_generator: None,
             reset_key,
+            supported_versions: vec![VERSION],
+            grease_version: false,
+            clock: Arc::new(StdClock),
+            rng_seed: None,
         }
     }
 }
@@ -247,10 +508,134 @@ impl EndpointConfig {
                 "local_cid_len must be at most 20",
             ));
         }
+        if let Some(ref generator) = self.cid_generator {
+            if generator.cid_len() != self.local_cid_len {
+                return Err(ConfigError::IllegalValue(
+                    "cid_generator must generate IDs of local_cid_len bytes",
+                ));
+            }
+        }
+        if self.supported_versions.is_empty() {
+            return Err(ConfigError::IllegalValue(
+                "supported_versions must not be empty",
+            ));
+        }
+        // Zero marks version negotiation packets, and the reserved versions are for greasing
+        if self
+            .supported_versions
+            .iter()
+            .any(|&x| x == 0 || is_reserved_version(x))
+        {
+            return Err(ConfigError::IllegalValue(
+                "supported_versions must not contain 0 or reserved versions",
+            ));
+        }
         Ok(())
     }
 }
 
+/// Generates the local connection IDs of an endpoint
+///
+/// Allows connection IDs to encode information such as the identity of the server, so that
+/// stateless load balancers can route packets to the right one, as in QUIC-LB.
+pub trait ConnectionIdGenerator: fmt::Debug + Send + Sync {
+    /// Generate a new connection ID, using `rng` for any randomness
+    ///
+    /// IDs must be hard to guess, and unlikely to collide with any issued before.
+    fn generate_cid(&self, rng: &mut dyn RngCore) -> ConnectionId;
+
+    /// Length of the generated connection IDs
+    fn cid_len(&self) -> usize;
+
+    /// Whether `cid`, taken from an incoming packet, could have been generated by this generator
+    ///
+    /// Packets carrying an invalid ID for an unknown connection are dropped without sending a
+    /// stateless reset. Defaults to accepting every ID.
+    fn validate(&self, cid: &ConnectionId) -> bool {
+        let _ = cid;
+        true
+    }
+}
+
+/// Source of the current time
+///
+/// Protocol logic is otherwise driven entirely by the timestamps passed in by the caller, so
+/// replacing this lets tests and simulators control time completely, and lets environments without
+/// the standard clocks supply their own.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current time, on the same monotonic clock as the timestamps passed to the endpoint
+    fn now(&self) -> Instant;
+
+    /// The current wall-clock time
+    ///
+    /// Embedded in address validation tokens, so must agree between servers sharing a
+    /// `TokenFormat`.
+    fn system_time(&self) -> SystemTime;
+}
+
+/// Reads the operating system's clocks
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StdClock;
+
+impl Clock for StdClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Whether `version` is one of those reserved to exercise version negotiation
+pub(crate) fn is_reserved_version(version: u32) -> bool {
+    version & 0x0f0f_0f0f == 0x0a0a_0a0a
+}
+
+/// A random reserved version, see `is_reserved_version`
+pub(crate) fn reserved_version<R: Rng>(rng: &mut R) -> u32 {
+    (rng.gen::<u32>() & 0xf0f0_f0f0) | 0x0a0a_0a0a
+}
+
+/// Mints and validates the address validation tokens sent in Retry packets
+///
+/// Lets a fleet of servers share a token format and keys, so that a client retried by one server
+/// can complete its handshake with another.
+pub trait TokenFormat: fmt::Debug + Send + Sync {
+    /// Produce a token for a client at `address`, whose first Initial was sent to `dst_cid`
+    fn generate(&self, address: &SocketAddr, dst_cid: &ConnectionId, issued: SystemTime)
+        -> Vec<u8>;
+
+    /// Recover the original destination connection ID and issue time from a token presented by a
+    /// client at `address`
+    ///
+    /// Returns `None` if the token wasn't minted for `address` by a trusted server. Expiry is
+    /// checked separately against `ServerConfig::retry_token_lifetime`.
+    fn check(&self, address: &SocketAddr, token: &[u8]) -> Option<(ConnectionId, SystemTime)>;
+}
+
+/// Notified of incoming connection attempts refused by a server, for monitoring
+pub trait RefusalObserver: fmt::Debug + Send + Sync {
+    /// Called when an attempt from `remote` is refused for `reason`
+    ///
+    /// Runs while the endpoint handles the packet, so it should return quickly.
+    fn refused(&self, remote: SocketAddr, reason: RefusalReason);
+}
+
+/// Why an incoming connection attempt was refused, see `RefusalObserver`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RefusalReason {
+    /// `ServerConfig::max_connections` was reached
+    ConnectionLimit,
+    /// `ServerConfig::max_connections_per_ip` was reached for the client's address
+    ConnectionLimitPerIp,
+    /// A handshake rate limit was exceeded, and the attempt dropped rather than retried
+    RateLimit,
+    /// The accept buffer was full, no connection IDs were left, or new connections are rejected;
+    /// answered with `TransportErrorCode::SERVER_BUSY`
+    ServerBusy,
+}
+
 /// Parameters governing incoming connections
 ///
 /// Default values should be suitable for most internet applications.
@@ -268,23 +653,78 @@ where
 
     /// Private key used to authenticate data included in handshake tokens.
     pub token_key: Vec<u8>,
+    /// Custom format for handshake tokens, replacing the built-in one keyed by `token_key`
+    pub token_format: Option<Arc<dyn TokenFormat>>,
     /// Whether to require clients to prove ownership of an address before committing resources.
     ///
     /// Introduces an additional round-trip to the handshake to make denial of service attacks more difficult.
     pub use_stateless_retry: bool,
     /// Microseconds after a stateless retry token was issued for which it's considered valid.
     pub retry_token_lifetime: u64,
+    /// Number of incomplete incoming handshakes at which to start requiring stateless retries
+    ///
+    /// Makes address validation kick in under load, such as during a flood of spoofed connection
+    /// attempts, while sparing clients the extra round-trip otherwise. Retries stop once enough
+    /// handshakes complete or time out. `None` by default; has no effect if `use_stateless_retry`
+    /// is set.
+    pub retry_threshold: Option<u32>,
+
+    /// Maximum number of handshakes to begin per second
+    ///
+    /// Protects the CPU-intensive cryptographic handshake from floods of connection attempts.
+    /// Excess attempts are dropped, or answered with a Retry if `retry_rate_limited` is set.
+    /// Unlimited by default.
+    pub handshake_rate_limit: Option<u32>,
+    /// Maximum number of handshakes to begin per second with any one IP address
+    ///
+    /// Unlike `handshake_rate_limit`, applies even to clients which have proven their address.
+    pub handshake_rate_limit_per_ip: Option<u32>,
+    /// Whether to answer connection attempts over the handshake rate limits with a Retry
+    ///
+    /// Clients presenting a valid token are then admitted regardless of `handshake_rate_limit`, as
+    /// they can't be spoofing their address.
+    pub retry_rate_limited: bool,
+
+    /// Maximum ratio of bytes sent to bytes received before a client's address is validated
+    ///
+    /// Limits how much a server can be abused to amplify attacks on spoofed addresses. Must be at
+    /// least 1; 3 by default, as required by the QUIC specification.
+    pub amplification_factor: u32,
+
+    /// Maximum number of incoming connections to maintain at once
+    ///
+    /// Further connection attempts are refused with `TransportErrorCode::CONNECTION_REFUSED`,
+    /// bounding the memory a server may devote to connections. Refusals are counted by
+    /// `Endpoint::connections_refused` and reported to `refusal_observer`. Unlimited by default.
+    pub max_connections: Option<u32>,
+    /// Maximum number of incoming connections to maintain at once with any one IP address
+    ///
+    /// Counted by the address each connection began with.
+    pub max_connections_per_ip: Option<u32>,
 
     /// Maximum number of incoming connections to buffer.
     ///
     /// Accepting a connection removes it from the buffer, so this does not need to be large.
     pub accept_buffer: u32,
+    /// Notified of each refused connection attempt, with the client's address and the reason
+    pub refusal_observer: Option<Arc<dyn RefusalObserver>>,
 
     /// Whether to allow clients to migrate to new addresses
     ///
     /// Improves behavior for clients that move between different internet connections or suffer NAT
     /// rebinding. Enabled by default.
     pub migration: bool,
+
+    /// IPv4 address clients should migrate to once the handshake is confirmed
+    ///
+    /// Lets a server hand connections off from e.g. a load-balanced address to one of its own.
+    /// Ignored if `EndpointConfig::local_cid_len` is zero, as connection IDs are needed to route
+    /// packets sent to it.
+    pub preferred_address_v4: Option<SocketAddrV4>,
+    /// IPv6 address clients should migrate to once the handshake is confirmed
+    ///
+    /// See `preferred_address_v4`.
+    pub preferred_address_v6: Option<SocketAddrV6>,
 }
 
 impl<S> fmt::Debug for ServerConfig<S>
@@ -296,10 +736,24 @@ where
             .field("transport", &self.transport)
             .field("crypto", &"ServerConfig { elided }")
             .field("token_key", &"[ elided ]")
+            .field("token_format", &self.token_format)
             .field("use_stateless_retry", &self.use_stateless_retry)
             .field("retry_token_lifetime", &self.retry_token_lifetime)
+            .field("retry_threshold", &self.retry_threshold)
+            .field("handshake_rate_limit", &self.handshake_rate_limit)
+            .field(
+                "handshake_rate_limit_per_ip",
+                &self.handshake_rate_limit_per_ip,
+            )
+            .field("retry_rate_limited", &self.retry_rate_limited)
+            .field("amplification_factor", &self.amplification_factor)
+            .field("max_connections", &self.max_connections)
+            .field("max_connections_per_ip", &self.max_connections_per_ip)
             .field("accept_buffer", &self.accept_buffer)
+            .field("refusal_observer", &self.refusal_observer)
             .field("migration", &self.migration)
+            .field("preferred_address_v4", &self.preferred_address_v4)
+            .field("preferred_address_v6", &self.preferred_address_v6)
             .finish()
     }
 }
@@ -319,16 +773,50 @@ where
             crypto: S::ServerConfig::new(),
 
             token_key,
+            token_format: None,
             use_stateless_retry: false,
             retry_token_lifetime: 15_000_000,
+            retry_threshold: None,
+
+            handshake_rate_limit: None,
+            handshake_rate_limit_per_ip: None,
+            retry_rate_limited: false,
 
+            amplification_factor: 3,
+            max_connections: None,
+            max_connections_per_ip: None,
             accept_buffer: 1024,
+            refusal_observer: None,
 
             migration: true,
+
+            preferred_address_v4: None,
+            preferred_address_v6: None,
         }
     }
 }
 
+impl<S> ServerConfig<S>
+where
+    S: crypto::Session,
+{
+    /// Whether a preferred address is advertised to clients of `endpoint`
+    pub(crate) fn has_preferred_address(&self, endpoint: &EndpointConfig) -> bool {
+        (self.preferred_address_v4.is_some() || self.preferred_address_v6.is_some())
+            && endpoint.local_cid_len > 0
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        self.transport.validate()?;
+        if self.amplification_factor == 0 {
+            return Err(ConfigError::IllegalValue(
+                "amplification_factor must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
 impl<S> Clone for ServerConfig<S>
 where
     S: crypto::Session,
@@ -339,10 +827,21 @@ where
             transport: self.transport.clone(),
             crypto: self.crypto.clone(),
             token_key: self.token_key.clone(),
+            token_format: self.token_format.clone(),
             use_stateless_retry: self.use_stateless_retry,
             retry_token_lifetime: self.retry_token_lifetime,
+            retry_threshold: self.retry_threshold,
+            handshake_rate_limit: self.handshake_rate_limit,
+            handshake_rate_limit_per_ip: self.handshake_rate_limit_per_ip,
+            retry_rate_limited: self.retry_rate_limited,
+            amplification_factor: self.amplification_factor,
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
             accept_buffer: self.accept_buffer,
+            refusal_observer: self.refusal_observer.clone(),
             migration: self.migration,
+            preferred_address_v4: self.preferred_address_v4,
+            preferred_address_v6: self.preferred_address_v6,
         }
     }
 }
@@ -459,6 +958,8 @@ pub(crate) enum EndpointEventInner {
     NeedIdentifiers(u64),
     /// Stop routing connection ID for this sequence number to the connection
     RetireConnectionId(u64),
+    /// The handshake completed
+    Established,
 }
 
 /// Protocol-level identifier for a connection.
@@ -471,8 +972,9 @@ pub struct ConnectionId {
 }
 
 impl ConnectionId {
-    pub(crate) fn new(bytes: &[u8]) -> Self {
-        debug_assert!(bytes.len() <= MAX_CID_SIZE);
+    /// Construct a connection ID from at most 20 bytes
+    pub fn new(bytes: &[u8]) -> Self {
+        assert!(bytes.len() <= MAX_CID_SIZE);
         let mut res = Self {
             len: bytes.len() as u8,
             bytes: [0; MAX_CID_SIZE],
@@ -0,0 +1,397 @@
+//! Deterministic simulation of endpoints communicating over an unreliable network
+//!
+//! Enabled by the `sim` feature. A `Simulation` owns a set of endpoints and their connections, and
+//! carries datagrams between them over a link with configurable delay, reordering, loss and
+//! bandwidth. Time is virtual: each step jumps straight to the next timer expiry or delivery, so
+//! scenarios spanning minutes of connection time run in milliseconds, and the network's behavior
+//! is entirely determined by a seed.
+//!
+//! Applications drive connections through `Node::connection_mut` between steps, exactly as they
+//! would through any other `Connection`. Endpoints should be configured with the simulation's
+//! `clock`, so that everything they do happens in virtual time, and with an `rng_seed` drawn from
+//! it, so that reusing the simulation's seed reproduces a run, up to the randomness of the crypto
+//! session.
+
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::BytesMut;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    crypto,
+    endpoint::{ConnectError, ConnectionHandle, DatagramEvent},
+    generic::{ClientConfig, Connection, Endpoint},
+    shared::{Clock, EcnCodepoint},
+    timer::TimerTable,
+    TimerSetting, Transmit,
+};
+
+/// Behavior of the simulated network
+#[derive(Debug, Clone)]
+pub struct LinkConfig {
+    /// One-way propagation delay
+    pub delay: Duration,
+    /// Upper bound on extra delay chosen uniformly for each datagram, which reorders them
+    pub jitter: Duration,
+    /// Probability of each datagram being lost, from 0 to 1
+    ///
+    /// Values outside that range are clamped to it, and NaN is treated as 0.
+    pub loss: f64,
+    /// Rate at which each endpoint can send, in bytes per second, or `None` for no limit
+    ///
+    /// Datagrams queue behind those sent before them until the link is free.
+    pub bandwidth: Option<u64>,
+    /// Size of the largest datagram carried; larger ones are dropped
+    pub mtu: usize,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(10),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            bandwidth: None,
+            mtu: 1500,
+        }
+    }
+}
+
+/// A set of endpoints sharing a simulated network
+pub struct Simulation<S>
+where
+    S: crypto::Session,
+{
+    /// Behavior of the network, which may be changed between steps
+    pub link: LinkConfig,
+    rng: StdRng,
+    now: Instant,
+    clock: Arc<SimClock>,
+    nodes: Vec<Node<S>>,
+    /// Datagrams in flight, by arrival time and order of sending
+    in_flight: BTreeMap<(Instant, u64), Datagram>,
+    next_datagram: u64,
+}
+
+impl<S> Simulation<S>
+where
+    S: crypto::Session,
+{
+    /// Create an empty network whose random behavior is determined by `seed`
+    pub fn new(link: LinkConfig, seed: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            link,
+            rng: StdRng::seed_from_u64(seed),
+            now,
+            clock: Arc::new(SimClock {
+                start: now,
+                start_system: SystemTime::now(),
+                now: Mutex::new(now),
+            }),
+            nodes: Vec::new(),
+            in_flight: BTreeMap::new(),
+            next_datagram: 0,
+        }
+    }
+
+    /// Attach `endpoint` to the network at `addr`, returning the index of its `Node`
+    pub fn add_endpoint(&mut self, addr: SocketAddr, endpoint: Endpoint<S>) -> usize {
+        assert!(
+            self.nodes.iter().all(|x| x.addr != addr),
+            "address already in use"
+        );
+        self.nodes.push(Node {
+            endpoint,
+            addr,
+            connections: HashMap::new(),
+            timers: HashMap::new(),
+            incoming: VecDeque::new(),
+            link_free: self.now,
+        });
+        self.nodes.len() - 1
+    }
+
+    /// The endpoint attached with index `index`
+    pub fn node_mut(&mut self, index: usize) -> &mut Node<S> {
+        &mut self.nodes[index]
+    }
+
+    /// The current virtual time
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// A clock reading the virtual time, for use as `EndpointConfig::clock`
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    /// A seed determined by the simulation's, for use as `EndpointConfig::rng_seed`
+    pub fn rng_seed(&mut self) -> [u8; 32] {
+        self.rng.gen()
+    }
+
+    /// Deliver datagrams and handle timeouts due now, then advance time to the next event
+    ///
+    /// Returns false if nothing remains to happen, e.g. because every connection has closed.
+    pub fn step(&mut self) -> bool {
+        let now = self.now;
+        while let Some(&key) = self.in_flight.keys().next() {
+            if key.0 > now {
+                break;
+            }
+            let datagram = self.in_flight.remove(&key).unwrap();
+            if let Some(node) = self
+                .nodes
+                .iter_mut()
+                .find(|x| x.addr == datagram.destination)
+            {
+                node.receive(now, datagram.source, datagram.ecn, datagram.contents);
+            }
+        }
+
+        let mut transmits = Vec::new();
+        for (index, node) in self.nodes.iter_mut().enumerate() {
+            node.drive(now, &mut |x| transmits.push((index, x)));
+        }
+        for (index, transmit) in transmits {
+            self.send(index, transmit);
+        }
+
+        let next_delivery = self.in_flight.keys().next().map(|x| x.0);
+        let next_timer = self
+            .nodes
+            .iter()
+            .flat_map(|x| x.timers.values())
+            .flat_map(|x| x.iter().filter_map(|(_, t)| *t))
+            .min();
+        let next = match (next_delivery, next_timer) {
+            (None, None) => return false,
+            (Some(t), None) | (None, Some(t)) => t,
+            (Some(x), Some(y)) => cmp::min(x, y),
+        };
+        self.now = cmp::max(self.now, next);
+        *self.clock.now.lock().unwrap() = self.now;
+        true
+    }
+
+    /// Step until nothing remains to happen
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    /// Step until `done` returns true, or nothing remains to happen
+    ///
+    /// Returns whether `done` was satisfied.
+    pub fn run_until(&mut self, mut done: impl FnMut(&mut Self) -> bool) -> bool {
+        loop {
+            if done(self) {
+                return true;
+            }
+            if !self.step() {
+                return done(self);
+            }
+        }
+    }
+
+    fn send(&mut self, source: usize, transmit: Transmit) {
+        let node = &mut self.nodes[source];
+        let len = transmit.contents.len();
+        if len > self.link.mtu {
+            return;
+        }
+        let sent = match self.link.bandwidth {
+            Some(rate) => {
+                let start = cmp::max(self.now, node.link_free);
+                node.link_free =
+                    start + Duration::from_nanos(len as u64 * 1_000_000_000 / cmp::max(rate, 1));
+                node.link_free
+            }
+            None => self.now,
+        };
+        // Written so that NaN counts as no loss
+        let loss = if self.link.loss > 0.0 {
+            self.link.loss.min(1.0)
+        } else {
+            0.0
+        };
+        if self.rng.gen_bool(loss) {
+            return;
+        }
+        let jitter = self.link.jitter.as_nanos() as u64;
+        let jitter = if jitter == 0 {
+            0
+        } else {
+            self.rng.gen_range(0, jitter + 1)
+        };
+        let arrival = sent + self.link.delay + Duration::from_nanos(jitter);
+        self.in_flight.insert(
+            (arrival, self.next_datagram),
+            Datagram {
+                source: node.addr,
+                destination: transmit.destination,
+                ecn: transmit.ecn,
+                contents: transmit.contents[..].into(),
+            },
+        );
+        self.next_datagram += 1;
+    }
+}
+
+/// An endpoint attached to a `Simulation`, and its connections
+pub struct Node<S>
+where
+    S: crypto::Session,
+{
+    endpoint: Endpoint<S>,
+    addr: SocketAddr,
+    connections: HashMap<ConnectionHandle, Connection<S>>,
+    timers: HashMap<ConnectionHandle, TimerTable<Option<Instant>>>,
+    incoming: VecDeque<ConnectionHandle>,
+    /// When the link will have finished sending the datagrams already queued on it
+    link_free: Instant,
+}
+
+impl<S> Node<S>
+where
+    S: crypto::Session,
+{
+    /// The address of this endpoint
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The endpoint itself
+    pub fn endpoint_mut(&mut self) -> &mut Endpoint<S> {
+        &mut self.endpoint
+    }
+
+    /// Initiate a connection to the endpoint at `remote`
+    pub fn connect(
+        &mut self,
+        config: ClientConfig<S>,
+        remote: SocketAddr,
+        server_name: &str,
+    ) -> Result<ConnectionHandle, ConnectError> {
+        let (ch, conn) = self.endpoint.connect(config, remote, server_name)?;
+        self.connections.insert(ch, conn);
+        Ok(ch)
+    }
+
+    /// Take the next connection initiated by a peer, if any
+    pub fn accept(&mut self) -> Option<ConnectionHandle> {
+        let ch = self.incoming.pop_front()?;
+        self.endpoint.accept();
+        Some(ch)
+    }
+
+    /// A connection which hasn't yet been drained
+    pub fn connection_mut(&mut self, ch: ConnectionHandle) -> Option<&mut Connection<S>> {
+        self.connections.get_mut(&ch)
+    }
+
+    fn receive(
+        &mut self,
+        now: Instant,
+        remote: SocketAddr,
+        ecn: Option<EcnCodepoint>,
+        data: BytesMut,
+    ) {
+        match self.endpoint.handle(now, remote, ecn, data) {
+            Some((ch, DatagramEvent::NewConnection(conn))) => {
+                self.connections.insert(ch, conn);
+                self.incoming.push_back(ch);
+            }
+            Some((ch, DatagramEvent::ConnectionEvent(event))) => {
+                if let Some(conn) = self.connections.get_mut(&ch) {
+                    conn.handle_event(event);
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn drive(&mut self, now: Instant, transmit: &mut dyn FnMut(Transmit)) {
+        while let Some(x) = self.endpoint.poll_transmit() {
+            transmit(x);
+        }
+
+        let mut drained = Vec::new();
+        for (&ch, conn) in &mut self.connections {
+            let timers = self.timers.entry(ch).or_default();
+            for (timer, setting) in timers.iter_mut() {
+                match *setting {
+                    Some(time) if time <= now => {
+                        *setting = None;
+                        conn.handle_timeout(now, timer);
+                    }
+                    _ => {}
+                }
+            }
+
+            loop {
+                let mut progress = false;
+                while let Some(event) = conn.poll_endpoint_events() {
+                    progress = true;
+                    if event.is_drained() {
+                        drained.push(ch);
+                    }
+                    if let Some(event) = self.endpoint.handle_event(ch, event) {
+                        conn.handle_event(event);
+                    }
+                }
+                while let Some(x) = conn.poll_transmit(now) {
+                    progress = true;
+                    transmit(x);
+                }
+                if !progress {
+                    break;
+                }
+            }
+
+            while let Some(update) = conn.poll_timers() {
+                timers[update.timer] = match update.update {
+                    TimerSetting::Stop => None,
+                    TimerSetting::Start(time) => Some(time),
+                };
+            }
+        }
+
+        for ch in drained {
+            self.connections.remove(&ch);
+            self.timers.remove(&ch);
+        }
+    }
+}
+
+/// Virtual time, shared between a `Simulation` and its endpoints
+#[derive(Debug)]
+struct SimClock {
+    start: Instant,
+    start_system: SystemTime,
+    now: Mutex<Instant>,
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system + (self.now() - self.start)
+    }
+}
+
+struct Datagram {
+    source: SocketAddr,
+    destination: SocketAddr,
+    ecn: Option<EcnCodepoint>,
+    contents: BytesMut,
+}
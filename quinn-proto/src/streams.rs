@@ -21,6 +21,9 @@ pub(crate) struct Streams {
     pub(crate) next_remote: [u64; 2],
     // Next to report to the application, once opened
     next_reported_remote: [u64; 2],
+    /// Whether to record streams whose state was discarded in `closed`
+    pub(crate) record_closed: bool,
+    pub(crate) closed: Vec<StreamId>,
 }
 
 impl Streams {
@@ -33,6 +36,8 @@ impl Streams {
             max_remote: [max_remote_bi, max_remote_uni],
             next_remote: [0, 0],
             next_reported_remote: [0, 0],
+            record_closed: false,
+            closed: Vec::new(),
         };
 
         for dir in Dir::iter() {
@@ -158,11 +163,13 @@ impl Streams {
     ///
     /// Called when one side of a stream transitions to a closed state
     pub(crate) fn maybe_cleanup(&mut self, id: StreamId) {
+        let mut removed = false;
         match self.send.entry(id) {
             hash_map::Entry::Vacant(_) => {}
             hash_map::Entry::Occupied(e) => {
                 if e.get().is_closed() {
                     e.remove_entry();
+                    removed = true;
                 }
             }
         }
@@ -171,9 +178,17 @@ impl Streams {
             hash_map::Entry::Occupied(e) => {
                 if e.get().is_closed() {
                     e.remove_entry();
+                    removed = true;
                 }
             }
         }
+        if removed
+            && self.record_closed
+            && !self.send.contains_key(&id)
+            && !self.recv.contains_key(&id)
+        {
+            self.closed.push(id);
+        }
     }
 
     pub(crate) fn recv_mut(&mut self, id: StreamId) -> Option<&mut Recv> {
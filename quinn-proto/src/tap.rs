@@ -0,0 +1,220 @@
+//! Inspection of the packets of individual connections, for debugging and custom analyzers
+//!
+//! Enabled by the `packet-tap` feature, which `qlog` implies. A `PacketTapFactory` set in
+//! `TransportConfig::packet_tap` may supply a `PacketTap` for each new connection, which is shown
+//! a summary of every packet sent or received once it's been decrypted, and optionally the
+//! datagrams carrying them as they appear on the wire.
+
+use std::{fmt, ops::Range, time::Instant};
+
+use bytes::Bytes;
+
+use crate::{
+    frame::{self, Frame},
+    packet::{Header, LongType},
+    ConnectionId, Side, StreamId, VarInt,
+};
+
+/// Supplies the `PacketTap` of each new connection
+pub trait PacketTapFactory: fmt::Debug + Send + Sync {
+    /// Construct a tap for a new connection, or `None` not to inspect it
+    ///
+    /// `initial_dst_cid` is the destination connection ID of the client's Initial packets, which
+    /// identifies the connection to both endpoints unless the server sent a Retry.
+    fn build(&self, side: Side, initial_dst_cid: &ConnectionId) -> Option<Box<dyn PacketTap>>;
+}
+
+/// Observer of the packets of a single connection
+pub trait PacketTap: Send {
+    /// A packet was sent or received
+    fn packet(&mut self, packet: &PacketSummary);
+
+    /// A datagram was sent or received, still encrypted
+    ///
+    /// Not called for the first datagram received by a server, which is decrypted by the
+    /// `Endpoint` before the connection exists.
+    fn datagram(&mut self, now: Instant, direction: Direction, contents: &[u8]) {
+        let _ = (now, direction, contents);
+    }
+}
+
+/// Whether a packet was sent or received
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    /// Sent to the peer
+    Sent,
+    /// Received from the peer
+    Received,
+}
+
+/// The kind of header a packet was sent with
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PacketType {
+    /// Initial packet, carrying the start of the handshake
+    Initial,
+    /// Handshake packet
+    Handshake,
+    /// 0-RTT packet, carrying application data before the handshake completes
+    ZeroRtt,
+    /// Short header packet, carrying application data
+    OneRtt,
+    /// Retry packet, asking the client to prove its address
+    Retry,
+    /// Version negotiation packet
+    VersionNegotiate,
+}
+
+/// A decrypted packet, as shown to a `PacketTap`
+#[derive(Debug, Clone)]
+pub struct PacketSummary {
+    /// When the packet was sent or received
+    pub time: Instant,
+    /// Whether the packet was sent or received
+    pub direction: Direction,
+    /// The kind of header the packet was sent with
+    pub ty: PacketType,
+    /// Packet number, for the packet types that have one
+    pub number: Option<u64>,
+    /// Size of the packet on the wire in bytes, including its header and authentication tag
+    ///
+    /// Counted the same way for sent and received packets, so both ends report the same size.
+    pub size: usize,
+    /// The frames carried by the packet, in order
+    pub frames: Vec<FrameSummary>,
+}
+
+impl PacketSummary {
+    pub(crate) fn new(
+        time: Instant,
+        direction: Direction,
+        header: &Header,
+        number: Option<u64>,
+        size: usize,
+        payload: Bytes,
+    ) -> Self {
+        let ty = match *header {
+            Header::Initial { .. } => PacketType::Initial,
+            Header::Long {
+                ty: LongType::Handshake,
+                ..
+            } => PacketType::Handshake,
+            Header::Long {
+                ty: LongType::ZeroRtt,
+                ..
+            } => PacketType::ZeroRtt,
+            Header::Short { .. } => PacketType::OneRtt,
+            Header::Retry { .. } => PacketType::Retry,
+            Header::VersionNegotiate { .. } => PacketType::VersionNegotiate,
+        };
+        let mut frames = Vec::new();
+        for frame in frame::Iter::new(payload) {
+            // Each byte of padding decodes as a frame of its own
+            if let Frame::Padding = frame {
+                if let Some(FrameSummary::Padding { ref mut length }) = frames.last_mut() {
+                    *length += 1;
+                    continue;
+                }
+            }
+            frames.push(FrameSummary::new(&frame));
+        }
+        Self {
+            time,
+            direction,
+            ty,
+            number,
+            size,
+            frames,
+        }
+    }
+}
+
+/// The parts of a frame most useful for analysis
+#[derive(Debug, Clone)]
+pub enum FrameSummary {
+    /// A run of padding
+    Padding {
+        /// Number of bytes of padding
+        length: usize,
+    },
+    /// Acknowledgement of packets
+    Ack {
+        /// The acknowledged packet numbers
+        ranges: Vec<Range<u64>>,
+    },
+    /// Handshake data
+    Crypto {
+        /// Offset of the data within the packet number space's handshake stream
+        offset: u64,
+        /// Number of bytes of data
+        length: usize,
+    },
+    /// Stream data
+    Stream {
+        /// The stream the data belongs to
+        id: StreamId,
+        /// Offset of the data within the stream
+        offset: u64,
+        /// Number of bytes of data
+        length: usize,
+        /// Whether this is the end of the stream
+        fin: bool,
+    },
+    /// Application datagram
+    Datagram {
+        /// Number of bytes of data
+        length: usize,
+    },
+    /// Abandonment of a stream by its sender
+    ResetStream {
+        /// The reset stream
+        id: StreamId,
+        /// Application-defined reason
+        error_code: VarInt,
+    },
+    /// Closure of the connection
+    Close {
+        /// Whether the application, rather than the transport, closed the connection
+        application: bool,
+        /// Reason for the closure, in the transport or application's error space
+        error_code: u64,
+    },
+    /// Any other frame, by name
+    Other(String),
+}
+
+impl FrameSummary {
+    fn new(frame: &Frame) -> Self {
+        match *frame {
+            Frame::Padding => FrameSummary::Padding { length: 1 },
+            Frame::Ack(ref x) => FrameSummary::Ack {
+                ranges: x.iter().collect(),
+            },
+            Frame::Crypto(ref x) => FrameSummary::Crypto {
+                offset: x.offset,
+                length: x.data.len(),
+            },
+            Frame::Stream(ref x) => FrameSummary::Stream {
+                id: x.id,
+                offset: x.offset,
+                length: x.data.len(),
+                fin: x.fin,
+            },
+            Frame::Datagram(ref x) => FrameSummary::Datagram {
+                length: x.data.len(),
+            },
+            Frame::ResetStream(ref x) => FrameSummary::ResetStream {
+                id: x.id,
+                error_code: x.error_code,
+            },
+            Frame::Close(frame::Close::Connection(ref x)) => FrameSummary::Close {
+                application: false,
+                error_code: x.error_code.into(),
+            },
+            Frame::Close(frame::Close::Application(ref x)) => FrameSummary::Close {
+                application: true,
+                error_code: x.error_code.into_inner(),
+            },
+            ref x => FrameSummary::Other(x.ty().to_string()),
+        }
+    }
+}
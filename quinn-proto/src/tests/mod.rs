@@ -1,8 +1,8 @@
 use std::{
     convert::TryInto,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use assert_matches::assert_matches;
@@ -13,6 +13,7 @@ use rustls::internal::msgs::enums::AlertDescription;
 use tracing::info;
 
 use super::*;
+mod plain;
 mod util;
 use util::*;
 
@@ -41,6 +42,8 @@ fn version_negotiate_server() {
             .any(|x| u32::from_be_bytes(x.try_into().unwrap()) == VERSION));
     }
     assert_matches!(server.poll_transmit(), None);
+    assert_eq!(server.version_negotiations_sent(), 1);
+    assert_eq!(server.connections_accepted(), 0);
 }
 
 #[test]
@@ -81,6 +84,75 @@ fn version_negotiate_client() {
     );
 }
 
+#[test]
+fn version_negotiation() {
+    let _guard = subscribe();
+    // Stands in for a version sharing the implemented wire format
+    const OTHER_VERSION: u32 = 0x5157_0001;
+    let endpoint_config = Arc::new(EndpointConfig {
+        supported_versions: vec![OTHER_VERSION, VERSION],
+        ..Default::default()
+    });
+
+    // The client falls back to a version the server supports
+    let mut pair = Pair::default();
+    pair.client.endpoint = Endpoint::new(endpoint_config.clone(), None).unwrap();
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(pair.client_conn_mut(client_ch).version(), VERSION);
+    assert_eq!(pair.server_conn_mut(server_ch).version(), VERSION);
+
+    // A second Version Negotiation packet, e.g. one forged to switch back, is ignored
+    let mut pair = Pair::default();
+    pair.client.endpoint = Endpoint::new(endpoint_config.clone(), None).unwrap();
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    let (time, _, ref vn) = *pair.client.inbound.back().unwrap();
+    let dst_cid_len = usize::from(vn[5]);
+    let header_len = 7 + dst_cid_len + usize::from(vn[6 + dst_cid_len]);
+    let mut forged = vn[..header_len].to_vec();
+    forged.extend_from_slice(&OTHER_VERSION.to_be_bytes());
+    pair.client.inbound.push_back((time, None, forged.into()));
+    pair.drive_client();
+    assert_eq!(pair.client_conn_mut(client_ch).version(), VERSION);
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected { .. })
+    );
+
+    // Otherwise, the client's preference is honored
+    let mut pair = Pair::new(endpoint_config, server_config());
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(pair.client_conn_mut(client_ch).version(), OTHER_VERSION);
+    assert_eq!(pair.server_conn_mut(server_ch).version(), OTHER_VERSION);
+}
+
+#[test]
+fn version_greasing() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.client.endpoint = Endpoint::new(
+        Arc::new(EndpointConfig {
+            grease_version: true,
+            ..Default::default()
+        }),
+        None,
+    )
+    .unwrap();
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    let version = u32::from_be_bytes(pair.server.inbound[0].2[1..5].try_into().unwrap());
+    assert_eq!(version & 0x0f0f_0f0f, 0x0a0a_0a0a);
+
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected { .. })
+    );
+    assert_eq!(pair.client_conn_mut(client_ch).version(), VERSION);
+}
+
 #[test]
 fn lifecycle() {
     let _guard = subscribe();
@@ -122,6 +194,31 @@ fn stateless_retry() {
     pair.connect();
 }
 
+#[test]
+fn adaptive_retry() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            retry_threshold: Some(1),
+            ..server_config()
+        },
+    );
+    // The second client arrives once the first is half-open, and must validate its address
+    pair.begin_connect(client_config());
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.server.half_open_connections(), 1);
+    assert_eq!(pair.server.retries_sent(), 1);
+
+    pair.drive();
+    assert_eq!(pair.server.half_open_connections(), 0);
+    assert_eq!(pair.server.retries_sent(), 1);
+    assert_eq!(pair.server.connections_accepted(), 2);
+    assert_eq!(pair.server.open_connections(), 2);
+}
+
 #[test]
 fn server_stateless_reset() {
     let _guard = subscribe();
@@ -184,6 +281,211 @@ fn client_stateless_reset() {
     );
 }
 
+/// Prefixes connection IDs with a fixed byte, as a load balancer might route on
+#[derive(Debug)]
+struct PrefixedCids(u8);
+
+impl ConnectionIdGenerator for PrefixedCids {
+    fn generate_cid(&self, rng: &mut dyn RngCore) -> ConnectionId {
+        let mut bytes = [0; 8];
+        rng.fill_bytes(&mut bytes);
+        bytes[0] = self.0;
+        ConnectionId::new(&bytes)
+    }
+
+    fn cid_len(&self) -> usize {
+        8
+    }
+
+    fn validate(&self, cid: &ConnectionId) -> bool {
+        cid[0] == self.0
+    }
+}
+
+#[test]
+fn cid_generator() {
+    let _guard = subscribe();
+    let endpoint_config = Arc::new(EndpointConfig {
+        cid_generator: Some(Arc::new(PrefixedCids(0x42))),
+        ..Default::default()
+    });
+    let mut pair = Pair::new(endpoint_config.clone(), server_config());
+    let (client_ch, _) = pair.connect();
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive_client();
+    assert_eq!(pair.server.inbound.back().unwrap().2[1], 0x42);
+    pair.drive();
+
+    // Only IDs the generator could have produced elicit stateless resets
+    let mut server = Endpoint::new(endpoint_config, Some(Arc::new(server_config()))).unwrap();
+    let mut packet = [0; 64];
+    packet[0] = 0x40;
+    packet[1] = 0x42;
+    assert!(server
+        .handle(pair.time, pair.client.addr, None, packet[..].into())
+        .is_none());
+    assert_matches!(server.poll_transmit(), Some(_));
+    packet[1] = 0x43;
+    assert!(server
+        .handle(pair.time, pair.client.addr, None, packet[..].into())
+        .is_none());
+    assert_matches!(server.poll_transmit(), None);
+}
+
+#[test]
+fn handshake_rate_limit() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            handshake_rate_limit: Some(1),
+            ..server_config()
+        },
+    );
+    pair.begin_connect(client_config());
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.server.known_connections(), 1);
+    assert_eq!(pair.server.retries_sent(), 0);
+
+    // The dropped client gets in once it retransmits in a later window
+    pair.drive();
+    assert_eq!(pair.server.known_connections(), 2);
+}
+
+#[test]
+fn handshake_rate_limit_retry() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            handshake_rate_limit: Some(1),
+            retry_rate_limited: true,
+            ..server_config()
+        },
+    );
+    pair.begin_connect(client_config());
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.server.known_connections(), 1);
+    assert_eq!(pair.server.retries_sent(), 1);
+
+    // Having proven its address, the retried client is admitted straight away
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.server.known_connections(), 2);
+}
+
+/// Tags tokens with a byte shared by a fleet of servers; unauthenticated, so only fit for tests
+#[derive(Debug)]
+struct TaggedTokens(u8);
+
+impl TokenFormat for TaggedTokens {
+    fn generate(&self, _: &SocketAddr, dst_cid: &ConnectionId, issued: SystemTime) -> Vec<u8> {
+        let mut token = vec![self.0, dst_cid.len() as u8];
+        token.extend_from_slice(dst_cid);
+        let secs = issued.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        token.extend_from_slice(&secs.to_be_bytes());
+        token
+    }
+
+    fn check(&self, _: &SocketAddr, token: &[u8]) -> Option<(ConnectionId, SystemTime)> {
+        if token.len() < 2 || token[0] != self.0 || token.len() != 2 + token[1] as usize + 8 {
+            return None;
+        }
+        let (cid, secs) = token[2..].split_at(token[1] as usize);
+        let issued = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(secs.try_into().unwrap()));
+        Some((ConnectionId::new(cid), issued))
+    }
+}
+
+#[test]
+fn token_format() {
+    let _guard = subscribe();
+    let server_config = ServerConfig {
+        use_stateless_retry: true,
+        token_format: Some(Arc::new(TaggedTokens(0x42))),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server_config.clone());
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.server.retries_sent(), 1);
+
+    // A sibling server sharing the token format accepts the retried client
+    pair.server.endpoint =
+        Endpoint::new(Default::default(), Some(Arc::new(server_config))).unwrap();
+    pair.drive();
+    pair.server.assert_accept();
+    assert_eq!(pair.server.retries_sent(), 0);
+}
+
+#[test]
+fn rng_seed() {
+    let _guard = subscribe();
+    // Version, connection IDs and their lengths, which are outside header protection
+    let initial_header = |seed| {
+        let config = Arc::new(EndpointConfig {
+            rng_seed: seed,
+            ..EndpointConfig::default()
+        });
+        let mut endpoint = Endpoint::new(config, None).unwrap();
+        let server_addr = "[::1]:4433".parse().unwrap();
+        let (_, mut conn) = endpoint
+            .connect(client_config(), server_addr, "localhost")
+            .unwrap();
+        let transmit = conn.poll_transmit(Instant::now()).unwrap();
+        transmit.contents[1..35].to_vec()
+    };
+    let header = initial_header(Some([7; 32]));
+    assert_eq!(initial_header(Some([7; 32])), header);
+    assert_ne!(initial_header(Some([8; 32])), header);
+    assert_ne!(initial_header(None), header);
+}
+
+/// Reads the system clocks, offset by a fixed amount
+#[derive(Debug)]
+struct SkewedClock(Duration);
+
+impl Clock for SkewedClock {
+    fn now(&self) -> Instant {
+        Instant::now() + self.0
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now() + self.0
+    }
+}
+
+#[test]
+fn clock_expires_tokens() {
+    let _guard = subscribe();
+    let server_config = ServerConfig {
+        use_stateless_retry: true,
+        token_format: Some(Arc::new(TaggedTokens(0x42))),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server_config.clone());
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.server.retries_sent(), 1);
+
+    // A sibling server whose clock is an hour ahead finds the token expired
+    let endpoint_config = Arc::new(EndpointConfig {
+        clock: Arc::new(SkewedClock(Duration::from_secs(3600))),
+        ..EndpointConfig::default()
+    });
+    pair.server.endpoint = Endpoint::new(endpoint_config, Some(Arc::new(server_config))).unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.server.retries_sent(), 1);
+    assert_eq!(pair.server.connections_accepted(), 0);
+}
+
 #[test]
 fn finish_stream() {
     let _guard = subscribe();
@@ -215,6 +517,42 @@ fn finish_stream() {
     assert_matches!(pair.server_conn_mut(server_ch).read_unordered(s), Ok(None));
 }
 
+#[test]
+fn write_chunks() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+
+    let mut chunks = [Bytes::from_static(b"hello "), Bytes::from_static(b"world")];
+    assert_eq!(
+        pair.client_conn_mut(client_ch)
+            .write_chunks(s, &mut chunks)
+            .unwrap(),
+        11
+    );
+    assert!(chunks.iter().all(|x| x.is_empty()));
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    pair.drive();
+
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
+    assert_matches!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    let mut buf = [0; 16];
+    let mut len = 0;
+    while let Some(n) = pair
+        .server_conn_mut(server_ch)
+        .read(s, &mut buf[len..])
+        .unwrap()
+    {
+        len += n;
+    }
+    assert_eq!(&buf[..len], b"hello world");
+}
+
 #[test]
 fn reset_stream() {
     let _guard = subscribe();
@@ -298,7 +636,8 @@ fn reject_self_signed_cert() {
 fn congestion() {
     let _guard = subscribe();
     let mut pair = Pair::default();
-    let (client_ch, _) = pair.connect();
+    // Fill the congestion window at once
+    let (client_ch, _) = pair.connect_with(unpaced());
 
     let initial_congestion_state = pair.client_conn_mut(client_ch).congestion_state();
     let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
@@ -365,12 +704,25 @@ fn zero_rtt_happypath() {
     info!("resuming session");
     let client_ch = pair.begin_connect(config.clone());
     assert!(pair.client_conn_mut(client_ch).has_0rtt());
+    assert_eq!(
+        pair.client_conn_mut(client_ch).zero_rtt_status(),
+        ZeroRttStatus::Pending
+    );
     let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
     const MSG: &[u8] = b"Hello, 0-RTT!";
     pair.client_conn_mut(client_ch).write(s, MSG).unwrap();
     pair.drive();
     assert!(pair.client_conn_mut(client_ch).accepted_0rtt());
+    assert_eq!(
+        pair.client_conn_mut(client_ch).zero_rtt_status(),
+        ZeroRttStatus::Accepted
+    );
+    assert!(pair.client_conn_mut(client_ch).zero_rtt_bytes_sent() > MSG.len() as u64);
     let server_ch = pair.server.assert_accept();
+    assert_eq!(
+        pair.server_conn_mut(server_ch).zero_rtt_status(),
+        ZeroRttStatus::Accepted
+    );
     assert_matches!(
         pair.server_conn_mut(server_ch).read_unordered(s),
         Ok(Some((ref data, 0))) if data == MSG
@@ -426,7 +778,16 @@ fn zero_rtt_rejection() {
     pair.client_conn_mut(client_ch).write(s, MSG).unwrap();
     pair.drive();
     assert!(!pair.client_conn_mut(client_ch).accepted_0rtt());
+    assert_eq!(
+        pair.client_conn_mut(client_ch).zero_rtt_status(),
+        ZeroRttStatus::Rejected
+    );
+    assert!(pair.client_conn_mut(client_ch).zero_rtt_bytes_sent() > 0);
     let server_conn = pair.server.assert_accept();
+    assert_eq!(
+        pair.server_conn_mut(server_conn).zero_rtt_status(),
+        ZeroRttStatus::NotAttempted
+    );
     assert_matches!(
         pair.server_conn_mut(server_conn).poll(),
         Some(Event::Connected)
@@ -468,6 +829,26 @@ fn alpn_success() {
     );
 }
 
+#[test]
+fn authentication_data() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let server_data = pair.server_conn_mut(server_ch).authentication_data();
+    assert_eq!(
+        server_data.server_name.as_ref().map(|x| &x[..]),
+        Some("localhost")
+    );
+    assert!(server_data.peer_certificates.is_none());
+    assert!(server_data.cipher_suite.is_some());
+
+    let client_data = pair.client_conn_mut(client_ch).authentication_data();
+    assert!(client_data.server_name.is_none());
+    assert_eq!(client_data.peer_certificates.map(|x| x.len()), Some(1));
+    assert_eq!(client_data.cipher_suite, server_data.cipher_suite);
+}
+
 #[test]
 fn stream_id_backpressure() {
     let _guard = subscribe();
@@ -624,6 +1005,96 @@ fn key_update_reordered() {
     assert_eq!(pair.server_conn_mut(server_ch).lost_packets(), 0);
 }
 
+#[test]
+fn force_key_update() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    assert!(pair.client_conn_mut(client_ch).force_key_update());
+    // The replaced keys are still retained
+    assert!(!pair.client_conn_mut(client_ch).force_key_update());
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+    pair.drive();
+
+    let stats = pair.client_conn_mut(client_ch).key_phase_stats();
+    assert_eq!(stats.local_updates, 1);
+    assert_eq!(stats.remote_updates, 0);
+    assert_ne!(stats.packets_sent, 0);
+    let stats = pair.server_conn_mut(server_ch).key_phase_stats();
+    assert_eq!(stats.local_updates, 0);
+    assert_eq!(stats.remote_updates, 1);
+    assert_eq!(pair.client_conn_mut(client_ch).lost_packets(), 0);
+    assert_eq!(pair.server_conn_mut(server_ch).lost_packets(), 0);
+}
+
+#[test]
+fn key_update_interval() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let client = ClientConfig {
+        transport: Arc::new(TransportConfig {
+            key_update_packets: 4,
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    };
+    let (client_ch, server_ch) = pair.connect_with(client);
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    for _ in 0..20 {
+        pair.client_conn_mut(client_ch).write(s, &[0; 32]).unwrap();
+        pair.drive();
+        // Let the previous keys be discarded
+        pair.time += Duration::from_secs(1);
+        pair.drive();
+    }
+
+    let stats = pair.client_conn_mut(client_ch).key_phase_stats();
+    assert!(stats.local_updates >= 2);
+    assert!(stats.packets_sent <= 4);
+    // The latest update may not have been used for any packets yet
+    let remote_updates = pair
+        .server_conn_mut(server_ch)
+        .key_phase_stats()
+        .remote_updates;
+    assert!(remote_updates + 1 >= stats.local_updates && remote_updates >= 2);
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
+    assert_eq!(pair.client_conn_mut(client_ch).lost_packets(), 0);
+}
+
+#[test]
+fn export_keying_material() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let client_ch = pair.begin_connect(client_config());
+    let mut client_secret = [0; 32];
+    assert_matches!(
+        pair.client_conn_mut(client_ch)
+            .export_keying_material(&mut client_secret, b"label", b""),
+        Err(crypto::ExportKeyingMaterialError)
+    );
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+
+    pair.client_conn_mut(client_ch)
+        .export_keying_material(&mut client_secret, b"label", b"context")
+        .unwrap();
+    let mut server_secret = [0; 32];
+    pair.server_conn_mut(server_ch)
+        .export_keying_material(&mut server_secret, b"label", b"context")
+        .unwrap();
+    assert_eq!(client_secret, server_secret);
+    assert_ne!(client_secret, [0; 32]);
+
+    pair.server_conn_mut(server_ch)
+        .export_keying_material(&mut server_secret, b"other label", b"context")
+        .unwrap();
+    assert_ne!(client_secret, server_secret);
+}
+
 #[test]
 fn initial_retransmit() {
     let _guard = subscribe();
@@ -725,17 +1196,68 @@ fn idle_timeout() {
 }
 
 #[test]
-fn server_busy() {
+fn idle_timeout_override() {
     let _guard = subscribe();
-    let mut pair = Pair::new(
-        Default::default(),
-        ServerConfig {
-            accept_buffer: 0,
-            ..server_config()
-        },
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    let now = pair.time;
+    let negotiated = pair.server_conn_mut(server_ch).idle_timeout();
+    assert_eq!(negotiated, TransportConfig::default().idle_timeout);
+    // Can't be extended past the negotiated value
+    assert_eq!(
+        pair.server_conn_mut(server_ch)
+            .set_idle_timeout(now, 10 * negotiated),
+        negotiated
     );
-    let client_ch = pair.begin_connect(client_config());
-    pair.drive();
+    assert_eq!(
+        pair.server_conn_mut(server_ch).set_idle_timeout(now, 500),
+        500
+    );
+
+    // The client hears nothing further from the server and keeps its own, longer timeout
+    pair.drive_server();
+    pair.time += Duration::from_millis(400);
+    pair.drive_server();
+    assert!(!pair.server_conn_mut(server_ch).is_closed());
+    pair.time += Duration::from_millis(200);
+    pair.drive_server();
+    assert!(pair.server_conn_mut(server_ch).is_closed());
+    assert!(!pair.client_conn_mut(client_ch).is_closed());
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::TimedOut,
+        })
+    );
+
+    // An override set during the handshake survives negotiation
+    let mut pair = Pair::default();
+    let client_ch = pair.begin_connect(client_config());
+    let now = pair.time;
+    assert_eq!(
+        pair.client_conn_mut(client_ch).set_idle_timeout(now, 500),
+        500
+    );
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected { .. })
+    );
+    assert_eq!(pair.client_conn_mut(client_ch).idle_timeout(), 500);
+}
+
+#[test]
+fn server_busy() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            accept_buffer: 0,
+            ..server_config()
+        },
+    );
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive();
     assert_matches!(
         pair.client_conn_mut(client_ch).poll(),
         Some(Event::ConnectionLost {
@@ -754,6 +1276,108 @@ fn server_busy() {
     assert_eq!(pair.server.known_cids(), 0);
 }
 
+#[test]
+fn max_connections() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            max_connections: Some(1),
+            ..server_config()
+        },
+    );
+    let (first_ch, _) = pair.connect();
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed {
+                reason: frame::ConnectionClose {
+                    error_code: TransportErrorCode::CONNECTION_REFUSED,
+                    ..
+                },
+            },
+        })
+    );
+    assert_eq!(pair.server.known_connections(), 1);
+    assert_eq!(pair.server.connections_refused(), 1);
+
+    // Room is made once the first connection is gone
+    let now = pair.time;
+    pair.client_conn_mut(first_ch)
+        .close(now, VarInt(0), Bytes::new());
+    pair.drive();
+    assert_eq!(pair.server.known_connections(), 0);
+    pair.connect();
+}
+
+#[derive(Debug, Default)]
+struct RefusalLog(std::sync::Mutex<Vec<(SocketAddr, RefusalReason)>>);
+
+impl RefusalObserver for RefusalLog {
+    fn refused(&self, remote: SocketAddr, reason: RefusalReason) {
+        self.0.lock().unwrap().push((remote, reason));
+    }
+}
+
+#[test]
+fn refusal_observer() {
+    let _guard = subscribe();
+    let cases = [
+        (
+            RefusalReason::ServerBusy,
+            ServerConfig {
+                accept_buffer: 1,
+                ..server_config()
+            },
+        ),
+        (
+            RefusalReason::ConnectionLimit,
+            ServerConfig {
+                max_connections: Some(1),
+                ..server_config()
+            },
+        ),
+        (
+            RefusalReason::ConnectionLimitPerIp,
+            ServerConfig {
+                max_connections_per_ip: Some(1),
+                ..server_config()
+            },
+        ),
+        (
+            RefusalReason::RateLimit,
+            ServerConfig {
+                handshake_rate_limit: Some(1),
+                ..server_config()
+            },
+        ),
+    ];
+    for (reason, config) in cases.iter().cloned() {
+        let log = Arc::new(RefusalLog::default());
+        let mut pair = Pair::new(
+            Default::default(),
+            ServerConfig {
+                refusal_observer: Some(log.clone()),
+                ..config
+            },
+        );
+        // The first attempt takes up the room allowed, so the second is refused
+        pair.begin_connect(client_config());
+        pair.begin_connect(client_config());
+        pair.drive_client();
+        pair.drive_server();
+        assert_eq!(
+            &log.0.lock().unwrap()[..],
+            &[(pair.client.addr, reason)][..],
+            "{:?}",
+            reason
+        );
+        assert_eq!(pair.server.connections_refused(), 1);
+    }
+}
+
 #[test]
 fn server_hs_retransmit() {
     let _guard = subscribe();
@@ -784,6 +1408,211 @@ fn migration() {
     assert_eq!(pair.server_conn_mut(server_ch).remote(), pair.client.addr);
 }
 
+#[test]
+fn path_stats() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    let old_addr = pair.client.addr;
+    assert!(pair.server_conn_mut(server_ch).current_path().validated);
+    pair.client.addr = SocketAddr::new(
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive_client();
+    pair.drive_server();
+    let paths = pair.server_conn_mut(server_ch).path_stats();
+    assert_eq!(paths.len(), 2);
+    assert_eq!(paths[0].remote, pair.client.addr);
+    assert!(!paths[0].validated);
+    assert_eq!(paths[1].remote, old_addr);
+    assert!(paths[1].validated && paths[1].bytes_received > paths[0].bytes_received);
+
+    pair.drive();
+    let paths = pair.server_conn_mut(server_ch).path_stats();
+    assert_eq!(paths.len(), 1);
+    assert_eq!(paths[0].remote, pair.client.addr);
+    assert!(paths[0].validated);
+}
+
+#[test]
+fn local_address_changed() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive_client();
+    let old_cid = pair.server.inbound.back().unwrap().2[1..9].to_vec();
+    pair.drive();
+
+    pair.client.addr = SocketAddr::new(
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    pair.client_conn_mut(client_ch).local_address_changed();
+    pair.drive_client();
+    let new_cid = pair.server.inbound.back().unwrap().2[1..9].to_vec();
+    assert_ne!(old_cid, new_cid);
+    pair.drive();
+    assert_matches!(pair.client_conn_mut(client_ch).poll(), None);
+    assert_eq!(pair.server_conn_mut(server_ch).remote(), pair.client.addr);
+}
+
+#[test]
+fn preferred_address() {
+    let _guard = subscribe();
+    let preferred = SocketAddrV6::new(
+        Ipv6Addr::LOCALHOST,
+        SERVER_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            preferred_address_v6: Some(preferred),
+            ..server_config()
+        },
+    );
+    let client_ch = pair.begin_connect(client_config());
+    let old_remote = pair.server.addr;
+    // The client moves once the handshake is confirmed
+    while pair.client_conn_mut(client_ch).remote() == old_remote {
+        assert!(pair.step());
+    }
+    assert_eq!(pair.client_conn_mut(client_ch).remote(), preferred.into());
+    pair.server.addr = preferred.into();
+    pair.drive();
+
+    let server_ch = pair.server.assert_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected { .. })
+    );
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::Connected { .. })
+    );
+    // Path validation succeeded
+    assert_eq!(pair.client_conn_mut(client_ch).remote(), preferred.into());
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
+}
+
+#[test]
+fn cid_rotation() {
+    let _guard = subscribe();
+    const INTERVAL: u64 = 1000;
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect_with(ClientConfig {
+        transport: Arc::new(TransportConfig {
+            cid_rotation_interval: INTERVAL,
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    });
+
+    let mut cids = Vec::new();
+    for _ in 0..3 {
+        pair.client_conn_mut(client_ch).ping();
+        pair.drive_client();
+        cids.push(pair.server.inbound.back().unwrap().2[1..9].to_vec());
+        pair.drive();
+        pair.time += Duration::from_millis(INTERVAL);
+    }
+    assert_ne!(cids[0], cids[1]);
+    assert_ne!(cids[1], cids[2]);
+    assert_matches!(pair.client_conn_mut(client_ch).poll(), None);
+    assert_matches!(pair.server_conn_mut(server_ch).poll(), None);
+}
+
+#[test]
+fn migration_amplification_limit() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    pair.client.addr = SocketAddr::new(
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive_client();
+    let recvd = pair.server.inbound.iter().map(|x| x.2.len()).sum::<usize>();
+
+    const MSG: &[u8] = &[0xAB; 64 * 1024];
+    let s = pair.server_conn_mut(server_ch).open(Dir::Uni).unwrap();
+    pair.server_conn_mut(server_ch).write(s, MSG).unwrap();
+    pair.server_conn_mut(server_ch).finish(s).unwrap();
+    pair.drive_server();
+    // Until the new path is validated, it might be spoofed
+    let sent = pair.client.inbound.iter().map(|x| x.2.len()).sum::<usize>();
+    assert!(sent > 0 && sent <= 3 * recvd, "{} > 3 * {}", sent, recvd);
+    assert_eq!(
+        pair.server_conn_mut(server_ch).amplification_limit(),
+        Some(AmplificationLimit {
+            allowed: 3 * recvd as u64,
+            sent: sent as u64,
+        })
+    );
+
+    pair.drive();
+    assert_eq!(pair.server_conn_mut(server_ch).amplification_limit(), None);
+    assert_eq!(pair.server_conn_mut(server_ch).remote(), pair.client.addr);
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
+    assert_matches!(pair.client_conn_mut(client_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    let mut len = 0;
+    while let Some((data, _)) = pair.client_conn_mut(client_ch).read_unordered(s).unwrap() {
+        len += data.len();
+    }
+    assert_eq!(len, MSG.len());
+}
+
+#[test]
+fn handshake_amplification_factor() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            amplification_factor: 2,
+            ..server_config()
+        },
+    );
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    let recvd = pair.server.inbound.iter().map(|x| x.2.len()).sum::<usize>();
+    pair.drive_server();
+    let server_ch = pair.server.assert_accept();
+    let limit = pair
+        .server_conn_mut(server_ch)
+        .amplification_limit()
+        .unwrap();
+    assert!(limit.allowed <= 2 * recvd as u64);
+    assert!(limit.sent > 0 && limit.sent <= limit.allowed);
+
+    pair.drive();
+    assert_eq!(pair.server_conn_mut(server_ch).amplification_limit(), None);
+
+    let server = ServerConfig {
+        amplification_factor: 0,
+        ..server_config()
+    };
+    assert_matches!(
+        Endpoint::new(Default::default(), Some(Arc::new(server))),
+        Err(ConfigError::IllegalValue(_))
+    );
+}
+
 fn test_flow_control(config: TransportConfig, window_size: usize) {
     let _guard = subscribe();
     let mut pair = Pair::new(
@@ -982,6 +1811,45 @@ fn zero_length_cid() {
         .unwrap()
         .close(pair.time, VarInt(42), Bytes::new());
     pair.connect();
+
+    // A second connection to the same address would be indistinguishable
+    assert_matches!(
+        pair.client
+            .connect(client_config(), pair.server.addr, "localhost"),
+        Err(ConnectError::RemoteInUse)
+    );
+}
+
+#[test]
+fn spin_bit() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    pair.client_conn_mut(client_ch).set_spin_enabled(true);
+    pair.server_conn_mut(server_ch).set_spin_enabled(true);
+
+    // Each round trip flips the bit seen on the client's packets
+    let mut spins = Vec::new();
+    for _ in 0..4 {
+        pair.client_conn_mut(client_ch).ping();
+        pair.drive_client();
+        let (_, _, ref packet) = *pair.server.inbound.back().unwrap();
+        assert_eq!(packet[0] & packet::LONG_HEADER_FORM, 0);
+        spins.push(packet[0] & packet::SPIN_BIT != 0);
+        pair.drive();
+    }
+    assert_eq!(spins, [spins[0], !spins[0], spins[0], !spins[0]]);
+
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            allow_spin: false,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (_, server_ch) = pair.connect();
+    assert!(!pair.server_conn_mut(server_ch).spin_enabled());
 }
 
 #[test]
@@ -1138,7 +2006,8 @@ fn stop_during_finish() {
 fn congested_tail_loss() {
     let _guard = subscribe();
     let mut pair = Pair::default();
-    let (client_ch, _) = pair.connect();
+    // Fill the congestion window at once
+    let (client_ch, _) = pair.connect_with(unpaced());
 
     let initial_congestion_state = pair.client_conn_mut(client_ch).congestion_state();
     let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
@@ -1166,41 +2035,584 @@ fn congested_tail_loss() {
         .unwrap();
 }
 
+fn unpaced() -> ClientConfig {
+    ClientConfig {
+        transport: Arc::new(TransportConfig {
+            pacing: false,
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    }
+}
+
 #[test]
-fn datagram_send_recv() {
+fn ecn_disabled() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect_with(ClientConfig {
+        transport: Arc::new(TransportConfig {
+            allow_ecn: false,
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    });
+    assert!(!pair.client_conn_mut(client_ch).using_ecn());
+    assert!(pair.server_conn_mut(server_ch).using_ecn());
+
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive_client();
+    assert!(!pair.server.inbound.is_empty());
+    assert!(pair.server.inbound.iter().all(|x| x.1.is_none()));
+    pair.drive();
+    assert!(pair.server_conn_mut(server_ch).using_ecn());
+}
+
+#[test]
+fn mtu_discovery() {
     let _guard = subscribe();
     let mut pair = Pair::default();
+    pair.mtu = 1400;
     let (client_ch, server_ch) = pair.connect();
-    assert_matches!(pair.server_conn_mut(server_ch).poll(), None);
-    assert_matches!(pair.client_conn_mut(client_ch).max_datagram_size(), Some(x) if x > 0);
+    pair.drive();
+    for &mtu in &[
+        pair.client_conn_mut(client_ch).mtu(),
+        pair.server_conn_mut(server_ch).mtu(),
+    ] {
+        assert!(mtu > 1380 && mtu <= 1400, "discovered MTU {}", mtu);
+    }
+    // Lost probes are no sign of congestion
+    assert_eq!(
+        pair.client_conn_mut(client_ch).congestion().window(),
+        TransportConfig::default().initial_window
+    );
 
-    const DATA: &[u8] = b"whee";
+    // The path shrinks, so packets of the discovered size are lost
+    pair.mtu = 1300;
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    const LEN: usize = 32 * 1024;
     pair.client_conn_mut(client_ch)
-        .send_datagram()
-        .unwrap()
-        .send(DATA.into())
+        .write(s, &[42; LEN])
         .unwrap();
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
     pair.drive();
+    assert_eq!(pair.client_conn_mut(client_ch).mtu(), MIN_MTU);
     assert_matches!(
         pair.server_conn_mut(server_ch).poll(),
-        Some(Event::DatagramReceived)
-    );
-    assert_eq!(
-        pair.server_conn_mut(server_ch).recv_datagram().unwrap(),
-        DATA
+        Some(Event::StreamOpened { dir: Dir::Uni })
     );
-    assert_matches!(pair.server_conn_mut(server_ch).recv_datagram(), None);
+    let mut received = 0;
+    while let Ok(Some((data, _))) = pair.server_conn_mut(server_ch).read_unordered(s) {
+        received += data.len();
+    }
+    assert_eq!(received, LEN);
 }
 
 #[test]
-fn datagram_window() {
+fn datagram_mtu_shrinks() {
     let _guard = subscribe();
-    const WINDOW: usize = 100;
-    let server = ServerConfig {
-        transport: Arc::new(TransportConfig {
-            datagram_receive_buffer_size: Some(WINDOW),
-            ..TransportConfig::default()
-        }),
+    let mut pair = Pair::default();
+    pair.mtu = 1400;
+    let (client_ch, server_ch) = pair.connect();
+    pair.drive();
+    let max = pair.server_conn_mut(server_ch).max_datagram_size().unwrap();
+    pair.server_conn_mut(server_ch)
+        .send_datagram()
+        .unwrap()
+        .send(vec![0xAB; max].into())
+        .unwrap();
+
+    // Migration resets the MTU estimate before the queued datagram is sent
+    pair.client.addr = SocketAddr::new(
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive();
+    assert_eq!(
+        pair.server_conn_mut(server_ch)
+            .stats()
+            .app_datagrams_dropped,
+        1
+    );
+    assert_matches!(pair.client_conn_mut(client_ch).recv_datagram(), None);
+    match pair.server_conn_mut(server_ch).send_datagram() {
+        Err(SendDatagramError::Dropped(1)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("unexpected success"),
+    }
+    // Reported once
+    assert!(pair.server_conn_mut(server_ch).send_datagram().is_ok());
+}
+
+#[test]
+fn max_udp_payload_size() {
+    let _guard = subscribe();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            initial_mtu: 1250,
+            max_udp_payload_size: 1300,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (client_ch, server_ch) = pair.connect();
+    pair.drive();
+    // The client's probes respect the limit the server advertised
+    assert_eq!(pair.client_conn_mut(client_ch).mtu(), 1300);
+    assert_eq!(pair.server_conn_mut(server_ch).mtu(), 1300);
+}
+
+#[test]
+fn delayed_ack() {
+    let _guard = subscribe();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            max_ack_delay: 10 * 1000,
+            ack_eliciting_threshold: 2,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (client_ch, _) = pair.connect();
+
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive_client();
+    pair.drive_server();
+    assert!(pair.client.inbound.is_empty());
+    // The second ack-eliciting packet is acknowledged immediately
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.client.inbound.len(), 1);
+
+    // Others are acknowledged once the delay expires
+    pair.drive_client();
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive_client();
+    pair.drive_server();
+    assert!(pair.client.inbound.is_empty());
+    let start = pair.time;
+    pair.step();
+    assert_eq!(pair.time, start + Duration::from_millis(10));
+    pair.drive_server();
+    assert_eq!(pair.client.inbound.len(), 1);
+}
+
+#[test]
+fn ack_frequency() {
+    let _guard = subscribe();
+    // Count the datagrams sent by the server in response to a series of client packets
+    fn server_datagrams(ack_frequency: Option<AckFrequencyConfig>) -> usize {
+        let mut pair = Pair::default();
+        let (client_ch, _) = pair.connect_with(ClientConfig {
+            transport: Arc::new(TransportConfig {
+                ack_frequency,
+                ..TransportConfig::default()
+            }),
+            ..client_config()
+        });
+        let mut count = 0;
+        for _ in 0..20 {
+            pair.client_conn_mut(client_ch).ping();
+            pair.drive_client();
+            pair.drive_server();
+            count += pair.client.inbound.len();
+        }
+        pair.drive();
+        count
+    }
+
+    let immediate = server_datagrams(None);
+    let delayed = server_datagrams(Some(AckFrequencyConfig::default()));
+    assert!(
+        delayed * 3 < immediate,
+        "{} datagrams sent with ACK_FREQUENCY, {} without",
+        delayed,
+        immediate
+    );
+}
+
+#[test]
+fn pacing() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    const LEN: usize = 64 * 1024;
+    pair.client_conn_mut(client_ch)
+        .write(s, &[42; LEN])
+        .unwrap();
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    pair.drive_client();
+    // Only an initial burst goes out before the pacer is out of tokens
+    let burst = pair.server.inbound.len();
+    assert!(burst > 0 && burst <= 10, "sent {} packets at once", burst);
+
+    pair.drive();
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
+    let mut received = 0;
+    while let Ok(Some((data, _))) = pair.server_conn_mut(server_ch).read_unordered(s) {
+        received += data.len();
+    }
+    assert_eq!(received, LEN);
+}
+
+#[test]
+fn datagram_send_recv() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    assert_matches!(pair.server_conn_mut(server_ch).poll(), None);
+    assert_matches!(pair.client_conn_mut(client_ch).max_datagram_size(), Some(x) if x > 0);
+
+    const DATA: &[u8] = b"whee";
+    pair.client_conn_mut(client_ch)
+        .send_datagram()
+        .unwrap()
+        .send(DATA.into())
+        .unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::DatagramReceived)
+    );
+    assert_eq!(
+        pair.server_conn_mut(server_ch).recv_datagram().unwrap(),
+        DATA
+    );
+    assert_matches!(pair.server_conn_mut(server_ch).recv_datagram(), None);
+    assert_eq!(
+        pair.client_conn_mut(client_ch).stats().app_datagrams_sent,
+        1
+    );
+    assert_eq!(
+        pair.server_conn_mut(server_ch)
+            .stats()
+            .app_datagrams_received,
+        1
+    );
+}
+
+#[test]
+fn datagram_0rtt() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let config = client_config();
+
+    // Establish a connection so the client can resume with 0-RTT
+    let client_ch = pair.begin_connect(config.clone());
+    pair.drive();
+    pair.server.assert_accept();
+    let now = pair.time;
+    pair.client_conn_mut(client_ch)
+        .close(now, VarInt(0), [][..].into());
+    pair.drive();
+
+    pair.client.addr = SocketAddr::new(
+        Ipv6Addr::LOCALHOST.into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    let client_ch = pair.begin_connect(config);
+    assert!(pair.client_conn_mut(client_ch).has_0rtt());
+    const DATA: &[u8] = b"whee";
+    pair.client_conn_mut(client_ch)
+        .send_datagram()
+        .unwrap()
+        .send(DATA.into())
+        .unwrap();
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert_eq!(
+        pair.server_conn_mut(server_ch).recv_datagram().unwrap(),
+        DATA
+    );
+    assert_eq!(
+        pair.client_conn_mut(client_ch)
+            .stats()
+            .app_datagrams_dropped,
+        0
+    );
+}
+
+#[test]
+fn connection_stats() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch)
+        .write(s, &[0xAB; 4096])
+        .unwrap();
+    pair.drive();
+
+    let client = pair.client_conn_mut(client_ch).stats();
+    let server = pair.server_conn_mut(server_ch).stats();
+    assert_eq!(client.datagrams_sent, server.datagrams_received);
+    assert_eq!(client.bytes_sent, server.bytes_received);
+    assert_eq!(server.datagrams_sent, client.datagrams_received);
+    assert!(client.bytes_sent > 4096);
+    assert!(client.packets_sent >= client.datagrams_sent);
+    assert_eq!(client.packets_lost, 0);
+    assert_eq!(client.bytes_in_flight, 0);
+    assert!(client.congestion_window > 0);
+    assert_eq!(client.congestion.window, client.congestion_window);
+}
+
+#[test]
+fn connection_stats_congestion_state() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            transport: Arc::new(TransportConfig {
+                congestion_controller_factory: Arc::new(congestion::BbrFactory),
+                ..TransportConfig::default()
+            }),
+            ..server_config()
+        },
+    );
+    // Bandwidth is only sampled over an interval of time
+    pair.latency = Duration::from_millis(10);
+    let (_, server_ch) = pair.connect();
+    let s = pair.server_conn_mut(server_ch).open(Dir::Uni).unwrap();
+    pair.server_conn_mut(server_ch)
+        .write(s, &[0xAB; 65536])
+        .unwrap();
+    pair.drive();
+
+    let stats = pair.server_conn_mut(server_ch).stats();
+    assert!(stats.congestion.phase.is_some());
+    assert!(stats.congestion.bandwidth.unwrap() > 0);
+    assert!(stats.congestion.min_rtt.is_some());
+}
+
+/// Collects the qlog traces of all connections in memory
+#[cfg(feature = "qlog")]
+#[derive(Debug, Default, Clone)]
+struct QlogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(feature = "qlog")]
+impl std::io::Write for QlogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "qlog")]
+impl crate::qlog::QlogFactory for QlogBuffer {
+    fn writer(&self, _: Side, _: &ConnectionId) -> Option<Box<dyn std::io::Write + Send>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+#[cfg(feature = "qlog")]
+#[test]
+fn qlog_trace() {
+    let _guard = subscribe();
+    let trace = QlogBuffer::default();
+    let transport = Arc::new(TransportConfig {
+        qlog: Some(Arc::new(trace.clone())),
+        ..TransportConfig::default()
+    });
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect_with(ClientConfig {
+        transport,
+        ..client_config()
+    });
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+    pair.drive();
+
+    let trace = String::from_utf8(trace.0.lock().unwrap().clone()).unwrap();
+    let records = trace.split('\x1e').skip(1).collect::<Vec<_>>();
+    assert!(records[0].starts_with("{\"qlog_version\":\"0.3\""));
+    for record in &records {
+        assert!(record.starts_with('{') && record.ends_with("}\n"));
+    }
+    let has = |needle: &str| records.iter().any(|x| x.contains(needle));
+    assert!(has(concat!(
+        "\"name\":\"transport:packet_sent\",\"data\":{\"header\":",
+        "{\"packet_type\":\"initial\",\"packet_number\":0},\"raw\":{\"length\":1200},",
+        "\"frames\":[{\"frame_type\":\"crypto\",\"offset\":0,"
+    )));
+    assert!(has("\"name\":\"transport:packet_received\""));
+    assert!(has(
+        "{\"frame_type\":\"stream\",\"stream_id\":2,\"offset\":0,\"length\":5,\"fin\":false}"
+    ));
+    assert!(has("\"name\":\"recovery:metrics_updated\""));
+}
+
+/// Records the packets and datagrams of all connections
+#[cfg(feature = "packet-tap")]
+#[derive(Debug, Default, Clone)]
+struct RecordingTap(Arc<std::sync::Mutex<(Vec<tap::PacketSummary>, usize)>>);
+
+#[cfg(feature = "packet-tap")]
+impl tap::PacketTap for RecordingTap {
+    fn packet(&mut self, packet: &tap::PacketSummary) {
+        self.0.lock().unwrap().0.push(packet.clone());
+    }
+
+    fn datagram(&mut self, _: Instant, direction: tap::Direction, _: &[u8]) {
+        if direction == tap::Direction::Sent {
+            self.0.lock().unwrap().1 += 1;
+        }
+    }
+}
+
+#[cfg(feature = "packet-tap")]
+impl tap::PacketTapFactory for RecordingTap {
+    fn build(&self, _: Side, _: &ConnectionId) -> Option<Box<dyn tap::PacketTap>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+#[cfg(feature = "packet-tap")]
+#[test]
+fn packet_tap() {
+    use tap::{Direction, FrameSummary, PacketType};
+    let _guard = subscribe();
+    let recording = RecordingTap::default();
+    let transport = Arc::new(TransportConfig {
+        packet_tap: Some(Arc::new(recording.clone())),
+        ..TransportConfig::default()
+    });
+    let server_recording = RecordingTap::default();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            packet_tap: Some(Arc::new(server_recording.clone())),
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (client_ch, _) = pair.connect_with(ClientConfig {
+        transport,
+        ..client_config()
+    });
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+    pair.drive();
+
+    let (ref packets, datagrams) = *recording.0.lock().unwrap();
+    let first = &packets[0];
+    assert_eq!(first.direction, Direction::Sent);
+    assert_eq!(first.ty, PacketType::Initial);
+    assert_eq!(first.number, Some(0));
+    assert_matches!(
+        first.frames[..],
+        [
+            FrameSummary::Crypto { offset: 0, .. },
+            FrameSummary::Padding { .. }
+        ]
+    );
+    assert!(packets.iter().any(|x| x.direction == Direction::Received));
+    let stream_data = packets
+        .iter()
+        .filter(|x| x.ty == PacketType::OneRtt)
+        .flat_map(|x| x.frames.iter())
+        .find_map(|x| match *x {
+            FrameSummary::Stream {
+                id, offset, length, ..
+            } if id == s => Some((offset, length)),
+            _ => None,
+        });
+    assert_eq!(stream_data, Some((0, 5)));
+    assert_eq!(
+        datagrams as u64,
+        pair.client_conn_mut(client_ch).stats().datagrams_sent
+    );
+
+    // Both ends agree on the size of each packet
+    let (ref server_packets, _) = *server_recording.0.lock().unwrap();
+    let received = server_packets
+        .iter()
+        .filter(|x| x.direction == Direction::Received)
+        .collect::<Vec<_>>();
+    assert!(!received.is_empty());
+    for packet in received {
+        assert!(packets.iter().any(|x| x.direction == Direction::Sent
+            && x.ty == packet.ty
+            && x.number == packet.number
+            && x.size == packet.size));
+    }
+}
+
+#[test]
+fn telemetry_events() {
+    let _guard = subscribe();
+    let transport = Arc::new(TransportConfig {
+        telemetry: true,
+        ..TransportConfig::default()
+    });
+    let server = ServerConfig {
+        transport: transport.clone(),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (client_ch, server_ch) = pair.connect_with(ClientConfig {
+        transport,
+        ..client_config()
+    });
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll_telemetry(),
+        Some(TelemetryEvent::HandshakeComplete)
+    );
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll_telemetry(),
+        Some(TelemetryEvent::StreamOpened { id }) if id == s
+    );
+    assert_matches!(pair.server_conn_mut(server_ch).poll_telemetry(), None);
+    while pair.server_conn_mut(server_ch).poll().is_some() {}
+    assert_matches!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read_unordered(s),
+        Ok(Some(_))
+    );
+    assert_matches!(pair.server_conn_mut(server_ch).read_unordered(s), Ok(None));
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll_telemetry(),
+        Some(TelemetryEvent::StreamClosed { id }) if id == s
+    );
+
+    let now = pair.time;
+    pair.client_conn_mut(client_ch)
+        .close(now, VarInt(42), Bytes::new());
+    pair.drive();
+    while pair.server_conn_mut(server_ch).poll().is_some() {}
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll_telemetry(),
+        Some(TelemetryEvent::Closed {
+            reason: ConnectionError::ApplicationClosed { .. }
+        })
+    );
+}
+
+#[test]
+fn datagram_window() {
+    let _guard = subscribe();
+    const WINDOW: usize = 100;
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            datagram_receive_buffer_size: Some(WINDOW),
+            ..TransportConfig::default()
+        }),
         ..server_config()
     };
     let mut pair = Pair::new(Default::default(), server);
@@ -1279,6 +2691,65 @@ fn datagram_unsupported() {
     }
 }
 
+#[test]
+fn peer_params() {
+    let _guard = subscribe();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            idle_timeout: 5_000,
+            stream_window_bidi: 7,
+            datagram_receive_buffer_size: None,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let client_ch = pair.begin_connect(client_config());
+    assert_eq!(pair.client_conn_mut(client_ch).peer_params(), None);
+    pair.drive_client();
+    pair.drive_server();
+    let server_ch = pair.server.assert_accept();
+    let params = pair.server_conn_mut(server_ch).peer_params().unwrap();
+    assert_eq!(params.idle_timeout, Some(Duration::from_millis(10_000)));
+    assert!(params.max_datagram_frame_size.is_some());
+    pair.drive();
+
+    let params = pair.client_conn_mut(client_ch).peer_params().unwrap();
+    assert_eq!(params.idle_timeout, Some(Duration::from_millis(5_000)));
+    assert_eq!(params.initial_max_streams_bidi, 7);
+    assert_eq!(params.max_datagram_frame_size, None);
+    assert!(!params.disable_active_migration);
+}
+
+#[test]
+fn custom_transport_params() {
+    let _guard = subscribe();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            custom_transport_params: vec![(0x4242, b"server".to_vec())],
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let client = ClientConfig {
+        transport: Arc::new(TransportConfig {
+            custom_transport_params: vec![(0x4242, b"client".to_vec()), (0x4243, Vec::new())],
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    };
+    let (client_ch, server_ch) = pair.connect_with(client);
+    assert_eq!(
+        pair.client_conn_mut(client_ch).peer_custom_params(),
+        &[(0x4242, b"server".to_vec())][..]
+    );
+    assert_eq!(
+        pair.server_conn_mut(server_ch).peer_custom_params(),
+        &[(0x4242, b"client".to_vec()), (0x4243, Vec::new())][..]
+    );
+}
+
 #[test]
 fn large_initial() {
     let _guard = subscribe();
@@ -1302,3 +2773,139 @@ fn large_initial() {
         Some(Event::Connected { .. })
     );
 }
+
+#[test]
+fn recovery_tuning() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let client_ch = pair.begin_connect(ClientConfig {
+        transport: Arc::new(TransportConfig {
+            initial_window: 20 * 1200,
+            packet_threshold: 10,
+            pto_backoff: 1,
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    });
+    pair.drive();
+    pair.server.assert_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected { .. })
+    );
+    assert!(pair.client_conn_mut(client_ch).congestion().window() >= 20 * 1200);
+
+    let invalid = ClientConfig {
+        transport: Arc::new(TransportConfig {
+            minimum_window: 40 * 1200,
+            initial_window: 20 * 1200,
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    };
+    let server_addr = pair.server.addr;
+    assert_matches!(
+        pair.client.connect(invalid, server_addr, "localhost"),
+        Err(ConnectError::Config(_))
+    );
+}
+
+/// Transfer `len` bytes from client to server over a simulated link, returning how long it took
+#[cfg(feature = "sim")]
+fn sim_transfer(link: sim::LinkConfig, seed: u64, len: usize) -> Duration {
+    let mut sim = sim::Simulation::new(link, seed);
+    let mut endpoint_config = || {
+        Arc::new(EndpointConfig {
+            clock: sim.clock(),
+            rng_seed: Some(sim.rng_seed()),
+            ..EndpointConfig::default()
+        })
+    };
+    let server = Endpoint::new(endpoint_config(), Some(Arc::new(server_config()))).unwrap();
+    let client = Endpoint::new(endpoint_config(), None).unwrap();
+    let server_addr = "[::1]:4433".parse().unwrap();
+    let server = sim.add_endpoint(server_addr, server);
+    let client = sim.add_endpoint("[::2]:7890".parse().unwrap(), client);
+    let client_ch = sim
+        .node_mut(client)
+        .connect(client_config(), server_addr, "localhost")
+        .unwrap();
+    let start = sim.now();
+
+    let data = vec![0xab; len];
+    let (mut client_stream, mut sent) = (None, 0);
+    let (mut server_ch, mut server_stream, mut received) = (None, None, 0);
+    let done = sim.run_until(|sim| {
+        let conn = sim.node_mut(client).connection_mut(client_ch).unwrap();
+        while conn.poll().is_some() {}
+        if client_stream.is_none() {
+            client_stream = conn.open(Dir::Uni);
+        }
+        if let Some(s) = client_stream.filter(|_| sent < len) {
+            sent += conn.write(s, &data[sent..]).unwrap_or(0);
+            if sent == len {
+                conn.finish(s).unwrap();
+            }
+        }
+
+        let node = sim.node_mut(server);
+        if server_ch.is_none() {
+            server_ch = node.accept();
+        }
+        let conn = match server_ch {
+            Some(ch) => node.connection_mut(ch).unwrap(),
+            None => return false,
+        };
+        while conn.poll().is_some() {}
+        if server_stream.is_none() {
+            server_stream = conn.accept(Dir::Uni);
+        }
+        let s = match server_stream {
+            Some(s) => s,
+            None => return false,
+        };
+        let mut buf = [0; 4096];
+        loop {
+            match conn.read(s, &mut buf) {
+                Ok(Some(n)) => received += n,
+                Ok(None) => return true,
+                Err(ReadError::Blocked) => return false,
+                Err(e) => panic!("unexpected read error: {}", e),
+            }
+        }
+    });
+    assert!(done, "transfer stalled");
+    assert_eq!(received, len);
+    sim.now() - start
+}
+
+#[cfg(feature = "sim")]
+#[test]
+fn sim_lossy_transfer() {
+    let _guard = subscribe();
+    let link = sim::LinkConfig {
+        delay: Duration::from_millis(50),
+        jitter: Duration::from_millis(10),
+        loss: 0.05,
+        bandwidth: Some(1_000_000),
+        ..sim::LinkConfig::default()
+    };
+    let elapsed = sim_transfer(link.clone(), 42, 256 * 1024);
+    // A quarter megabyte at 1MB/s takes at least a quarter second, and virtual time means
+    // losses and timeouts cost nothing in real time.
+    assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(60), "{:?}", elapsed);
+}
+
+#[cfg(feature = "sim")]
+#[test]
+fn sim_invalid_loss() {
+    let _guard = subscribe();
+    for &loss in &[-0.5, std::f64::NAN] {
+        let link = sim::LinkConfig {
+            loss,
+            ..sim::LinkConfig::default()
+        };
+        sim_transfer(link, 42, 1024);
+    }
+}
@@ -0,0 +1,326 @@
+//! A plaintext `crypto::Session`, showing that the protocol logic doesn't depend on rustls
+
+use ::ring::hmac;
+use bytes::BytesMut;
+
+use super::*;
+use crate::{
+    crypto::{self, ExportKeyingMaterialError},
+    generic,
+};
+
+/// A handshake which carries each side's transport parameters without any protection
+///
+/// Each message is a 16-bit length followed by that many bytes. The client sends its
+/// parameters, the server answers with its own and a Finished message in the Handshake space,
+/// and the client's Finished completes the handshake.
+struct PlainSession {
+    side: Side,
+    state: HandshakeState,
+    params: Vec<u8>,
+    peer_params: Option<Vec<u8>>,
+    /// Received bytes not yet making up a whole message
+    incoming: Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum HandshakeState {
+    /// Our parameters are to be sent
+    Hello,
+    AwaitHello,
+    /// The Handshake keys are to be returned
+    HandshakeKeys,
+    AwaitFinished,
+    /// Our Finished and the 1-RTT keys are to be returned
+    Finished,
+    Done,
+}
+
+impl PlainSession {
+    fn new(side: Side, params: &[u8]) -> Self {
+        Self {
+            side,
+            state: match side {
+                Side::Client => HandshakeState::Hello,
+                Side::Server => HandshakeState::AwaitHello,
+            },
+            params: params.to_vec(),
+            peer_params: None,
+            incoming: Vec::new(),
+        }
+    }
+
+    /// Take the next whole message received, if any
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        if self.incoming.len() < 2 {
+            return None;
+        }
+        let len = usize::from(u16::from_be_bytes([self.incoming[0], self.incoming[1]]));
+        if self.incoming.len() < 2 + len {
+            return None;
+        }
+        let message = self.incoming[2..2 + len].to_vec();
+        self.incoming.drain(..2 + len);
+        Some(message)
+    }
+}
+
+fn write_message(buf: &mut Vec<u8>, message: &[u8]) {
+    buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    buf.extend_from_slice(message);
+}
+
+impl crypto::Session for PlainSession {
+    type AuthenticationData = ();
+    type ClientConfig = PlainConfig;
+    type HmacKey = hmac::Key;
+    type Keys = PlainKeys;
+    type ServerConfig = PlainConfig;
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn authentication_data(&self) {}
+
+    fn early_crypto(&self) -> Option<PlainKeys> {
+        None
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        match self.side {
+            Side::Client => Some(false),
+            Side::Server => None,
+        }
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.state != HandshakeState::Done
+    }
+
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<(), TransportError> {
+        use HandshakeState::*;
+        self.incoming.extend_from_slice(buf);
+        while let Some(message) = self.next_message() {
+            self.state = match (self.side, self.state) {
+                (Side::Client, AwaitHello) => HandshakeKeys,
+                (Side::Server, AwaitHello) => Hello,
+                (Side::Client, AwaitFinished) => Finished,
+                (Side::Server, AwaitFinished) => Done,
+                _ => return Err(TransportError::PROTOCOL_VIOLATION("unexpected message")),
+            };
+            if self.peer_params.is_none() {
+                self.peer_params = Some(message);
+            }
+        }
+        Ok(())
+    }
+
+    fn sni_hostname(&self) -> Option<&str> {
+        None
+    }
+
+    fn transport_parameters(&self) -> Option<&[u8]> {
+        self.peer_params.as_ref().map(|x| &x[..])
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<PlainKeys> {
+        use HandshakeState::*;
+        let (next, keys) = match (self.side, self.state) {
+            (Side::Client, Hello) => (AwaitHello, false),
+            (Side::Server, Hello) => (Finished, true),
+            (_, HandshakeKeys) => (AwaitFinished, true),
+            (Side::Client, Finished) => (Done, true),
+            (Side::Server, Finished) => (AwaitFinished, true),
+            _ => return None,
+        };
+        match self.state {
+            Hello => write_message(buf, &self.params),
+            Finished => write_message(buf, &[]),
+            _ => {}
+        }
+        self.state = next;
+        if keys {
+            Some(PlainKeys)
+        } else {
+            None
+        }
+    }
+
+    fn update_keys(&self, _: &PlainKeys) -> PlainKeys {
+        PlainKeys
+    }
+
+    fn export_keying_material(
+        &self,
+        _: &mut [u8],
+        _: &[u8],
+        _: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        Err(ExportKeyingMaterialError)
+    }
+}
+
+struct PlainConfig;
+
+impl crypto::ClientConfig<PlainSession> for PlainConfig {
+    fn new() -> Self {
+        PlainConfig
+    }
+
+    fn start_session(&self, _: &str, params: &[u8]) -> Result<PlainSession, ConnectError> {
+        Ok(PlainSession::new(Side::Client, params))
+    }
+}
+
+impl crypto::ServerConfig<PlainSession> for PlainConfig {
+    fn new() -> Self {
+        PlainConfig
+    }
+
+    fn start_session(&self, params: &[u8]) -> PlainSession {
+        PlainSession::new(Side::Server, params)
+    }
+}
+
+/// Packet protection which leaves packets as they are, but for a tag of zeroes
+struct PlainKeys;
+
+const TAG_LEN: usize = 16;
+
+impl crypto::Keys for PlainKeys {
+    type HeaderKeys = PlainKeys;
+
+    fn new_initial(_: &ConnectionId, _: Side) -> Self {
+        PlainKeys
+    }
+
+    fn encrypt(&self, _: u64, buf: &mut [u8], _: usize) {
+        let len = buf.len();
+        for x in &mut buf[len - TAG_LEN..] {
+            *x = 0;
+        }
+    }
+
+    fn decrypt(&self, _: u64, _: &[u8], payload: &mut BytesMut) -> Result<(), ()> {
+        if payload.len() < TAG_LEN {
+            return Err(());
+        }
+        let len = payload.len() - TAG_LEN;
+        if payload[len..].iter().any(|&x| x != 0) {
+            return Err(());
+        }
+        payload.truncate(len);
+        Ok(())
+    }
+
+    fn header_keys(&self) -> PlainKeys {
+        PlainKeys
+    }
+
+    fn tag_len(&self) -> usize {
+        TAG_LEN
+    }
+}
+
+impl crypto::HeaderKeys for PlainKeys {
+    fn decrypt(&self, _: usize, _: &mut [u8]) {}
+
+    fn encrypt(&self, _: usize, _: &mut [u8]) {}
+
+    fn sample_size(&self) -> usize {
+        16
+    }
+}
+
+#[test]
+fn handshake() {
+    let _guard = subscribe();
+    let server_config = generic::ServerConfig::<PlainSession> {
+        transport: Arc::new(TransportConfig {
+            custom_transport_params: vec![(0x4242, b"server".to_vec())],
+            stream_window_uni: 3,
+            ..TransportConfig::default()
+        }),
+        ..generic::ServerConfig::default()
+    };
+    let client_config = generic::ClientConfig::<PlainSession> {
+        transport: Arc::new(TransportConfig {
+            custom_transport_params: vec![(0x4242, b"client".to_vec())],
+            idle_timeout: 5_000,
+            ..TransportConfig::default()
+        }),
+        ..generic::ClientConfig::default()
+    };
+    let server_addr = "[::1]:4433".parse().unwrap();
+    let client_addr = "[::1]:4434".parse().unwrap();
+    let mut server =
+        generic::Endpoint::new(Default::default(), Some(Arc::new(server_config))).unwrap();
+    let mut client = generic::Endpoint::new(Default::default(), None).unwrap();
+    let (_, mut client_conn) = client
+        .connect(client_config, server_addr, "localhost")
+        .unwrap();
+    let now = Instant::now();
+
+    // The server's connection only exists once the client's Initial arrived
+    let mut server_conn = None;
+    let mut connected = (false, false);
+    for _ in 0..8 {
+        for contents in drain_transmits(now, &mut client_conn) {
+            match server.handle(now, client_addr, None, contents) {
+                Some((_, DatagramEvent::NewConnection(conn))) => server_conn = Some(conn),
+                Some((_, DatagramEvent::ConnectionEvent(event))) => {
+                    server_conn.as_mut().unwrap().handle_event(event)
+                }
+                None => {}
+            }
+        }
+        let server_conn = server_conn.as_mut().unwrap();
+        for contents in drain_transmits(now, server_conn) {
+            if let Some((_, DatagramEvent::ConnectionEvent(event))) =
+                client.handle(now, server_addr, None, contents)
+            {
+                client_conn.handle_event(event);
+            }
+        }
+        connected.0 |= connected_event(&mut client_conn);
+        connected.1 |= connected_event(server_conn);
+    }
+    assert_eq!(connected, (true, true));
+
+    let server_conn = server_conn.unwrap();
+    assert_eq!(
+        client_conn.peer_custom_params(),
+        &[(0x4242, b"server".to_vec())][..]
+    );
+    assert_eq!(
+        server_conn.peer_custom_params(),
+        &[(0x4242, b"client".to_vec())][..]
+    );
+    assert_eq!(
+        client_conn.peer_params().unwrap().initial_max_streams_uni,
+        3
+    );
+    assert_eq!(
+        server_conn.peer_params().unwrap().idle_timeout,
+        Some(Duration::from_millis(5_000))
+    );
+}
+
+fn drain_transmits(now: Instant, conn: &mut generic::Connection<PlainSession>) -> Vec<BytesMut> {
+    let mut transmits = Vec::new();
+    while let Some(transmit) = conn.poll_transmit(now) {
+        transmits.push(transmit.contents[..].into());
+    }
+    transmits
+}
+
+fn connected_event(conn: &mut generic::Connection<PlainSession>) -> bool {
+    let mut connected = false;
+    while let Some(event) = conn.poll() {
+        if let Event::Connected = event {
+            connected = true;
+        }
+    }
+    connected
+}
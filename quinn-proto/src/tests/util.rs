@@ -26,6 +26,8 @@ pub struct Pair {
     pub latency: Duration,
     /// Number of spin bit flips
     pub spins: u64,
+    /// Largest datagram delivered, in either direction
+    pub mtu: usize,
     last_spin: bool,
 }
 
@@ -48,6 +50,7 @@ impl Pair {
             time: Instant::now(),
             latency: Duration::new(0, 0),
             spins: 0,
+            mtu: 65535,
             last_spin: false,
         }
     }
@@ -100,7 +103,7 @@ impl Pair {
             if let Some(ref socket) = self.client.socket {
                 socket.send_to(&x.contents, x.destination).unwrap();
             }
-            if self.server.addr == x.destination {
+            if self.server.addr == x.destination && x.contents.len() <= self.mtu {
                 self.server
                     .inbound
                     .push_back((self.time + self.latency, x.ecn, x.contents));
@@ -116,7 +119,7 @@ impl Pair {
             if let Some(ref socket) = self.server.socket {
                 socket.send_to(&x.contents, x.destination).unwrap();
             }
-            if self.client.addr == x.destination {
+            if self.client.addr == x.destination && x.contents.len() <= self.mtu {
                 self.client
                     .inbound
                     .push_back((self.time + self.latency, x.ecn, x.contents));
@@ -125,8 +128,12 @@ impl Pair {
     }
 
     pub fn connect(&mut self) -> (ConnectionHandle, ConnectionHandle) {
+        self.connect_with(client_config())
+    }
+
+    pub fn connect_with(&mut self, config: ClientConfig) -> (ConnectionHandle, ConnectionHandle) {
         info!("connecting");
-        let client_ch = self.begin_connect(client_config());
+        let client_ch = self.begin_connect(config);
         self.drive();
         let server_ch = self.server.assert_accept();
         assert_matches!(
@@ -171,7 +178,7 @@ pub struct TestEndpoint {
     pub endpoint: Endpoint,
     pub addr: SocketAddr,
     socket: Option<UdpSocket>,
-    timers: TimerTable<Option<Instant>>,
+    timers: HashMap<ConnectionHandle, TimerTable<Option<Instant>>>,
     pub outbound: VecDeque<Transmit>,
     delayed: VecDeque<Transmit>,
     pub inbound: VecDeque<(Instant, Option<EcnCodepoint>, Box<[u8]>)>,
@@ -242,7 +249,8 @@ impl TestEndpoint {
 
         let mut endpoint_events: Vec<(ConnectionHandle, EndpointEvent)> = vec![];
         for (ch, conn) in self.connections.iter_mut() {
-            for (timer, setting) in &mut self.timers {
+            let timers = self.timers.entry(*ch).or_default();
+            for (timer, setting) in &mut *timers {
                 if let Some(time) = *setting {
                     if time <= now {
                         trace!("{:?} timeout", timer);
@@ -252,10 +260,8 @@ impl TestEndpoint {
                 }
             }
 
-            for (_, mut events) in self.conn_events.drain() {
-                for event in events.drain(..) {
-                    conn.handle_event(event);
-                }
+            for event in self.conn_events.remove(ch).into_iter().flatten() {
+                conn.handle_event(event);
             }
 
             while let Some(event) = conn.poll_endpoint_events() {
@@ -267,7 +273,7 @@ impl TestEndpoint {
             }
 
             while let Some(x) = conn.poll_timers() {
-                self.timers[x.timer] = match x.update {
+                timers[x.timer] = match x.update {
                     TimerSetting::Stop => {
                         trace!("{:?} stop", x.timer);
                         None
@@ -287,17 +293,29 @@ impl TestEndpoint {
                 }
             }
         }
+
+        let connections = &self.connections;
+        self.timers.retain(|ch, _| connections.contains_key(ch));
     }
 
     pub fn next_wakeup(&self) -> Option<Instant> {
-        let next_timer = self.timers.iter().filter_map(|(_, t)| *t).min();
+        let next_timer = self
+            .timers
+            .values()
+            .flat_map(|x| x.iter().filter_map(|(_, t)| *t))
+            .min();
         let next_inbound = self.inbound.front().map(|x| x.0);
         min_opt(next_timer, next_inbound)
     }
 
     fn is_idle(&self) -> bool {
         let t = self.next_wakeup();
-        t == self.timers[Timer(TimerKind::Idle)] || t == self.timers[Timer(TimerKind::KeepAlive)]
+        t.is_none()
+            || self.timers.values().any(|x| {
+                t == x[Timer(TimerKind::Idle)]
+                    || t == x[Timer(TimerKind::KeepAlive)]
+                    || t == x[Timer(TimerKind::CidRotation)]
+            })
     }
 
     pub fn delay_outbound(&mut self) {
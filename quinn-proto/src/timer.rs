@@ -21,30 +21,39 @@ pub(crate) enum TimerKind {
     PathValidation = 4,
     /// When to send a `PING` frame to keep the connection alive
     KeepAlive = 5,
+    /// When the pacer permits sending the next packet
+    Pacing = 6,
+    /// When to send an acknowledgement delayed at the peer's request
+    MaxAckDelay = 7,
+    /// When to switch to a fresh remote connection ID
+    CidRotation = 8,
 }
 
 impl TimerKind {
-    const VALUES: [Self; 6] = [
+    const VALUES: [Self; 9] = [
         TimerKind::LossDetection,
         TimerKind::Idle,
         TimerKind::Close,
         TimerKind::KeyDiscard,
         TimerKind::PathValidation,
         TimerKind::KeepAlive,
+        TimerKind::Pacing,
+        TimerKind::MaxAckDelay,
+        TimerKind::CidRotation,
     ];
 }
 
 /// A table of data associated with each distinct kind of `Timer`
 #[derive(Debug, Copy, Clone, Default)]
 pub struct TimerTable<T> {
-    data: [T; 6],
+    data: [T; 9],
 }
 
 impl<T> TimerTable<T> {
     /// Create a table initialized with the value returned by `f` for each timer
     pub fn new(mut f: impl FnMut() -> T) -> Self {
         Self {
-            data: [f(), f(), f(), f(), f(), f()],
+            data: [f(), f(), f(), f(), f(), f(), f(), f(), f()],
         }
     }
 
@@ -126,3 +126,8 @@ errors! {
     CRYPTO_BUFFER_EXCEEDED(0xD) "received more data in CRYPTO frames than can be buffered";
     KEY_UPDATE_ERROR(0xE) "key update error";
 }
+
+impl Code {
+    /// The server refused to accept a new connection; the name later drafts give `SERVER_BUSY`
+    pub const CONNECTION_REFUSED: Self = Self::SERVER_BUSY;
+}
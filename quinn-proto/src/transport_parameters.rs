@@ -1,4 +1,7 @@
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
+};
 
 use bytes::{buf::ext::BufExt as _, Buf, BufMut};
 use err_derive::Error;
@@ -7,7 +10,8 @@ use crate::{
     coding::{BufExt, BufMutExt, UnexpectedEnd},
     crypto,
     shared::{ConnectionId, ResetToken, ServerConfig},
-    Side, TransportConfig, TransportError, VarInt, MAX_CID_SIZE, REM_CID_COUNT, RESET_TOKEN_SIZE,
+    Side, TransportConfig, TransportError, VarInt, MAX_CID_SIZE, MIN_INITIAL_SIZE,
+    RESET_TOKEN_SIZE,
 };
 
 // Apply a given macro to a list of all the transport parameters having integer types, along with
@@ -39,17 +43,23 @@ macro_rules! apply_params {
 
 macro_rules! make_struct {
     {$($name:ident ($code:expr) = $default:expr,)*} => {
-        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        #[derive(Debug, Clone, Eq, PartialEq)]
         pub struct TransportParameters {
             $(pub $name : u64,)*
 
             pub disable_active_migration: bool,
             pub max_datagram_frame_size: Option<VarInt>,
+            /// Shortest delay the sender can be asked to wait before acknowledging, in microseconds,
+            /// if it supports the ACK frequency extension
+            pub min_ack_delay: Option<VarInt>,
 
             // Server-only
             pub original_connection_id: Option<ConnectionId>,
             pub stateless_reset_token: Option<ResetToken>,
             pub preferred_address: Option<PreferredAddress>,
+
+            /// Parameters not interpreted by quinn-proto, see `TransportConfig::custom_transport_params`
+            pub custom: Vec<(u16, Vec<u8>)>,
         }
 
         impl Default for TransportParameters {
@@ -60,10 +70,13 @@ macro_rules! make_struct {
 
                     disable_active_migration: false,
                     max_datagram_frame_size: None,
+                    min_ack_delay: None,
 
                     original_connection_id: None,
                     stateless_reset_token: None,
                     preferred_address: None,
+
+                    custom: Vec::new(),
                 }
             }
         }
@@ -85,12 +98,19 @@ impl TransportParameters {
             initial_max_stream_data_bidi_remote: config.stream_receive_window,
             initial_max_stream_data_uni: config.stream_receive_window,
             idle_timeout: config.idle_timeout,
-            max_ack_delay: 0,
+            max_packet_size: config.max_udp_payload_size.into(),
+            // Rounded up, so the peer never underestimates the delay
+            max_ack_delay: (Duration::from_micros(config.max_ack_delay)
+                + Duration::from_micros(999))
+            .as_millis() as u64,
             disable_active_migration: server_config.map_or(false, |c| !c.migration),
-            active_connection_id_limit: REM_CID_COUNT,
+            active_connection_id_limit: config.active_connection_id_limit,
             max_datagram_frame_size: config
                 .datagram_receive_buffer_size
                 .map(|x| (x.min(u16::max_value().into()) as u16).into()),
+            // Acknowledgements are never delayed unless the peer asks, so any delay is fine
+            min_ack_delay: Some(VarInt::from_u32(0)),
+            custom: config.custom_transport_params.clone(),
             ..Self::default()
         }
     }
@@ -114,12 +134,68 @@ impl TransportParameters {
     }
 }
 
+/// Transport parameters advertised by the peer, see `Connection::peer_params`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PeerParams {
+    /// How long the peer lets the connection idle before closing it, if at all
+    pub idle_timeout: Option<Duration>,
+    /// Largest UDP payload the peer is willing to receive
+    pub max_udp_payload_size: u64,
+    /// Connection-level flow control limit the peer started out with
+    pub initial_max_data: u64,
+    /// Flow control limit for data on each bidirectional stream we open
+    pub initial_max_stream_data_bidi_local: u64,
+    /// Flow control limit for data on each bidirectional stream the peer opens
+    pub initial_max_stream_data_bidi_remote: u64,
+    /// Flow control limit for data on each unidirectional stream we open
+    pub initial_max_stream_data_uni: u64,
+    /// Number of bidirectional streams we may open to begin with
+    pub initial_max_streams_bidi: u64,
+    /// Number of unidirectional streams we may open to begin with
+    pub initial_max_streams_uni: u64,
+    /// Longest the peer intends to delay acknowledgements
+    pub max_ack_delay: Duration,
+    /// Whether the peer forbids migrating the connection to a new address
+    pub disable_active_migration: bool,
+    /// Number of connection IDs the peer is willing to keep track of
+    pub active_connection_id_limit: u64,
+    /// Largest datagram frame the peer accepts, if it supports datagrams at all
+    pub max_datagram_frame_size: Option<u64>,
+    /// Shortest acknowledgement delay the peer can be asked for, if it supports the ACK frequency
+    /// extension
+    pub min_ack_delay: Option<Duration>,
+}
+
+impl From<&TransportParameters> for PeerParams {
+    fn from(x: &TransportParameters) -> Self {
+        Self {
+            idle_timeout: match x.idle_timeout {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            max_udp_payload_size: x.max_packet_size,
+            initial_max_data: x.initial_max_data,
+            initial_max_stream_data_bidi_local: x.initial_max_stream_data_bidi_local,
+            initial_max_stream_data_bidi_remote: x.initial_max_stream_data_bidi_remote,
+            initial_max_stream_data_uni: x.initial_max_stream_data_uni,
+            initial_max_streams_bidi: x.initial_max_streams_bidi,
+            initial_max_streams_uni: x.initial_max_streams_uni,
+            max_ack_delay: Duration::from_millis(x.max_ack_delay),
+            disable_active_migration: x.disable_active_migration,
+            active_connection_id_limit: x.active_connection_id_limit,
+            max_datagram_frame_size: x.max_datagram_frame_size.map(u64::from),
+            min_ack_delay: x.min_ack_delay.map(|x| Duration::from_micros(x.into())),
+        }
+    }
+}
+
+/// An address the server would rather the client migrated to once the handshake is confirmed
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PreferredAddress {
-    address_v4: Option<SocketAddrV4>,
-    address_v6: Option<SocketAddrV6>,
-    connection_id: ConnectionId,
-    stateless_reset_token: [u8; RESET_TOKEN_SIZE],
+    pub(crate) address_v4: Option<SocketAddrV4>,
+    pub(crate) address_v6: Option<SocketAddrV6>,
+    pub(crate) connection_id: ConnectionId,
+    pub(crate) stateless_reset_token: ResetToken,
 }
 
 impl PreferredAddress {
@@ -171,11 +247,24 @@ impl PreferredAddress {
             address_v4,
             address_v6,
             connection_id: cid,
-            stateless_reset_token: token,
+            stateless_reset_token: token.into(),
         })
     }
 }
 
+/// Whether `id` belongs to a parameter interpreted by quinn-proto itself
+pub(crate) fn is_known_id(id: u16) -> bool {
+    macro_rules! known {
+        {$($name:ident ($code:expr) = $default:expr,)*} => {
+            match id {
+                0x0000 | 0x0002 | 0x000c | 0x000d | 0x0020 | 0xde1a $(| $code)* => true,
+                _ => false,
+            }
+        }
+    }
+    apply_params!(known)
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
 pub enum Error {
     #[error(display = "version negotiation was tampered with")]
@@ -246,12 +335,24 @@ impl TransportParameters {
             buf.write(x);
         }
 
+        if let Some(x) = self.min_ack_delay {
+            buf.write::<u16>(0xde1a);
+            buf.write::<u16>(x.size() as u16);
+            buf.write(x);
+        }
+
         if let Some(ref x) = self.preferred_address {
             buf.write::<u16>(0x000d);
             buf.write::<u16>(x.wire_size());
             x.write(&mut buf);
         }
 
+        for &(id, ref value) in &self.custom {
+            buf.write::<u16>(id);
+            buf.write::<u16>(value.len() as u16);
+            buf.put_slice(value);
+        }
+
         w.write::<u16>(buf.len() as u16);
         w.put_slice(&buf);
     }
@@ -321,10 +422,24 @@ impl TransportParameters {
                         Some(PreferredAddress::read(&mut r.take(len as usize))?);
                 }
                 0x0020 => {
-                    if len > 8 || params.max_datagram_frame_size.is_some() {
+                    if params.max_datagram_frame_size.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    let value = r.get::<VarInt>()?;
+                    if len != value.size() as u16 {
+                        return Err(Error::Malformed);
+                    }
+                    params.max_datagram_frame_size = Some(value);
+                }
+                0xde1a => {
+                    if params.min_ack_delay.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    let value = r.get::<VarInt>()?;
+                    if len != value.size() as u16 {
                         return Err(Error::Malformed);
                     }
-                    params.max_datagram_frame_size = Some(r.get().unwrap());
+                    params.min_ack_delay = Some(value);
                 }
                 _ => {
                     macro_rules! parse {
@@ -335,7 +450,16 @@ impl TransportParameters {
                                     if len != VarInt::from_u64(params.$name).unwrap().size() as u16 || got.$name { return Err(Error::Malformed); }
                                     got.$name = true;
                                 })*
-                                _ => r.advance(len as usize),
+                                // Reserved ids only exist to exercise this path, so aren't kept
+                                _ if id % 31 == 27 => r.advance(len as usize),
+                                _ => {
+                                    if params.custom.iter().any(|&(x, _)| x == id) {
+                                        return Err(Error::Malformed);
+                                    }
+                                    let mut value = vec![0; len as usize];
+                                    r.copy_to_slice(&mut value);
+                                    params.custom.push((id, value));
+                                }
                             }
                         }
                     }
@@ -346,7 +470,11 @@ impl TransportParameters {
 
         // Semantic validation
         if params.ack_delay_exponent > 20
+            || params.max_packet_size < MIN_INITIAL_SIZE as u64
             || params.max_ack_delay >= 1 << 14
+            || params
+                .min_ack_delay
+                .map_or(false, |x| x.into_inner() > params.max_ack_delay * 1000)
             || (side.is_server()
                 && (params.original_connection_id.is_some()
                     || params.stateless_reset_token.is_some()
@@ -362,6 +490,7 @@ impl TransportParameters {
 #[cfg(test)]
 mod test {
     use super::*;
+    use assert_matches::assert_matches;
 
     #[test]
     fn coding() {
@@ -371,12 +500,14 @@ mod test {
             initial_max_streams_uni: 16,
             ack_delay_exponent: 2,
             max_packet_size: 1200,
+            min_ack_delay: Some(VarInt::from_u32(2000)),
             preferred_address: Some(PreferredAddress {
                 address_v4: Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 42)),
                 address_v6: None,
                 connection_id: ConnectionId::new(&[]),
-                stateless_reset_token: [0xab; RESET_TOKEN_SIZE],
+                stateless_reset_token: [0xab; RESET_TOKEN_SIZE].into(),
             }),
+            custom: vec![(0x4242, b"hello".to_vec()), (0x4243, Vec::new())],
             ..TransportParameters::default()
         };
         params.write(&mut buf);
@@ -385,4 +516,17 @@ mod test {
             params
         );
     }
+
+    #[test]
+    fn truncated_varint() {
+        let read = |mut buf: &[u8]| TransportParameters::read(Side::Client, &mut buf);
+        // No value at all
+        assert_matches!(read(&[0, 4, 0xde, 0x1a, 0, 0]), Err(Error::Malformed));
+        assert_matches!(read(&[0, 4, 0x00, 0x20, 0, 0]), Err(Error::Malformed));
+        // A length disagreeing with the size of the encoded value
+        assert_matches!(
+            read(&[0, 6, 0xde, 0x1a, 0, 2, 0x40, 0x01]),
+            Err(Error::Malformed)
+        );
+    }
 }
@@ -1,12 +1,15 @@
 use std::{io, net::SocketAddr, str, sync::Arc};
 
 use err_derive::Error;
-use proto::{ClientConfig, EndpointConfig, ServerConfig};
-use rustls::TLSError;
+use proto::{
+    ClientConfig, ConfigError, EndpointConfig, RefusalObserver, ServerConfig, TokenFormat,
+    TransportConfig,
+};
+use rustls::{CipherSuite, ProducesTickets, TLSError};
 
 use crate::{
     endpoint::{Endpoint, EndpointDriver, EndpointRef, Incoming},
-    udp::UdpSocket,
+    runtime::{self, AsyncUdpSocket, Runtime},
     Certificate, CertificateChain, PrivateKey,
 };
 
@@ -18,6 +21,7 @@ pub struct EndpointBuilder {
     server_config: Option<ServerConfig>,
     config: EndpointConfig,
     client_config: ClientConfig,
+    runtime: Option<Arc<dyn Runtime>>,
 }
 
 #[allow(missing_docs)]
@@ -46,10 +50,35 @@ impl EndpointBuilder {
         self,
         socket: std::net::UdpSocket,
     ) -> Result<(EndpointDriver, Endpoint, Incoming), EndpointError> {
+        let socket = self
+            .runtime
+            .as_ref()
+            .ok_or(EndpointError::NoRuntime)?
+            .wrap_udp_socket(socket)
+            .map_err(EndpointError::Socket)?;
+        self.build(socket)
+    }
+
+    /// Build an endpoint around a custom socket implementation.
+    ///
+    /// Lets datagrams be carried by something other than an OS socket, such as a tunnel, a
+    /// userspace network stack, or an in-memory transport for tests.
+    pub fn with_async_socket(
+        self,
+        socket: impl AsyncUdpSocket,
+    ) -> Result<(EndpointDriver, Endpoint, Incoming), EndpointError> {
+        self.build(Box::new(socket))
+    }
+
+    fn build(
+        self,
+        socket: Box<dyn AsyncUdpSocket>,
+    ) -> Result<(EndpointDriver, Endpoint, Incoming), EndpointError> {
+        let runtime = self.runtime.ok_or(EndpointError::NoRuntime)?;
         let addr = socket.local_addr().map_err(EndpointError::Socket)?;
-        let socket = UdpSocket::from_std(socket).map_err(EndpointError::Socket)?;
         let rc = EndpointRef::new(
             socket,
+            runtime,
             proto::Endpoint::new(Arc::new(self.config), self.server_config.map(Arc::new))?,
             addr.is_ipv6(),
         );
@@ -76,6 +105,15 @@ impl EndpointBuilder {
         self.client_config = config;
         self
     }
+
+    /// Set the runtime which drives timers and socket I/O
+    ///
+    /// Defaults to `TokioRuntime` with the `runtime-tokio` feature, or `AsyncStdRuntime` with only
+    /// the `runtime-async-std` feature. Must be set if neither is enabled.
+    pub fn runtime(&mut self, runtime: Arc<dyn Runtime>) -> &mut Self {
+        self.runtime = Some(runtime);
+        self
+    }
 }
 
 impl Default for EndpointBuilder {
@@ -84,6 +122,7 @@ impl Default for EndpointBuilder {
             server_config: None,
             config: EndpointConfig::default(),
             client_config: ClientConfig::default(),
+            runtime: runtime::default_runtime(),
         }
     }
 }
@@ -97,6 +136,9 @@ pub enum EndpointError {
     /// An error in the Quinn transport configuration
     #[error(display = "configuration error: {:?}", _0)]
     Config(#[source] proto::ConfigError),
+    /// No runtime was set, and there's no default without a runtime feature
+    #[error(display = "no runtime to drive the endpoint")]
+    NoRuntime,
 }
 
 /// Helper for constructing a `ServerConfig` to be passed to `EndpointBuilder::listen` to enable
@@ -116,6 +158,12 @@ impl ServerConfigBuilder {
         self.config
     }
 
+    /// Set the transport parameters of incoming connections.
+    pub fn transport_config(&mut self, config: Arc<TransportConfig>) -> &mut Self {
+        self.config.transport = config;
+        self
+    }
+
     /// Enable NSS-compatible cryptographic key logging to the `SSLKEYLOGFILE` environment variable.
     ///
     /// Useful for debugging encrypted communications with protocol analyzers such as Wireshark.
@@ -124,6 +172,24 @@ impl ServerConfigBuilder {
         self
     }
 
+    /// Restrict the TLS 1.3 cipher suites that may be negotiated.
+    ///
+    /// Fails if `suites` is empty or contains a suite which isn't supported. rustls doesn't allow
+    /// restricting key exchange groups.
+    pub fn cipher_suites(&mut self, suites: &[CipherSuite]) -> Result<&mut Self, TLSError> {
+        Arc::make_mut(&mut self.config.crypto).ciphersuites = tls13_suites(suites)?;
+        Ok(self)
+    }
+
+    /// Issue self-contained session tickets encrypted by `ticketer`.
+    ///
+    /// By default, resumption state is kept in memory by the server that issued it. Use
+    /// `TicketKeys` to let servers sharing keys resume each other's sessions.
+    pub fn session_ticketer(&mut self, ticketer: Arc<dyn ProducesTickets>) -> &mut Self {
+        Arc::make_mut(&mut self.config.crypto).ticketer = ticketer;
+        self
+    }
+
     /// Set the certificate chain that will be presented to clients.
     pub fn certificate(
         &mut self,
@@ -134,6 +200,99 @@ impl ServerConfigBuilder {
         Ok(self)
     }
 
+    /// Set the certificate chain that will be presented to clients, with Certificate Transparency
+    /// proofs.
+    ///
+    /// `scts` is an encoded `SignedCertificateTimestampList` (RFC 6962) for the end-entity
+    /// certificate, as issued by the logs it was submitted to.
+    pub fn certificate_with_scts(
+        &mut self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+        scts: Vec<u8>,
+    ) -> Result<&mut Self, TLSError> {
+        Arc::make_mut(&mut self.config.crypto).set_single_cert_with_ocsp_and_sct(
+            cert_chain.certs,
+            key.inner,
+            Vec::new(),
+            scts,
+        )?;
+        Ok(self)
+    }
+
+    /// Select the certificate chain presented to each client with `resolver`.
+    ///
+    /// See `CertificateResolver` for selection based on the server name requested by the client.
+    pub fn certificate_resolver(
+        &mut self,
+        resolver: Arc<dyn rustls::ResolvesServerCert>,
+    ) -> &mut Self {
+        Arc::make_mut(&mut self.config.crypto).cert_resolver = resolver;
+        self
+    }
+
+    /// Require clients to present a certificate signed by one of `roots`.
+    ///
+    /// The certificate chain presented by the client is available through
+    /// `Connection::authentication_data()` once the connection is established.
+    pub fn require_client_certificate(
+        &mut self,
+        roots: impl IntoIterator<Item = Certificate>,
+    ) -> Result<&mut Self, webpki::Error> {
+        let verifier = rustls::AllowAnyAuthenticatedClient::new(root_store(roots)?);
+        self.replace_crypto(rustls::ServerConfig::new(verifier));
+        Ok(self)
+    }
+
+    /// Ask clients for a certificate signed by one of `roots`, but accept anonymous clients too.
+    pub fn request_client_certificate(
+        &mut self,
+        roots: impl IntoIterator<Item = Certificate>,
+    ) -> Result<&mut Self, webpki::Error> {
+        let verifier = rustls::AllowAnyAnonymousOrAuthenticatedClient::new(root_store(roots)?);
+        self.replace_crypto(rustls::ServerConfig::new(verifier));
+        Ok(self)
+    }
+
+    /// Use a custom verifier for client certificates.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn client_certificate_verifier(
+        &mut self,
+        verifier: Arc<dyn rustls::ClientCertVerifier>,
+    ) -> &mut Self {
+        self.replace_crypto(rustls::ServerConfig::new(verifier));
+        self
+    }
+
+    /// Require clients to authenticate with one of the public keys in `keys`.
+    ///
+    /// See `PinnedKeyVerifier`.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn require_pinned_client_keys(
+        &mut self,
+        keys: impl IntoIterator<Item = Vec<u8>>,
+    ) -> &mut Self {
+        self.client_certificate_verifier(crate::PinnedKeyVerifier::new(keys))
+    }
+
+    /// Carry the current settings over to `crypto`, which has a different client cert verifier
+    ///
+    /// rustls only allows setting the verifier on construction.
+    fn replace_crypto(&mut self, mut crypto: rustls::ServerConfig) {
+        let old = &self.config.crypto;
+        crypto.ciphersuites = old.ciphersuites.clone();
+        crypto.ignore_client_order = old.ignore_client_order;
+        crypto.mtu = old.mtu;
+        crypto.session_storage = old.session_storage.clone();
+        crypto.ticketer = old.ticketer.clone();
+        crypto.cert_resolver = old.cert_resolver.clone();
+        crypto.alpn_protocols = old.alpn_protocols.clone();
+        crypto.versions = old.versions.clone();
+        crypto.key_log = old.key_log.clone();
+        crypto.max_early_data_size = old.max_early_data_size;
+        self.config.crypto = Arc::new(crypto);
+    }
+
     /// Set the application-layer protocols to accept, in order of descending preference.
     ///
     /// When set, clients which don't declare support for at least one of the supplied protocols will be rejected.
@@ -152,6 +311,61 @@ impl ServerConfigBuilder {
         self.config.use_stateless_retry = enabled;
         self
     }
+
+    /// Maximum number of incoming connections to maintain at once, in all and with any one IP
+    /// address
+    pub fn max_connections(&mut self, total: Option<u32>, per_ip: Option<u32>) -> &mut Self {
+        self.config.max_connections = total;
+        self.config.max_connections_per_ip = per_ip;
+        self
+    }
+
+    /// Report each refused connection attempt to `observer`
+    pub fn refusal_observer(&mut self, observer: Arc<dyn RefusalObserver>) -> &mut Self {
+        self.config.refusal_observer = Some(observer);
+        self
+    }
+
+    /// Maximum number of handshakes to begin per second, in all and with any one IP address
+    ///
+    /// Excess connection attempts are dropped, or answered with a Retry if `retry` is set.
+    pub fn handshake_rate_limit(
+        &mut self,
+        total: Option<u32>,
+        per_ip: Option<u32>,
+        retry: bool,
+    ) -> &mut Self {
+        self.config.handshake_rate_limit = total;
+        self.config.handshake_rate_limit_per_ip = per_ip;
+        self.config.retry_rate_limited = retry;
+        self
+    }
+
+    /// Maximum ratio of bytes sent to bytes received before a client's address is validated
+    ///
+    /// Fails if `factor` is 0.
+    pub fn amplification_factor(&mut self, factor: u32) -> Result<&mut Self, ConfigError> {
+        if factor == 0 {
+            return Err(ConfigError::IllegalValue(
+                "amplification_factor must be at least 1",
+            ));
+        }
+        self.config.amplification_factor = factor;
+        Ok(self)
+    }
+
+    /// Use a custom format for the tokens clients present to prove they can receive packets
+    pub fn token_format(&mut self, format: Arc<dyn TokenFormat>) -> &mut Self {
+        self.config.token_format = Some(format);
+        self
+    }
+
+    /// Require clients to prove they can receive packets only while at least `threshold`
+    /// incoming handshakes are in progress
+    pub fn retry_threshold(&mut self, threshold: Option<u32>) -> &mut Self {
+        self.config.retry_threshold = threshold;
+        self
+    }
 }
 
 impl Default for ServerConfigBuilder {
@@ -162,11 +376,45 @@ impl Default for ServerConfigBuilder {
     }
 }
 
+fn root_store(
+    roots: impl IntoIterator<Item = Certificate>,
+) -> Result<rustls::RootCertStore, webpki::Error> {
+    let mut store = rustls::RootCertStore::empty();
+    for cert in roots {
+        store.add(&cert.inner)?;
+    }
+    Ok(store)
+}
+
+/// Look up the rustls implementations of the TLS 1.3 cipher suites in `suites`, keeping their order
+fn tls13_suites(
+    suites: &[CipherSuite],
+) -> Result<Vec<&'static rustls::SupportedCipherSuite>, TLSError> {
+    if suites.is_empty() {
+        return Err(TLSError::General("no cipher suites".into()));
+    }
+    suites
+        .iter()
+        .map(|&suite| {
+            rustls::ALL_CIPHERSUITES
+                .iter()
+                .cloned()
+                .find(|x| {
+                    x.suite == suite && x.usable_for_version(rustls::ProtocolVersion::TLSv1_3)
+                })
+                .ok_or_else(|| {
+                    TLSError::General(format!("unsupported TLS 1.3 cipher suite {:?}", suite))
+                })
+        })
+        .collect()
+}
+
 /// Helper for creating new outgoing connections.
 ///
 /// If the `native-certs` and `ct-logs` features are enabled, `ClientConfigBuilder::default()` will
 /// construct a configuration that trusts the host OS certificate store and uses built-in
 /// certificate transparency logs respectively. These features are both enabled by default.
+#[derive(Clone, Debug)]
 pub struct ClientConfigBuilder {
     config: ClientConfig,
 }
@@ -197,6 +445,43 @@ impl ClientConfigBuilder {
         Ok(self)
     }
 
+    /// Trust the certificate authorities in the host OS certificate store.
+    ///
+    /// Replaces the trusted authorities, including any added by `add_certificate_authority`.
+    /// `ClientConfigBuilder::default()` already does this, ignoring errors.
+    #[cfg(feature = "native-certs")]
+    pub fn with_native_roots(&mut self) -> io::Result<&mut Self> {
+        Arc::make_mut(&mut self.config.crypto).root_store =
+            rustls_native_certs::load_native_certs()?;
+        Ok(self)
+    }
+
+    /// Trust the Mozilla root certificates bundled by the `webpki-roots` crate.
+    ///
+    /// An alternative to the OS certificate store where it is missing or unreadable, e.g. in
+    /// minimal containers. Replaces the trusted authorities, including any added by
+    /// `add_certificate_authority`.
+    #[cfg(feature = "webpki-roots")]
+    pub fn with_webpki_roots(&mut self) -> &mut Self {
+        let mut store = rustls::RootCertStore::empty();
+        store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        Arc::make_mut(&mut self.config.crypto).root_store = store;
+        self
+    }
+
+    /// Set the certificate chain that will be presented to servers requesting client
+    /// authentication.
+    pub fn certificate(
+        &mut self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<&mut Self, TLSError> {
+        rustls::sign::any_supported_type(&key.inner)
+            .map_err(|_| TLSError::General("invalid private key".into()))?;
+        Arc::make_mut(&mut self.config.crypto).set_single_client_cert(cert_chain.certs, key.inner);
+        Ok(self)
+    }
+
     /// Enable NSS-compatible cryptographic key logging to the `SSLKEYLOGFILE` environment variable.
     ///
     /// Useful for debugging encrypted communications with protocol analyzers such as Wireshark.
@@ -205,6 +490,60 @@ impl ClientConfigBuilder {
         self
     }
 
+    /// Check the Certificate Transparency proofs sent by servers against `logs`.
+    ///
+    /// Handshakes fail if a server sends signed certificate timestamps, none of which can be
+    /// verified by one of `logs`. Servers which send none are still accepted, as rustls can't
+    /// require them. `None` disables the check. With the `ct-logs` feature,
+    /// `ClientConfigBuilder::default()` uses the logs known to Google.
+    pub fn ct_logs(&mut self, logs: Option<&'static [&'static sct::Log<'static>]>) -> &mut Self {
+        Arc::make_mut(&mut self.config.crypto).ct_logs = logs;
+        self
+    }
+
+    /// Restrict the TLS 1.3 cipher suites that may be negotiated, in order of descending preference.
+    ///
+    /// Fails if `suites` is empty or contains a suite which isn't supported. rustls doesn't allow
+    /// restricting key exchange groups.
+    pub fn cipher_suites(&mut self, suites: &[CipherSuite]) -> Result<&mut Self, TLSError> {
+        Arc::make_mut(&mut self.config.crypto).ciphersuites = tls13_suites(suites)?;
+        Ok(self)
+    }
+
+    /// Use a custom verifier for server certificates.
+    ///
+    /// The verifier is given the trusted roots configured on this builder, but is free to ignore
+    /// them.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn certificate_verifier(
+        &mut self,
+        verifier: Arc<dyn rustls::ServerCertVerifier>,
+    ) -> &mut Self {
+        Arc::make_mut(&mut self.config.crypto)
+            .dangerous()
+            .set_certificate_verifier(verifier);
+        self
+    }
+
+    /// Trust servers holding one of the public keys in `keys`, instead of the configured roots.
+    ///
+    /// See `PinnedKeyVerifier`.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn pinned_server_keys(&mut self, keys: impl IntoIterator<Item = Vec<u8>>) -> &mut Self {
+        self.certificate_verifier(crate::PinnedKeyVerifier::new(keys))
+    }
+
+    /// Store session tickets received from servers in `cache`.
+    ///
+    /// Cached tickets allow later connections to the same server to resume the session and send
+    /// 0-RTT data, see `Connecting::into_0rtt`. Defaults to an in-memory cache shared by all
+    /// connections using this configuration; use `FileSessionCache` to keep tickets across
+    /// process restarts.
+    pub fn session_cache(&mut self, cache: Arc<dyn rustls::StoresClientSessions>) -> &mut Self {
+        Arc::make_mut(&mut self.config.crypto).session_persistence = cache;
+        self
+    }
+
     /// Set the application-layer protocols to accept, in order of descending preference.
     ///
     /// When set, clients which don't declare support for at least one of the supplied protocols will be rejected.
@@ -218,6 +557,12 @@ impl ClientConfigBuilder {
         self
     }
 
+    /// Set the transport parameters of outgoing connections, e.g. to enable keep-alive.
+    pub fn transport_config(&mut self, config: Arc<TransportConfig>) -> &mut Self {
+        self.config.transport = config;
+        self
+    }
+
     /// Begin connecting from `endpoint` to `addr`.
     pub fn build(self) -> ClientConfig {
         self.config
@@ -16,14 +16,18 @@ use futures::{
     channel::{mpsc, oneshot},
     FutureExt, StreamExt,
 };
-use proto::{ConnectionError, ConnectionHandle, ConnectionId, Dir, StreamId, TimerUpdate};
-use tokio::time::{delay_until, Delay, Instant as TokioInstant};
+use proto::{
+    congestion, crypto, AmplificationLimit, ConnectionError, ConnectionHandle, ConnectionId,
+    ConnectionStats, Dir, KeyPhaseStats, PathStats, PeerParams, StreamId, TelemetryEvent,
+    TimerUpdate, ZeroRttStatus,
+};
 use tracing::{info_span, trace};
 
 use crate::{
     broadcast::{self, Broadcast},
+    runtime::{AsyncTimer, Runtime},
     streams::{RecvStream, SendStream, WriteError},
-    ConnectionEvent, EndpointEvent, VarInt,
+    AuthenticationData, ConnectionEvent, EndpointEvent, VarInt,
 };
 
 /// In-progress connection attempt future
@@ -326,6 +330,52 @@ impl Connection {
         self.0.lock().unwrap().inner.max_datagram_size()
     }
 
+    /// State of the congestion controller of the current path
+    ///
+    /// The controller is selected by `TransportConfig::congestion_controller_factory`.
+    pub fn congestion_state(&self) -> congestion::ControllerState {
+        self.0.lock().unwrap().inner.congestion().state()
+    }
+
+    /// Whether outgoing packets are marked as ECN-capable
+    ///
+    /// See `TransportConfig::allow_ecn`.
+    pub fn using_ecn(&self) -> bool {
+        self.0.lock().unwrap().inner.using_ecn()
+    }
+
+    /// The QUIC version negotiated with the peer
+    pub fn version(&self) -> u32 {
+        self.0.lock().unwrap().inner.version()
+    }
+
+    /// How much data may be sent to the peer before its address is validated
+    ///
+    /// `None` unless this is a server still validating the client's address. Useful in diagnosing
+    /// slow handshakes.
+    pub fn amplification_limit(&self) -> Option<AmplificationLimit> {
+        self.0.lock().unwrap().inner.amplification_limit()
+    }
+
+    /// Transport parameters advertised by the peer
+    ///
+    /// `None` on a client until the handshake completes, unless 0-RTT was attempted.
+    pub fn peer_params(&self) -> Option<PeerParams> {
+        self.0.lock().unwrap().inner.peer_params()
+    }
+
+    /// Transport parameters sent by the peer that quinn doesn't interpret itself
+    ///
+    /// See `TransportConfig::custom_transport_params`.
+    pub fn peer_custom_params(&self) -> Vec<(u16, Vec<u8>)> {
+        self.0.lock().unwrap().inner.peer_custom_params().to_vec()
+    }
+
+    /// Largest UDP payload known to get through the current path, found by path MTU discovery
+    pub fn mtu(&self) -> u16 {
+        self.0.lock().unwrap().inner.mtu()
+    }
+
     /// The peer's UDP address.
     pub fn remote_address(&self) -> SocketAddr {
         self.0.lock().unwrap().inner.remote()
@@ -341,10 +391,102 @@ impl Connection {
         self.0.lock().unwrap().inner.protocol().map(|x| x.into())
     }
 
-    // Update traffic keys spontaneously for testing purposes.
-    #[doc(hidden)]
-    pub fn force_key_update(&self) {
-        self.0.lock().unwrap().inner.initiate_key_update()
+    /// Cryptographic identity and parameters negotiated with the peer
+    ///
+    /// Includes the peer's certificate chain, the negotiated application protocol and, on the
+    /// server side, the server name requested by the client.
+    pub fn authentication_data(&self) -> AuthenticationData {
+        self.0.lock().unwrap().inner.authentication_data()
+    }
+
+    /// Fill `output` with keying material derived from the connection's secrets (RFC 5705)
+    ///
+    /// Both endpoints obtain the same value for the same `label` and `context`. Fails until the
+    /// handshake has completed.
+    pub fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), crypto::ExportKeyingMaterialError> {
+        self.0
+            .lock()
+            .unwrap()
+            .inner
+            .export_keying_material(output, label, context)
+    }
+
+    /// Switch to fresh 1-RTT keys for outgoing packets
+    ///
+    /// Returns false if a key update isn't currently permitted, e.g. before the handshake is
+    /// confirmed or too soon after the previous update.
+    pub fn force_key_update(&self) -> bool {
+        self.0.lock().unwrap().inner.force_key_update()
+    }
+
+    /// Current statistics on the connection's performance, such as RTT and loss
+    pub fn stats(&self) -> ConnectionStats {
+        self.0.lock().unwrap().inner.stats()
+    }
+
+    /// Statistics on the path currently used to send to the peer
+    pub fn current_path(&self) -> PathStats {
+        self.0.lock().unwrap().inner.current_path()
+    }
+
+    /// Statistics on all known paths, starting with the current one
+    ///
+    /// While a migration is being validated, the path it replaced is also reported.
+    pub fn path_stats(&self) -> Vec<PathStats> {
+        self.0.lock().unwrap().inner.path_stats()
+    }
+
+    /// Subscribe to telemetry events describing this connection's lifecycle
+    ///
+    /// Events are only recorded if `TransportConfig::telemetry` is set. Those recorded before the
+    /// first subscription are delivered to it.
+    pub fn telemetry(&self) -> mpsc::UnboundedReceiver<TelemetryEvent> {
+        let (send, recv) = mpsc::unbounded();
+        let mut conn = self.0.lock().unwrap();
+        conn.telemetry.push(send);
+        conn.wake();
+        recv
+    }
+
+    /// Statistics on the 1-RTT keys used by this connection
+    pub fn key_phase_stats(&self) -> KeyPhaseStats {
+        self.0.lock().unwrap().inner.key_phase_stats()
+    }
+
+    /// The runtime driving this connection's endpoint
+    pub fn runtime(&self) -> Arc<dyn Runtime> {
+        self.0.lock().unwrap().runtime.clone()
+    }
+
+    /// Override the idle timeout of this connection, in milliseconds
+    ///
+    /// The timeout can only be shortened from the value negotiated during the handshake, so
+    /// configure a generous `TransportConfig::idle_timeout` and shorten it for connections that
+    /// warrant it, e.g. those from unauthenticated clients. 0 restores the negotiated value.
+    /// Returns the timeout now in effect.
+    pub fn set_idle_timeout(&self, timeout: u64) -> u64 {
+        let conn = &mut *self.0.lock().unwrap();
+        let timeout = conn.inner.set_idle_timeout(Instant::now(), timeout);
+        conn.wake();
+        timeout
+    }
+
+    /// Whether 0-RTT data was attempted and what became of it
+    ///
+    /// Clients see `Pending` until the handshake reveals whether the server accepted early data.
+    /// Data written to streams opened before a rejection is lost and must be resent.
+    pub fn zero_rtt_status(&self) -> ZeroRttStatus {
+        self.0.lock().unwrap().inner.zero_rtt_status()
+    }
+
+    /// Bytes of 0-RTT packets sent on this connection
+    pub fn zero_rtt_bytes_sent(&self) -> u64 {
+        self.0.lock().unwrap().inner.zero_rtt_bytes_sent()
     }
 }
 
@@ -549,6 +691,11 @@ pub enum SendDatagramError {
     /// Datagram support is disabled locally
     #[error(display = "datagram support disabled")]
     Disabled,
+    /// This many previously queued datagrams were dropped because they no longer fit in a packet
+    ///
+    /// Reported once; sending may be retried immediately.
+    #[error(display = "{} queued datagrams dropped", _0)]
+    Dropped(u64),
 }
 
 #[derive(Debug)]
@@ -558,6 +705,7 @@ impl ConnectionRef {
     pub(crate) fn new(
         handle: ConnectionHandle,
         conn: proto::Connection,
+        runtime: Arc<dyn Runtime>,
         endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
         conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
     ) -> Self {
@@ -569,6 +717,7 @@ impl ConnectionRef {
             on_connected: None,
             connected: false,
             timers: Default::default(),
+            runtime,
             conn_events,
             endpoint_events,
             blocked_writers: HashMap::new(),
@@ -582,6 +731,7 @@ impl ConnectionRef {
             error: None,
             ref_count: 0,
             send_datagram_blocked: Broadcast::new(),
+            telemetry: Vec::new(),
         })))
     }
 }
@@ -623,7 +773,8 @@ pub struct ConnectionInner {
     handle: ConnectionHandle,
     on_connected: Option<oneshot::Sender<bool>>,
     connected: bool,
-    timers: proto::TimerTable<Option<Delay>>,
+    timers: proto::TimerTable<Option<Pin<Box<dyn AsyncTimer>>>>,
+    runtime: Arc<dyn Runtime>,
     conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
     endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
     pub(crate) blocked_writers: HashMap<StreamId, Waker>,
@@ -639,6 +790,7 @@ pub struct ConnectionInner {
     /// Number of live handles that can be used to initiate or handle I/O; excludes the driver
     ref_count: usize,
     send_datagram_blocked: Broadcast,
+    telemetry: Vec<mpsc::UnboundedSender<TelemetryEvent>>,
 }
 
 impl ConnectionInner {
@@ -670,6 +822,9 @@ impl ConnectionInner {
                 Poll::Ready(Some(ConnectionEvent::Close { reason, error_code })) => {
                     self.close(error_code, reason);
                 }
+                Poll::Ready(Some(ConnectionEvent::Rebind)) => {
+                    self.inner.local_address_changed();
+                }
                 Poll::Ready(None) => {
                     return Err(ConnectionError::TransportError(proto::TransportError {
                         code: proto::TransportErrorCode::INTERNAL_ERROR,
@@ -745,13 +900,22 @@ impl ConnectionInner {
                 }
             }
         }
+
+        // Recorded events are held by the protocol state until someone is listening
+        if self.telemetry.is_empty() {
+            return;
+        }
+        while let Some(event) = self.inner.poll_telemetry() {
+            self.telemetry
+                .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+        }
     }
 
     fn drive_timers(&mut self, cx: &mut Context, now: Instant) -> bool {
         let mut keep_going = false;
         for (timer, slot) in &mut self.timers {
             if let Some(ref mut delay) = slot {
-                match delay.poll_unpin(cx) {
+                match delay.as_mut().poll(cx) {
                     Poll::Ready(()) => {
                         *slot = None;
                         trace!("{:?} timeout", timer);
@@ -777,11 +941,11 @@ impl ConnectionInner {
                 } => match self.timers[timer] {
                     ref mut x @ None => {
                         trace!(time = ?time.duration_since(self.epoch), "{:?} timer start", timer);
-                        *x = Some(delay_until(TokioInstant::from_std(time)));
+                        *x = Some(self.runtime.new_timer(time));
                     }
                     Some(ref mut x) => {
                         trace!(time = ?time.duration_since(self.epoch), "{:?} timer reset", timer);
-                        x.reset(TokioInstant::from_std(time));
+                        x.as_mut().reset(time);
                     }
                 },
                 TimerUpdate {
@@ -867,6 +1031,7 @@ impl ConnectionInner {
                 Poll::Ready(Err(SendDatagramError::UnsupportedByPeer))
             }
             proto::SendDatagramError::Disabled => Poll::Ready(Err(SendDatagramError::Disabled)),
+            proto::SendDatagramError::Dropped(n) => Poll::Ready(Err(SendDatagramError::Dropped(n))),
         }
     }
 }
@@ -1,7 +1,9 @@
 use std::{
     collections::{HashMap, VecDeque},
+    fmt,
     future::Future,
-    io,
+    io::{self, IoSliceMut},
+    mem::{self, MaybeUninit},
     net::{SocketAddr, SocketAddrV6},
     pin::Pin,
     str,
@@ -13,12 +15,13 @@ use std::{
 use bytes::Bytes;
 use futures::{channel::mpsc, FutureExt, StreamExt};
 use proto::{self as proto, ClientConfig, ConnectError, ConnectionHandle, DatagramEvent};
+use tracing::trace;
 
 use crate::{
     builders::EndpointBuilder,
     connection::{Connecting, ConnectionDriver, ConnectionRef},
-    udp::UdpSocket,
-    ConnectionEvent, EndpointEvent, VarInt, IO_LOOP_BOUND,
+    runtime::{AsyncUdpSocket, RecvMeta, Runtime},
+    ConnectionEvent, EndpointEvent, VarInt, BATCH_SIZE, IO_LOOP_BOUND,
 };
 
 /// A QUIC endpoint.
@@ -81,14 +84,34 @@ impl Endpoint {
     ///
     /// Allows the endpoint's address to be updated live, affecting all active connections. Incoming
     /// connections and connections to servers unreachable from the new address will be lost.
+    /// Outgoing connections migrate to the new address, e.g. when a client switches networks.
     ///
     /// On error, the old UDP socket is retained.
     pub fn rebind(&self, socket: std::net::UdpSocket) -> io::Result<()> {
+        let socket = self.inner.lock().unwrap().runtime.wrap_udp_socket(socket)?;
+        self.rebind_socket(socket)
+    }
+
+    /// Switch to a custom socket implementation
+    ///
+    /// See `rebind` and `EndpointBuilder::with_async_socket`.
+    pub fn rebind_async_socket(&self, socket: impl AsyncUdpSocket) -> io::Result<()> {
+        self.rebind_socket(Box::new(socket))
+    }
+
+    fn rebind_socket(&self, socket: Box<dyn AsyncUdpSocket>) -> io::Result<()> {
         let addr = socket.local_addr()?;
-        let socket = UdpSocket::from_std(socket)?;
         let mut inner = self.inner.lock().unwrap();
         inner.socket = socket;
         inner.ipv6 = addr.is_ipv6();
+        for sender in inner.connections.values() {
+            // Ignoring errors from dropped connections
+            let _ = sender.unbounded_send(ConnectionEvent::Rebind);
+        }
+        // Wake the driver so it starts polling the new socket
+        if let Some(task) = inner.driver.take() {
+            task.wake();
+        }
         Ok(())
     }
 
@@ -97,6 +120,11 @@ impl Endpoint {
         self.inner.lock().unwrap().socket.local_addr()
     }
 
+    /// The runtime driving this endpoint, for running related tasks and timers on it
+    pub fn runtime(&self) -> Arc<dyn Runtime> {
+        self.inner.lock().unwrap().runtime.clone()
+    }
+
     /// Close all of this endpoint's connections immediately and cease accepting new connections.
     ///
     /// See `Connection::close` for details.
@@ -115,6 +143,67 @@ impl Endpoint {
             task.wake();
         }
     }
+
+    /// Aggregate counters describing the endpoint's activity
+    pub fn stats(&self) -> EndpointStats {
+        let endpoint = self.inner.lock().unwrap();
+        EndpointStats {
+            connections_accepted: endpoint.inner.connections_accepted(),
+            connections_refused: endpoint.inner.connections_refused(),
+            version_negotiations_sent: endpoint.inner.version_negotiations_sent(),
+            retries_sent: endpoint.inner.retries_sent(),
+            open_connections: endpoint.inner.open_connections(),
+            ..endpoint.io_stats
+        }
+    }
+
+    /// Wait for all of this endpoint's connections to be cleanly shut down
+    ///
+    /// Waiting for this before exiting gives CONNECTION_CLOSE frames a chance to reach peers,
+    /// which would otherwise have to wait out their idle timeout. Doesn't close any connections
+    /// or stop accepting new ones; see `close`.
+    pub fn wait_idle(&self) -> WaitIdle {
+        WaitIdle(self.inner.clone())
+    }
+}
+
+/// Statistics on an endpoint, see `Endpoint::stats`
+#[derive(Debug, Default, Copy, Clone)]
+pub struct EndpointStats {
+    /// Incoming connections whose handshake was begun
+    pub connections_accepted: u64,
+    /// Incoming connections refused due to load, connection limits or handshake rate limits
+    pub connections_refused: u64,
+    /// Version Negotiation packets sent to clients using unsupported versions
+    pub version_negotiations_sent: u64,
+    /// Retry packets sent to validate client addresses
+    pub retries_sent: u64,
+    /// UDP datagrams received
+    pub datagrams_received: u64,
+    /// Bytes received in UDP datagrams
+    pub bytes_received: u64,
+    /// UDP datagrams sent
+    pub datagrams_sent: u64,
+    /// Bytes sent in UDP datagrams
+    pub bytes_sent: u64,
+    /// Connections currently open, including those being drained
+    pub open_connections: usize,
+}
+
+/// Future produced by `Endpoint::wait_idle`
+#[derive(Debug)]
+pub struct WaitIdle(EndpointRef);
+
+impl Future for WaitIdle {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let endpoint = &mut *self.0.lock().unwrap();
+        if endpoint.connections.is_empty() {
+            return Poll::Ready(());
+        }
+        endpoint.idle_waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
 }
 
 /// A future that drives IO on an endpoint
@@ -167,14 +256,17 @@ impl Drop for EndpointDriver {
         // Drop all outgoing channels, signaling the termination of the endpoint to the associated
         // connections.
         endpoint.connections.clear();
+        endpoint.wake_idle_waiters();
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct EndpointInner {
-    socket: UdpSocket,
+    socket: Box<dyn AsyncUdpSocket>,
+    runtime: Arc<dyn Runtime>,
     inner: proto::Endpoint,
     outgoing: VecDeque<proto::Transmit>,
+    recv_buf: RecvBuf,
     incoming: VecDeque<ConnectionDriver>,
     incoming_reader: Option<Waker>,
     /// Whether the `Incoming` stream has not yet been dropped
@@ -190,35 +282,48 @@ pub(crate) struct EndpointInner {
     /// Set if the endpoint has been manually closed
     close: Option<(VarInt, Bytes)>,
     driver_lost: bool,
+    /// Tasks waiting for `connections` to become empty
+    idle_waiters: Vec<Waker>,
+    /// Datagram counters, maintained here because the protocol state doesn't see all I/O
+    io_stats: EndpointStats,
 }
 
 impl EndpointInner {
     fn drive_recv(&mut self, cx: &mut Context, now: Instant) -> Result<bool, io::Error> {
-        let mut buf = [0; 64 * 1024];
-        let mut recvd = 0;
+        // Taken out of `self` while borrowed, so that datagrams can be handled meanwhile
+        let mut recv_buf = mem::replace(&mut self.recv_buf, RecvBuf(Box::new([])));
+        let result = self.recv_into(cx, now, &mut recv_buf.0);
+        self.recv_buf = recv_buf;
+        result
+    }
+
+    fn recv_into(
+        &mut self,
+        cx: &mut Context,
+        now: Instant,
+        recv_buf: &mut [u8],
+    ) -> Result<bool, io::Error> {
+        let mut metas = [RecvMeta::default(); BATCH_SIZE];
+        let mut iovs = MaybeUninit::<[IoSliceMut<'_>; BATCH_SIZE]>::uninit();
+        recv_buf
+            .chunks_mut(recv_buf.len() / BATCH_SIZE)
+            .enumerate()
+            .for_each(|(i, buf)| unsafe {
+                iovs.as_mut_ptr()
+                    .cast::<IoSliceMut>()
+                    .add(i)
+                    .write(IoSliceMut::new(buf));
+            });
+        let mut iovs = unsafe { iovs.assume_init() };
+        let mut calls = 0;
         loop {
-            match self.socket.poll_recv(cx, &mut buf) {
-                Poll::Ready(Ok((n, addr, ecn))) => {
-                    match self.inner.handle(now, addr, ecn, (&buf[0..n]).into()) {
-                        Some((handle, DatagramEvent::NewConnection(conn))) => {
-                            let conn = ConnectionDriver(self.create_connection(handle, conn));
-                            if !self.incoming_live {
-                                conn.0.lock().unwrap().implicit_close();
-                            }
-                            self.incoming.push_back(conn);
-                            if let Some(task) = self.incoming_reader.take() {
-                                task.wake();
-                            }
-                        }
-                        Some((handle, DatagramEvent::ConnectionEvent(event))) => {
-                            // Ignoring errors from dropped connections that haven't yet been cleaned up
-                            let _ = self
-                                .connections
-                                .get_mut(&handle)
-                                .unwrap()
-                                .unbounded_send(ConnectionEvent::Proto(event));
-                        }
-                        None => {}
+            match self.socket.poll_recv(cx, &mut iovs, &mut metas) {
+                Poll::Ready(Ok(msgs)) => {
+                    for (meta, buf) in metas.iter().zip(iovs.iter()).take(msgs) {
+                        trace!(src = %meta.addr, dst = ?meta.dst_ip, len = meta.len, "got datagram");
+                        self.io_stats.datagrams_received += 1;
+                        self.io_stats.bytes_received += meta.len as u64;
+                        self.handle_datagram(now, meta, &buf[0..meta.len]);
                     }
                 }
                 Poll::Pending => {
@@ -233,14 +338,38 @@ impl EndpointInner {
                     return Err(e);
                 }
             }
-            recvd += 1;
-            if recvd >= IO_LOOP_BOUND {
+            calls += 1;
+            if calls >= IO_LOOP_BOUND {
                 return Ok(true);
             }
         }
         Ok(false)
     }
 
+    fn handle_datagram(&mut self, now: Instant, meta: &RecvMeta, data: &[u8]) {
+        match self.inner.handle(now, meta.addr, meta.ecn, data.into()) {
+            Some((handle, DatagramEvent::NewConnection(conn))) => {
+                let conn = ConnectionDriver(self.create_connection(handle, conn));
+                if !self.incoming_live {
+                    conn.0.lock().unwrap().implicit_close();
+                }
+                self.incoming.push_back(conn);
+                if let Some(task) = self.incoming_reader.take() {
+                    task.wake();
+                }
+            }
+            Some((handle, DatagramEvent::ConnectionEvent(event))) => {
+                // Ignoring errors from dropped connections that haven't yet been cleaned up
+                let _ = self
+                    .connections
+                    .get_mut(&handle)
+                    .unwrap()
+                    .unbounded_send(ConnectionEvent::Proto(event));
+            }
+            None => {}
+        }
+    }
+
     fn drive_incoming(&mut self, cx: &mut Context) {
         for i in (0..self.incoming.len()).rev() {
             match self.incoming[i].poll_unpin(cx) {
@@ -256,7 +385,7 @@ impl EndpointInner {
     fn drive_send(&mut self, cx: &mut Context) -> Result<bool, io::Error> {
         let mut calls = 0;
         loop {
-            while self.outgoing.len() < crate::udp::BATCH_SIZE {
+            while self.outgoing.len() < BATCH_SIZE {
                 match self.inner.poll_transmit() {
                     Some(x) => self.outgoing.push_back(x),
                     None => break,
@@ -267,7 +396,10 @@ impl EndpointInner {
             }
             match self.socket.poll_send(cx, self.outgoing.as_slices().0) {
                 Poll::Ready(Ok(n)) => {
-                    self.outgoing.drain(..n);
+                    for transmit in self.outgoing.drain(..n) {
+                        self.io_stats.datagrams_sent += 1;
+                        self.io_stats.bytes_sent += transmit.contents.len() as u64;
+                    }
                     calls += 1;
                     if calls == IO_LOOP_BOUND {
                         return Ok(true);
@@ -294,6 +426,9 @@ impl EndpointInner {
                     Proto(e) => {
                         if e.is_drained() {
                             self.connections.remove(&ch);
+                            if self.connections.is_empty() {
+                                self.wake_idle_waiters();
+                            }
                         }
                         if let Some(event) = self.inner.handle_event(ch, e) {
                             // Ignoring errors from dropped connections that haven't yet been cleaned up
@@ -314,6 +449,12 @@ impl EndpointInner {
         }
     }
 
+    fn wake_idle_waiters(&mut self) {
+        for task in self.idle_waiters.drain(..) {
+            task.wake();
+        }
+    }
+
     fn create_connection(
         &mut self,
         handle: ConnectionHandle,
@@ -328,7 +469,13 @@ impl EndpointInner {
             .unwrap();
         }
         self.connections.insert(handle, send);
-        ConnectionRef::new(handle, conn, self.sender.clone(), recv)
+        ConnectionRef::new(
+            handle,
+            conn,
+            self.runtime.clone(),
+            self.sender.clone(),
+            recv,
+        )
     }
 }
 
@@ -379,19 +526,38 @@ impl Drop for Incoming {
     }
 }
 
+/// Space for a batch of received datagrams, allocated once per endpoint
+struct RecvBuf(Box<[u8]>);
+
+impl fmt::Debug for RecvBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("RecvBuf")
+    }
+}
+
+/// Size of the space for each received datagram, enough for any UDP datagram
+const RECV_BUF_SIZE: usize = 64 * 1024;
+
 #[derive(Debug)]
 pub(crate) struct EndpointRef(Arc<Mutex<EndpointInner>>);
 
 impl EndpointRef {
-    pub(crate) fn new(socket: UdpSocket, inner: proto::Endpoint, ipv6: bool) -> Self {
+    pub(crate) fn new(
+        socket: Box<dyn AsyncUdpSocket>,
+        runtime: Arc<dyn Runtime>,
+        inner: proto::Endpoint,
+        ipv6: bool,
+    ) -> Self {
         let (sender, events) = mpsc::unbounded();
         Self(Arc::new(Mutex::new(EndpointInner {
             socket,
+            runtime,
             inner,
             ipv6,
             sender,
             events,
             outgoing: VecDeque::new(),
+            recv_buf: RecvBuf(vec![0; RECV_BUF_SIZE * BATCH_SIZE].into_boxed_slice()),
             incoming: VecDeque::new(),
             incoming_live: true,
             incoming_reader: None,
@@ -400,6 +566,8 @@ impl EndpointRef {
             ref_count: 0,
             close: None,
             driver_lost: false,
+            idle_waiters: Vec::new(),
+            io_stats: EndpointStats::default(),
         })))
     }
 }
@@ -47,12 +47,20 @@
 
 mod broadcast;
 mod builders;
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
 mod platform;
+#[cfg(feature = "runtime-tokio")]
 mod udp;
 
+#[cfg(feature = "qlog")]
+pub use proto::qlog;
+#[cfg(feature = "packet-tap")]
+pub use proto::tap;
 pub use proto::{
-    crypto, ClientConfig, ConnectError, ConnectionError, ConnectionId, DatagramEvent, ServerConfig,
-    Transmit, TransportConfig, VarInt,
+    congestion, crypto, AckFrequencyConfig, AmplificationLimit, AuthenticationData, ClientConfig,
+    ConfigError, ConnectError, ConnectionError, ConnectionId, ConnectionStats, DatagramEvent,
+    KeyPhaseStats, PathStats, PeerParams, RefusalObserver, RefusalReason, ServerConfig,
+    TelemetryEvent, TokenFormat, Transmit, TransportConfig, VarInt, ZeroRttStatus,
 };
 
 pub use crate::builders::{
@@ -62,11 +70,18 @@ pub use crate::builders::{
 mod connection;
 pub use connection::{
     Connecting, Connection, ConnectionDriver, Datagrams, IncomingBiStreams, IncomingUniStreams,
-    NewConnection, OpenBi, OpenUni,
+    NewConnection, OpenBi, OpenUni, ZeroRttAccepted,
 };
 
 mod endpoint;
-pub use endpoint::{Endpoint, EndpointDriver, Incoming};
+pub use endpoint::{Endpoint, EndpointDriver, EndpointStats, Incoming, WaitIdle};
+
+mod runtime;
+#[cfg(feature = "runtime-async-std")]
+pub use runtime::AsyncStdRuntime;
+#[cfg(feature = "runtime-tokio")]
+pub use runtime::TokioRuntime;
+pub use runtime::{AsyncTimer, AsyncUdpSocket, RecvMeta, Runtime};
 
 mod streams;
 pub use streams::{
@@ -75,7 +90,12 @@ pub use streams::{
 };
 
 mod tls;
-pub use tls::{Certificate, CertificateChain, PrivateKey};
+#[cfg(feature = "dangerous_configuration")]
+pub use tls::PinnedKeyVerifier;
+pub use tls::{
+    Certificate, CertificateChain, CertificateResolver, FileSessionCache, PrivateKey,
+    ReloadableCertificate, TicketKeys,
+};
 
 #[cfg(test)]
 mod tests;
@@ -86,6 +106,8 @@ enum ConnectionEvent {
         error_code: VarInt,
         reason: bytes::Bytes,
     },
+    /// The endpoint switched to a new socket
+    Rebind,
     Proto(proto::ConnectionEvent),
 }
 
@@ -100,3 +122,8 @@ enum EndpointEvent {
 /// This helps ensure we don't starve anything when the CPU is slower than the link. Value selected
 /// more or less arbitrarily.
 const IO_LOOP_BOUND: usize = 10;
+
+/// Number of UDP packets to send or receive at a time
+///
+/// Chosen somewhat arbitrarily; might benefit from additional tuning.
+const BATCH_SIZE: usize = 32;
@@ -1,15 +1,17 @@
-use std::{io, net::SocketAddr};
+use std::io::{self, IoSliceMut};
 
 use mio::net::UdpSocket;
 
-use proto::{EcnCodepoint, Transmit};
+use proto::Transmit;
+
+use super::RecvMeta;
 
 impl super::UdpExt for UdpSocket {
     fn init_ext(&self) -> io::Result<()> {
         Ok(())
     }
 
-    fn send_ext(&self, transmits: &[Transmit]) -> io::Result<usize> {
+    fn send_ext(&self, transmits: &[Transmit], _max_segments: usize) -> io::Result<usize> {
         let mut sent = 0;
         for transmit in transmits {
             match self.send_to(&transmit.contents, &transmit.destination) {
@@ -30,7 +32,18 @@ impl super::UdpExt for UdpSocket {
         Ok(sent)
     }
 
-    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)> {
-        self.recv_from(buf).map(|(x, y)| (x, y, None))
+    fn recv_ext(&self, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        let (len, addr) = self.recv_from(&mut bufs[0])?;
+        meta[0] = RecvMeta {
+            addr,
+            len,
+            ..RecvMeta::default()
+        };
+        Ok(1)
     }
 }
+
+/// Segmentation offload is unsupported
+pub fn max_gso_segments() -> usize {
+    1
+}
@@ -1,18 +1,33 @@
 //! Uniform interface to send/recv UDP packets with ECN information.
-use proto::{EcnCodepoint, Transmit};
-use std::{io, net::SocketAddr};
+use proto::Transmit;
+use std::io::{self, IoSliceMut};
+
+use crate::runtime::RecvMeta;
 
 #[cfg(unix)]
 mod cmsg;
 #[cfg(unix)]
 mod unix;
+#[cfg(unix)]
+pub use unix::max_gso_segments;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
 
 // No ECN support
 #[cfg(not(unix))]
 mod fallback;
+#[cfg(not(unix))]
+pub use fallback::max_gso_segments;
 
 pub trait UdpExt {
     fn init_ext(&self) -> io::Result<()>;
-    fn send_ext(&self, transmits: &[Transmit]) -> io::Result<usize>;
-    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)>;
+    /// Send a prefix of `transmits`, returning its length
+    ///
+    /// Consecutive datagrams of equal size to the same destination are passed to the kernel
+    /// together in groups of up to `max_segments`, to be split up by segmentation offload.
+    fn send_ext(&self, transmits: &[Transmit], max_segments: usize) -> io::Result<usize>;
+    /// Receive datagrams into a prefix of `bufs`, returning its length
+    ///
+    /// `meta[i]` describes the datagram received into `bufs[i]`.
+    fn recv_ext(&self, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize>;
 }
@@ -1,7 +1,7 @@
 use std::{
-    io,
+    io::{self, IoSliceMut},
     mem::{self, MaybeUninit},
-    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     os::unix::io::AsRawFd,
     ptr,
 };
@@ -9,7 +9,7 @@ use std::{
 use mio::net::UdpSocket;
 use proto::{EcnCodepoint, Transmit};
 
-use super::cmsg;
+use super::{cmsg, RecvMeta};
 
 #[cfg(target_os = "freebsd")]
 type IpTosTy = libc::c_uchar;
@@ -28,7 +28,8 @@ impl super::UdpExt for UdpSocket {
             mem::size_of::<libc::sockaddr_in6>()
         );
         assert!(
-            CMSG_LEN >= unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as _) as usize }
+            CMSG_LEN
+                >= 2 * unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as _) as usize }
         );
         assert!(
             mem::align_of::<libc::cmsghdr>() <= mem::align_of::<cmsg::Aligned<[u8; 0]>>(),
@@ -53,6 +54,28 @@ impl super::UdpExt for UdpSocket {
                 return Err(io::Error::last_os_error());
             }
         }
+        // Learn the destination address of each datagram
+        #[cfg(target_os = "linux")]
+        {
+            let on: libc::c_int = 1;
+            let (level, name) = if addr.is_ipv4() {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO)
+            } else {
+                (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)
+            };
+            let rc = unsafe {
+                libc::setsockopt(
+                    self.as_raw_fd(),
+                    level,
+                    name,
+                    &on as *const _ as _,
+                    mem::size_of_val(&on) as _,
+                )
+            };
+            if rc == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
         if addr.is_ipv6() {
             let on: libc::c_int = 1;
             let rc = unsafe {
@@ -72,28 +95,31 @@ impl super::UdpExt for UdpSocket {
     }
 
     #[cfg(not(target_os = "macos"))]
-    fn send_ext(&self, transmits: &[Transmit]) -> io::Result<usize> {
-        use crate::udp::BATCH_SIZE;
+    fn send_ext(&self, transmits: &[Transmit], max_segments: usize) -> io::Result<usize> {
+        use crate::BATCH_SIZE;
+        let transmits = &transmits[..transmits.len().min(BATCH_SIZE)];
         let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
         let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
         let mut cmsgs = [cmsg::Aligned(MaybeUninit::uninit()); BATCH_SIZE];
-        for (i, transmit) in transmits.iter().enumerate().take(BATCH_SIZE) {
+        // Number of transmits carried by each message
+        let mut segments = [0; BATCH_SIZE];
+        let mut msg_count = 0;
+        let mut i = 0;
+        while i < transmits.len() {
+            let n = gso_segments(&transmits[i..], max_segments);
             prepare_msg(
-                transmit,
-                &mut msgs[i].msg_hdr,
-                &mut iovecs[i],
-                &mut cmsgs[i],
+                &transmits[i..i + n],
+                &mut msgs[msg_count].msg_hdr,
+                &mut iovecs[i..i + n],
+                &mut cmsgs[msg_count],
             );
+            segments[msg_count] = n;
+            msg_count += 1;
+            i += n;
         }
         loop {
-            let n = unsafe {
-                libc::sendmmsg(
-                    self.as_raw_fd(),
-                    msgs.as_mut_ptr(),
-                    transmits.len().min(crate::udp::BATCH_SIZE) as _,
-                    0,
-                )
-            };
+            let n =
+                unsafe { libc::sendmmsg(self.as_raw_fd(), msgs.as_mut_ptr(), msg_count as _, 0) };
             if n == -1 {
                 let e = io::Error::last_os_error();
                 if e.kind() == io::ErrorKind::Interrupted {
@@ -101,18 +127,23 @@ impl super::UdpExt for UdpSocket {
                 }
                 return Err(e);
             }
-            return Ok(n as usize);
+            return Ok(segments[..n as usize].iter().sum());
         }
     }
 
     #[cfg(target_os = "macos")]
-    fn send_ext(&self, transmits: &[Transmit]) -> io::Result<usize> {
+    fn send_ext(&self, transmits: &[Transmit], _max_segments: usize) -> io::Result<usize> {
         let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
         let mut iov: libc::iovec = unsafe { mem::zeroed() };
         let mut ctrl = cmsg::Aligned(MaybeUninit::uninit());
         let mut sent = 0;
         while sent < transmits.len() {
-            prepare_msg(&transmits[sent], &mut hdr, &mut iov, &mut ctrl);
+            prepare_msg(
+                std::slice::from_ref(&transmits[sent]),
+                &mut hdr,
+                std::slice::from_mut(&mut iov),
+                &mut ctrl,
+            );
             let n = unsafe { libc::sendmsg(self.as_raw_fd(), &hdr, 0) };
             if n == -1 {
                 let e = io::Error::last_os_error();
@@ -133,21 +164,52 @@ impl super::UdpExt for UdpSocket {
         Ok(sent)
     }
 
-    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)> {
-        let mut name = MaybeUninit::<libc::sockaddr_storage>::uninit();
-        let mut iov = libc::iovec {
-            iov_base: buf.as_ptr() as *mut _,
-            iov_len: buf.len(),
+    #[cfg(not(target_os = "macos"))]
+    fn recv_ext(&self, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        use crate::BATCH_SIZE;
+        let mut names = [MaybeUninit::<libc::sockaddr_storage>::uninit(); BATCH_SIZE];
+        let mut ctrls = [cmsg::Aligned(MaybeUninit::<[u8; CMSG_LEN]>::uninit()); BATCH_SIZE];
+        let mut hdrs = unsafe { mem::zeroed::<[libc::mmsghdr; BATCH_SIZE]>() };
+        let max_msg_count = bufs.len().min(BATCH_SIZE);
+        for i in 0..max_msg_count {
+            prepare_recv(
+                &mut bufs[i],
+                &mut names[i],
+                &mut ctrls[i],
+                &mut hdrs[i].msg_hdr,
+            );
+        }
+        let msg_count = loop {
+            let n = unsafe {
+                libc::recvmmsg(
+                    self.as_raw_fd(),
+                    hdrs.as_mut_ptr(),
+                    max_msg_count as _,
+                    0,
+                    ptr::null_mut(),
+                )
+            };
+            if n == -1 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            break n as usize;
         };
+        for i in 0..msg_count {
+            meta[i] = decode_recv(&names[i], &hdrs[i].msg_hdr, hdrs[i].msg_len as usize);
+        }
+        Ok(msg_count)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn recv_ext(&self, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        let mut name = MaybeUninit::<libc::sockaddr_storage>::uninit();
         let mut ctrl = cmsg::Aligned(MaybeUninit::<[u8; CMSG_LEN]>::uninit());
         let mut hdr = unsafe { mem::zeroed::<libc::msghdr>() };
-        hdr.msg_name = name.as_mut_ptr() as _;
-        hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
-        hdr.msg_iov = &mut iov;
-        hdr.msg_iovlen = 1;
-        hdr.msg_control = ctrl.0.as_mut_ptr() as _;
-        hdr.msg_controllen = CMSG_LEN as _;
-        hdr.msg_flags = 0;
+        prepare_recv(&mut bufs[0], &mut name, &mut ctrl, &mut hdr);
         let n = loop {
             let n = unsafe { libc::recvmsg(self.as_raw_fd(), &mut hdr, 0) };
             if n == -1 {
@@ -162,48 +224,28 @@ impl super::UdpExt for UdpSocket {
             }
             break n;
         };
-        let name = unsafe { name.assume_init() };
-        let ecn_bits = match unsafe { cmsg::Iter::new(&hdr).next() } {
-            Some(cmsg) => match (cmsg.cmsg_level, cmsg.cmsg_type) {
-                // FreeBSD uses IP_RECVTOS here, and we can be liberal because cmsgs are opt-in.
-                (libc::IPPROTO_IP, libc::IP_TOS) | (libc::IPPROTO_IP, libc::IP_RECVTOS) => unsafe {
-                    cmsg::decode::<u8>(cmsg)
-                },
-                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => unsafe {
-                    // Temporary hack around broken macos ABI. Remove once upstream fixes it.
-                    // https://bugreport.apple.com/web/?problemID=48761855
-                    if cfg!(target_os = "macos")
-                        && cmsg.cmsg_len as usize
-                            == libc::CMSG_LEN(mem::size_of::<u8>() as _) as usize
-                    {
-                        cmsg::decode::<u8>(cmsg)
-                    } else {
-                        cmsg::decode::<libc::c_int>(cmsg) as u8
-                    }
-                },
-                _ => 0,
-            },
-            None => 0,
-        };
-        let addr = match libc::c_int::from(name.ss_family) {
-            libc::AF_INET => unsafe { SocketAddr::V4(ptr::read(&name as *const _ as _)) },
-            libc::AF_INET6 => unsafe { SocketAddr::V6(ptr::read(&name as *const _ as _)) },
-            _ => unreachable!(),
-        };
-        Ok((n as usize, addr, EcnCodepoint::from_bits(ecn_bits)))
+        meta[0] = decode_recv(&name, &hdr, n as usize);
+        Ok(1)
     }
 }
 
-const CMSG_LEN: usize = 24;
+/// Room for the control messages of a single datagram: the ECN codepoint and either the segment
+/// size or the destination address
+pub(super) const CMSG_LEN: usize = 88;
 
-fn prepare_msg(
-    transmit: &Transmit,
+/// Set up `hdr` to send `transmits`, which must share a destination and ECN codepoint, as a single
+/// datagram or with segmentation offload
+pub(super) fn prepare_msg(
+    transmits: &[Transmit],
     hdr: &mut libc::msghdr,
-    iov: &mut libc::iovec,
+    iovs: &mut [libc::iovec],
     ctrl: &mut cmsg::Aligned<MaybeUninit<[u8; CMSG_LEN]>>,
 ) {
-    iov.iov_base = transmit.contents.as_ptr() as *const _ as *mut _;
-    iov.iov_len = transmit.contents.len();
+    for (iov, transmit) in iovs.iter_mut().zip(transmits) {
+        iov.iov_base = transmit.contents.as_ptr() as *const _ as *mut _;
+        iov.iov_len = transmit.contents.len();
+    }
+    let transmit = &transmits[0];
 
     let (name, namelen) = match transmit.destination {
         SocketAddr::V4(ref addr) => (addr as *const _ as _, mem::size_of::<libc::sockaddr_in>()),
@@ -211,8 +253,8 @@ fn prepare_msg(
     };
     hdr.msg_name = name;
     hdr.msg_namelen = namelen as _;
-    hdr.msg_iov = iov;
-    hdr.msg_iovlen = 1;
+    hdr.msg_iov = iovs.as_mut_ptr();
+    hdr.msg_iovlen = transmits.len() as _;
 
     hdr.msg_control = ctrl.0.as_mut_ptr() as _;
     hdr.msg_controllen = CMSG_LEN as _;
@@ -223,5 +265,183 @@ fn prepare_msg(
     } else {
         encoder.push(libc::IPPROTO_IPV6, libc::IPV6_TCLASS, ecn);
     }
+    #[cfg(target_os = "linux")]
+    {
+        if transmits.len() > 1 {
+            let segment_size = transmit.contents.len() as u16;
+            encoder.push(gso::SOL_UDP, gso::UDP_SEGMENT, segment_size);
+        }
+    }
     encoder.finish();
 }
+
+fn prepare_recv(
+    buf: &mut IoSliceMut,
+    name: &mut MaybeUninit<libc::sockaddr_storage>,
+    ctrl: &mut cmsg::Aligned<MaybeUninit<[u8; CMSG_LEN]>>,
+    hdr: &mut libc::msghdr,
+) {
+    hdr.msg_name = name.as_mut_ptr() as _;
+    hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+    // `IoSliceMut` is guaranteed to be ABI-compatible with `iovec`
+    hdr.msg_iov = buf as *mut IoSliceMut as *mut libc::iovec;
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = ctrl.0.as_mut_ptr() as _;
+    hdr.msg_controllen = CMSG_LEN as _;
+    hdr.msg_flags = 0;
+}
+
+pub(super) fn decode_recv(
+    name: &MaybeUninit<libc::sockaddr_storage>,
+    hdr: &libc::msghdr,
+    len: usize,
+) -> RecvMeta {
+    let name = unsafe { name.assume_init() };
+    let mut ecn_bits = 0;
+    let mut dst_ip = None;
+    for cmsg in unsafe { cmsg::Iter::new(hdr) } {
+        match (cmsg.cmsg_level, cmsg.cmsg_type) {
+            // FreeBSD uses IP_RECVTOS here, and we can be liberal because cmsgs are opt-in.
+            (libc::IPPROTO_IP, libc::IP_TOS) | (libc::IPPROTO_IP, libc::IP_RECVTOS) => unsafe {
+                ecn_bits = cmsg::decode::<u8>(cmsg);
+            },
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => unsafe {
+                // Temporary hack around broken macos ABI. Remove once upstream fixes it.
+                // https://bugreport.apple.com/web/?problemID=48761855
+                ecn_bits = if cfg!(target_os = "macos")
+                    && cmsg.cmsg_len as usize == libc::CMSG_LEN(mem::size_of::<u8>() as _) as usize
+                {
+                    cmsg::decode::<u8>(cmsg)
+                } else {
+                    cmsg::decode::<libc::c_int>(cmsg) as u8
+                };
+            },
+            #[cfg(target_os = "linux")]
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = unsafe { cmsg::decode::<libc::in_pktinfo>(cmsg) };
+                dst_ip = Some(Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)).into());
+            }
+            #[cfg(target_os = "linux")]
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info = unsafe { cmsg::decode::<libc::in6_pktinfo>(cmsg) };
+                dst_ip = Some(IpAddr::from(info.ipi6_addr.s6_addr));
+            }
+            _ => {}
+        }
+    }
+    let addr = match libc::c_int::from(name.ss_family) {
+        libc::AF_INET => unsafe { SocketAddr::V4(ptr::read(&name as *const _ as _)) },
+        libc::AF_INET6 => unsafe { SocketAddr::V6(ptr::read(&name as *const _ as _)) },
+        _ => unreachable!(),
+    };
+    RecvMeta {
+        addr,
+        len,
+        ecn: EcnCodepoint::from_bits(ecn_bits),
+        dst_ip,
+    }
+}
+
+/// Number of leading `transmits` which can be sent as a single datagram with segmentation offload
+///
+/// All segments but the last must be of the same size, and the last may not be larger.
+#[cfg(not(target_os = "macos"))]
+fn gso_segments(transmits: &[Transmit], max_segments: usize) -> usize {
+    let first = &transmits[0];
+    let size = first.contents.len();
+    let mut total = size;
+    let mut n = 1;
+    for transmit in &transmits[1..] {
+        let len = transmit.contents.len();
+        if n == max_segments
+            || transmit.destination != first.destination
+            || transmit.ecn != first.ecn
+            || len > size
+            || total + len > MAX_GSO_BYTES
+        {
+            break;
+        }
+        total += len;
+        n += 1;
+        if len < size {
+            break;
+        }
+    }
+    n
+}
+
+/// Largest number of bytes the kernel will split up with segmentation offload, leaving room for
+/// headers
+#[cfg(not(target_os = "macos"))]
+const MAX_GSO_BYTES: usize = 64000;
+
+/// Number of datagrams the kernel can split a single send into, or 1 if segmentation offload is
+/// unsupported
+#[cfg(target_os = "linux")]
+pub fn max_gso_segments() -> usize {
+    // Try setting the option on a throwaway socket, as it's unknown to kernels older than 4.18
+    let socket = match std::net::UdpSocket::bind("[::]:0")
+        .or_else(|_| std::net::UdpSocket::bind("0.0.0.0:0"))
+    {
+        Ok(x) => x,
+        Err(_) => return 1,
+    };
+    let size: libc::c_int = 1200;
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            gso::SOL_UDP,
+            gso::UDP_SEGMENT,
+            &size as *const _ as _,
+            mem::size_of_val(&size) as _,
+        )
+    };
+    if rc == -1 {
+        1
+    } else {
+        gso::MAX_SEGMENTS
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn max_gso_segments() -> usize {
+    1
+}
+
+#[cfg(target_os = "linux")]
+mod gso {
+    pub const SOL_UDP: libc::c_int = 17;
+    /// Socket option and control message setting the segment size, from `linux/udp.h`
+    pub const UDP_SEGMENT: libc::c_int = 103;
+    /// Largest number of segments the kernel accepts in one send, `UDP_MAX_SEGMENTS`
+    pub const MAX_SEGMENTS: usize = 64;
+}
+
+#[cfg(all(test, not(target_os = "macos")))]
+mod tests {
+    use super::*;
+
+    fn transmit(port: u16, len: usize) -> Transmit {
+        Transmit {
+            destination: SocketAddr::new([127, 0, 0, 1].into(), port),
+            ecn: None,
+            contents: vec![0; len].into(),
+        }
+    }
+
+    #[test]
+    fn gso_grouping() {
+        let transmits = [
+            transmit(1, 1200),
+            transmit(1, 1200),
+            transmit(1, 1000),
+            transmit(1, 1200),
+            transmit(2, 1200),
+        ];
+        // A shorter datagram ends a group, as does a change of destination
+        assert_eq!(gso_segments(&transmits, 64), 3);
+        assert_eq!(gso_segments(&transmits[3..], 64), 1);
+        assert_eq!(gso_segments(&transmits, 2), 2);
+        assert_eq!(gso_segments(&transmits, 1), 1);
+    }
+}
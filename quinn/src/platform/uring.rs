@@ -0,0 +1,526 @@
+//! Socket I/O through io_uring, for Linux 5.7 and later
+//!
+//! A receive is kept outstanding for every slot of a buffer group provided to the kernel, so
+//! datagrams are copied out of the socket as soon as they arrive, and sends are queued without
+//! waiting for the socket to become writable. The ring signals completions through an eventfd,
+//! which is driven by the tokio reactor like any other file descriptor.
+//!
+//! Receive buffers are provided rather than registered: registered buffers are only usable by the
+//! fixed read and write operations, which carry neither the peer's address nor control messages,
+//! so they can't stand in for `recvmsg` on an unconnected socket.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, IoSliceMut},
+    mem::{self, MaybeUninit},
+    os::unix::io::{AsRawFd, RawFd},
+    ptr, slice,
+    task::{Context, Poll},
+};
+
+use futures::ready;
+use io_uring::{cqueue, opcode, squeue, types::Fd, IoUring};
+use mio::{unix::EventedFd, Evented, PollOpt, Ready, Token};
+use tokio::io::PollEvented;
+use tracing::{debug, warn};
+
+use proto::Transmit;
+
+use super::{
+    cmsg,
+    unix::{decode_recv, prepare_msg, CMSG_LEN},
+    RecvMeta,
+};
+use crate::BATCH_SIZE;
+
+/// Drives the I/O of a UDP socket through an io_uring instance
+pub struct Uring {
+    // Dropped before the buffers below, once `drop` has waited for the kernel to be done with them
+    ring: IoUring,
+    /// Number of queued entries whose completions weren't yet taken
+    pending: usize,
+    socket: RawFd,
+    event: PollEvented<EventFd>,
+    /// Error from a send which completed since the last call to `poll_send`
+    send_error: Option<io::Error>,
+    /// Provided to the kernel as buffer group `BUFFER_GROUP`, `RECV_BUF_SIZE` bytes per buffer
+    recv_buf: Box<[u8]>,
+    recv: Box<[RecvOp]>,
+    /// Completed receives which weren't yet passed on
+    received: VecDeque<Received>,
+    send: Box<[SendOp]>,
+    /// Indices of the elements of `send` which aren't in use
+    send_idle: Vec<usize>,
+}
+
+// The raw pointers in the message headers only refer to memory owned by the `Uring`
+unsafe impl Send for Uring {}
+
+impl Uring {
+    /// Set up a ring for `socket`, failing if the kernel lacks the features needed
+    pub fn new(socket: &impl AsRawFd) -> io::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        if !ring.params().is_feature_fast_poll() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "io_uring lacks support for socket I/O",
+            ));
+        }
+        let event = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if event == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let event = EventFd(event);
+        ring.submitter().register_eventfd(event.0)?;
+
+        let mut uring = Self {
+            ring,
+            pending: 0,
+            socket: socket.as_raw_fd(),
+            event: PollEvented::new(event)?,
+            send_error: None,
+            recv_buf: vec![0; RECV_BUF_SIZE * BATCH_SIZE].into_boxed_slice(),
+            recv: (0..BATCH_SIZE).map(|_| RecvOp::new()).collect(),
+            received: VecDeque::with_capacity(BATCH_SIZE),
+            send: (0..BATCH_SIZE).map(|_| SendOp::new()).collect(),
+            send_idle: (0..BATCH_SIZE).collect(),
+        };
+        let provide = opcode::ProvideBuffers::new(
+            uring.recv_buf.as_mut_ptr(),
+            RECV_BUF_SIZE as i32,
+            BATCH_SIZE as u16,
+            BUFFER_GROUP,
+            0,
+        );
+        uring.push(&provide.build().user_data(PROVIDE))?;
+        for i in 0..BATCH_SIZE {
+            uring.start_recv(i)?;
+        }
+        uring.ring.submit()?;
+        Ok(uring)
+    }
+
+    /// Queue a prefix of `transmits` to be sent, returning its length
+    ///
+    /// Sends complete after the datagrams were passed on, so an error from one is returned by the
+    /// next call instead.
+    pub fn poll_send(
+        &mut self,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_completions(cx, |x| !x.send_idle.is_empty()))?;
+        if let Some(e) = self.send_error.take() {
+            return Poll::Ready(Err(e));
+        }
+        let mut sent = 0;
+        for transmit in transmits {
+            let i = match self.send_idle.pop() {
+                Some(i) => i,
+                None => break,
+            };
+            self.start_send(i, transmit)?;
+            sent += 1;
+        }
+        self.ring.submit()?;
+        Poll::Ready(Ok(sent))
+    }
+
+    /// Receive datagrams into a prefix of `bufs`, returning its length
+    ///
+    /// Like the kernel's own truncation, datagrams larger than the buffer they'd be received into
+    /// are dropped rather than passed on in part.
+    pub fn poll_recv(
+        &mut self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.poll_completions(cx, |x| !x.received.is_empty()))?;
+            let mut count = 0;
+            while count < bufs.len() {
+                match self.received.front() {
+                    None => break,
+                    // Report errors on their own, so no datagrams are lost
+                    Some(x) if x.result.is_err() && count > 0 => break,
+                    Some(_) => {}
+                }
+                let received = self.received.pop_front().unwrap();
+                let (buffer, len) = match received.result {
+                    Ok(x) => x,
+                    Err(e) => {
+                        self.start_recv(received.op)?;
+                        self.ring.submit()?;
+                        return Poll::Ready(Err(e));
+                    }
+                };
+                let op = &self.recv[received.op];
+                if len > bufs[count].len() || op.hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                    debug!("dropping {}-byte datagram too large for its buffer", len);
+                } else {
+                    let start = usize::from(buffer) * RECV_BUF_SIZE;
+                    bufs[count][..len].copy_from_slice(&self.recv_buf[start..start + len]);
+                    meta[count] = decode_recv(&op.name, &op.hdr, len);
+                    count += 1;
+                }
+                self.provide(buffer)?;
+                self.start_recv(received.op)?;
+            }
+            self.ring.submit()?;
+            // Wait for more if every datagram was dropped
+            if count > 0 {
+                return Poll::Ready(Ok(count));
+            }
+        }
+    }
+
+    /// Process completions until `done` holds, or there are no more
+    fn poll_completions(
+        &mut self,
+        cx: &mut Context,
+        done: fn(&Self) -> bool,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            self.reap();
+            if done(self) {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(self.event.poll_read_ready(cx, Ready::readable()))?;
+            // Reset the eventfd, so it's readable again once more completions arrive
+            let mut count = 0u64;
+            let n = unsafe {
+                libc::read(
+                    self.event.get_ref().0,
+                    &mut count as *mut u64 as _,
+                    mem::size_of_val(&count),
+                )
+            };
+            if n == -1 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.event.clear_read_ready(cx, Ready::readable())?;
+                    return Poll::Pending;
+                }
+                return Poll::Ready(Err(e));
+            }
+        }
+    }
+
+    fn reap(&mut self) {
+        for cqe in self.ring.completion() {
+            self.pending -= 1;
+            let i = (cqe.user_data() & INDEX_MASK) as usize;
+            match cqe.user_data() & !INDEX_MASK {
+                RECV => {
+                    let result = if cqe.result() < 0 {
+                        Err(io::Error::from_raw_os_error(-cqe.result()))
+                    } else {
+                        let buffer = cqueue::buffer_select(cqe.flags())
+                            .expect("receive completed without selecting a buffer");
+                        Ok((buffer, cqe.result() as usize))
+                    };
+                    self.received.push_back(Received { op: i, result });
+                }
+                SEND => {
+                    if cqe.result() < 0 {
+                        let e = io::Error::from_raw_os_error(-cqe.result());
+                        debug!("failed to send datagram: {}", e);
+                        self.send_error = Some(e);
+                    }
+                    self.send[i].transmit = None;
+                    self.send_idle.push(i);
+                }
+                _ => {
+                    if cqe.result() < 0 {
+                        let e = io::Error::from_raw_os_error(-cqe.result());
+                        warn!("failed to provide receive buffers: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queue `entry` for submission
+    ///
+    /// When the submission queue is full, the entries already queued are submitted first to make
+    /// room.
+    fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        // Entries only refer to memory owned by `self`, which `drop` keeps alive until the
+        // operations complete
+        unsafe {
+            if self.ring.submission().push(entry).is_err() {
+                self.ring.submit()?;
+                self.ring.submission().push(entry).map_err(|_| {
+                    io::Error::new(io::ErrorKind::Other, "io_uring submission queue full")
+                })?;
+            }
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Queue a receive for the `i`th receive operation
+    fn start_recv(&mut self, i: usize) -> io::Result<()> {
+        let op = &mut self.recv[i];
+        // The kernel fills in the address of the selected buffer
+        op.iov = libc::iovec {
+            iov_base: ptr::null_mut(),
+            iov_len: RECV_BUF_SIZE,
+        };
+        op.hdr.msg_name = op.name.as_mut_ptr() as _;
+        op.hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+        op.hdr.msg_iov = &mut op.iov;
+        op.hdr.msg_iovlen = 1;
+        op.hdr.msg_control = op.ctrl.0.as_mut_ptr() as _;
+        op.hdr.msg_controllen = CMSG_LEN as _;
+        op.hdr.msg_flags = 0;
+        let entry = opcode::RecvMsg::new(Fd(self.socket), &mut op.hdr)
+            .buf_group(BUFFER_GROUP)
+            .build()
+            .flags(squeue::Flags::BUFFER_SELECT)
+            .user_data(RECV | i as u64);
+        self.push(&entry)
+    }
+
+    /// Queue a send of a copy of `transmit` with the `i`th send operation
+    fn start_send(&mut self, i: usize, transmit: &Transmit) -> io::Result<()> {
+        let op = &mut self.send[i];
+        let transmit = op.transmit.get_or_insert(Transmit {
+            destination: transmit.destination,
+            ecn: transmit.ecn,
+            contents: transmit.contents.clone(),
+        });
+        prepare_msg(
+            slice::from_ref(transmit),
+            &mut op.hdr,
+            slice::from_mut(&mut op.iov),
+            &mut op.ctrl,
+        );
+        let entry = opcode::SendMsg::new(Fd(self.socket), &op.hdr)
+            .build()
+            .user_data(SEND | i as u64);
+        self.push(&entry)
+    }
+
+    /// Give `buffer` back to the kernel
+    fn provide(&mut self, buffer: u16) -> io::Result<()> {
+        let start = usize::from(buffer) * RECV_BUF_SIZE;
+        let entry = opcode::ProvideBuffers::new(
+            self.recv_buf[start..].as_mut_ptr(),
+            RECV_BUF_SIZE as i32,
+            1,
+            BUFFER_GROUP,
+            buffer,
+        );
+        self.push(&entry.build().user_data(PROVIDE))
+    }
+
+    /// Submit queued entries and block until every one has completed, discarding the completions
+    fn drain(&mut self) -> io::Result<()> {
+        loop {
+            self.pending -= self.ring.completion().count();
+            if self.pending == 0 {
+                return Ok(());
+            }
+            match self.ring.submit_and_wait(1) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        // Closing the ring doesn't wait for outstanding operations, which refer to our buffers
+        let result = (|| {
+            for i in 0..BATCH_SIZE as u64 {
+                for &op in &[RECV, SEND] {
+                    let cancel = opcode::AsyncCancel::new(op | i);
+                    self.push(&cancel.build().user_data(CANCEL))?;
+                }
+            }
+            self.drain()
+        })();
+        if let Err(e) = result {
+            // Better to leak the buffers than to free memory the kernel may still write to
+            warn!("failed to cancel io_uring operations: {}", e);
+            mem::forget(mem::take(&mut self.recv_buf));
+            mem::forget(mem::take(&mut self.recv));
+            mem::forget(mem::take(&mut self.send));
+        }
+    }
+}
+
+impl fmt::Debug for Uring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Uring")
+            .field("fd", &self.ring.as_raw_fd())
+            .field("received", &self.received.len())
+            .field("sending", &(BATCH_SIZE - self.send_idle.len()))
+            .finish()
+    }
+}
+
+struct RecvOp {
+    hdr: libc::msghdr,
+    iov: libc::iovec,
+    name: MaybeUninit<libc::sockaddr_storage>,
+    ctrl: cmsg::Aligned<MaybeUninit<[u8; CMSG_LEN]>>,
+}
+
+impl RecvOp {
+    fn new() -> Self {
+        Self {
+            hdr: unsafe { mem::zeroed() },
+            iov: unsafe { mem::zeroed() },
+            name: MaybeUninit::uninit(),
+            ctrl: cmsg::Aligned(MaybeUninit::uninit()),
+        }
+    }
+}
+
+struct SendOp {
+    hdr: libc::msghdr,
+    iov: libc::iovec,
+    ctrl: cmsg::Aligned<MaybeUninit<[u8; CMSG_LEN]>>,
+    /// The datagram being sent, if any
+    transmit: Option<Transmit>,
+}
+
+impl SendOp {
+    fn new() -> Self {
+        Self {
+            hdr: unsafe { mem::zeroed() },
+            iov: unsafe { mem::zeroed() },
+            ctrl: cmsg::Aligned(MaybeUninit::uninit()),
+            transmit: None,
+        }
+    }
+}
+
+struct Received {
+    /// Index of the receive operation
+    op: usize,
+    /// The buffer holding the datagram and its length
+    result: io::Result<(u16, usize)>,
+}
+
+/// An eventfd which can be registered with the reactor, closed on drop
+struct EventFd(RawFd);
+
+impl Evented for EventFd {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Size of each receive buffer, enough for any UDP datagram
+const RECV_BUF_SIZE: usize = 64 * 1024;
+/// Room for a receive and a buffer to be provided for each receive operation, and the sends
+const RING_ENTRIES: u32 = 4 * BATCH_SIZE as u32;
+/// Identifier of the group of receive buffers
+const BUFFER_GROUP: u16 = 0;
+
+// Kinds of operation, in the upper bits of the user data of each entry
+const RECV: u64 = 1 << 32;
+const SEND: u64 = 2 << 32;
+const PROVIDE: u64 = 3 << 32;
+const CANCEL: u64 = 4 << 32;
+/// Bits of the user data holding the index of the operation
+const INDEX_MASK: u64 = (1 << 32) - 1;
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+    use futures::future::poll_fn;
+    use tokio::runtime::Builder;
+
+    use super::*;
+
+    // Many sandboxes and CI containers forbid io_uring, so run with `--ignored` where it's known to
+    // be available
+    #[test]
+    #[ignore]
+    fn loopback() {
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            let addr = socket.local_addr().unwrap();
+            let mut uring = Uring::new(&socket).expect("io_uring unavailable");
+            let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let transmit = |destination| Transmit {
+                destination,
+                ecn: None,
+                contents: b"hello"[..].into(),
+            };
+
+            let transmits = [transmit(peer.local_addr().unwrap())];
+            let n = poll_fn(|cx| uring.poll_send(cx, &transmits)).await;
+            assert_eq!(n.unwrap(), 1);
+            let mut buf = [0; 16];
+            let (len, from) = peer.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"hello");
+            assert_eq!(from, addr);
+
+            peer.send_to(b"world", addr).unwrap();
+            let mut meta = [RecvMeta {
+                addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                len: 0,
+                ecn: None,
+                dst_ip: None,
+            }];
+            let n = poll_fn(|cx| uring.poll_recv(cx, &mut [IoSliceMut::new(&mut buf)], &mut meta))
+                .await;
+            assert_eq!(n.unwrap(), 1);
+            assert_eq!(meta[0].addr, peer.local_addr().unwrap());
+            assert_eq!(&buf[..meta[0].len], b"world");
+
+            // Sending to port 0 fails, which the next call reports
+            let transmits = [transmit(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))];
+            let n = poll_fn(|cx| uring.poll_send(cx, &transmits)).await;
+            assert_eq!(n.unwrap(), 1);
+            let n = poll_fn(|cx| uring.poll_send(cx, &[])).await;
+            assert!(n.is_err());
+            let n = poll_fn(|cx| uring.poll_send(cx, &[])).await;
+            assert_eq!(n.unwrap(), 0);
+        });
+    }
+}
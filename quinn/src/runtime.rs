@@ -0,0 +1,115 @@
+//! Abstraction over the async runtime which drives timers, tasks and socket I/O
+//!
+//! An implementation for tokio is included with the `runtime-tokio` feature, which is enabled by
+//! default, and one for async-std with the `runtime-async-std` feature. Applications on other
+//! runtimes can implement `Runtime` themselves and pass it to `EndpointBuilder::runtime`.
+
+use std::{
+    fmt,
+    future::Future,
+    io::{self, IoSliceMut},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use proto::{EcnCodepoint, Transmit};
+
+#[cfg(feature = "runtime-tokio")]
+mod tokio;
+#[cfg(feature = "runtime-tokio")]
+pub use self::tokio::TokioRuntime;
+
+#[cfg(feature = "runtime-async-std")]
+mod async_std;
+#[cfg(feature = "runtime-async-std")]
+pub use self::async_std::AsyncStdRuntime;
+
+/// Timers, background tasks and UDP sockets as provided by an async runtime
+///
+/// The `EndpointDriver` and `ConnectionDriver`s are still spawned by the application. `spawn`
+/// lets libraries built on quinn, such as quinn-h3, run tasks of their own on the same runtime.
+pub trait Runtime: Send + Sync + fmt::Debug + 'static {
+    /// Construct a timer which expires at `t`
+    fn new_timer(&self, t: Instant) -> Pin<Box<dyn AsyncTimer>>;
+    /// Drive `future` to completion in the background
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>);
+    /// Convert `socket` into a socket whose I/O is driven by the runtime
+    fn wrap_udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>>;
+}
+
+/// A timer which can be moved to a different point in time
+pub trait AsyncTimer: Send + fmt::Debug + 'static {
+    /// Change the time at which the timer expires
+    fn reset(self: Pin<&mut Self>, t: Instant);
+    /// Wait for the timer to expire
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>;
+}
+
+impl Future for dyn AsyncTimer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        AsyncTimer::poll(self, cx)
+    }
+}
+
+/// A UDP socket whose I/O is driven by an async runtime
+pub trait AsyncUdpSocket: Send + fmt::Debug + 'static {
+    /// Send a prefix of `transmits`, returning its length
+    fn poll_send(&mut self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>>;
+    /// Receive datagrams into a prefix of `bufs`, returning its length
+    ///
+    /// `meta[i]` describes the datagram received into `bufs[i]`.
+    fn poll_recv(
+        &mut self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>>;
+    /// Address the socket is bound to
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// Metadata of a received datagram
+#[derive(Debug, Copy, Clone)]
+pub struct RecvMeta {
+    /// Address the datagram was sent from
+    pub addr: SocketAddr,
+    /// Length of the datagram
+    pub len: usize,
+    /// Explicit congestion notification bits the datagram was marked with
+    pub ecn: Option<EcnCodepoint>,
+    /// Local IP address the datagram was sent to, where the platform reports it
+    pub dst_ip: Option<IpAddr>,
+}
+
+impl Default for RecvMeta {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+            len: 0,
+            ecn: None,
+            dst_ip: None,
+        }
+    }
+}
+
+/// The runtime used when none is set explicitly, if any
+///
+/// tokio is preferred when both runtimes are enabled.
+pub(crate) fn default_runtime() -> Option<std::sync::Arc<dyn Runtime>> {
+    #[cfg(feature = "runtime-tokio")]
+    {
+        Some(std::sync::Arc::new(TokioRuntime))
+    }
+    #[cfg(all(not(feature = "runtime-tokio"), feature = "runtime-async-std"))]
+    {
+        Some(std::sync::Arc::new(AsyncStdRuntime))
+    }
+    #[cfg(not(any(feature = "runtime-tokio", feature = "runtime-async-std")))]
+    {
+        None
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    future::Future,
+    io::{self, IoSliceMut},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use async_io::{Async, Timer};
+use futures::ready;
+use proto::Transmit;
+
+use super::{AsyncTimer, AsyncUdpSocket, RecvMeta, Runtime};
+use crate::platform::{self, UdpExt};
+
+/// Drives timers, tasks and I/O with async-std
+#[derive(Debug, Copy, Clone)]
+pub struct AsyncStdRuntime;
+
+impl Runtime for AsyncStdRuntime {
+    fn new_timer(&self, t: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(Timer::at(t))
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        async_std::task::spawn(future);
+    }
+
+    fn wrap_udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        let socket = mio::net::UdpSocket::from_socket(socket)?;
+        socket.init_ext()?;
+        Ok(Box::new(UdpSocket {
+            io: Async::new(socket)?,
+            max_segments: platform::max_gso_segments(),
+        }))
+    }
+}
+
+impl AsyncTimer for Timer {
+    fn reset(self: Pin<&mut Self>, t: Instant) {
+        self.get_mut().set_at(t)
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        Future::poll(self, cx).map(|_| ())
+    }
+}
+
+/// A UDP socket woken by async-std's reactor, with the same specializations as the tokio one
+#[derive(Debug)]
+struct UdpSocket {
+    io: Async<mio::net::UdpSocket>,
+    /// Number of datagrams which may be sent as one with segmentation offload
+    max_segments: usize,
+}
+
+impl AsyncUdpSocket for UdpSocket {
+    fn poll_send(&mut self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.io.poll_writable(cx))?;
+            return match self.io.get_ref().send_ext(transmits, self.max_segments) {
+                Ok(n) => Poll::Ready(Ok(n)),
+                // Wait for the next readiness event
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                // Segmentation offload fails with EIO when the interface can't compute checksums
+                Err(ref e) if self.max_segments > 1 && e.raw_os_error() == Some(libc::EIO) => {
+                    tracing::debug!("segmentation offload unavailable, disabling it");
+                    self.max_segments = 1;
+                    continue;
+                }
+                Err(e) => Poll::Ready(Err(e)),
+            };
+        }
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.io.poll_readable(cx))?;
+            return match self.io.get_ref().recv_ext(bufs, meta) {
+                Ok(n) => Poll::Ready(Ok(n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => Poll::Ready(Err(e)),
+            };
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+}
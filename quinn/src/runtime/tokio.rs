@@ -0,0 +1,40 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use tokio::time::{delay_until, Delay, Instant as TokioInstant};
+
+use super::{AsyncTimer, AsyncUdpSocket, Runtime};
+use crate::udp::UdpSocket;
+
+/// Drives timers, tasks and I/O with the tokio runtime the endpoint was created in
+#[derive(Debug, Copy, Clone)]
+pub struct TokioRuntime;
+
+impl Runtime for TokioRuntime {
+    fn new_timer(&self, t: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(delay_until(TokioInstant::from_std(t)))
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        tokio::spawn(future);
+    }
+
+    fn wrap_udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Ok(Box::new(UdpSocket::from_std(socket)?))
+    }
+}
+
+impl AsyncTimer for Delay {
+    fn reset(self: Pin<&mut Self>, t: Instant) {
+        Delay::reset(self.get_mut(), TokioInstant::from_std(t))
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        Future::poll(self, cx)
+    }
+}
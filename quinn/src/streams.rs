@@ -1,7 +1,6 @@
 use std::{
     future::Future,
     io,
-    mem::MaybeUninit,
     pin::Pin,
     str,
     task::{Context, Poll},
@@ -53,14 +52,43 @@ impl SendStream {
         WriteAll { stream: self, buf }
     }
 
+    /// Write owned chunks of data to the stream without copying them
+    ///
+    /// Yields the number of bytes written on success. Written data is split off the front of
+    /// `bufs`, so a partial write leaves the remainder in place for a later call.
+    pub fn write_chunks<'a>(&'a mut self, bufs: &'a mut [Bytes]) -> WriteChunks<'a> {
+        WriteChunks { stream: self, bufs }
+    }
+
+    /// Convenience method to write every chunk in `bufs` to the stream
+    pub fn write_all_chunks<'a>(&'a mut self, bufs: &'a mut [Bytes]) -> WriteAllChunks<'a> {
+        WriteAllChunks { stream: self, bufs }
+    }
+
     fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, WriteError>> {
+        self.poll_write_with(cx, |conn, stream| conn.write(stream, buf))
+    }
+
+    /// Poll variant of `write_chunks`
+    pub fn poll_write_chunks(
+        &mut self,
+        cx: &mut Context,
+        bufs: &mut [Bytes],
+    ) -> Poll<Result<usize, WriteError>> {
+        self.poll_write_with(cx, |conn, stream| conn.write_chunks(stream, bufs))
+    }
+
+    fn poll_write_with<F>(&mut self, cx: &mut Context, write: F) -> Poll<Result<usize, WriteError>>
+    where
+        F: FnOnce(&mut proto::Connection, StreamId) -> Result<usize, proto::WriteError>,
+    {
         use proto::WriteError::*;
         let mut conn = self.conn.lock().unwrap();
         if self.is_0rtt {
             conn.check_0rtt()
                 .map_err(|()| WriteError::ZeroRttRejected)?;
         }
-        let n = match conn.inner.write(self.stream, buf) {
+        let n = match write(&mut conn.inner, self.stream) {
             Ok(n) => n,
             Err(Blocked) => {
                 if let Some(ref x) = conn.error {
@@ -146,6 +174,7 @@ impl AsyncWrite for SendStream {
     }
 }
 
+#[cfg(feature = "runtime-tokio")]
 impl tokio::io::AsyncWrite for SendStream {
     fn poll_write(
         self: Pin<&mut Self>,
@@ -355,6 +384,10 @@ impl RecvStream {
         self.all_data_read = true;
         Ok(())
     }
+    #[doc(hidden)]
+    pub fn id(&self) -> StreamId {
+        self.stream
+    }
 }
 
 /// Future produced by `read_to_end`
@@ -424,8 +457,9 @@ impl AsyncRead for RecvStream {
     }
 }
 
+#[cfg(feature = "runtime-tokio")]
 impl tokio::io::AsyncRead for RecvStream {
-    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [MaybeUninit<u8>]) -> bool {
+    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [std::mem::MaybeUninit<u8>]) -> bool {
         false
     }
 
@@ -620,6 +654,39 @@ impl<'a> Future for WriteAll<'a> {
     }
 }
 
+/// Future produced by `SendStream::write_chunks`
+pub struct WriteChunks<'a> {
+    stream: &'a mut SendStream,
+    bufs: &'a mut [Bytes],
+}
+
+impl<'a> Future for WriteChunks<'a> {
+    type Output = Result<usize, WriteError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.stream.poll_write_chunks(cx, this.bufs)
+    }
+}
+
+/// Future produced by `SendStream::write_all_chunks`
+pub struct WriteAllChunks<'a> {
+    stream: &'a mut SendStream,
+    bufs: &'a mut [Bytes],
+}
+
+impl<'a> Future for WriteAllChunks<'a> {
+    type Output = Result<(), WriteError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            if this.bufs.iter().all(|x| x.is_empty()) {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.stream.poll_write_chunks(cx, this.bufs))?;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UnknownStream {}
 
@@ -1,11 +1,18 @@
 use std::{
-    io,
+    collections::{HashMap, VecDeque},
+    io::{self, IoSliceMut},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     str,
-    sync::Arc,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use futures::{future, FutureExt, StreamExt, TryFutureExt};
+use ring::{
+    rand::SystemRandom,
+    signature::{self, EcdsaKeyPair, KeyPair},
+};
 use tokio::{
     runtime::{Builder, Runtime},
     time::{Duration, Instant},
@@ -14,8 +21,8 @@ use tracing::{info, info_span};
 use tracing_futures::Instrument as _;
 
 use super::{
-    ClientConfigBuilder, Endpoint, EndpointDriver, Incoming, NewConnection, RecvStream, SendStream,
-    ServerConfigBuilder,
+    AsyncUdpSocket, ClientConfigBuilder, Endpoint, EndpointBuilder, EndpointDriver, Incoming,
+    NewConnection, RecvMeta, RecvStream, SendStream, ServerConfigBuilder, Transmit,
 };
 
 #[test]
@@ -202,8 +209,94 @@ fn read_after_close() {
     });
 }
 
+#[test]
+fn wait_idle() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(|| endpoint());
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        while let Some(conn) = incoming.next().await {
+            if let Ok(new_conn) = conn.await {
+                tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+            }
+        }
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        endpoint.close(0u32.into(), b"shutdown");
+        tokio::time::timeout(Duration::from_secs(5), endpoint.wait_idle())
+            .await
+            .expect("connections didn't drain");
+        let stats = endpoint.stats();
+        assert_eq!(stats.connections_accepted, 1);
+        assert_eq!(stats.open_connections, 0);
+        assert!(stats.datagrams_received > 0 && stats.bytes_sent >= stats.bytes_received);
+    });
+}
+
+#[cfg(feature = "runtime-async-std")]
+#[test]
+fn async_std_runtime() {
+    let _guard = subscribe();
+    let mut builder = endpoint_builder();
+    builder.runtime(Arc::new(crate::AsyncStdRuntime));
+    let (driver, endpoint, mut incoming) = builder
+        .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .unwrap();
+    let runtime = endpoint.runtime();
+    runtime.spawn(Box::pin(driver.unwrap_or_else(|e| panic!("{}", e))));
+    const MSG: &[u8] = b"hello from async-std";
+    let server_runtime = runtime.clone();
+    runtime.spawn(Box::pin(async move {
+        let new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        server_runtime.spawn(Box::pin(new_conn.driver.unwrap_or_else(|_| ())));
+        let mut s = new_conn.connection.open_uni().await.unwrap();
+        s.write_all(MSG).await.unwrap();
+        s.finish().await.unwrap();
+    }));
+    async_std::task::block_on(async move {
+        let mut new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        runtime.spawn(Box::pin(new_conn.driver.unwrap_or_else(|_| ())));
+        let stream = new_conn
+            .uni_streams
+            .next()
+            .await
+            .expect("incoming streams")
+            .expect("missing stream");
+        let msg = stream
+            .read_to_end(usize::max_value())
+            .await
+            .expect("read_to_end");
+        assert_eq!(msg, MSG);
+        // Timers run on async-std as well
+        let timer = runtime.new_timer(std::time::Instant::now() + Duration::from_millis(10));
+        timer.await;
+    });
+}
+
 /// Construct an endpoint suitable for connecting to itself
 fn endpoint() -> (EndpointDriver, Endpoint, Incoming) {
+    endpoint_builder()
+        .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .unwrap()
+}
+
+fn endpoint_builder() -> EndpointBuilder {
     let mut endpoint = Endpoint::builder();
 
     let mut server_config = ServerConfigBuilder::default();
@@ -217,11 +310,7 @@ fn endpoint() -> (EndpointDriver, Endpoint, Incoming) {
     let mut client_config = ClientConfigBuilder::default();
     client_config.add_certificate_authority(cert).unwrap();
     endpoint.default_client_config(client_config.build());
-
-    let (x, y, z) = endpoint
-        .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
-        .unwrap();
-    (x, y, z)
+    endpoint
 }
 
 #[test]
@@ -324,6 +413,237 @@ fn zero_rtt() {
     runtime.block_on(handle).unwrap();
 }
 
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn pinned_keys() {
+    let _guard = subscribe();
+    let certified = |name: &str| {
+        let cert = rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+        let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+        let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+        let public_key = cert.public_key().unwrap().to_vec();
+        (
+            crate::CertificateChain::from_certs(vec![cert]),
+            key,
+            public_key,
+        )
+    };
+    let (server_chain, server_key, server_public) = certified("server.invalid");
+    let (client_chain, client_key, client_public) = certified("client.invalid");
+
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .certificate(server_chain, server_key)
+        .unwrap()
+        .require_pinned_client_keys(vec![client_public.clone()]);
+    let mut endpoint = Endpoint::builder();
+    endpoint.listen(server_config.build());
+    let mut client_config = ClientConfigBuilder::default();
+    client_config
+        .certificate(client_chain, client_key)
+        .unwrap()
+        .pinned_server_keys(vec![server_public]);
+    endpoint.default_client_config(client_config.build());
+
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(|| {
+        endpoint
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.block_on(async move {
+        let addr = endpoint.local_addr().unwrap();
+        let (client, server) = future::join(
+            endpoint.connect(&addr, "localhost").unwrap(),
+            incoming.next(),
+        )
+        .await;
+        client.expect("client connect");
+        server.unwrap().await.expect("server accept");
+
+        // Pinning some other key rejects the server
+        let mut untrusting = ClientConfigBuilder::default();
+        untrusting.pinned_server_keys(vec![client_public]);
+        let result = endpoint
+            .connect_with(untrusting.build(), &addr, "localhost")
+            .unwrap()
+            .await;
+        assert!(result.is_err());
+    });
+}
+
+#[test]
+fn cipher_suites() {
+    let _guard = subscribe();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .certificate(crate::CertificateChain::from_certs(vec![cert.clone()]), key)
+        .unwrap()
+        .cipher_suites(&[rustls::CipherSuite::TLS13_AES_256_GCM_SHA384])
+        .unwrap();
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let mut endpoint = Endpoint::builder();
+    endpoint.listen(server_config.build());
+    endpoint.default_client_config(client_config.build());
+
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(|| {
+        endpoint
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.block_on(async move {
+        let addr = endpoint.local_addr().unwrap();
+        let (client, server) = future::join(
+            endpoint.connect(&addr, "localhost").unwrap(),
+            incoming.next(),
+        )
+        .await;
+        let client = client.expect("client connect");
+        server.unwrap().await.expect("server accept");
+        assert_eq!(
+            client.connection.authentication_data().cipher_suite,
+            Some(rustls::CipherSuite::TLS13_AES_256_GCM_SHA384)
+        );
+    });
+
+    let mut config = ClientConfigBuilder::default();
+    assert!(config.cipher_suites(&[]).is_err());
+    assert!(config
+        .cipher_suites(&[rustls::CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256])
+        .is_err());
+}
+
+#[test]
+fn certificate_transparency() {
+    let _guard = subscribe();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert_der = cert.serialize_der().unwrap();
+    let cert = crate::Certificate::from_der(&cert_der).unwrap();
+
+    let log_key = ecdsa_key();
+    let log: &'static sct::Log<'static> = Box::leak(Box::new(sct::Log {
+        description: "test log",
+        url: "ct.invalid",
+        operated_by: "quinn",
+        key: Box::leak(log_key.public_key().as_ref().into()),
+        id: [0x42; 32],
+        max_merge_delay: 0,
+    }));
+    let logs: &'static [&'static sct::Log<'static>] = Box::leak(Box::new([log]));
+
+    let server = |scts| {
+        let mut server_config = ServerConfigBuilder::default();
+        server_config
+            .certificate_with_scts(
+                crate::CertificateChain::from_certs(vec![cert.clone()]),
+                key.clone(),
+                scts,
+            )
+            .unwrap();
+        let mut endpoint = Endpoint::builder();
+        endpoint.listen(server_config.build());
+        endpoint
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    };
+    let mut client_config = ClientConfigBuilder::default();
+    client_config
+        .add_certificate_authority(cert.clone())
+        .unwrap()
+        .ct_logs(Some(logs));
+    let client_config = client_config.build();
+
+    let mut runtime = rt_basic();
+    let ((valid_driver, endpoint, mut valid), (invalid_driver, invalid_endpoint, mut invalid)) =
+        runtime.enter(|| {
+            (
+                server(sct_list(&cert_der, &log.id, &log_key)),
+                // Signed by a key other than the log's
+                server(sct_list(&cert_der, &log.id, &ecdsa_key())),
+            )
+        });
+    runtime.spawn(valid_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(invalid_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.block_on(async move {
+        let addr = endpoint.local_addr().unwrap();
+        let (client, server) = future::join(
+            endpoint
+                .connect_with(client_config.clone(), &addr, "localhost")
+                .unwrap(),
+            valid.next(),
+        )
+        .await;
+        client.expect("client connect");
+        server.unwrap().await.expect("server accept");
+
+        let addr = invalid_endpoint.local_addr().unwrap();
+        let (client, server) = future::join(
+            endpoint
+                .connect_with(client_config, &addr, "localhost")
+                .unwrap(),
+            invalid.next().then(|x| x.unwrap()),
+        )
+        .await;
+        match client {
+            Err(crate::ConnectionError::TransportError(e)) => {
+                assert!(e.reason.contains("invalid certificate timestamp"))
+            }
+            x => panic!("unexpected result: {:?}", x.map(|_| ())),
+        }
+        assert!(server.is_err());
+    });
+}
+
+fn ecdsa_key() -> EcdsaKeyPair {
+    let alg = &signature::ECDSA_P256_SHA256_ASN1_SIGNING;
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &SystemRandom::new()).unwrap();
+    EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref()).unwrap()
+}
+
+/// Encode a `SignedCertificateTimestampList` holding an SCT for `cert` signed by `key` (RFC 6962)
+fn sct_list(cert: &[u8], log_id: &[u8; 32], key: &EcdsaKeyPair) -> Vec<u8> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+        - 60_000;
+    let mut signed = vec![0, 0]; // v1, certificate_timestamp
+    signed.extend_from_slice(&timestamp.to_be_bytes());
+    signed.extend_from_slice(&[0, 0]); // x509_entry
+    signed.extend_from_slice(&(cert.len() as u32).to_be_bytes()[1..]);
+    signed.extend_from_slice(cert);
+    signed.extend_from_slice(&[0, 0]); // no extensions
+    let signature = key.sign(&SystemRandom::new(), &signed).unwrap();
+
+    let mut sct = vec![0];
+    sct.extend_from_slice(log_id);
+    sct.extend_from_slice(&timestamp.to_be_bytes());
+    sct.extend_from_slice(&[0, 0]);
+    sct.extend_from_slice(&[4, 3]); // ecdsa_secp256r1_sha256
+    sct.extend_from_slice(&(signature.as_ref().len() as u16).to_be_bytes());
+    sct.extend_from_slice(signature.as_ref());
+
+    let mut list = ((sct.len() + 2) as u16).to_be_bytes().to_vec();
+    list.extend_from_slice(&(sct.len() as u16).to_be_bytes());
+    list.extend_from_slice(&sct);
+    list
+}
+
+#[test]
+fn zero_amplification_factor() {
+    let mut config = ServerConfigBuilder::default();
+    assert!(config.amplification_factor(0).is_err());
+    assert!(config.amplification_factor(1).is_ok());
+}
+
 #[test]
 fn echo_v6() {
     run_echo(
@@ -437,6 +757,220 @@ fn run_echo(client_addr: SocketAddr, server_addr: SocketAddr) {
     r3.unwrap();
 }
 
+#[test]
+fn custom_socket() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let network = Network::default();
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 4433);
+    let (driver, endpoint, mut incoming) = runtime.enter(|| {
+        endpoint_builder()
+            .with_async_socket(network.bind(addr))
+            .unwrap()
+    });
+    assert_eq!(endpoint.local_addr().unwrap(), addr);
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let new_conn = incoming.next().await.unwrap().await.unwrap();
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        new_conn
+            .bi_streams
+            .take_while(|x| future::ready(x.is_ok()))
+            .for_each(|s| echo(s.unwrap()))
+            .await;
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let (mut send, recv) = new_conn.connection.open_bi().await.expect("stream open");
+        send.write_all(b"foo").await.expect("write");
+        send.finish().await.expect("finish");
+        let data = recv.read_to_end(usize::max_value()).await.expect("read");
+        assert_eq!(&data[..], b"foo");
+    });
+}
+
+#[test]
+fn client_migration() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let network = Network::default();
+    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 4433);
+    let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4433);
+    let builder = endpoint_builder();
+    let (server_driver, _, mut incoming) = runtime.enter(|| {
+        builder
+            .clone()
+            .with_async_socket(network.bind(server_addr))
+            .unwrap()
+    });
+    let (client_driver, client, _) = runtime.enter(|| {
+        builder
+            .with_async_socket(network.bind(client_addr))
+            .unwrap()
+    });
+    runtime.spawn(server_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(client_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let new_conn = incoming.next().await.unwrap().await.unwrap();
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        new_conn
+            .bi_streams
+            .take_while(|x| future::ready(x.is_ok()))
+            .for_each(|s| echo(s.unwrap()))
+            .await;
+    });
+    runtime.block_on(async move {
+        let new_conn = client
+            .connect(&server_addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let conn = new_conn.connection;
+
+        // Move to a different address, as if switching networks, and abandon the old one
+        let new_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2)), 4433);
+        client.rebind_async_socket(network.bind(new_addr)).unwrap();
+        network.0.lock().unwrap().remove(&client_addr);
+        assert_eq!(client.local_addr().unwrap(), new_addr);
+
+        let (mut send, recv) = conn.open_bi().await.expect("stream open");
+        send.write_all(b"foo").await.expect("write");
+        send.finish().await.expect("finish");
+        let data = recv.read_to_end(usize::max_value()).await.expect("read");
+        assert_eq!(&data[..], b"foo");
+    });
+}
+
+#[test]
+fn rebind_recv() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let network = Network::default();
+    let old_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 4433);
+    let new_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 4434);
+    let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4433);
+    let builder = endpoint_builder();
+    let (server_driver, server, mut incoming) = runtime.enter(|| {
+        builder
+            .clone()
+            .with_async_socket(network.bind(old_addr))
+            .unwrap()
+    });
+    let (client_driver, client, _) = runtime.enter(|| {
+        builder
+            .with_async_socket(network.bind(client_addr))
+            .unwrap()
+    });
+    runtime.spawn(server_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(client_driver.unwrap_or_else(|e| panic!("{}", e)));
+    // Let the server's driver go idle waiting on the old socket
+    runtime.block_on(async { tokio::time::delay_for(Duration::from_millis(10)).await });
+
+    server.rebind_async_socket(network.bind(new_addr)).unwrap();
+    network.0.lock().unwrap().remove(&old_addr);
+    runtime.spawn(async move {
+        let new_conn = incoming.next().await.unwrap().await.unwrap();
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let mut s = new_conn.connection.open_uni().await.unwrap();
+        s.write_all(b"foo").await.unwrap();
+        s.finish().await.unwrap();
+    });
+    runtime.block_on(async move {
+        let mut new_conn = client
+            .connect(&new_addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let stream = new_conn.uni_streams.next().await.unwrap().unwrap();
+        let data = stream.read_to_end(usize::max_value()).await.unwrap();
+        assert_eq!(&data[..], b"foo");
+    });
+}
+
+/// In-memory transport, delivering datagrams between the sockets bound on it
+#[derive(Debug, Clone, Default)]
+struct Network(Arc<Mutex<HashMap<SocketAddr, Mailbox>>>);
+
+impl Network {
+    fn bind(&self, addr: SocketAddr) -> MemorySocket {
+        self.0.lock().unwrap().insert(addr, Mailbox::default());
+        MemorySocket {
+            network: self.clone(),
+            addr,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Mailbox {
+    datagrams: VecDeque<(SocketAddr, Box<[u8]>)>,
+    reader: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct MemorySocket {
+    network: Network,
+    addr: SocketAddr,
+}
+
+impl AsyncUdpSocket for MemorySocket {
+    fn poll_send(&mut self, _: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        let mut mailboxes = self.network.0.lock().unwrap();
+        for transmit in transmits {
+            // Datagrams to unknown addresses are lost
+            if let Some(mailbox) = mailboxes.get_mut(&transmit.destination) {
+                mailbox
+                    .datagrams
+                    .push_back((self.addr, transmit.contents.clone()));
+                if let Some(reader) = mailbox.reader.take() {
+                    reader.wake();
+                }
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut mailboxes = self.network.0.lock().unwrap();
+        let mailbox = mailboxes.get_mut(&self.addr).unwrap();
+        if mailbox.datagrams.is_empty() {
+            mailbox.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let mut count = 0;
+        while count < bufs.len() {
+            let (addr, contents) = match mailbox.datagrams.pop_front() {
+                Some(x) => x,
+                None => break,
+            };
+            bufs[count][..contents.len()].copy_from_slice(&contents);
+            meta[count] = RecvMeta {
+                addr,
+                len: contents.len(),
+                ..RecvMeta::default()
+            };
+            count += 1;
+        }
+        Poll::Ready(Ok(count))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
 async fn echo((mut send, recv): (SendStream, RecvStream)) {
     let data = recv
         .read_to_end(usize::max_value())
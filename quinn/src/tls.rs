@@ -1,6 +1,20 @@
-use std::fmt;
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    fs, io, mem,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
 
-use rustls::internal::pemfile;
+use ring::{
+    aead,
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::{
+    internal::pemfile,
+    sign::{self, CertifiedKey},
+    ProducesTickets, ResolvesServerCert, SignatureScheme, StoresClientSessions, TLSError,
+};
 
 /// A single TLS certificate
 #[derive(Debug, Clone)]
@@ -15,6 +29,73 @@ impl Certificate {
             inner: rustls::Certificate(der.to_vec()),
         })
     }
+
+    /// The DER-encoded SubjectPublicKeyInfo of the certificate's key
+    ///
+    /// Identifies the key a peer must prove possession of when authenticated by
+    /// `PinnedKeyVerifier`.
+    pub fn public_key(&self) -> Result<&[u8], ParseError> {
+        public_key(&self.inner.0).ok_or(ParseError("malformed certificate"))
+    }
+}
+
+/// Find the SubjectPublicKeyInfo within a DER-encoded X.509 certificate
+fn public_key(cert: &[u8]) -> Option<&[u8]> {
+    let (cert, _) = der_read(cert, 0x30)?;
+    let (tbs, _) = der_read(cert.contents, 0x30)?;
+    let mut fields = tbs.contents;
+    // Skip the explicitly tagged version, if any
+    let (first, rest) = der_any(fields)?;
+    if first.tag == 0xa0 {
+        fields = rest;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    let (_, rest) = der_read(fields, 0x02)?;
+    let (_, rest) = der_read(rest, 0x30)?;
+    let (_, rest) = der_read(rest, 0x30)?;
+    let (_, rest) = der_read(rest, 0x30)?;
+    let (_, rest) = der_read(rest, 0x30)?;
+    let (spki, _) = der_read(rest, 0x30)?;
+    Some(spki.whole)
+}
+
+struct Der<'a> {
+    tag: u8,
+    contents: &'a [u8],
+    whole: &'a [u8],
+}
+
+fn der_read(input: &[u8], tag: u8) -> Option<(Der<'_>, &[u8])> {
+    let (x, rest) = der_any(input)?;
+    if x.tag != tag {
+        return None;
+    }
+    Some((x, rest))
+}
+
+fn der_any(input: &[u8]) -> Option<(Der<'_>, &[u8])> {
+    let tag = *input.first()?;
+    let first = *input.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let bytes = input.get(2..2 + n)?;
+        (bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize), 2 + n)
+    };
+    let end = header.checked_add(len)?;
+    let whole = input.get(..end)?;
+    Some((
+        Der {
+            tag,
+            contents: &whole[header..],
+            whole,
+        },
+        &input[end..],
+    ))
 }
 
 /// A chain of signed TLS certificates ending the one to be used by a server
@@ -100,3 +181,454 @@ impl fmt::Display for ParseError {
         f.pad(self.0)
     }
 }
+
+/// Authenticates peers by their public key alone, in place of a certificate authority
+///
+/// Suited to closed deployments without a PKI: each peer presents a certificate, typically
+/// self-signed, whose key is checked against a fixed set of trusted SubjectPublicKeyInfos (see
+/// `Certificate::public_key`). Names, validity periods and issuers are ignored, while the TLS
+/// handshake still proves that the peer holds the corresponding private key. rustls doesn't
+/// support RFC 7250 raw public keys on the wire, so the key travels in a certificate.
+#[cfg(feature = "dangerous_configuration")]
+pub struct PinnedKeyVerifier {
+    keys: Vec<Vec<u8>>,
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl PinnedKeyVerifier {
+    /// Trust peers holding any of the keys in `keys`, each a DER-encoded SubjectPublicKeyInfo
+    pub fn new(keys: impl IntoIterator<Item = Vec<u8>>) -> Arc<Self> {
+        Arc::new(Self {
+            keys: keys.into_iter().collect(),
+        })
+    }
+
+    fn verify(&self, presented_certs: &[rustls::Certificate]) -> Result<(), TLSError> {
+        let cert = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let key =
+            public_key(&cert.0).ok_or_else(|| TLSError::General("malformed certificate".into()))?;
+        if !self.keys.iter().any(|x| x[..] == *key) {
+            return Err(TLSError::General("untrusted public key".into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl rustls::ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, TLSError> {
+        self.verify(presented_certs)?;
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl rustls::ClientCertVerifier for PinnedKeyVerifier {
+    fn client_auth_root_subjects(&self) -> rustls::DistinguishedNames {
+        rustls::DistinguishedNames::new()
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[rustls::Certificate],
+    ) -> Result<rustls::ClientCertVerified, TLSError> {
+        self.verify(presented_certs)?;
+        Ok(rustls::ClientCertVerified::assertion())
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl fmt::Debug for PinnedKeyVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PinnedKeyVerifier")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+/// Encrypts session tickets under keys supplied by the application
+///
+/// Servers sharing the same keys can resume sessions, including 0-RTT, established by one
+/// another. Keys are rotated with `rotate`, after which tickets encrypted under the previous key
+/// are still accepted until the next rotation; rotating no more often than the ticket lifetime
+/// keeps every outstanding ticket usable. Install with `ServerConfigBuilder::session_ticketer`.
+pub struct TicketKeys {
+    keys: RwLock<(aead::LessSafeKey, Option<aead::LessSafeKey>)>,
+    lifetime: u32,
+    rng: SystemRandom,
+}
+
+impl TicketKeys {
+    /// Encrypt tickets with the secret `key`, advertising a lifetime of `lifetime` seconds
+    pub fn new(key: &[u8; 32], lifetime: u32) -> Arc<Self> {
+        Arc::new(Self {
+            keys: RwLock::new((ticket_key(key), None)),
+            lifetime,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Encrypt new tickets with `key`, still accepting those encrypted under the current key
+    pub fn rotate(&self, key: &[u8; 32]) {
+        let mut keys = self.keys.write().unwrap();
+        let previous = mem::replace(&mut keys.0, ticket_key(key));
+        keys.1 = Some(previous);
+    }
+}
+
+fn ticket_key(key: &[u8; 32]) -> aead::LessSafeKey {
+    aead::LessSafeKey::new(aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key).unwrap())
+}
+
+impl ProducesTickets for TicketKeys {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0; aead::NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+        let mut ticket = Vec::with_capacity(nonce.len() + plain.len() + aead::MAX_TAG_LEN);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(plain);
+        let keys = self.keys.read().unwrap();
+        let tag = keys
+            .0
+            .seal_in_place_separate_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut ticket[nonce.len()..],
+            )
+            .ok()?;
+        ticket.extend_from_slice(tag.as_ref());
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < aead::NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = cipher.split_at(aead::NONCE_LEN);
+        let keys = self.keys.read().unwrap();
+        let open = |key: &aead::LessSafeKey| {
+            let nonce = aead::Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut plain = sealed.to_vec();
+            let len = key
+                .open_in_place(nonce, aead::Aad::empty(), &mut plain)
+                .ok()?
+                .len();
+            plain.truncate(len);
+            Some(plain)
+        };
+        open(&keys.0).or_else(|| keys.1.as_ref().and_then(open))
+    }
+}
+
+impl fmt::Debug for TicketKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TicketKeys")
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
+}
+
+/// Selects the certificate chain presented to a client based on the server name it requested
+///
+/// Names may start with a `*.` wildcard label, which matches exactly one label. Clients which
+/// don't send a server name or request an unknown name are served the default certificate, if
+/// any.
+#[derive(Default)]
+pub struct CertificateResolver {
+    by_name: HashMap<String, CertifiedKey>,
+    default: Option<CertifiedKey>,
+}
+
+impl CertificateResolver {
+    /// Create a resolver without any certificates
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `cert_chain` to clients requesting `name`
+    pub fn add(
+        &mut self,
+        name: &str,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<&mut Self, TLSError> {
+        let certified = certified_key(cert_chain, key)?;
+        self.by_name.insert(name.to_ascii_lowercase(), certified);
+        Ok(self)
+    }
+
+    /// Serve `cert_chain` to clients for which no other certificate matches
+    pub fn set_default(
+        &mut self,
+        cert_chain: CertificateChain,
+        key: PrivateKey,
+    ) -> Result<&mut Self, TLSError> {
+        self.default = Some(certified_key(cert_chain, key)?);
+        Ok(self)
+    }
+
+    fn lookup(&self, name: &str) -> Option<&CertifiedKey> {
+        let name = name.to_ascii_lowercase();
+        if let Some(certified) = self.by_name.get(&name) {
+            return Some(certified);
+        }
+        let parent = &name[name.find('.')?..];
+        self.by_name.get(&format!("*{}", parent))
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef<'_>>,
+        _: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        server_name
+            .and_then(|name| self.lookup(name.into()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+impl fmt::Debug for CertificateResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CertificateResolver")
+            .field("names", &self.by_name.keys().collect::<Vec<_>>())
+            .field("default", &self.default.is_some())
+            .finish()
+    }
+}
+
+/// A certificate chain which can be replaced while an endpoint is running
+///
+/// Install with `ServerConfigBuilder::certificate_resolver` and keep a reference around. After
+/// calling `replace`, new handshakes present the new certificate while established connections
+/// are unaffected.
+pub struct ReloadableCertificate(RwLock<CertifiedKey>);
+
+impl ReloadableCertificate {
+    /// Start out serving `cert_chain`
+    pub fn new(cert_chain: CertificateChain, key: PrivateKey) -> Result<Self, TLSError> {
+        Ok(Self(RwLock::new(certified_key(cert_chain, key)?)))
+    }
+
+    /// Atomically swap the certificate chain and key served to new connections
+    pub fn replace(&self, cert_chain: CertificateChain, key: PrivateKey) -> Result<(), TLSError> {
+        let certified = certified_key(cert_chain, key)?;
+        *self.0.write().unwrap() = certified;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertificate {
+    fn resolve(
+        &self,
+        _: Option<webpki::DNSNameRef<'_>>,
+        _: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+impl fmt::Debug for ReloadableCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReloadableCertificate").finish()
+    }
+}
+
+/// A client session cache storing each entry in a file, so that it survives process restarts
+///
+/// Install with `ClientConfigBuilder::session_cache`. Entries are keyed by server name and
+/// contain the session tickets used for resumption and 0-RTT, so the directory should only be
+/// readable by the user running the client.
+#[derive(Debug)]
+pub struct FileSessionCache {
+    dir: PathBuf,
+}
+
+impl FileSessionCache {
+    /// Store entries in `dir`, which is created if needed
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &[u8]) -> PathBuf {
+        let mut name = String::with_capacity(2 * key.len());
+        for byte in key {
+            write!(name, "{:02x}", byte).unwrap();
+        }
+        self.dir.join(name)
+    }
+}
+
+impl StoresClientSessions for FileSessionCache {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let path = self.path(&key);
+        // Write to a temporary file first so concurrent readers never see partial entries
+        let tmp = path.with_extension("tmp");
+        match fs::write(&tmp, &value).and_then(|()| fs::rename(&tmp, &path)) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("failed to store session in {}: {}", path.display(), e);
+                false
+            }
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        fs::read(self.path(key)).ok()
+    }
+}
+
+fn certified_key(cert_chain: CertificateChain, key: PrivateKey) -> Result<CertifiedKey, TLSError> {
+    let key = sign::any_supported_type(&key.inner)
+        .map_err(|_| TLSError::General("invalid private key".into()))?;
+    Ok(CertifiedKey::new(cert_chain.certs, Arc::new(key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certified(name: &str) -> (CertificateChain, PrivateKey) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+        let key = PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+        let cert = Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+        (CertificateChain::from_certs(vec![cert]), key)
+    }
+
+    fn served(resolver: &CertificateResolver, name: &str) -> Option<Vec<u8>> {
+        resolver.lookup(name).map(|x| x.cert[0].0.clone())
+    }
+
+    #[test]
+    fn resolve_by_name() {
+        let mut resolver = CertificateResolver::new();
+        let (exact_chain, exact_key) = certified("www.example.com");
+        let exact = exact_chain.certs[0].0.clone();
+        resolver
+            .add("www.example.com", exact_chain, exact_key)
+            .unwrap();
+        let (wildcard_chain, wildcard_key) = certified("*.example.com");
+        let wildcard = wildcard_chain.certs[0].0.clone();
+        resolver
+            .add("*.example.com", wildcard_chain, wildcard_key)
+            .unwrap();
+
+        assert_eq!(served(&resolver, "WWW.example.com"), Some(exact));
+        assert_eq!(served(&resolver, "api.example.com"), Some(wildcard));
+        assert_eq!(served(&resolver, "a.b.example.com"), None);
+        assert_eq!(served(&resolver, "example.com"), None);
+        assert!(resolver.resolve(None, &[]).is_none());
+
+        let (default_chain, default_key) = certified("localhost");
+        let default = default_chain.certs[0].0.clone();
+        resolver.set_default(default_chain, default_key).unwrap();
+        assert_eq!(
+            resolver.resolve(None, &[]).map(|x| x.cert[0].0.clone()),
+            Some(default)
+        );
+    }
+
+    #[test]
+    fn certificate_public_key() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let der = Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+        assert_eq!(
+            der.public_key().unwrap(),
+            &cert.get_key_pair().public_key_der()[..]
+        );
+        assert!(Certificate::from_der(&[0x30, 0x03, 0x30, 0x01])
+            .unwrap()
+            .public_key()
+            .is_err());
+    }
+
+    #[cfg(feature = "dangerous_configuration")]
+    #[test]
+    fn pinned_keys() {
+        use rustls::{ClientCertVerifier, ServerCertVerifier};
+
+        let (trusted, _) = certified("trusted");
+        let (untrusted, _) = certified("untrusted");
+        let key = public_key(&trusted.certs[0].0).unwrap().to_vec();
+        let verifier = PinnedKeyVerifier::new(vec![key]);
+        assert!(verifier.verify_client_cert(&trusted.certs).is_ok());
+        assert!(verifier.verify_client_cert(&untrusted.certs).is_err());
+        assert!(verifier.verify_client_cert(&[]).is_err());
+        let name = webpki::DNSNameRef::try_from_ascii_str("example.com").unwrap();
+        let roots = rustls::RootCertStore::empty();
+        assert!(verifier
+            .verify_server_cert(&roots, &trusted.certs, name, &[])
+            .is_ok());
+        assert!(verifier
+            .verify_server_cert(&roots, &untrusted.certs, name, &[])
+            .is_err());
+    }
+
+    #[test]
+    fn ticket_key_rotation() {
+        let keys = TicketKeys::new(&[1; 32], 3600);
+        let other = TicketKeys::new(&[1; 32], 3600);
+        let first = keys.encrypt(b"first").unwrap();
+        assert_eq!(other.decrypt(&first).unwrap(), b"first");
+
+        keys.rotate(&[2; 32]);
+        let second = keys.encrypt(b"second").unwrap();
+        assert_eq!(keys.decrypt(&first).unwrap(), b"first");
+        assert_eq!(keys.decrypt(&second).unwrap(), b"second");
+        assert!(other.decrypt(&second).is_none());
+
+        keys.rotate(&[3; 32]);
+        assert!(keys.decrypt(&first).is_none());
+        assert_eq!(keys.decrypt(&second).unwrap(), b"second");
+        assert!(keys.decrypt(&second[..8]).is_none());
+    }
+
+    #[test]
+    fn reload() {
+        let (old_chain, old_key) = certified("localhost");
+        let old = old_chain.certs[0].0.clone();
+        let reloadable = ReloadableCertificate::new(old_chain, old_key).unwrap();
+        let served = || reloadable.resolve(None, &[]).map(|x| x.cert[0].0.clone());
+        assert_eq!(served(), Some(old));
+
+        let (new_chain, new_key) = certified("localhost");
+        let new = new_chain.certs[0].0.clone();
+        reloadable.replace(new_chain, new_key).unwrap();
+        assert_eq!(served(), Some(new));
+    }
+
+    #[test]
+    fn file_session_cache() {
+        let dir = std::env::temp_dir().join(format!("quinn-sessions-{}", std::process::id()));
+        let cache = FileSessionCache::new(&dir).unwrap();
+        assert_eq!(cache.get(b"example.com"), None);
+        assert!(cache.put(b"example.com".to_vec(), b"ticket".to_vec()));
+        assert!(cache.put(b"example.com".to_vec(), b"newer ticket".to_vec()));
+
+        // A new instance, as after a restart, sees the latest entry
+        let cache = FileSessionCache::new(&dir).unwrap();
+        assert_eq!(cache.get(b"example.com"), Some(b"newer ticket".to_vec()));
+        assert_eq!(cache.get(b"example.org"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
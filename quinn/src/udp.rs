@@ -1,5 +1,5 @@
 use std::{
-    io,
+    io::{self, IoSliceMut},
     net::SocketAddr,
     task::{Context, Poll},
 };
@@ -9,50 +9,92 @@ use mio;
 
 use tokio::io::PollEvented;
 
-use proto::{EcnCodepoint, Transmit};
+use proto::Transmit;
 
-use crate::platform::UdpExt;
+use crate::{
+    platform::{self, UdpExt},
+    runtime::{AsyncUdpSocket, RecvMeta},
+};
 
 /// Tokio-compatible UDP socket with some useful specializations.
 ///
 /// Unlike a standard tokio UDP socket, this allows ECN bits to be read and written on some
-/// platforms.
+/// platforms, and sends batches of datagrams with segmentation offload where available. With the
+/// `io-uring` feature, I/O goes through io_uring instead of waiting for readiness, if the kernel
+/// supports it.
 #[derive(Debug)]
 pub struct UdpSocket {
+    // Dropped before the socket, as it may still be in use by queued operations
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring: Option<platform::uring::Uring>,
     io: PollEvented<mio::net::UdpSocket>,
+    /// Number of datagrams which may be sent as one with segmentation offload
+    max_segments: usize,
 }
 
 impl UdpSocket {
     pub fn from_std(socket: std::net::UdpSocket) -> io::Result<UdpSocket> {
         let io = mio::net::UdpSocket::from_socket(socket)?;
         io.init_ext()?;
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let uring = match platform::uring::Uring::new(&io) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                tracing::debug!("io_uring unavailable, waiting for readiness instead: {}", e);
+                None
+            }
+        };
         let io = PollEvented::new(io)?;
-        Ok(UdpSocket { io })
+        Ok(UdpSocket {
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring,
+            io,
+            max_segments: platform::max_gso_segments(),
+        })
     }
+}
 
-    pub fn poll_send(
-        &self,
-        cx: &mut Context,
-        transmits: &[Transmit],
-    ) -> Poll<Result<usize, io::Error>> {
-        ready!(self.io.poll_write_ready(cx))?;
-        match self.io.get_ref().send_ext(transmits) {
-            Ok(n) => Poll::Ready(Ok(n)),
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                self.io.clear_write_ready(cx)?;
-                Poll::Pending
+impl AsyncUdpSocket for UdpSocket {
+    fn poll_send(&mut self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(ref mut uring) = self.uring {
+                return uring.poll_send(cx, transmits);
             }
-            Err(e) => Poll::Ready(Err(e)),
+        }
+        ready!(self.io.poll_write_ready(cx))?;
+        loop {
+            return match self.io.get_ref().send_ext(transmits, self.max_segments) {
+                Ok(n) => Poll::Ready(Ok(n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.io.clear_write_ready(cx)?;
+                    Poll::Pending
+                }
+                // Segmentation offload fails with EIO when the interface can't compute checksums
+                Err(ref e) if self.max_segments > 1 && e.raw_os_error() == Some(libc::EIO) => {
+                    tracing::debug!("segmentation offload unavailable, disabling it");
+                    self.max_segments = 1;
+                    continue;
+                }
+                Err(e) => Poll::Ready(Err(e)),
+            };
         }
     }
 
-    pub fn poll_recv(
-        &self,
+    fn poll_recv(
+        &mut self,
         cx: &mut Context,
-        buf: &mut [u8],
-    ) -> Poll<Result<(usize, SocketAddr, Option<EcnCodepoint>), io::Error>> {
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(ref mut uring) = self.uring {
+                return uring.poll_recv(cx, bufs, meta);
+            }
+        }
         ready!(self.io.poll_read_ready(cx, mio::Ready::readable()))?;
-        match self.io.get_ref().recv_ext(buf) {
+        match self.io.get_ref().recv_ext(bufs, meta) {
             Ok(n) => Poll::Ready(Ok(n)),
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 self.io.clear_read_ready(cx, mio::Ready::readable())?;
@@ -62,12 +104,7 @@ impl UdpSocket {
         }
     }
 
-    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
         self.io.get_ref().local_addr()
     }
 }
-
-/// Number of UDP packets to send at a time
-///
-/// Chosen somewhat arbitrarily; might benefit from additional tuning.
-pub const BATCH_SIZE: usize = 32;